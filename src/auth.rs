@@ -0,0 +1,48 @@
+use anyhow::Result;
+use futures_util::future::BoxFuture;
+use std::fmt::Debug;
+
+/// Credentials presented to the server on login. The secret is sent as the login password, so
+/// token-based schemes (JWT, OAuth) only need a server that validates the token.
+#[derive(Clone)]
+pub struct AuthCredentials {
+    pub password: String,
+}
+
+impl Debug for AuthCredentials {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("AuthCredentials")
+            .field("password", &"***")
+            .finish()
+    }
+}
+
+/// Provides the credentials for each login attempt, it is called on every (re)connect so
+/// short-lived tokens can be refreshed.
+pub trait AuthProvider: Debug + Send + Sync {
+    fn credentials(&self) -> BoxFuture<'_, Result<AuthCredentials>>;
+}
+
+/// The default provider, which always returns the password from ClientConfig.
+#[derive(Debug, Clone)]
+pub struct StaticPasswordProvider {
+    password: String,
+}
+
+impl StaticPasswordProvider {
+    pub fn new(password: &str) -> Self {
+        Self {
+            password: password.to_string(),
+        }
+    }
+}
+
+impl AuthProvider for StaticPasswordProvider {
+    fn credentials(&self) -> BoxFuture<'_, Result<AuthCredentials>> {
+        Box::pin(async move {
+            Ok(AuthCredentials {
+                password: self.password.clone(),
+            })
+        })
+    }
+}
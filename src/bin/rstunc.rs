@@ -3,6 +3,7 @@ use clap::builder::TypedValueParser as _;
 use clap::Parser;
 use log::error;
 use rstun::*;
+use std::net::{IpAddr, SocketAddr};
 
 fn main() {
     let args = RstuncArgs::parse();
@@ -29,6 +30,103 @@ fn main() {
         error!("{e}");
     });
 
+    let config = config.and_then(|mut config| {
+        config.source_ip_pool = parse_source_ip_pool(&args.source_ip_pool).map_err(|e| {
+            error!("{e}");
+        })?;
+        config.local_bind_addr = args.local_bind_addr;
+        config.hop_jitter_percent = args.hop_jitter_percent;
+        config.migration_port_range =
+            parse_port_range(&args.migration_port_range).map_err(|e| {
+                error!("{e}");
+            })?;
+        config.server_name_override = args.server_name.clone();
+        config.fallback_server_addrs = args
+            .fallback_servers
+            .split(',')
+            .filter(|addr| !addr.is_empty())
+            .map(|addr| addr.to_string())
+            .collect();
+        config.failover_after_attempts = args.failover_after;
+        config.warm_standby = args.warm_standby;
+        config.max_inbound_connections = args.max_inbound_connections;
+        config.max_udp_sessions = args.max_udp_sessions;
+        config.enable_0rtt = args.enable_0rtt;
+        config.client_cert_path = args.client_cert.clone();
+        config.client_key_path = args.client_key.clone();
+        config.connection_label = args.connection_label.clone();
+        config.stream_receive_window = args.stream_receive_window;
+        config.receive_window = args.receive_window;
+        config.send_window = args.send_window;
+        config.max_concurrent_bidi_streams = args.max_concurrent_bidi_streams;
+        config.max_concurrent_uni_streams = args.max_concurrent_uni_streams;
+        config.congestion = args.congestion.parse().map_err(|e| {
+            error!("{e}");
+        })?;
+        config.doh_servers = args
+            .doh
+            .split(',')
+            .filter(|url| !url.is_empty())
+            .map(|url| url.to_string())
+            .collect();
+        config.verify_dane = args.verify_dane;
+        config.pinned_spki_sha256 = args.pin_spki.clone();
+        config.allow_insecure = args.allow_insecure;
+        config.max_cert_chain_len = args.max_cert_chain_len;
+        config.cert_expiry_warning_days = args.cert_expiry_warning_days;
+        config.reject_expired_cert = args.reject_expired_cert;
+        config.run_as_uid = args.uid;
+        config.run_as_gid = args.gid;
+        config.alpn_protocols = Some(
+            args.alpn
+                .split(',')
+                .filter(|p| !p.is_empty())
+                .map(|p| p.to_string())
+                .collect(),
+        );
+        config.dns_strategy = args.dns_strategy.parse().map_err(|e| {
+            error!("{e}");
+        })?;
+        config.dns_query_ordering = args.dns_query_ordering.parse().map_err(|e| {
+            error!("{e}");
+        })?;
+        config.report_dns_resolution = args.report_dns_resolution;
+        config.dns_cache_ttl_secs = Some(args.dns_cache_ttl_secs);
+        config.dns_stale_grace_secs = args.dns_stale_grace_secs;
+        config.max_tx_bps = args.max_tx_bps;
+        config.max_rx_bps = args.max_rx_bps;
+        config.migration_fallback_grace_ms = args.migration_fallback_grace_ms;
+        config.migrate_on_loss_threshold_percent = args.migrate_on_loss_percent;
+        config.migrate_on_rtt_threshold_ms = args.migrate_on_rtt_ms;
+        config.stream_idle_timeout_ms = args.stream_idle_timeout_ms;
+        config.drain_timeout_ms = args.drain_timeout_ms;
+        config.proxy_handshake_timeout_ms = args.proxy_handshake_timeout_ms;
+        config.traffic_report_interval_secs = Some(args.traffic_report_interval_secs);
+        config.heartbeat_interval_secs = args.heartbeat_interval_secs;
+        config.datagram_heartbeat_interval_ms = args.datagram_heartbeat_interval_ms;
+        config.password_challenge = args.password_challenge;
+        config.metrics_addr = args.metrics_addr;
+        config.initial_rtt_ms = args.initial_rtt_ms;
+        config.max_lifetime_reconnects = args.max_lifetime_reconnects;
+        config.retry_policy.max_delay_ms = args.retry_max_delay_ms;
+        config.retry_policy.max_times = args.retry_max_times;
+        config.retry_policy.connect_timeout_ms = args.connect_timeout_ms;
+        // the mappings overriding the retry policy copied it before these were set
+        for tunnel in &mut config.tunnels {
+            if let Some(retry_policy) = tunnel.retry_policy.as_mut() {
+                retry_policy.max_delay_ms = args.retry_max_delay_ms;
+                retry_policy.max_times = args.retry_max_times;
+                if retry_policy.connect_timeout_ms == 0 {
+                    retry_policy.connect_timeout_ms = args.connect_timeout_ms;
+                }
+            }
+        }
+        config.check_flow_control_windows().map_err(|e| {
+            error!("{e}");
+        })?;
+        Ok(config)
+    });
+
     if let Ok(config) = config {
         let mut client = Client::new(config);
 
@@ -45,6 +143,31 @@ fn main() {
     }
 }
 
+fn parse_source_ip_pool(ips: &str) -> Result<Vec<IpAddr>, String> {
+    ips.split(',')
+        .filter(|ip| !ip.trim().is_empty())
+        .map(|ip| {
+            ip.trim()
+                .parse::<IpAddr>()
+                .map_err(|_| format!("invalid IP in --source-ip-pool: {ip}"))
+        })
+        .collect()
+}
+
+fn parse_port_range(range: &str) -> Result<Option<(u16, u16)>, String> {
+    if range.is_empty() {
+        return Ok(None);
+    }
+    let invalid = || format!("invalid --migration-port-range: {range}, expected FIRST-LAST");
+    let (first, last) = range.split_once('-').ok_or_else(invalid)?;
+    let first = first.trim().parse::<u16>().map_err(|_| invalid())?;
+    let last = last.trim().parse::<u16>().map_err(|_| invalid())?;
+    if first == 0 || first > last {
+        return Err(invalid());
+    }
+    Ok(Some((first, last)))
+}
+
 #[derive(Parser, Debug)]
 #[command(author, version, about, long_about = None)]
 struct RstuncArgs {
@@ -52,17 +175,49 @@ struct RstuncArgs {
     #[arg(short = 'a', long)]
     server_addr: String,
 
+    /// Name to verify the server certificate against and send as SNI, e.g. when connecting by IP to a server whose certificate is issued for a domain
+    #[arg(long)]
+    server_name: Option<String>,
+
+    /// Comma-separated servers to fail over to in order when the server is unreachable
+    #[arg(long, default_value = "")]
+    fallback_servers: String,
+
+    /// Number of failed connect attempts in a row before failing over to the next server
+    #[arg(long, default_value_t = 3)]
+    failover_after: u32,
+
     /// Password for server authentication (must match server's --password)
     #[arg(short = 'p', long, required = true)]
     password: String,
 
+    /// Log in with an HMAC of a nonce sent by the server instead of the password, falls back to sending the password to servers that don't support it
+    #[arg(long, default_value_t = false)]
+    password_challenge: bool,
+
     /// Comma-separated list of TCP tunnel mappings. Each mapping is in the form MODE^[ip:]port^[ip:]port, e.g. OUT^8080^0.0.0.0:9090
     /// MODE is either OUT or IN. Use OUT^8000^ANY to use the server's default upstream for OUT mode.
+    /// Append ^ONEWAY if data only flows towards the upstream, e.g. OUT^5140^10.0.0.5:514^ONEWAY
+    /// Use IN^unix:/run/app.sock^9000 to send the connections of an IN mapping to a Unix socket (Unix only)
+    /// Append ^PROXY_PROTOCOL to IN mappings to send the address of the client to the upstream in a PROXY protocol v2 header
+    /// Use OUT^1080^SOCKS5 to serve SOCKS5 locally and connect to the destinations clients ask for
+    /// Use OUT^8118^HTTP likewise to serve as an HTTP CONNECT proxy
+    /// Append ^ALLOW=PATTERN or ^DENY=PATTERN to SOCKS5/HTTP mappings to restrict the destinations,
+    /// PATTERN is a host, *.domain or CIDR, e.g. OUT^1080^SOCKS5^ALLOW=*.example.com^DENY=10.0.0.0/8
+    /// Append ^GROUP to mappings that should all stop once any of them fails fatally
+    /// Append ^COALESCE=MS to batch small writes for up to MS milliseconds, e.g. for bulk transfers
+    /// Append ^COMPRESS=zstd or ^COMPRESS=lz4 to compress the tunneled data (compression feature)
+    /// Append ^CC=NAME, ^STREAM_WINDOW=BYTES, ^RECV_WINDOW=BYTES, ^SEND_WINDOW=BYTES or ^KEEPALIVE=MS
+    /// to override the client-wide transport settings for the tunnel
+    /// Append ^HOP=MS to migrate the tunnel at its own interval, ^HOP=0 never migrates it
     #[arg(short = 't', long, verbatim_doc_comment, default_value = "")]
     tcp_mappings: String,
 
     /// Comma-separated list of UDP tunnel mappings. Each mapping is in the form MODE^[ip:]port^[ip:]port, e.g. OUT^8080^0.0.0.0:9090
     /// MODE is either OUT or IN. Use OUT^8000^ANY to use the server's default upstream for OUT mode.
+    /// Append ^DATAGRAM to carry the packets in QUIC datagrams, which aren't retransmitted, e.g. for games or VoIP
+    /// Append ^GROUP to mappings that should all stop once any of them fails fatally
+    /// Append ^CC=NAME, ^RECV_WINDOW=BYTES, ^KEEPALIVE=MS etc. as for TCP mappings
     #[arg(short = 'u', long, verbatim_doc_comment, default_value = "")]
     udp_mappings: String,
 
@@ -83,10 +238,41 @@ struct RstuncArgs {
     #[arg(short = 'r', long, default_value_t = 5000)]
     wait_before_retry_ms: u64,
 
+    /// Max delay in milliseconds the retry backoff grows to from --wait-before-retry-ms
+    #[arg(long, default_value_t = 10000)]
+    retry_max_delay_ms: u64,
+
+    /// Timeout in milliseconds of each connect attempt, the handshake and the login included, doubled on every retry, 0 leaves it to the QUIC handshake
+    #[arg(long, default_value_t = 0)]
+    connect_timeout_ms: u64,
+
+    /// Give up after retrying to connect this many times in a row, 0 means retrying forever
+    #[arg(long, default_value_t = 0)]
+    retry_max_times: usize,
+
+    /// Stop a tunnel once it reconnected this many times after losing its connection, 0 means
+    /// unlimited
+    #[arg(long, default_value_t = 0)]
+    max_lifetime_reconnects: u64,
+
+    /// Max connections the IN TCP tunnels have open to their local servers at once, the
+    /// streams the server opens beyond it are rejected, 0 means unlimited
+    #[arg(long, default_value_t = 0)]
+    max_inbound_connections: usize,
+
+    /// Max UDP sessions the IN UDP tunnels relay to their local servers at once, the new ones
+    /// beyond it are rejected, 0 means unlimited
+    #[arg(long, default_value_t = 0)]
+    max_udp_sessions: usize,
+
     /// QUIC idle timeout in milliseconds
     #[arg(long, default_value_t = 30000)]
     quic_timeout_ms: u64,
 
+    /// RTT in milliseconds assumed before it's measured, e.g. 600 for satellite links, 0 means 333
+    #[arg(long, default_value_t = 0)]
+    initial_rtt_ms: u64,
+
     /// TCP idle timeout in milliseconds
     #[arg(long, default_value_t = 30000)]
     tcp_timeout_ms: u64,
@@ -95,9 +281,73 @@ struct RstuncArgs {
     #[arg(long, default_value_t = 5000)]
     udp_timeout_ms: u64,
 
+    /// Close the tunneled TCP streams idle in both directions for this many milliseconds while
+    /// keeping the connection up, 0 disables it
+    #[arg(long, default_value_t = 0)]
+    stream_idle_timeout_ms: u64,
+
+    /// On Ctrl-C, wait up to this many milliseconds for the tunneled TCP streams in flight to
+    /// finish before closing the connections, 0 closes them right away
+    #[arg(long, default_value_t = 0)]
+    drain_timeout_ms: u64,
+
+    /// Drop the local clients of the SOCKS5 and HTTP proxies that don't complete the handshake
+    /// within this many milliseconds, 0 means --tcp-timeout-ms
+    #[arg(long, default_value_t = 0)]
+    proxy_handshake_timeout_ms: u64,
+
     #[arg(long, default_value_t = 0)]
     hop_interval_ms: u64,
 
+    /// Randomize each migration interval by up to this percent of --hop-interval-ms, 0 disables it
+    #[arg(long, default_value_t = 0, value_parser = clap::value_parser!(u8).range(0..=100))]
+    hop_jitter_percent: u8,
+
+    /// Local port range migrations move to in turn, e.g. "40000-40100", random ports if not set
+    #[arg(long, default_value = "")]
+    migration_port_range: String,
+
+    /// Move back to the old local port if the server isn't heard from within this many
+    /// milliseconds after a migration, 0 disables it
+    #[arg(long, default_value_t = 0)]
+    migration_fallback_grace_ms: u64,
+
+    /// Migrate once this percent of the packets sent within a second or so are lost, 0 disables it
+    #[arg(long, default_value_t = 0, value_parser = clap::value_parser!(u8).range(0..=100))]
+    migrate_on_loss_percent: u8,
+
+    /// Migrate once the RTT of a connection reaches this many milliseconds, 0 disables it
+    #[arg(long, default_value_t = 0)]
+    migrate_on_rtt_ms: u64,
+
+    /// Per-stream QUIC receive window in bytes, 0 means 1MB
+    #[arg(long, default_value_t = 0)]
+    stream_receive_window: u64,
+
+    /// Per-connection QUIC receive window in bytes, 0 means 2MB
+    #[arg(long, default_value_t = 0)]
+    receive_window: u64,
+
+    /// Per-connection QUIC send window in bytes, 0 means 2MB
+    #[arg(long, default_value_t = 0)]
+    send_window: u64,
+
+    /// Max concurrent bidirectional streams, 0 means the quinn default [default: 1024]
+    #[arg(long)]
+    max_concurrent_bidi_streams: Option<u64>,
+
+    /// Max concurrent unidirectional streams, 0 means the quinn default [default: 1024]
+    #[arg(long)]
+    max_concurrent_uni_streams: Option<u64>,
+
+    /// Congestion controller, one of bbr, cubic and newreno
+    #[arg(long, default_value = "bbr")]
+    congestion: String,
+
+    /// Comma-separated DoH server URLs for DNS resolution, e.g. "https://dns.google/dns-query". Tried before --dot and --dns (doh feature).
+    #[arg(long, verbatim_doc_comment, default_value = "")]
+    doh: String,
+
     /// Comma-separated DoT servers (domains) for DNS resolution, e.g. "dns.google,one.one.one.one". Takes precedence over --dns if set.
     #[arg(long, verbatim_doc_comment, default_value = "")]
     dot: String,
@@ -106,6 +356,115 @@ struct RstuncArgs {
     #[arg(long, verbatim_doc_comment, default_value = "")]
     dns: String,
 
+    /// Address families to resolve the server domain to, one of ipv4, ipv6, both, ipv4-then-ipv6 and ipv6-then-ipv4
+    #[arg(long, default_value = "both")]
+    dns_strategy: String,
+
+    /// Order to query the --dot/--dns servers in, statistics (fastest first) or configured
+    #[arg(long, default_value = "statistics")]
+    dns_query_ordering: String,
+
+    /// Log all the addresses the server domain resolves to and which one is selected
+    #[arg(long, default_value_t = false)]
+    report_dns_resolution: bool,
+
+    /// Reuse the resolved server address for this many seconds before resolving it again, 0 disables caching
+    #[arg(long, default_value_t = 60)]
+    dns_cache_ttl_secs: u64,
+
+    /// Keep using the expired server address for this many seconds while resolving it fails, 0 disables it
+    #[arg(long, default_value_t = 0)]
+    dns_stale_grace_secs: u64,
+
+    /// Comma-separated local IPs to originate connections from, rotated per connection and per migration, e.g. "10.0.0.2,10.0.0.3"
+    #[arg(long, verbatim_doc_comment, default_value = "")]
+    source_ip_pool: String,
+
+    /// Local IP to originate connections from, e.g. the IP of the VPN interface, ignored if --source-ip-pool is set
+    #[arg(long)]
+    local_bind_addr: Option<IpAddr>,
+
+    /// Keep a second logged-in idle connection per outbound tunnel to fail over to without reconnecting
+    #[arg(long, default_value_t = false)]
+    warm_standby: bool,
+
+    /// Send the login as 0-RTT early data when reconnecting with a cached session ticket, which can be replayed
+    #[arg(long, default_value_t = false)]
+    enable_0rtt: bool,
+
+    /// Verify the server certificate against the DNSSEC-validated TLSA records of its domain (dane feature)
+    #[arg(long, default_value_t = false)]
+    verify_dane: bool,
+
+    /// Accept the server certificate by the hex SHA-256 of its SubjectPublicKeyInfo, e.g. for a self-signed server reached by IP
+    #[arg(long, default_value = "")]
+    pin_spki: String,
+
+    /// Skip the verification of the server certificate when connecting by IP without --cert, for testing only
+    #[arg(long, default_value_t = false)]
+    allow_insecure: bool,
+
+    /// Reject server certificate chains longer than this, the server certificate included
+    #[arg(long)]
+    max_cert_chain_len: Option<usize>,
+
+    /// Post the CertExpiryWarning event when the server certificate expires within this many days, 0 disables it
+    #[arg(long, default_value_t = 0)]
+    cert_expiry_warning_days: u32,
+
+    /// Close the connection if the server certificate has expired, e.g. with --pin-spki, which doesn't check it
+    #[arg(long, default_value_t = false)]
+    reject_expired_cert: bool,
+
+    /// Comma-separated ALPN protocols offered to the server, empty for servers predating ALPN
+    #[arg(long, default_value = DEFAULT_ALPN_PROTOCOL)]
+    alpn: String,
+
+    /// Max bits per second sent into all the tunnels, 0 means unlimited
+    #[arg(long, default_value_t = 0)]
+    max_tx_bps: u64,
+
+    /// Max bits per second received from all the tunnels, 0 means unlimited
+    #[arg(long, default_value_t = 0)]
+    max_rx_bps: u64,
+
+    /// Interval in seconds for reporting the traffic data, 0 disables it
+    #[arg(long, default_value_t = 30)]
+    traffic_report_interval_secs: u64,
+
+    /// Interval in seconds for posting the heartbeat event, 0 disables it
+    #[arg(long, default_value_t = 0)]
+    heartbeat_interval_secs: u64,
+
+    /// Interval in milliseconds for sending a heartbeat in a QUIC datagram on each tunnel to measure its RTT, 0 disables it
+    #[arg(long, default_value_t = 0)]
+    datagram_heartbeat_interval_ms: u64,
+
+    /// Address to serve the Prometheus metrics on at /metrics, e.g. 127.0.0.1:9090, needs the
+    /// metrics feature
+    #[arg(long)]
+    metrics_addr: Option<SocketAddr>,
+
+    /// Path to the client certificate for servers requiring mutual TLS
+    #[arg(long, default_value = "")]
+    client_cert: String,
+
+    /// Path to the key of the client certificate
+    #[arg(long, default_value = "")]
+    client_key: String,
+
+    /// Switch to this user ID once the local servers are bound, e.g. to privileged ports (Unix)
+    #[arg(long)]
+    uid: Option<u32>,
+
+    /// Switch to this group ID once the local servers are bound (Unix)
+    #[arg(long)]
+    gid: Option<u32>,
+
+    /// Label sent to the server and logged alongside the connection, e.g. "$(hostname)-web"
+    #[arg(long)]
+    connection_label: Option<String>,
+
     /// Log level
     #[arg(short = 'l', long, default_value_t = String::from("I"),
         value_parser = PossibleValuesParser::new(["T", "D", "I", "W", "E"]).map(|v| match v.as_str() {
@@ -57,6 +57,16 @@ async fn run(mut args: RstundArgs) -> Result<()> {
         udp_timeout_ms: args.udp_timeout_ms,
         dashboard_server: "".to_string(),
         dashboard_server_credential: "".to_string(),
+        allow_icmp: args.allow_icmp,
+        accept_0rtt: args.accept_0rtt,
+        client_ca_path: args.client_ca,
+        alpn_protocols: Some(
+            args.alpn
+                .split(',')
+                .filter(|p| !p.is_empty())
+                .map(|p| p.to_string())
+                .collect(),
+        ),
     };
 
     let mut server = Server::new(config);
@@ -147,6 +157,22 @@ struct RstundArgs {
     #[arg(long, default_value_t = 5000)]
     udp_timeout_ms: u64,
 
+    /// Send ICMP echo requests (ping) on behalf of the clients, requires a build with the icmp feature
+    #[arg(long, default_value_t = false)]
+    allow_icmp: bool,
+
+    /// Accept the logins clients send as 0-RTT early data when resuming, which can be replayed
+    #[arg(long, default_value_t = false)]
+    accept_0rtt: bool,
+
+    /// Path to the CA certificates the client certificates must chain to, enables mutual TLS
+    #[arg(long, default_value = "")]
+    client_ca: String,
+
+    /// Comma-separated ALPN protocols accepted from the clients, empty for clients predating ALPN
+    #[arg(long, default_value = DEFAULT_ALPN_PROTOCOL)]
+    alpn: String,
+
     /// Log level
     #[arg(short = 'l', long, default_value_t = String::from("I"),
         value_parser = PossibleValuesParser::new(["T", "D", "I", "W", "E"]).map(|v| match v.as_str() {
@@ -1,40 +1,110 @@
 use crate::{
+    effective_alpn_protocols,
+    otel::OtelSpan,
     pem_util, socket_addr_with_unspecified_ip_port,
-    tcp::{tcp_tunnel::TcpTunnel, AsyncStream, StreamReceiver, StreamRequest},
-    tunnel_info_bridge::{TunnelInfo, TunnelInfoBridge, TunnelInfoType, TunnelTraffic},
-    tunnel_message::TunnelMessage,
-    udp::{udp_server::UdpServer, udp_tunnel::UdpTunnel, UdpReceiver, UdpSender},
-    ClientConfig, LoginInfo, SelectedCipherSuite, TcpServer, Tunnel, TunnelConfig, TunnelMode,
-    UpstreamType,
+    tcp::{
+        destination_policy::DestinationPolicy, http_connect, proxy_protocol::ProxyHeader, socks5,
+        tcp_tunnel::TcpTunnel, AsyncStream, DialBacklog, DialTarget, IdleStreams, StreamMessage,
+        StreamReceiver, StreamRequest,
+    },
+    tunnel_info_bridge::{
+        CertExpiryWarning, CertVerificationFailure, CongestionSample, ConnectionEvent,
+        ConnectionEventKind, ConnectionStats, HandshakeLatency, Heartbeat, InboundBacklog,
+        InfoFormat, LoginFailure, LoginFailureReason, MigrationSuspension, MigrationUnsupported,
+        MtuBlackHole, PerTunnelTraffic, ReconnectBackoff, ReconnectLimitReached, ServerResolution,
+        TunnelHeartbeat, TunnelInfo, TunnelInfoBridge, TunnelInfoType, TunnelLogRecord,
+        TunnelStats, TunnelTraffic,
+    },
+    tunnel_message::{
+        password_hmac, LoginReqExt, LoginRespExt, PingRequest, TunnelMessage, ERR_BAD_PASSWORD,
+    },
+    udp::{
+        udp_server::UdpServer,
+        udp_tunnel::{UdpSessions, UdpTunnel},
+        UdpReceiver, UdpSender,
+    },
+    util::{
+        heartbeat::{DatagramHeartbeats, HeartbeatProbe},
+        rate_limiter::RateLimiters,
+    },
+    AuthProvider, ClientConfig, Compression, CongestionControl, DnsStrategy, LoginInfo,
+    QuicTransport, RetryPolicy, SelectedCipherSuite, StaticPasswordProvider, TcpServer, Transport,
+    Tunnel, TunnelConfig, TunnelMode, UpstreamType, SUPPORTED_CIPHER_SUITE_STRS,
 };
 use anyhow::{bail, Context, Result};
-use backon::ExponentialBuilder;
 use backon::Retryable;
+use futures_util::future::join_all;
+use futures_util::stream::{FuturesUnordered, StreamExt};
 use log::{debug, error, info, warn};
 use quinn::{congestion, crypto::rustls::QuicClientConfig, Connection, Endpoint, TransportConfig};
-use quinn::{IdleTimeout, VarInt};
+use quinn::{IdleTimeout, RecvStream, VarInt, ZeroRttAccepted};
 use rs_utilities::dns::{self, DNSQueryOrdering, DNSResolverConfig, DNSResolverLookupIpStrategy};
 use rs_utilities::log_and_bail;
 use rustls::{
-    client::danger::ServerCertVerified,
+    client::{
+        danger::{ServerCertVerified, ServerCertVerifier},
+        ClientSessionMemoryCache, ClientSessionStore, Resumption, WebPkiServerVerifier,
+    },
     crypto::{ring::cipher_suite, CryptoProvider},
     RootCertStore, SupportedCipherSuite,
 };
-use rustls_platform_verifier::{self, BuilderVerifierExt};
 use serde::Serialize;
-use std::collections::HashMap;
+use std::collections::{HashMap, VecDeque};
+use std::sync::atomic::{AtomicU32, AtomicU64, Ordering};
 use std::{
     fmt::Display,
-    net::{IpAddr, SocketAddr},
+    net::{IpAddr, Ipv6Addr, SocketAddr, SocketAddrV6},
     str::FromStr,
     sync::{Arc, Mutex, Once},
     time::Duration,
 };
+use tokio::io::BufReader;
 use tokio::net::TcpStream;
+use tokio::runtime::RuntimeFlavor;
+use tokio::sync::{watch, Notify, Semaphore};
+use tokio::task::JoinHandle;
+use x509_parser::prelude::{FromDer, GeneralName, X509Certificate};
 
 const TIME_FORMAT: &str = "%Y-%m-%d %H:%M:%S.%3f";
 const DEFAULT_SERVER_PORT: u16 = 3515;
-const POST_TRAFFIC_DATA_INTERVAL_SECS: u64 = 30;
+const DEFAULT_TRAFFIC_REPORT_INTERVAL_SECS: u64 = 30;
+const DEFAULT_SHUTDOWN_TIMEOUT_MS: u64 = 3000;
+const MTU_BLACK_HOLE_WATCH_SECS: u64 = 5;
+/// how long a migration holds its permit after rebinding, for the new path to be validated
+const MIGRATION_SETTLE_MS: u64 = 500;
+/// how long the server has to answer on the new path of a migration without
+/// `migration_fallback_grace_ms`, before the migration counts as unanswered
+const MIGRATION_PROBE_MS: u64 = 3000;
+/// migrations in a row the server didn't answer on the new path, after which it's taken not to
+/// support migration and the migration stops, a single one may be a lost packet
+const MAX_UNANSWERED_MIGRATIONS: u32 = 2;
+const DEFAULT_FAILOVER_AFTER_ATTEMPTS: u32 = 3;
+const DEFAULT_DNS_CACHE_TTL_SECS: u64 = 60;
+/// connect attempts of a tunnel in a row the server certificate failed verification in before
+/// the client terminates, a single one may be a captive portal
+const MAX_CERT_VERIFICATION_FAILURES: u32 = 3;
+const PRIMARY_SERVER_PROBE_INTERVAL_SECS: u64 = 30;
+/// how often the migration task looks for endpoints bound since, which then start their schedule
+const MIGRATION_SCHEDULE_POLL_MS: u64 = 1000;
+/// stands in for the secrets in `Client::export_config`
+const REDACTED: &str = "<redacted>";
+const MTU_BLACK_HOLE_MIN_SENT_PACKETS: u64 = 10;
+/// packets the connections of an endpoint must have sent since the previous sample for their
+/// loss rate to trigger a migration
+const PATH_DEGRADATION_MIN_SENT_PACKETS: u64 = 20;
+/// an endpoint migrated for a degraded path isn't migrated for that again within this, so a
+/// path that's slow wherever it goes doesn't keep hopping
+const PATH_DEGRADATION_COOLDOWN_SECS: u64 = 30;
+const CONNECT_RESULT_WINDOW: usize = 20;
+const PING_PAYLOAD_LEN: u16 = 56;
+const MAX_WORKERS_PER_CPU: usize = 8;
+const DEFAULT_BIND_MAX_RETRIES: usize = 10;
+const DEFAULT_MAX_CONCURRENT_STREAMS: u64 = 1024;
+/// servers the session tickets are kept for, the primary, fallbacks and redirects
+const TLS_SESSION_CACHE_SIZE: usize = 32;
+/// the Connection Attempt Delay of Happy Eyeballs (RFC 8305), before the next server address
+/// is tried alongside the ones still connecting
+const HAPPY_EYEBALLS_DELAY_MS: u64 = 250;
 static INIT: Once = Once::new();
 
 #[derive(Clone, Serialize, PartialEq)]
@@ -62,29 +132,254 @@ impl Display for ClientState {
     }
 }
 
+#[derive(Default)]
+struct TunnelRecord {
+    effective_quic_timeout_ms: Option<u64>,
+    /// whether the latest login sent early data in 0-RTT, which the server may have replayed
+    used_0rtt: Option<bool>,
+    /// results of the last CONNECT_RESULT_WINDOW connection attempts, oldest first
+    connect_results: VecDeque<bool>,
+    /// kept across reconnects of an inbound TCP tunnel, so the peak covers its lifetime
+    dial_backlog: Option<Arc<DialBacklog>>,
+    /// times the tunnel reconnected after its connection closed, over the client's lifetime
+    reconnects: u64,
+    /// why the latest connect attempt failed, cleared once one succeeds
+    last_error: Option<String>,
+    /// whether the latest login of a UDP tunnel carries the packets in QUIC datagrams
+    udp_datagrams: Option<bool>,
+    /// cipher suite of the latest connection, e.g. aes-128-gcm
+    negotiated_cipher: Option<String>,
+    /// whether the server of an inbound TCP tunnel with `TunnelConfig::proxy_protocol` set sends
+    /// the addresses of its clients
+    proxy_protocol: Option<bool>,
+    /// the compression of a TCP tunnel with `TunnelConfig::compression` set the server agreed
+    /// on, None if it fell back to no compression
+    compression: Option<Compression>,
+    /// when the connection serving the tunnel was set up, None while it's not connected
+    connected_since: Option<std::time::Instant>,
+    /// how long the QUIC handshake of the latest connection took
+    handshake_ms: Option<u64>,
+    /// how long the login exchange of the latest connection took, after the handshake
+    login_ms: Option<u64>,
+    /// whether the server agreed to echo the datagram heartbeats at the latest login
+    datagram_heartbeat: bool,
+    /// whether the server resolves the domain names the local clients of a SOCKS5 or HTTP
+    /// CONNECT tunnel ask for, as of the latest login
+    domain_destinations: bool,
+    /// the heartbeats of the connection serving the tunnel, None while it's not connected
+    heartbeat: Option<Arc<HeartbeatProbe>>,
+}
+
+impl TunnelRecord {
+    fn record_connect_result(&mut self, succeeded: bool) {
+        if self.connect_results.len() == CONNECT_RESULT_WINDOW {
+            self.connect_results.pop_front();
+        }
+        self.connect_results.push_back(succeeded);
+        if succeeded {
+            self.last_error = None;
+        }
+    }
+}
+
+/// a server address resolved from its domain, see `ClientConfig::dns_cache_ttl_secs`
+struct CachedServerAddr {
+    addr: SocketAddr,
+    resolved_at: std::time::Instant,
+}
+
+type StateListener = Arc<Mutex<dyn FnMut(ClientState) + 'static + Send + Sync>>;
+
 struct State {
+    tunnel_records: HashMap<usize, TunnelRecord>,
+    /// tasks serving the network based tunnels, so a single one can be removed
+    tunnel_tasks: HashMap<usize, JoinHandle<()>>,
+    /// local server addresses of the network based tunnels, known once their hosts resolved
+    tunnel_local_addrs: HashMap<usize, SocketAddr>,
+    /// index of the next tunnel added by `Client::add_tunnel`
+    next_tunnel_index: usize,
     tcp_servers: HashMap<SocketAddr, TcpServer>,
     udp_servers: HashMap<SocketAddr, UdpServer>,
     endpoint: Option<Endpoint>,
+    /// endpoints owned by a single tunnel, used when the source IP is chosen per connection or
+    /// the tunnel overrides the hop interval
+    tunnel_endpoints: HashMap<usize, Endpoint>,
+    /// hop intervals of the tunnel endpoints overriding `ClientConfig::hop_interval_ms`
+    tunnel_hop_intervals: HashMap<usize, u64>,
+    source_ip_index: usize,
+    /// of the port within `migration_port_range` bound last
+    migration_port_offset: usize,
+    runtime_handle: Option<tokio::runtime::Handle>,
+    /// set once an MTU black hole is detected, PLPMTUD is disabled for new connections
+    mtu_clamped: bool,
+    /// the periodic migration is skipped until then, see `Client::suspend_migration`
+    migration_suspended_until: Option<tokio::time::Instant>,
+    /// the periodic migration is skipped until resumed, see `Client::pause_migration`
+    migration_paused: bool,
+    /// migrations in a row the server didn't answer on the new path
+    unanswered_migrations: u32,
+    /// set once the server is taken not to support migration, which stops the migration task
+    migration_unsupported: bool,
+    /// set once the server rejects a login asking for the password challenge, which it doesn't
+    /// support, the password is sent as is from then on
+    password_challenge_unsupported: bool,
     connections: HashMap<SocketAddr, Connection>,
+    /// set up on the first `Client::ping`
+    icmp_conn: Option<Connection>,
+    server_resolution: Option<ServerResolution>,
+    /// keyed by the DOMAIN:PORT server address
+    dns_cache: HashMap<String, CachedServerAddr>,
+    /// index into [server_addr] + fallback_server_addrs of the server connected to
+    server_index: usize,
+    /// connect attempts of all the tunnels that failed in a row
+    connect_failures: u32,
     client_state: ClientState,
     total_traffic_data: TunnelTraffic,
+    /// endpoints rebound to a new local address so far
+    migrations: u64,
     tunnel_info_bridge: TunnelInfoBridge,
     on_info_report_enabled: bool,
+    state_listener: Option<StateListener>,
 }
 
 impl State {
     fn new() -> Self {
         Self {
+            tunnel_records: HashMap::new(),
+            tunnel_tasks: HashMap::new(),
+            tunnel_local_addrs: HashMap::new(),
+            next_tunnel_index: 0,
             tcp_servers: HashMap::new(),
             udp_servers: HashMap::new(),
             endpoint: None,
+            tunnel_endpoints: HashMap::new(),
+            tunnel_hop_intervals: HashMap::new(),
+            source_ip_index: 0,
+            migration_port_offset: 0,
+            runtime_handle: None,
+            mtu_clamped: false,
+            migration_suspended_until: None,
+            migration_paused: false,
+            unanswered_migrations: 0,
+            migration_unsupported: false,
+            password_challenge_unsupported: false,
             connections: HashMap::new(),
+            icmp_conn: None,
+            server_resolution: None,
+            dns_cache: HashMap::new(),
+            server_index: 0,
+            connect_failures: 0,
             client_state: ClientState::Idle,
             total_traffic_data: TunnelTraffic::default(),
+            migrations: 0,
             tunnel_info_bridge: TunnelInfoBridge::new(),
             on_info_report_enabled: false,
+            state_listener: None,
+        }
+    }
+
+    /// the traffic of the client so far, and that of the current connection of each tunnel
+    /// keyed by its local server address
+    fn traffic(&self) -> (TunnelTraffic, PerTunnelTraffic) {
+        let mut total = self.total_traffic_data.clone();
+        let mut per_tunnel = PerTunnelTraffic::default();
+        for (local_server_addr, conn) in &self.connections {
+            let traffic = TunnelTraffic::from(&conn.stats());
+            total.add(&traffic);
+            per_tunnel.tunnels.insert(*local_server_addr, traffic);
         }
+        (total, per_tunnel)
+    }
+
+    /// the tunnels sending datagram heartbeats, ordered by index
+    fn tunnel_heartbeats(&self) -> Vec<TunnelHeartbeat> {
+        let mut heartbeats: Vec<_> = self
+            .tunnel_records
+            .iter()
+            .filter_map(|(index, record)| {
+                let probe = record.heartbeat.as_ref()?;
+                Some(TunnelHeartbeat {
+                    index: *index,
+                    rtt_ms: probe.rtt_ms(),
+                    unanswered: probe.unanswered(),
+                })
+            })
+            .collect();
+        heartbeats.sort_by_key(|heartbeat| heartbeat.index);
+        heartbeats
+    }
+
+    fn endpoints(&self) -> Vec<Endpoint> {
+        self.endpoint
+            .iter()
+            .chain(self.tunnel_endpoints.values())
+            .cloned()
+            .collect()
+    }
+
+    /// the endpoints with the connections using them, keyed by the index of the tunnel owning
+    /// them, None for the shared endpoint
+    fn endpoints_with_connections(&self) -> Vec<(Option<usize>, Endpoint, Vec<Connection>)> {
+        let owned_addrs: HashMap<usize, SocketAddr> = self
+            .tunnel_endpoints
+            .keys()
+            .filter_map(|index| Some((*index, *self.tunnel_local_addrs.get(index)?)))
+            .collect();
+        let shared = self.endpoint.iter().map(|endpoint| {
+            let conns = self
+                .connections
+                .iter()
+                .filter(|(addr, _)| !owned_addrs.values().any(|owned| owned == *addr))
+                .map(|(_, conn)| conn.clone())
+                .collect();
+            (None, endpoint.clone(), conns)
+        });
+        let owned = self.tunnel_endpoints.iter().map(|(index, endpoint)| {
+            let conns = owned_addrs
+                .get(index)
+                .and_then(|addr| self.connections.get(addr))
+                .cloned()
+                .into_iter()
+                .collect();
+            (Some(*index), endpoint.clone(), conns)
+        });
+        shared.chain(owned).collect()
+    }
+
+    /// the connections using `endpoint`, told apart by the local address, as each endpoint is
+    /// bound to a socket of its own
+    fn endpoint_connections(&self, endpoint: &Endpoint) -> Vec<Connection> {
+        let Ok(local_addr) = endpoint.local_addr() else {
+            return Vec::new();
+        };
+        self.endpoints_with_connections()
+            .into_iter()
+            .find(|(_, candidate, _)| candidate.local_addr().is_ok_and(|addr| addr == local_addr))
+            .map(|(_, _, conns)| conns)
+            .unwrap_or_default()
+    }
+
+    /// the endpoints with their hop intervals and the connections using them, keyed by the
+    /// index of the tunnel owning them, None for the shared endpoint. The endpoints of tunnels
+    /// pinned with HOP=0 are left out, as they never migrate
+    fn migration_schedule(
+        &self,
+        default_hop_interval_ms: u64,
+    ) -> Vec<(Option<usize>, Endpoint, u64, Vec<Connection>)> {
+        self.endpoints_with_connections()
+            .into_iter()
+            .filter_map(|(index, endpoint, conns)| {
+                let hop_interval_ms = match index {
+                    Some(index) => match self.tunnel_hop_intervals.get(&index) {
+                        Some(0) => return None,
+                        Some(hop_interval_ms) => *hop_interval_ms,
+                        None => default_hop_interval_ms,
+                    },
+                    None => default_hop_interval_ms,
+                };
+                Some((index, endpoint, hop_interval_ms, conns))
+            })
+            .collect()
     }
 
     fn post_tunnel_info<T>(&self, server_info: TunnelInfo<T>)
@@ -98,7 +393,6 @@ impl State {
 }
 
 struct LoginConfig {
-    local_addr: SocketAddr,
     remote_addr: SocketAddr,
     quinn_client_cfg: quinn::ClientConfig,
     domain: String,
@@ -108,6 +402,31 @@ struct LoginConfig {
 pub struct Client {
     config: ClientConfig,
     inner_state: Arc<Mutex<State>>,
+    connect_permits: Arc<Semaphore>,
+    migration_permits: Arc<Semaphore>,
+    /// set to true once a tunnel of the fail_together group fails fatally
+    tunnel_group_failed: Arc<watch::Sender<bool>>,
+    /// shared by all the tunnels, also counting the bytes for the per-direction rates
+    rate_limiters: RateLimiters,
+    /// the address the server redirected the client to, or the one failed over to, used
+    /// instead of `server_addr`
+    server_redirect: Arc<watch::Sender<Option<String>>>,
+    /// streams of the TCP tunnels swept for idleness, and waited for by the drain on stop,
+    /// None if both `stream_idle_timeout_ms` and `drain_timeout_ms` are 0
+    idle_streams: Option<Arc<IdleStreams>>,
+    /// shared by the inbound TCP tunnels, None if `max_inbound_connections` is 0
+    inbound_connection_permits: Option<Arc<Semaphore>>,
+    /// shared by the inbound UDP tunnels, capped by `max_udp_sessions`
+    udp_sessions: Arc<UdpSessions>,
+    /// the latest server certificate that failed verification, for reporting why
+    rejected_cert: Arc<Mutex<Option<RejectedCert>>>,
+    /// number of destinations the SOCKS5 and HTTP tunnels refused by their allow/deny patterns
+    denied_destinations: Arc<AtomicU64>,
+    /// the client state as set by `set_and_post_tunnel_state`, awaited by `Client::spawn`
+    state_changes: Arc<watch::Sender<ClientState>>,
+    /// the TLS session tickets of the servers, kept across the rustls configs built for each
+    /// connect, so reconnects resume the session
+    tls_sessions: Arc<RecordingSessionStore>,
 }
 
 macro_rules! inner_state {
@@ -117,16 +436,41 @@ macro_rules! inner_state {
 }
 
 impl Client {
-    pub fn new(config: ClientConfig) -> Self {
+    pub fn new(mut config: ClientConfig) -> Self {
         INIT.call_once(|| {
             rustls::crypto::ring::default_provider()
                 .install_default()
                 .unwrap();
         });
 
+        config.workers = Self::worker_threads(config.workers);
+        let connect_permits = Arc::new(Semaphore::new(Self::max_connect_permits(&config)));
+        let migration_permits = Arc::new(Semaphore::new(Self::max_migration_permits(&config)));
+        let rate_limiters = RateLimiters::new(config.max_tx_bps, config.max_rx_bps);
+        let idle_streams =
+            (config.stream_idle_timeout_ms > 0 || config.drain_timeout_ms > 0).then(|| {
+                Arc::new(IdleStreams::new(Duration::from_millis(
+                    config.stream_idle_timeout_ms,
+                )))
+            });
+        let inbound_connection_permits = (config.max_inbound_connections > 0)
+            .then(|| Arc::new(Semaphore::new(config.max_inbound_connections)));
+        let udp_sessions = Arc::new(UdpSessions::new(config.max_udp_sessions));
         Client {
             config,
             inner_state: Arc::new(Mutex::new(State::new())),
+            connect_permits,
+            migration_permits,
+            tunnel_group_failed: Arc::new(watch::Sender::new(false)),
+            rate_limiters,
+            server_redirect: Arc::new(watch::Sender::new(None)),
+            idle_streams,
+            inbound_connection_permits,
+            udp_sessions,
+            rejected_cert: Arc::new(Mutex::new(None)),
+            denied_destinations: Arc::new(AtomicU64::new(0)),
+            state_changes: Arc::new(watch::Sender::new(ClientState::Idle)),
+            tls_sessions: Arc::new(RecordingSessionStore::new(TLS_SESSION_CACHE_SIZE)),
         }
     }
 
@@ -147,26 +491,233 @@ impl Client {
             });
     }
 
+    /// Starts tunneling on the current runtime, unlike `start_tunneling` it doesn't block. The
+    /// returned handle completes once the client is stopped or terminates, and aborting it
+    /// stops the client, so it can be managed by the caller's own supervisor
+    pub fn spawn(&mut self) -> JoinHandle<()> {
+        self.connect_and_serve_async();
+
+        let mut state_changes = self.state_changes.subscribe();
+        let mut stop_on_abort = StopOnAbort(Some(self.clone()));
+        tokio::spawn(async move {
+            state_changes
+                .wait_for(|state| {
+                    *state == ClientState::Stopping || *state == ClientState::Terminated
+                })
+                .await
+                .ok();
+            stop_on_abort.disarm();
+        })
+    }
+
     pub fn connect_and_serve_async(&mut self) {
-        for (index, tunnel_config) in self.config.tunnels.iter().cloned().enumerate() {
-            let mut this = self.clone();
+        inner_state!(self, runtime_handle) = Some(tokio::runtime::Handle::current());
+        if self.config.run_as_uid.is_some() || self.config.run_as_gid.is_some() {
+            let this = self.clone();
             tokio::spawn(async move {
-                this.connect_and_serve::<TcpStream>(
-                    index,
-                    Tunnel::NetworkBased(tunnel_config),
-                    None,
-                    None,
-                )
-                .await;
+                match this.bind_local_servers_and_drop_privileges().await {
+                    Ok(tunnels) => this.spawn_tunnels(tunnels),
+                    Err(e) => {
+                        this.post_tunnel_log(format!("won't start tunneling: {e:#}").as_str());
+                        this.set_and_post_tunnel_state(ClientState::Terminated);
+                    }
+                }
             });
+        } else {
+            self.spawn_tunnels(self.config.tunnels.clone());
         }
 
         self.report_traffic_data_in_background();
-        if self.config.hop_interval_ms > 0 {
+        self.serve_metrics_in_background();
+        self.sweep_idle_streams_in_background();
+        self.post_heartbeats_in_background();
+        if self.is_migration_enabled() {
             self.start_migration_task();
         }
     }
 
+    fn spawn_tunnels(&self, tunnels: Vec<TunnelConfig>) {
+        let runtime_handle = tokio::runtime::Handle::current();
+        for (index, tunnel_config) in tunnels.into_iter().enumerate() {
+            self.spawn_tunnel(&runtime_handle, index, tunnel_config);
+        }
+    }
+
+    fn spawn_tunnel(
+        &self,
+        runtime_handle: &tokio::runtime::Handle,
+        index: usize,
+        tunnel_config: TunnelConfig,
+    ) {
+        let mut this = self.clone();
+        let task = runtime_handle.spawn(async move {
+            let fail_together = tunnel_config.fail_together;
+            let tunnel_config = match Self::resolve_local_server_host(tunnel_config).await {
+                Ok(tunnel_config) => tunnel_config,
+                Err(e) => {
+                    this.post_tunnel_log(format!("{index}:{e}").as_str());
+                    if fail_together {
+                        this.fail_tunnel_group(index);
+                    }
+                    return;
+                }
+            };
+            if let Some(addr) = tunnel_config.tracking_addr() {
+                inner_state!(this, tunnel_local_addrs).insert(index, addr);
+            }
+
+            this.connect_and_serve::<TcpStream>(
+                index,
+                Tunnel::NetworkBased(Box::new(tunnel_config)),
+                None,
+                None,
+            )
+            .await;
+        });
+        inner_state!(self, tunnel_tasks).insert(index, task);
+    }
+
+    /// Starts tunneling `tunnel_config` alongside the configured tunnels while the client is
+    /// running, and returns the index to remove it with. Its hop interval only takes effect if
+    /// migration was enabled when the client started
+    pub fn add_tunnel(&self, tunnel_config: TunnelConfig) -> Result<usize> {
+        if tunnel_config.tracking_addr().is_none() && tunnel_config.local_server_host.is_none() {
+            log_and_bail!("the tunnel has no local server address");
+        }
+        if let Some(overrides) = &tunnel_config.transport {
+            self.config.check_flow_control_windows_of(overrides)?;
+        }
+        let Some(runtime_handle) = inner_state!(self, runtime_handle).clone() else {
+            log_and_bail!("client is not running, can't add the tunnel");
+        };
+        if self.should_quit() {
+            log_and_bail!("client is stopping, can't add the tunnel");
+        }
+
+        let index = {
+            let mut state = self.inner_state.lock().unwrap();
+            // indexed after the configured tunnels and the ICMP channel
+            let index = state.next_tunnel_index.max(self.config.tunnels.len() + 1);
+            state.next_tunnel_index = index + 1;
+            index
+        };
+        self.post_tunnel_log(format!("{index}:tunnel added").as_str());
+        self.spawn_tunnel(&runtime_handle, index, tunnel_config);
+        Ok(index)
+    }
+
+    /// Stops the tunnel at `index`, either configured or added by `add_tunnel`, closing its
+    /// connection and local server, the other tunnels are unaffected
+    pub fn remove_tunnel(&self, index: usize) -> Result<()> {
+        let (task, conn, tcp_server, udp_server, runtime_handle) = {
+            let mut state = self.inner_state.lock().unwrap();
+            let Some(task) = state.tunnel_tasks.remove(&index) else {
+                log_and_bail!("no tunnel at index {index}");
+            };
+            state.tunnel_records.remove(&index);
+            state.tunnel_endpoints.remove(&index);
+            state.tunnel_hop_intervals.remove(&index);
+            let addr = state.tunnel_local_addrs.remove(&index);
+            let conn = addr.and_then(|addr| state.connections.remove(&addr));
+            if let (Some(addr), Some(conn)) = (addr, &conn) {
+                state.post_tunnel_info(Self::connection_event(
+                    index,
+                    addr,
+                    conn,
+                    ConnectionEventKind::Closed,
+                    Some("tunnel removed".to_string()),
+                ));
+            }
+            (
+                task,
+                conn,
+                addr.and_then(|addr| state.tcp_servers.remove(&addr)),
+                addr.and_then(|addr| state.udp_servers.remove(&addr)),
+                state.runtime_handle.clone(),
+            )
+        };
+
+        task.abort();
+        if let Some(conn) = conn {
+            conn.close(VarInt::from_u32(0), b"tunnel removed");
+        }
+        if let Some(runtime_handle) = runtime_handle {
+            runtime_handle.spawn(async move {
+                if let Some(mut s) = tcp_server {
+                    s.shutdown().await.ok();
+                }
+                if let Some(mut s) = udp_server {
+                    s.shutdown().await.ok();
+                }
+            });
+        }
+        self.post_tunnel_log(format!("{index}:tunnel removed").as_str());
+        Ok(())
+    }
+
+    /// Closes the connection of a network based tunnel, which then logs in again as after any
+    /// disconnect, e.g. once its upstream restarted. The local server, the other tunnels and
+    /// the migration are unaffected, and the reconnect counts towards `max_lifetime_reconnects`
+    pub fn reconnect_tunnel(&self, index: usize) -> Result<()> {
+        let conn = {
+            let state = self.inner_state.lock().unwrap();
+            let Some(addr) = state.tunnel_local_addrs.get(&index) else {
+                log_and_bail!("no tunnel at index {index}");
+            };
+            state.connections.get(addr).cloned()
+        };
+        let Some(conn) = conn.filter(|conn| conn.close_reason().is_none()) else {
+            log_and_bail!("{index}:tunnel isn't connected");
+        };
+
+        conn.close(VarInt::from_u32(0), b"reconnect requested");
+        self.post_tunnel_log(format!("{index}:reconnect requested").as_str());
+        Ok(())
+    }
+
+    /// Binds the local servers of the outbound tunnels, which the tunnels pick up once logged
+    /// in, then switches to the configured user. Any failure stops all the tunnels rather than
+    /// keep running privileged
+    async fn bind_local_servers_and_drop_privileges(&self) -> Result<Vec<TunnelConfig>> {
+        let mut tunnels = Vec::with_capacity(self.config.tunnels.len());
+        for tunnel_config in self.config.tunnels.iter().cloned() {
+            let mut tunnel_config = Self::resolve_local_server_host(tunnel_config).await?;
+            // resolved once, so the tunnel serves from the server bound here
+            tunnel_config.local_server_host = None;
+
+            if tunnel_config.mode == TunnelMode::Out {
+                let addr = tunnel_config.local_server_addr.unwrap();
+                let retry_policy = self.retry_policy(Some(&tunnel_config));
+                match tunnel_config.upstream.upstream_type {
+                    UpstreamType::Tcp | UpstreamType::Socks5 | UpstreamType::HttpConnect => {
+                        self.start_tunnel_tcp_server(&tunnel_config).await?;
+                    }
+                    UpstreamType::Udp => {
+                        self.start_udp_server_with_policy(addr, &retry_policy)
+                            .await?;
+                    }
+                }
+            }
+            tunnels.push(tunnel_config);
+        }
+
+        self.drop_privileges()?;
+        Ok(tunnels)
+    }
+
+    #[cfg(unix)]
+    fn drop_privileges(&self) -> Result<()> {
+        let (uid, gid) = (self.config.run_as_uid, self.config.run_as_gid);
+        crate::privilege::drop_privileges(uid, gid)?;
+        self.post_tunnel_log(format!("dropped privileges, uid:{uid:?}, gid:{gid:?}").as_str());
+        Ok(())
+    }
+
+    #[cfg(not(unix))]
+    fn drop_privileges(&self) -> Result<()> {
+        log_and_bail!("dropping privileges is only supported on Unix");
+    }
+
     pub fn connect_and_serve_tcp_async<S: AsyncStream>(
         &mut self,
         stream_receiver: StreamReceiver<S>,
@@ -196,807 +747,4284 @@ impl Client {
         });
     }
 
+    fn is_migration_enabled(&self) -> bool {
+        self.config.hop_interval_ms > 0
+            || self.is_degradation_migration_enabled()
+            || self
+                .config
+                .tunnels
+                .iter()
+                .any(|tunnel| tunnel.hop_interval_ms.is_some_and(|ms| ms > 0))
+    }
+
+    fn is_degradation_migration_enabled(&self) -> bool {
+        self.config.migrate_on_loss_threshold_percent > 0
+            || self.config.migrate_on_rtt_threshold_ms > 0
+    }
+
+    /// Migrates each endpoint on its own schedule, as the tunnels owning their endpoints may
+    /// override the hop interval, and also once its path degrades, see `path_degradation`
     fn start_migration_task(&self) {
-        let state = self.inner_state.clone();
+        let this = self.clone();
         let hop_interval = self.config.hop_interval_ms;
+        let jitter_percent = self.config.hop_jitter_percent;
 
         tokio::spawn(async move {
-            let mut interval = tokio::time::interval(Duration::from_millis(hop_interval));
-            interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Skip);
-            interval.tick().await;
-
+            // recomputed for every migration, so they don't follow a fixed period
+            let next_hop = |interval_ms| {
+                tokio::time::Instant::now()
+                    + Duration::from_millis(jittered_interval_ms(interval_ms, jitter_percent))
+            };
+            let mut next_hops: HashMap<Option<usize>, tokio::time::Instant> = HashMap::new();
+            let mut path_samples = HashMap::new();
+            let mut degradation_cooldowns: HashMap<Option<usize>, tokio::time::Instant> =
+                HashMap::new();
             loop {
-                interval.tick().await;
+                let schedule = {
+                    let state = this.inner_state.lock().unwrap();
+                    if state.migration_unsupported {
+                        break;
+                    }
+                    state.migration_schedule(hop_interval)
+                };
+                next_hops.retain(|key, _| schedule.iter().any(|(k, ..)| k == key));
+                degradation_cooldowns.retain(|key, _| schedule.iter().any(|(k, ..)| k == key));
+
+                let now = tokio::time::Instant::now();
+                let mut due = Vec::new();
+                let mut sampled = Vec::new();
+                for (key, endpoint, interval_ms, conns) in schedule {
+                    let degradation = this.path_degradation(&conns, &mut path_samples);
+                    sampled.extend(conns.iter().map(Connection::stable_id));
+                    if let Some(degradation) = degradation {
+                        let cooling_down = degradation_cooldowns
+                            .get(&key)
+                            .is_some_and(|until| *until > now);
+                        if !cooling_down {
+                            this.post_tunnel_log(
+                                format!("path degraded ({degradation}), migrating").as_str(),
+                            );
+                            degradation_cooldowns.insert(
+                                key,
+                                now + Duration::from_secs(PATH_DEGRADATION_COOLDOWN_SECS),
+                            );
+                            if interval_ms > 0 {
+                                next_hops.insert(key, next_hop(interval_ms));
+                            }
+                            due.push(endpoint);
+                            continue;
+                        }
+                    }
+
+                    if interval_ms == 0 {
+                        next_hops.remove(&key);
+                        continue;
+                    }
+                    let next = next_hops
+                        .entry(key)
+                        .or_insert_with(|| next_hop(interval_ms));
+                    if *next <= now {
+                        *next = next_hop(interval_ms);
+                        due.push(endpoint);
+                    }
+                }
+                path_samples.retain(|id, _| sampled.contains(id));
 
-                let endpoint = { state.lock().unwrap().endpoint.clone() };
-                if let Some(endpoint) = endpoint {
-                    Self::migrate_endpoint(&endpoint).await.ok();
+                // the hops due while suspended are skipped
+                if !due.is_empty() && !this.is_migration_suspended() {
+                    join_all(
+                        due.iter()
+                            .map(|endpoint| this.migrate_endpoint_with_permit(endpoint)),
+                    )
+                    .await;
+                    this.watch_for_mtu_black_holes();
                 }
+
+                // endpoints bound in the meantime are picked up on the next poll
+                let poll_at = now + Duration::from_millis(MIGRATION_SCHEDULE_POLL_MS);
+                let wake_at = next_hops.values().copied().fold(poll_at, std::cmp::min);
+                tokio::time::sleep_until(wake_at).await;
             }
         });
     }
 
-    async fn migrate_endpoint(endpoint: &Endpoint) -> Result<()> {
-        let current_addr = endpoint.local_addr()?;
-        let new_addr = socket_addr_with_unspecified_ip_port(current_addr.is_ipv6());
-        let socket = std::net::UdpSocket::bind(new_addr)?;
-        debug!(
-            "endpoint will migrated from {} to {}",
-            current_addr,
-            socket.local_addr()?
-        );
-        endpoint.rebind(socket)?;
-        Ok(())
-    }
-
-    pub async fn start_tcp_server(&self, addr: SocketAddr) -> Result<TcpServer> {
-        let bind_tcp_server = || async { TcpServer::bind_and_start(addr).await };
-        let tcp_server = bind_tcp_server
-            .retry(
-                ExponentialBuilder::default()
-                    .with_max_delay(Duration::from_secs(10))
-                    .with_max_times(10),
-            )
-            .sleep(tokio::time::sleep)
-            .notify(|err: &anyhow::Error, dur: Duration| {
-                warn!("will start tcp server ({addr}) after {dur:?}, err: {err:?}");
-            })
-            .await?;
-
-        inner_state!(self, tcp_servers).insert(addr, tcp_server.clone());
+    /// The loss rate of the connections since the previous sample, or the highest of their RTTs,
+    /// if either reached its threshold. The loss rate is only judged once enough packets were
+    /// sent, so a few losses on an idle connection don't count.
+    fn path_degradation(
+        &self,
+        conns: &[Connection],
+        path_samples: &mut HashMap<usize, (u64, u64)>,
+    ) -> Option<String> {
+        let (mut sent_packets, mut lost_packets, mut max_rtt) = (0, 0, Duration::ZERO);
+        for conn in conns.iter().filter(|conn| conn.close_reason().is_none()) {
+            let path = conn.stats().path;
+            let (sent_before, lost_before) = path_samples
+                .insert(conn.stable_id(), (path.sent_packets, path.lost_packets))
+                .unwrap_or((path.sent_packets, path.lost_packets));
+            // the counters restart with a new connection of the same id
+            sent_packets += path.sent_packets.saturating_sub(sent_before);
+            lost_packets += path.lost_packets.saturating_sub(lost_before);
+            max_rtt = max_rtt.max(path.rtt);
+        }
 
-        Ok(tcp_server)
+        let loss_threshold = self.config.migrate_on_loss_threshold_percent as u64;
+        if loss_threshold > 0
+            && sent_packets >= PATH_DEGRADATION_MIN_SENT_PACKETS
+            && lost_packets * 100 >= sent_packets * loss_threshold
+        {
+            return Some(format!("lost {lost_packets} of {sent_packets} packets"));
+        }
+        let rtt_threshold = self.config.migrate_on_rtt_threshold_ms;
+        if rtt_threshold > 0 && max_rtt >= Duration::from_millis(rtt_threshold) {
+            return Some(format!("rtt:{}ms", max_rtt.as_millis()));
+        }
+        None
     }
 
-    pub async fn start_udp_server(&self, addr: SocketAddr) -> Result<UdpServer> {
-        // create a local udp server for 'OUT' tunnel
-        let bind_udp_server = || async { UdpServer::bind_and_start(addr).await };
-        let udp_server = bind_udp_server
-            .retry(
-                ExponentialBuilder::default()
-                    .with_max_delay(Duration::from_secs(10))
-                    .with_max_times(10),
-            )
-            .sleep(tokio::time::sleep)
-            .notify(|err: &anyhow::Error, dur: Duration| {
-                warn!("will start udp server ({addr}) after {dur:?}, err: {err:?}");
-            })
-            .await?;
-
-        inner_state!(self, udp_servers).insert(addr, udp_server.clone());
-        Ok(udp_server)
-    }
+    /// Pins the current path by skipping the periodic migration for `duration`, after which it
+    /// resumes on its own. Suspending again while suspended extends the window if it ends later.
+    pub fn suspend_migration(&self, duration: Duration) {
+        if !self.is_migration_enabled() {
+            debug!("migration is disabled, nothing to suspend");
+            return;
+        }
 
-    pub fn get_config(&self) -> ClientConfig {
-        self.config.clone()
-    }
+        let Some(runtime_handle) = inner_state!(self, runtime_handle).clone() else {
+            warn!("client is not running, can't suspend migration");
+            return;
+        };
 
-    #[allow(clippy::unnecessary_to_owned)]
-    pub fn stop(&self) {
-        self.set_and_post_tunnel_state(ClientState::Stopping);
+        let until = tokio::time::Instant::now() + duration;
+        {
+            let mut state = self.inner_state.lock().unwrap();
+            if state
+                .migration_suspended_until
+                .is_some_and(|prev| prev >= until)
+            {
+                return;
+            }
+            state.migration_suspended_until = Some(until);
+            state.post_tunnel_info(TunnelInfo::new(
+                TunnelInfoType::MigrationSuspension,
+                Box::new(MigrationSuspension {
+                    suspended: true,
+                    duration_ms: duration.as_millis() as u64,
+                }),
+            ));
+        }
+        self.post_tunnel_log(
+            format!("migration suspended for {}ms", duration.as_millis()).as_str(),
+        );
 
-        if let Ok(mut state) = self.inner_state.lock() {
-            for mut s in state.tcp_servers.values().cloned() {
-                tokio::spawn(async move {
-                    s.shutdown().await.ok();
-                });
+        let this = self.clone();
+        runtime_handle.spawn(async move {
+            tokio::time::sleep_until(until).await;
+            // a later call may have extended the window, which resumes on its own timer
+            let mut state = this.inner_state.lock().unwrap();
+            if state.migration_suspended_until != Some(until) {
+                return;
             }
-            for mut s in state.udp_servers.values().cloned() {
-                tokio::spawn(async move {
-                    s.shutdown().await.ok();
-                });
+            state.migration_suspended_until = None;
+            if state.migration_paused {
+                return;
             }
+            state.post_tunnel_info(TunnelInfo::new(
+                TunnelInfoType::MigrationSuspension,
+                Box::new(MigrationSuspension {
+                    suspended: false,
+                    duration_ms: 0,
+                }),
+            ));
+            drop(state);
+            this.post_tunnel_log("migration resumed");
+        });
+    }
 
-            for c in state.connections.values().cloned() {
-                tokio::spawn(async move {
-                    c.close(VarInt::from_u32(1), b"");
-                });
+    /// Skips the periodic migration until `resume_migration` is called, e.g. for a transfer of
+    /// unknown length, the migration task keeps running meanwhile
+    pub fn pause_migration(&self) {
+        if !self.is_migration_enabled() {
+            debug!("migration is disabled, nothing to pause");
+            return;
+        }
+
+        {
+            let mut state = self.inner_state.lock().unwrap();
+            if state.migration_paused {
+                return;
             }
+            state.migration_paused = true;
+            state.post_tunnel_info(TunnelInfo::new(
+                TunnelInfoType::MigrationSuspension,
+                Box::new(MigrationSuspension {
+                    suspended: true,
+                    duration_ms: 0,
+                }),
+            ));
+        }
+        self.post_tunnel_log("migration paused");
+    }
 
-            state.tcp_servers.clear();
-            state.udp_servers.clear();
-            state.connections.clear();
+    /// Resumes the migration paused by `pause_migration` or suspended by `suspend_migration`
+    /// right away
+    pub fn resume_migration(&self) {
+        {
+            let mut state = self.inner_state.lock().unwrap();
+            if !state.migration_paused && state.migration_suspended_until.is_none() {
+                return;
+            }
+            state.migration_paused = false;
+            state.migration_suspended_until = None;
+            state.post_tunnel_info(TunnelInfo::new(
+                TunnelInfoType::MigrationSuspension,
+                Box::new(MigrationSuspension {
+                    suspended: false,
+                    duration_ms: 0,
+                }),
+            ));
         }
+        self.post_tunnel_log("migration resumed");
+    }
 
-        std::thread::sleep(Duration::from_secs(3));
+    fn is_migration_suspended(&self) -> bool {
+        let state = self.inner_state.lock().unwrap();
+        state.migration_paused
+            || state
+                .migration_suspended_until
+                .is_some_and(|until| until > tokio::time::Instant::now())
     }
 
-    #[allow(clippy::unnecessary_to_owned)]
-    pub async fn stop_async(&self) {
-        self.set_and_post_tunnel_state(ClientState::Stopping);
+    /// Rebinds the endpoint right away instead of waiting for the next hop, this is meant to be
+    /// called by embedders when the OS reports a network change (e.g. WiFi ↔ cellular), so the
+    /// connections move to the new default route before they time out. The endpoints are
+    /// migrated in the background, at most `max_concurrent_migrations` at a time
+    pub fn notify_network_changed(&self) {
+        let (endpoints, runtime_handle) = {
+            let state = self.inner_state.lock().unwrap();
+            (state.endpoints(), state.runtime_handle.clone())
+        };
 
-        let mut tasks = tokio::task::JoinSet::new();
-        if let Ok(mut state) = self.inner_state.lock() {
-            for mut s in state.tcp_servers.values().cloned() {
-                tasks.spawn(async move {
-                    s.shutdown().await.ok();
-                });
-            }
-            for mut s in state.udp_servers.values().cloned() {
-                tasks.spawn(async move {
-                    s.shutdown().await.ok();
-                });
-            }
+        let Some(runtime_handle) = runtime_handle.filter(|_| !endpoints.is_empty()) else {
+            debug!("network changed, but there's no endpoint to migrate");
+            return;
+        };
 
-            for c in state.connections.values().cloned() {
-                tasks.spawn(async move {
-                    c.close(VarInt::from_u32(1), b"");
-                });
+        let this = self.clone();
+        runtime_handle.spawn(async move {
+            let results = join_all(
+                endpoints
+                    .iter()
+                    .map(|endpoint| this.migrate_endpoint_with_permit(endpoint)),
+            )
+            .await;
+            for result in results {
+                match result {
+                    Ok(_) => this.post_tunnel_log("network changed, endpoint migrated"),
+                    Err(e) => this.post_tunnel_log(
+                        format!("network changed, failed to migrate endpoint: {e}").as_str(),
+                    ),
+                }
             }
+            this.watch_for_mtu_black_holes();
+        });
+    }
 
-            state.tcp_servers.clear();
-            state.udp_servers.clear();
-            state.connections.clear();
+    /// Migrates all the endpoints right away and returns how many moved once they did, whether
+    /// or not the periodic migration is enabled. Unlike `notify_network_changed`, the caller
+    /// learns the outcome, the error of a failed migration is returned if none moved
+    pub async fn migrate_now(&self) -> Result<usize> {
+        let endpoints = self.inner_state.lock().unwrap().endpoints();
+        if endpoints.is_empty() {
+            bail!("there's no endpoint to migrate");
+        }
+
+        let results = join_all(
+            endpoints
+                .iter()
+                .map(|endpoint| self.migrate_endpoint_with_permit(endpoint)),
+        )
+        .await;
+        let mut migrated = 0;
+        let mut last_err = None;
+        for result in results {
+            match result {
+                Ok(_) => migrated += 1,
+                Err(e) => {
+                    warn!("failed to migrate endpoint: {e}");
+                    last_err = Some(e);
+                }
+            }
         }
 
-        while tasks.join_next().await.is_some() {}
+        if migrated == 0 {
+            return Err(last_err.unwrap_or_else(|| anyhow::anyhow!("no endpoint migrated")));
+        }
+        self.post_tunnel_log(format!("migrated {migrated} endpoint(s) on request").as_str());
+        self.watch_for_mtu_black_holes();
+        Ok(migrated)
     }
 
-    async fn connect_and_serve<S: AsyncStream>(
-        &mut self,
-        index: usize,
-        tunnel: Tunnel,
-        mut stream_receiver: Option<StreamReceiver<S>>,
-        mut ch: Option<(UdpSender, UdpReceiver)>,
-    ) {
-        let login_info = LoginInfo {
-            password: self.config.password.clone(),
-            tunnel: tunnel.clone(),
-        };
+    /// The new path may have a smaller MTU, in which case the large packets are silently
+    /// dropped while the small ones (i.e. acks) still get through, and the connection stalls
+    /// until PLPMTUD recovers. Connections that stall right after the migration are closed,
+    /// and reconnected with MTU discovery disabled.
+    fn watch_for_mtu_black_holes(&self) {
+        let conns: Vec<(SocketAddr, Connection)> = inner_state!(self, connections)
+            .iter()
+            .map(|(addr, conn)| (*addr, conn.clone()))
+            .collect();
+        if conns.is_empty() {
+            return;
+        }
 
-        let mut pending_network_based_stream = None;
-        let mut pending_channel_based_stream = None;
-        loop {
-            let connect = || async {
-                let login_cfg = self.prepare_login_config().await?;
-                let endpoint = { self.inner_state.lock().unwrap().endpoint.clone() };
-                let endpoint = if let Some(endpoint) = endpoint {
-                    Self::migrate_endpoint(&endpoint).await?;
-                    endpoint
-                } else {
-                    let mut endpoint = quinn::Endpoint::client(login_cfg.local_addr)?;
-                    endpoint.set_default_client_config(login_cfg.quinn_client_cfg);
-                    inner_state!(self, endpoint) = Some(endpoint.clone());
-                    endpoint
-                };
+        let this = self.clone();
+        tokio::spawn(async move {
+            let stats_before: Vec<_> = conns.iter().map(|(_, conn)| conn.stats()).collect();
+            tokio::time::sleep(Duration::from_secs(MTU_BLACK_HOLE_WATCH_SECS)).await;
 
-                let conn = self
-                    .login(
-                        index,
-                        &endpoint,
-                        &login_info,
-                        &login_cfg.remote_addr,
-                        login_cfg.domain.as_str(),
-                    )
-                    .await?;
+            for ((local_server_addr, conn), before) in conns.iter().zip(stats_before) {
+                if conn.close_reason().is_some() {
+                    continue;
+                }
 
-                Ok(conn)
+                let after = conn.stats();
+                let sent_packets = after.path.sent_packets - before.path.sent_packets;
+                let lost_packets = after.path.lost_packets - before.path.lost_packets;
+                let detected_by_quinn =
+                    after.path.black_holes_detected > before.path.black_holes_detected;
+                let stalled = after.frame_rx.acks > before.frame_rx.acks
+                    && sent_packets >= MTU_BLACK_HOLE_MIN_SENT_PACKETS
+                    && lost_packets * 2 >= sent_packets;
+                if !detected_by_quinn && !stalled {
+                    continue;
+                }
+
+                let clamped = !detected_by_quinn;
+                if clamped {
+                    inner_state!(this, mtu_clamped) = true;
+                    conn.close(VarInt::from_u32(0), b"mtu black hole");
+                }
+
+                let msg = format!(
+                    "{local_server_addr}:MTU black hole after migration, mtu:{}, lost {lost_packets} of {sent_packets} packets{}",
+                    after.path.current_mtu,
+                    if clamped { ", reconnecting with the minimum MTU" } else { "" }
+                );
+                warn!("{msg}");
+                this.post_tunnel_log(msg.as_str());
+
+                let state = this.inner_state.lock().unwrap();
+                state.post_tunnel_info(TunnelInfo::new(
+                    TunnelInfoType::MtuBlackHole,
+                    Box::new(MtuBlackHole {
+                        local_server_addr: *local_server_addr,
+                        current_mtu: after.path.current_mtu,
+                        sent_packets,
+                        lost_packets,
+                        clamped,
+                    }),
+                ));
+            }
+        });
+    }
+
+    /// Migrates once fewer than `max_concurrent_migrations` are in flight, the permit is held
+    /// for a while after rebinding, so the hops of many endpoints are spread out rather than
+    /// binding a burst of sockets at once
+    async fn migrate_endpoint_with_permit(&self, endpoint: &Endpoint) -> Result<()> {
+        if self.migration_permits.available_permits() == 0 {
+            debug!("waiting for other endpoints to finish migrating");
+        }
+        let _permit = self.migration_permits.acquire().await?;
+
+        let grace_ms = self.config.migration_fallback_grace_ms;
+        let old_addr = endpoint.local_addr()?;
+        // only the connections of this endpoint tell whether its new path works
+        let conns = self
+            .inner_state
+            .lock()
+            .unwrap()
+            .endpoint_connections(endpoint);
+        let rx_before = Self::received_datagrams(&conns);
+        self.migrate_endpoint(endpoint)?;
+        let new_addr = endpoint.local_addr()?;
+
+        if grace_ms > 0 && rx_before.is_some() {
+            tokio::time::sleep(Duration::from_millis(grace_ms)).await;
+            // quinn pings on rebinding, so a working path gets a response within the window
+            let answered = Self::received_datagrams(&conns) != rx_before;
+            if !answered {
+                self.fall_back_to_old_path(endpoint, old_addr);
+            }
+            self.record_migration_answer(answered, new_addr);
+        } else if rx_before.is_some() {
+            // checked in the background, as without the fallback nothing waits for the answer
+            let this = self.clone();
+            let endpoint = endpoint.clone();
+            tokio::spawn(async move {
+                tokio::time::sleep(Duration::from_millis(MIGRATION_PROBE_MS)).await;
+                let answered = Self::received_datagrams(&conns) != rx_before;
+                // unless it hopped again in the meantime, as the old path works
+                if this.record_migration_answer(answered, new_addr)
+                    && endpoint.local_addr().is_ok_and(|addr| addr == new_addr)
+                {
+                    this.fall_back_to_old_path(&endpoint, old_addr);
+                }
+            });
+        }
+        tokio::time::sleep(Duration::from_millis(
+            MIGRATION_SETTLE_MS.saturating_sub(grace_ms),
+        ))
+        .await;
+        Ok(())
+    }
+
+    /// Counts the migrations the server didn't answer on the new path in a row, judged by the
+    /// datagrams received by the connections of the migrated endpoint alone, once there are
+    /// MAX_UNANSWERED_MIGRATIONS of them the server is taken not to support migration, e.g. it
+    /// disabled it, and the migration task stops rather than keep breaking the connections.
+    /// Returns whether it stopped because of this migration
+    fn record_migration_answer(&self, answered: bool, new_addr: SocketAddr) -> bool {
+        let unanswered_migrations = {
+            let mut state = self.inner_state.lock().unwrap();
+            if state.migration_unsupported {
+                return false;
+            }
+            if answered {
+                state.unanswered_migrations = 0;
+                return false;
+            }
+            state.unanswered_migrations += 1;
+            if state.unanswered_migrations < MAX_UNANSWERED_MIGRATIONS {
+                return false;
+            }
+            state.migration_unsupported = true;
+            state.post_tunnel_info(TunnelInfo::new(
+                TunnelInfoType::MigrationUnsupported,
+                Box::new(MigrationUnsupported {
+                    unanswered_migrations: state.unanswered_migrations,
+                    last_local_addr: new_addr,
+                }),
+            ));
+            state.unanswered_migrations
+        };
+        warn!(
+            "the server didn't answer on the new path of {unanswered_migrations} migrations in a row, it may not support migration, migration is disabled"
+        );
+        self.post_tunnel_log(
+            format!("migration disabled, {unanswered_migrations} migrations unanswered by the server, last from {new_addr}")
+                .as_str(),
+        );
+        true
+    }
+
+    /// The old socket is gone with the rebind, so its local address is bound again, which
+    /// brings the connections back to the old path unless another socket took the port since
+    fn fall_back_to_old_path(&self, endpoint: &Endpoint, old_addr: SocketAddr) {
+        let result = std::net::UdpSocket::bind(old_addr)
+            .map_err(anyhow::Error::from)
+            .and_then(|socket| Ok(endpoint.rebind(socket)?));
+        match result {
+            Ok(_) => self.post_tunnel_log(
+                format!("new path not validated, endpoint moved back to {old_addr}").as_str(),
+            ),
+            Err(e) => self.post_tunnel_log(
+                format!("new path not validated, failed to move back to {old_addr}: {e}").as_str(),
+            ),
+        }
+    }
+
+    /// datagrams received by `conns` so far, None if there's no connection
+    fn received_datagrams(conns: &[Connection]) -> Option<u64> {
+        if conns.is_empty() {
+            return None;
+        }
+        Some(conns.iter().map(|conn| conn.stats().udp_rx.datagrams).sum())
+    }
+
+    fn migrate_endpoint(&self, endpoint: &Endpoint) -> Result<()> {
+        let current_addr = endpoint.local_addr()?;
+        let socket = match self.config.migration_port_range {
+            Some(port_range) => self.bind_in_port_range(current_addr, port_range)?,
+            None => self.bind_with_source_ip(current_addr.is_ipv6(), std::net::UdpSocket::bind)?,
+        };
+        debug!(
+            "endpoint will migrated from {} to {}",
+            current_addr,
+            socket.local_addr()?
+        );
+        endpoint.rebind(socket)?;
+        self.inner_state.lock().unwrap().migrations += 1;
+        Ok(())
+    }
+
+    /// Binds the ports of `first..=last` in turn, starting after the one bound last time, until
+    /// one other than the current port can be bound
+    fn bind_in_port_range(
+        &self,
+        current_addr: SocketAddr,
+        (first, last): (u16, u16),
+    ) -> Result<std::net::UdpSocket> {
+        if first == 0 || first > last {
+            log_and_bail!("invalid migration_port_range: {first}-{last}");
+        }
+
+        let ports = (last - first) as usize + 1;
+        for _ in 0..ports {
+            let port = {
+                let mut state = self.inner_state.lock().unwrap();
+                state.migration_port_offset = (state.migration_port_offset + 1) % ports;
+                first + state.migration_port_offset as u16
             };
-            let result = connect
-                .retry(
-                    ExponentialBuilder::default()
-                        .with_max_delay(Duration::from_secs(10))
-                        .with_max_times(usize::MAX),
-                )
-                .when(|_| !self.should_quit())
+            if port == current_addr.port() {
+                continue;
+            }
+
+            match self.bind_with_source_ip(current_addr.is_ipv6(), |addr| {
+                std::net::UdpSocket::bind(SocketAddr::new(addr.ip(), port))
+            }) {
+                Ok(socket) => return Ok(socket),
+                Err(e) => debug!("failed to bind port {port} for migration: {e}"),
+            }
+        }
+
+        log_and_bail!("no port in migration_port_range {first}-{last} can be bound");
+    }
+
+    /// Binds with the next IP of the same family from `source_ip_pool` in a round-robin manner,
+    /// IPs that fail to bind (i.e. not assigned to this host) are skipped. `local_bind_addr`, or
+    /// else the unspecified address, is used if the pool is empty.
+    fn bind_with_source_ip<T>(
+        &self,
+        ipv6: bool,
+        bind: impl Fn(SocketAddr) -> std::io::Result<T>,
+    ) -> Result<T> {
+        let pool: Vec<IpAddr> = self
+            .config
+            .source_ip_pool
+            .iter()
+            .filter(|ip| ip.is_ipv6() == ipv6)
+            .cloned()
+            .collect();
+
+        if pool.is_empty() {
+            if !self.config.source_ip_pool.is_empty() {
+                log_and_bail!(
+                    "no {} address in source_ip_pool",
+                    if ipv6 { "IPv6" } else { "IPv4" }
+                );
+            }
+            if let Some(ip) = self.config.local_bind_addr {
+                if ip.is_ipv6() != ipv6 {
+                    log_and_bail!(
+                        "local_bind_addr {ip} is not an {} address",
+                        if ipv6 { "IPv6" } else { "IPv4" }
+                    );
+                }
+                return bind(SocketAddr::new(ip, 0))
+                    .with_context(|| format!("failed to bind local_bind_addr {ip}"));
+            }
+            return Ok(bind(socket_addr_with_unspecified_ip_port(ipv6))?);
+        }
+
+        for _ in 0..pool.len() {
+            let ip = {
+                let mut state = self.inner_state.lock().unwrap();
+                state.source_ip_index = state.source_ip_index.wrapping_add(1);
+                pool[state.source_ip_index % pool.len()]
+            };
+
+            match bind(SocketAddr::new(ip, 0)) {
+                Ok(bound) => return Ok(bound),
+                Err(e) => warn!(
+                    "source IP {ip} is not usable, probably not assigned to this host, err: {e}"
+                ),
+            }
+        }
+
+        log_and_bail!("none of the IPs in source_ip_pool can be bound: {pool:?}");
+    }
+
+    fn fail_tunnel_group(&self, index: usize) {
+        if !self.tunnel_group_failed.send_replace(true) {
+            self.post_tunnel_log(format!("{index}:failed, stopping the tunnel group").as_str());
+        }
+    }
+
+    /// Resolves `local_server_host` to the first address that can be bound on this host, so the
+    /// same config works on hosts where the name resolves to a different IP.
+    async fn resolve_local_server_host(mut tunnel_config: TunnelConfig) -> Result<TunnelConfig> {
+        let Some(host) = tunnel_config.local_server_host.as_deref() else {
+            return Ok(tunnel_config);
+        };
+
+        let addrs: Vec<SocketAddr> = tokio::net::lookup_host(host)
+            .await
+            .with_context(|| format!("failed to resolve local server host '{host}'"))?
+            .collect();
+
+        // binding to port 0 tells whether the IP is assigned to this host without taking the port
+        let Some(addr) = addrs
+            .iter()
+            .find(|addr| std::net::UdpSocket::bind(SocketAddr::new(addr.ip(), 0)).is_ok())
+        else {
+            log_and_bail!("'{host}' resolved to {addrs:?}, none of which is assigned to this host");
+        };
+
+        info!("local server host '{host}' resolved to {addr}");
+        tunnel_config.local_server_addr = Some(*addr);
+        Ok(tunnel_config)
+    }
+
+    pub async fn start_tcp_server(&self, addr: SocketAddr) -> Result<TcpServer> {
+        self.start_tcp_server_with_policy(addr, &self.config.retry_policy)
+            .await
+    }
+
+    async fn start_tcp_server_with_policy(
+        &self,
+        addr: SocketAddr,
+        retry_policy: &RetryPolicy,
+    ) -> Result<TcpServer> {
+        let bind_tcp_server = || async { TcpServer::bind_and_start(addr).await };
+        let started_at = std::time::Instant::now();
+        let tcp_server = bind_tcp_server
+            .retry(retry_policy.backoff(DEFAULT_BIND_MAX_RETRIES))
+            .when(|_| !retry_policy.is_expired(started_at))
+            .sleep(tokio::time::sleep)
+            .notify(|err: &anyhow::Error, dur: Duration| {
+                warn!("will start tcp server ({addr}) after {dur:?}, err: {err:?}");
+            })
+            .await?;
+
+        inner_state!(self, tcp_servers).insert(addr, tcp_server.clone());
+
+        Ok(tcp_server)
+    }
+
+    /// Binds the local server of an outbound TCP tunnel on local_server_addr and its
+    /// extra_local_server_addrs, the server is shut down if any of them can't be bound
+    async fn start_tunnel_tcp_server(&self, tunnel_config: &TunnelConfig) -> Result<TcpServer> {
+        let addr = tunnel_config.local_server_addr.unwrap();
+        let retry_policy = self.retry_policy(Some(tunnel_config));
+        let mut tcp_server = self
+            .start_tcp_server_with_policy(addr, &retry_policy)
+            .await?;
+
+        for extra_addr in &tunnel_config.extra_local_server_addrs {
+            let listen_also = || async { tcp_server.listen_also(*extra_addr).await };
+            let started_at = std::time::Instant::now();
+            let result = listen_also
+                .retry(retry_policy.backoff(DEFAULT_BIND_MAX_RETRIES))
+                .when(|_| !retry_policy.is_expired(started_at))
                 .sleep(tokio::time::sleep)
                 .notify(|err: &anyhow::Error, dur: Duration| {
-                    warn!("will retry after {dur:?}, err: {err:?}");
+                    warn!("will listen on {extra_addr} for {addr} after {dur:?}, err: {err:?}");
                 })
                 .await;
+            if let Err(e) = result {
+                inner_state!(self, tcp_servers).remove(&addr);
+                tcp_server.shutdown().await.ok();
+                return Err(e.context(format!("failed to listen on {extra_addr} for {addr}")));
+            }
+        }
 
-            if self.should_quit() {
-                break;
+        Ok(tcp_server)
+    }
+
+    /// the addresses the local server of the tunnel listens on, for logging
+    fn listen_addrs(tcp_server: &TcpServer, tunnel_config: &TunnelConfig) -> String {
+        std::iter::once(tcp_server.addr())
+            .chain(tunnel_config.extra_local_server_addrs.iter().copied())
+            .map(|addr| addr.to_string())
+            .collect::<Vec<_>>()
+            .join(" and ")
+    }
+
+    pub async fn start_udp_server(&self, addr: SocketAddr) -> Result<UdpServer> {
+        self.start_udp_server_with_policy(addr, &self.config.retry_policy)
+            .await
+    }
+
+    async fn start_udp_server_with_policy(
+        &self,
+        addr: SocketAddr,
+        retry_policy: &RetryPolicy,
+    ) -> Result<UdpServer> {
+        // create a local udp server for 'OUT' tunnel
+        let bind_udp_server = || async { UdpServer::bind_and_start(addr).await };
+        let started_at = std::time::Instant::now();
+        let udp_server = bind_udp_server
+            .retry(retry_policy.backoff(DEFAULT_BIND_MAX_RETRIES))
+            .when(|_| !retry_policy.is_expired(started_at))
+            .sleep(tokio::time::sleep)
+            .notify(|err: &anyhow::Error, dur: Duration| {
+                warn!("will start udp server ({addr}) after {dur:?}, err: {err:?}");
+            })
+            .await?;
+
+        inner_state!(self, udp_servers).insert(addr, udp_server.clone());
+        Ok(udp_server)
+    }
+
+    /// Number of tunnels that are attempting to connect at the moment
+    pub fn connecting_tunnels(&self) -> usize {
+        Self::max_connect_permits(&self.config) - self.connect_permits.available_permits()
+    }
+
+    /// Number of endpoints that are migrating at the moment
+    pub fn migrating_endpoints(&self) -> usize {
+        Self::max_migration_permits(&self.config) - self.migration_permits.available_permits()
+    }
+
+    /// the tunnel's own policy if set, channel-based tunnels always use the client-wide one
+    fn retry_policy(&self, tunnel_config: Option<&TunnelConfig>) -> RetryPolicy {
+        tunnel_config
+            .and_then(|cfg| cfg.retry_policy.clone())
+            .unwrap_or_else(|| self.config.retry_policy.clone())
+    }
+
+    /// 0 means the number of logical CPUs, the runtime builder panics on 0 workers
+    fn worker_threads(workers: usize) -> usize {
+        let cpus = num_cpus::get();
+        let max_workers = cpus * MAX_WORKERS_PER_CPU;
+        if workers == 0 {
+            cpus
+        } else if workers > max_workers {
+            warn!("{workers} workers requested, capped at {max_workers} for {cpus} CPUs");
+            max_workers
+        } else {
+            workers
+        }
+    }
+
+    fn max_connect_permits(config: &ClientConfig) -> usize {
+        if config.max_concurrent_reconnects > 0 {
+            config.max_concurrent_reconnects
+        } else {
+            Semaphore::MAX_PERMITS
+        }
+    }
+
+    fn max_migration_permits(config: &ClientConfig) -> usize {
+        if config.max_concurrent_migrations > 0 {
+            config.max_concurrent_migrations
+        } else {
+            Semaphore::MAX_PERMITS
+        }
+    }
+
+    pub fn get_config(&self) -> ClientConfig {
+        self.config.clone()
+    }
+
+    /// Blocking version of `stop_async`, which returns once the shutdown finishes or
+    /// `drain_timeout_ms` and `shutdown_timeout_ms` elapse. A current-thread runtime can't be
+    /// blocked on, so the shutdown is left to finish in the background there.
+    #[allow(clippy::unnecessary_to_owned)]
+    pub fn stop(&self) {
+        let current = tokio::runtime::Handle::try_current().ok();
+        let in_runtime = current.is_some();
+        let Some(handle) = current.or_else(|| inner_state!(self, runtime_handle).clone()) else {
+            // never started, so there's no task to wait for, closing the connections doesn't
+            // require a runtime
+            self.set_and_post_tunnel_state(ClientState::Stopping);
+            let mut state = self.inner_state.lock().unwrap();
+            for c in state.connections.values() {
+                c.close(VarInt::from_u32(1), b"");
+            }
+            if let Some(c) = state.icmp_conn.take() {
+                c.close(VarInt::from_u32(1), b"");
             }
+            state.tcp_servers.clear();
+            state.udp_servers.clear();
+            state.connections.clear();
+            return;
+        };
 
-            match result {
-                Ok(conn) => match &tunnel {
-                    Tunnel::NetworkBased(tunnel_config) => {
-                        let local_server_addr = tunnel_config.local_server_addr.unwrap();
-                        inner_state!(self, connections).insert(local_server_addr, conn.clone());
+        if handle.runtime_flavor() != RuntimeFlavor::MultiThread {
+            let this = self.clone();
+            handle.spawn(async move { this.stop_async().await });
+        } else if in_runtime {
+            tokio::task::block_in_place(|| handle.block_on(self.stop_async()));
+        } else {
+            handle.block_on(self.stop_async());
+        }
+    }
+
+    /// Stops the local servers, waits up to `drain_timeout_ms` for the TCP streams in flight to
+    /// finish, then closes the connections
+    #[allow(clippy::unnecessary_to_owned)]
+    pub async fn stop_async(&self) {
+        self.set_and_post_tunnel_state(ClientState::Stopping);
+
+        let mut tasks = tokio::task::JoinSet::new();
+        let mut connections = Vec::new();
+        if let Ok(mut state) = self.inner_state.lock() {
+            for mut s in state.tcp_servers.values().cloned() {
+                tasks.spawn(async move {
+                    s.shutdown().await.ok();
+                });
+            }
+            for mut s in state.udp_servers.values().cloned() {
+                tasks.spawn(async move {
+                    s.shutdown().await.ok();
+                });
+            }
+
+            // the tunnels drop theirs as they stop, these stay open for the drain
+            connections = state.connections.values().cloned().collect();
+
+            if let Some(c) = state.icmp_conn.take() {
+                c.close(VarInt::from_u32(1), b"");
+            }
+
+            state.tcp_servers.clear();
+            state.udp_servers.clear();
+            state.connections.clear();
+        }
+
+        self.drain_streams().await;
+        for c in connections {
+            tasks.spawn(async move {
+                c.close(VarInt::from_u32(1), b"");
+            });
+        }
+
+        let shutdown_timeout_ms = if self.config.shutdown_timeout_ms > 0 {
+            self.config.shutdown_timeout_ms
+        } else {
+            DEFAULT_SHUTDOWN_TIMEOUT_MS
+        };
+
+        // bound the total wait rather than each task, so shutdown time doesn't grow with the
+        // number of connections
+        let join_all = async { while tasks.join_next().await.is_some() {} };
+        if tokio::time::timeout(Duration::from_millis(shutdown_timeout_ms), join_all)
+            .await
+            .is_err()
+        {
+            warn!(
+                "shutdown didn't finish in {shutdown_timeout_ms}ms, aborting {} remaining tasks",
+                tasks.len()
+            );
+            tasks.abort_all();
+        }
+    }
+
+    /// waits up to `drain_timeout_ms` for the TCP streams in flight to finish
+    async fn drain_streams(&self) {
+        let drain_timeout_ms = self.config.drain_timeout_ms;
+        let Some(idle_streams) = self.idle_streams.as_ref().filter(|_| drain_timeout_ms > 0) else {
+            return;
+        };
+        let in_flight = idle_streams.in_flight();
+        if in_flight == 0 {
+            return;
+        }
+
+        self.post_tunnel_log(
+            format!("draining {in_flight} streams for up to {drain_timeout_ms}ms").as_str(),
+        );
+        let drained = async {
+            while idle_streams.in_flight() > 0 {
+                tokio::time::sleep(Duration::from_millis(100)).await;
+            }
+        };
+        if tokio::time::timeout(Duration::from_millis(drain_timeout_ms), drained)
+            .await
+            .is_err()
+        {
+            warn!(
+                "{} streams still in flight after draining for {drain_timeout_ms}ms, closing them",
+                idle_streams.in_flight()
+            );
+        } else {
+            info!("all the streams in flight finished");
+        }
+    }
+
+    async fn connect_and_serve<S: AsyncStream>(
+        &mut self,
+        index: usize,
+        tunnel: Tunnel,
+        mut stream_receiver: Option<StreamReceiver<S>>,
+        mut ch: Option<(UdpSender, UdpReceiver)>,
+    ) {
+        let login_info = LoginInfo {
+            password: self.config.password.clone(),
+            tunnel: tunnel.clone(),
+        };
+
+        // an inbound tunnel binds a port on the server, which a second login can't do
+        let warm_standby = self.config.warm_standby
+            && !matches!(&tunnel, Tunnel::NetworkBased(cfg) if cfg.mode == TunnelMode::In);
+        if self.config.warm_standby && !warm_standby {
+            warn!("{index}:warm standby is not supported for inbound tunnels");
+        }
+
+        let fail_together = matches!(&tunnel, Tunnel::NetworkBased(cfg) if cfg.fail_together);
+        let tunnel_config = match &tunnel {
+            Tunnel::NetworkBased(cfg) => Some(cfg.as_ref()),
+            _ => None,
+        };
+        let retry_policy = self.retry_policy(tunnel_config);
+
+        let mut standby: Option<JoinHandle<Option<Connection>>> = None;
+        let mut handover: Option<JoinHandle<Option<Connection>>> = None;
+        let mut pending_network_based_stream = None;
+        let mut pending_proxy_stream = None;
+        let mut pending_channel_based_stream = None;
+        loop {
+            let tunnel_span = OtelSpan::start("rstun.tunnel");
+            tunnel_span.set_attr("tunnel.index", index);
+            tunnel_span.set_attr("tunnel.type", &login_info);
+            tunnel_span.set_attr("tunnel.cipher", &self.config.cipher);
+
+            let started_at = std::time::Instant::now();
+            let retries = AtomicU32::new(0);
+            let cert_failures = AtomicU32::new(0);
+            let redirected = self.server_redirect.subscribe();
+            let connect = || async {
+                let connect_timeout = retry_policy.connect_timeout(retries.load(Ordering::Relaxed));
+                // staggers the recovery when all tunnels reconnect at the same time
+                if self.connect_permits.available_permits() == 0 {
+                    debug!("{index}:waiting for other tunnels to finish connecting");
+                }
+                let _permit = self.connect_permits.acquire().await?;
+
+                let login_cfg = self.prepare_login_config(tunnel_config).await?;
+                let per_tunnel_endpoint = self.owns_endpoint(tunnel_config);
+                let endpoint =
+                    if let Some(endpoint) = self.tunnel_endpoint(index, per_tunnel_endpoint) {
+                        self.migrate_endpoint(&endpoint)?;
+                        endpoint
+                    } else {
+                        let endpoint = self.bind_with_source_ip(
+                            login_cfg.remote_addr.is_ipv6(),
+                            quinn::Endpoint::client,
+                        )?;
+                        let mut state = self.inner_state.lock().unwrap();
+                        if per_tunnel_endpoint {
+                            state.tunnel_endpoints.insert(index, endpoint.clone());
+                            if let Some(hop_interval_ms) =
+                                tunnel_config.and_then(|cfg| cfg.hop_interval_ms)
+                            {
+                                state.tunnel_hop_intervals.insert(index, hop_interval_ms);
+                            }
+                        } else {
+                            state.endpoint = Some(endpoint.clone());
+                        }
+                        endpoint
+                    };
+
+                let login_span = OtelSpan::start("rstun.login");
+                login_span.set_attr("server.addr", login_cfg.remote_addr);
+                let login = login_span.instrument(self.login(
+                    index,
+                    &endpoint,
+                    &login_info,
+                    &login_cfg,
+                    "",
+                ));
+                let result = match connect_timeout {
+                    Some(timeout) => {
+                        tokio::time::timeout(timeout, login)
+                            .await
+                            .unwrap_or_else(|_| {
+                                let msg = format!(
+                                    "connect to {} timed out in {timeout:?}",
+                                    login_cfg.remote_addr
+                                );
+                                self.post_login_failure(
+                                    index,
+                                    LoginFailureReason::Timeout,
+                                    msg.clone(),
+                                );
+                                Err(anyhow::anyhow!("{index}:{msg}"))
+                            })
+                    }
+                    None => login.await,
+                };
+                match &result {
+                    Err(e) if Self::is_cert_rejected_error(e) => {
+                        cert_failures.fetch_add(1, Ordering::Relaxed);
+                    }
+                    _ => cert_failures.store(0, Ordering::Relaxed),
+                }
+                let conn = result.inspect_err(|e| login_span.set_error(e))?;
+                if let Some(used_0rtt) = self.used_0rtt(index) {
+                    login_span.set_attr("tls.used_0rtt", used_0rtt);
+                }
+
+                Ok(conn)
+            };
+
+            // the connection to the server redirected to is ready once the old one is drained,
+            // otherwise the old connection failed before that
+            let handover_conn = match handover.take() {
+                Some(handle) if handle.is_finished() => handle.await.ok().flatten(),
+                Some(handle) => {
+                    handle.abort();
+                    None
+                }
+                None => None,
+            };
+            if handover_conn.is_some() {
+                // the standby is connected to the old server
+                if let Some(handle) = standby.take() {
+                    handle.abort();
+                }
+            }
+
+            let standby_conn = match standby.take() {
+                Some(handle) => handle
+                    .await
+                    .ok()
+                    .flatten()
+                    .filter(|conn| conn.close_reason().is_none()),
+                None => None,
+            };
+            let result = if let Some(conn) = handover_conn {
+                self.post_tunnel_log(
+                    format!(
+                        "{index}:switched over to the redirected connection to {}",
+                        conn.remote_address()
+                    )
+                    .as_str(),
+                );
+                Ok(conn)
+            } else if let Some(conn) = standby_conn {
+                self.post_tunnel_log(
+                    format!(
+                        "{index}:failing over to the standby connection to {}",
+                        conn.remote_address()
+                    )
+                    .as_str(),
+                );
+                Ok(conn)
+            } else {
+                tunnel_span
+                    .instrument(
+                        connect
+                            .retry(retry_policy.backoff(usize::MAX))
+                            .when(|_| {
+                                !self.should_stop_tunnel(fail_together)
+                                    && !retry_policy.is_expired(started_at)
+                                    && cert_failures.load(Ordering::Relaxed)
+                                        < MAX_CERT_VERIFICATION_FAILURES
+                            })
+                            .sleep(tokio::time::sleep)
+                            .notify(|err: &anyhow::Error, dur: Duration| {
+                                let attempt = retries.fetch_add(1, Ordering::Relaxed) + 1;
+                                self.record_connect_result(index, false);
+                                self.post_reconnect_backoff(
+                                    index,
+                                    attempt,
+                                    dur,
+                                    &retry_policy,
+                                    err,
+                                );
+                            }),
+                    )
+                    .await
+                    .inspect(|_| self.record_connect_result(index, true))
+            };
+
+            if self.should_stop_tunnel(fail_together) {
+                break;
+            }
+
+            match result {
+                Ok(conn) => {
+                    tunnel_span.set_attr("server.addr", conn.remote_address());
+                    inner_state!(self, tunnel_records)
+                        .entry(index)
+                        .or_default()
+                        .connected_since = Some(std::time::Instant::now());
+                    if warm_standby {
+                        standby = Some(self.maintain_standby_in_background(
+                            index,
+                            login_info.clone(),
+                            conn.clone(),
+                            retry_policy.clone(),
+                        ));
+                    }
+
+                    // inbound tunnels bind a port on the server, they move to the new server
+                    // once the old one closes the connection
+                    let drain = Arc::new(Notify::new());
+                    if !matches!(&tunnel, Tunnel::NetworkBased(cfg) if cfg.mode == TunnelMode::In) {
+                        handover = Some(self.handover_on_redirect(
+                            index,
+                            login_info.clone(),
+                            redirected,
+                            drain.clone(),
+                            retry_policy.clone(),
+                        ));
+                    }
+
+                    match &tunnel {
+                        Tunnel::NetworkBased(tunnel_config) => {
+                            let local_server_addr = tunnel_config.tracking_addr().unwrap();
+                            {
+                                let mut state = self.inner_state.lock().unwrap();
+                                state.connections.insert(local_server_addr, conn.clone());
+                                self.start_datagram_heartbeats(&mut state, index, &conn);
+                                state.post_tunnel_info(Self::connection_event(
+                                    index,
+                                    local_server_addr,
+                                    &conn,
+                                    ConnectionEventKind::Opened,
+                                    None,
+                                ));
+                            }
+
+                            let mut group_failed = self.tunnel_group_failed.subscribe();
+                            let serve = tunnel_span.instrument(self.handle_network_based_tunnel(
+                                index,
+                                conn.clone(),
+                                tunnel_config,
+                                &mut pending_network_based_stream,
+                                &mut pending_proxy_stream,
+                                &drain,
+                            ));
+                            if fail_together {
+                                tokio::select! {
+                                    _ = serve => {}
+                                    _ = group_failed.wait_for(|failed| *failed) => {
+                                        conn.close(VarInt::from_u32(0), b"tunnel group failed");
+                                    }
+                                }
+                            } else {
+                                serve.await;
+                            }
+
+                            {
+                                let mut state = self.inner_state.lock().unwrap();
+                                state.connections.remove(&local_server_addr);
+                                if let Some(record) = state.tunnel_records.get_mut(&index) {
+                                    record.heartbeat = None;
+                                }
+                                // drained connections stay open for the streams in flight
+                                let reason = conn
+                                    .close_reason()
+                                    .map_or("drained".to_string(), |e| e.to_string());
+                                state.post_tunnel_info(Self::connection_event(
+                                    index,
+                                    local_server_addr,
+                                    &conn,
+                                    ConnectionEventKind::Closed,
+                                    Some(reason),
+                                ));
+                            }
+                        }
+                        Tunnel::ChannelBased(upstream_type) => match upstream_type {
+                            UpstreamType::Tcp => {
+                                self.post_tunnel_log(
+                                    format!(
+                                        "{index}:STREAM_OUT start serving via {}",
+                                        conn.remote_address()
+                                    )
+                                    .as_str(),
+                                );
+                                self.set_and_post_tunnel_state(ClientState::Tunneling);
+
+                                let stream_receiver = stream_receiver.as_mut().unwrap();
+                                tunnel_span
+                                    .instrument(TcpTunnel::start_serving(
+                                        true,
+                                        &conn,
+                                        stream_receiver,
+                                        &mut pending_channel_based_stream,
+                                        self.config.tcp_timeout_ms,
+                                        false,
+                                        0,
+                                        &self.rate_limiters,
+                                        Some(&drain),
+                                        self.idle_streams.as_deref(),
+                                        false,
+                                        Compression::None,
+                                    ))
+                                    .await;
+                            }
+
+                            UpstreamType::Socks5 | UpstreamType::HttpConnect => {
+                                unreachable!("proxy tunnels are network based")
+                            }
+
+                            UpstreamType::Udp => {
+                                self.post_tunnel_log(
+                                    format!(
+                                        "{index}:UDP_OUT start serving via {}",
+                                        conn.remote_address()
+                                    )
+                                    .as_str(),
+                                );
+                                self.set_and_post_tunnel_state(ClientState::Tunneling);
+
+                                let ch = ch.as_mut().unwrap();
+                                tunnel_span
+                                    .instrument(UdpTunnel::start_serving(
+                                        &conn,
+                                        &ch.0,
+                                        &mut ch.1,
+                                        self.config.udp_timeout_ms,
+                                        &self.rate_limiters,
+                                        Some(&drain),
+                                        false,
+                                        &DatagramHeartbeats::None,
+                                    ))
+                                    .await;
+                            }
+                        },
+                        Tunnel::IcmpChannel => unreachable!("the ICMP channel is set up by ping()"),
+                    }
+
+                    if let Some(record) = inner_state!(self, tunnel_records).get_mut(&index) {
+                        record.connected_since = None;
+                    }
+
+                    let stats = conn.stats();
+                    tunnel_span.set_u64("bytes.rx", stats.udp_rx.bytes);
+                    tunnel_span.set_u64("bytes.tx", stats.udp_tx.bytes);
+                }
+
+                Err(e) => {
+                    tunnel_span.set_error(&e);
+                    error!("{e}");
+                    inner_state!(self, tunnel_records)
+                        .entry(index)
+                        .or_default()
+                        .last_error = Some(format!("{e:#}"));
+                    info!(
+                        "[{login_info}] quit after having retried for {} times in {:?}",
+                        retries.load(Ordering::Relaxed),
+                        started_at.elapsed()
+                    );
+                    if cert_failures.load(Ordering::Relaxed) >= MAX_CERT_VERIFICATION_FAILURES {
+                        self.post_tunnel_log(
+                            format!(
+                                "{index}:server certificate keeps failing verification, giving up"
+                            )
+                            .as_str(),
+                        );
+                        self.set_and_post_tunnel_state(ClientState::Terminated);
+                    }
+                    break;
+                }
+            };
+
+            if self.should_stop_tunnel(fail_together) || !self.record_reconnect(index) {
+                break;
+            }
+        }
+        if fail_together && !self.should_quit() {
+            self.fail_tunnel_group(index);
+        }
+        self.post_tunnel_log(format!("[{login_info}] quit").as_str());
+    }
+
+    async fn handle_network_based_tunnel(
+        &mut self,
+        index: usize,
+        conn: Connection,
+        tunnel_config: &TunnelConfig,
+        pending_request: &mut Option<StreamRequest<TcpStream>>,
+        pending_proxy_request: &mut Option<StreamRequest<BufReader<TcpStream>>>,
+        drain: &Notify,
+    ) {
+        let upstream_type = &tunnel_config.upstream.upstream_type;
+        if tunnel_config.mode == TunnelMode::Out {
+            match upstream_type {
+                UpstreamType::Tcp => {
+                    self.serve_outbound_tcp(
+                        index,
+                        conn.clone(),
+                        tunnel_config,
+                        pending_request,
+                        drain,
+                    )
+                    .await
+                    .ok();
+                }
+                UpstreamType::Udp => {
+                    self.serve_outbound_udp(index, conn.clone(), tunnel_config, drain)
+                        .await
+                        .ok();
+                }
+                UpstreamType::Socks5 | UpstreamType::HttpConnect => {
+                    self.serve_outbound_proxy(
+                        index,
+                        conn.clone(),
+                        tunnel_config,
+                        pending_proxy_request,
+                        drain,
+                    )
+                    .await
+                    .ok();
+                }
+            }
+        } else {
+            match upstream_type {
+                UpstreamType::Tcp => {
+                    self.serve_inbound_tcp(index, conn.clone(), tunnel_config)
+                        .await
+                        .ok();
+                }
+                UpstreamType::Udp => {
+                    let local_server_addr = tunnel_config.local_server_addr.unwrap();
+                    self.serve_inbound_udp(index, conn.clone(), local_server_addr)
+                        .await
+                        .ok();
+                }
+                UpstreamType::Socks5 | UpstreamType::HttpConnect => {
+                    unreachable!("proxy tunnels are outbound only")
+                }
+            }
+        }
+
+        inner_state!(self, total_traffic_data).add(&TunnelTraffic::from(&conn.stats()));
+    }
+
+    /// the transport settings of `tunnel_config` take precedence over the client-wide ones
+    async fn prepare_login_config(
+        &self,
+        tunnel_config: Option<&TunnelConfig>,
+    ) -> Result<LoginConfig> {
+        let overrides = tunnel_config
+            .and_then(|cfg| cfg.transport.as_deref().cloned())
+            .unwrap_or_default();
+        let (stream_receive_window, receive_window, send_window) =
+            self.config.flow_control_windows(&overrides);
+        let mut transport_cfg = TransportConfig::default();
+        transport_cfg.stream_receive_window(VarInt::from_u64(stream_receive_window)?);
+        transport_cfg.receive_window(VarInt::from_u64(receive_window)?);
+        transport_cfg.send_window(send_window);
+        match overrides.congestion.unwrap_or(self.config.congestion) {
+            CongestionControl::Bbr => transport_cfg
+                .congestion_controller_factory(Arc::new(congestion::BbrConfig::default())),
+            CongestionControl::Cubic => transport_cfg
+                .congestion_controller_factory(Arc::new(congestion::CubicConfig::default())),
+            CongestionControl::NewReno => transport_cfg
+                .congestion_controller_factory(Arc::new(congestion::NewRenoConfig::default())),
+        };
+        // 0 keeps quinn's default
+        let bidi_streams = self
+            .config
+            .max_concurrent_bidi_streams
+            .unwrap_or(DEFAULT_MAX_CONCURRENT_STREAMS);
+        if bidi_streams > 0 {
+            transport_cfg.max_concurrent_bidi_streams(VarInt::from_u64(bidi_streams)?);
+        }
+        let uni_streams = self
+            .config
+            .max_concurrent_uni_streams
+            .unwrap_or(DEFAULT_MAX_CONCURRENT_STREAMS);
+        if uni_streams > 0 {
+            transport_cfg.max_concurrent_uni_streams(VarInt::from_u64(uni_streams)?);
+        }
+        if inner_state!(self, mtu_clamped) {
+            // stick to the initial MTU (1200), which every QUIC path must support
+            transport_cfg.mtu_discovery_config(None);
+        }
+
+        if self.config.quic_timeout_ms > 0 {
+            let timeout = IdleTimeout::from(VarInt::from_u32(self.config.quic_timeout_ms as u32));
+            transport_cfg.max_idle_timeout(Some(timeout));
+            transport_cfg.keep_alive_interval(Some(Duration::from_millis(
+                self.config.quic_timeout_ms * 2 / 3,
+            )));
+        }
+        if let Some(ms) = overrides.keep_alive_interval_ms {
+            transport_cfg.keep_alive_interval((ms > 0).then(|| Duration::from_millis(ms)));
+        }
+        if self.config.initial_rtt_ms > 0 {
+            // the handshake retransmits after a multiple of it, too soon on long-delay paths
+            transport_cfg.initial_rtt(Duration::from_millis(self.config.initial_rtt_ms));
+        }
+
+        let remote_addr = self.parse_server_addr().await?;
+        let (mut tls_client_cfg, domain) = self.parse_client_config_and_domain()?;
+        tls_client_cfg.alpn_protocols = effective_alpn_protocols(&self.config.alpn_protocols)
+            .into_iter()
+            .map(String::into_bytes)
+            .collect();
+        tls_client_cfg.resumption = Resumption::store(self.tls_sessions.clone());
+        tls_client_cfg.enable_early_data = self.config.enable_0rtt;
+        if self.config.verify_dane {
+            if !self.config.pinned_spki_sha256.is_empty() {
+                log_and_bail!("DANE verification and SPKI pinning can't be used together");
+            }
+            self.enable_dane(&mut tls_client_cfg, &domain, remote_addr.port())
+                .await?;
+        }
+        let quic_client_cfg = Arc::new(QuicClientConfig::try_from(tls_client_cfg)?);
+        let mut client_cfg = quinn::ClientConfig::new(quic_client_cfg);
+        client_cfg.transport_config(Arc::new(transport_cfg));
+
+        Ok(LoginConfig {
+            remote_addr,
+            quinn_client_cfg: client_cfg,
+            domain,
+        })
+    }
+
+    /// each tunnel owns its endpoint if the source IP is chosen per connection, or if it's
+    /// migrated on its own schedule
+    fn owns_endpoint(&self, tunnel_config: Option<&TunnelConfig>) -> bool {
+        !self.config.source_ip_pool.is_empty()
+            || tunnel_config.is_some_and(|cfg| cfg.hop_interval_ms.is_some())
+    }
+
+    fn tunnel_endpoint(&self, index: usize, owns_endpoint: bool) -> Option<Endpoint> {
+        let state = self.inner_state.lock().unwrap();
+        if owns_endpoint {
+            state.tunnel_endpoints.get(&index).cloned()
+        } else {
+            state.endpoint.clone()
+        }
+    }
+
+    /// keeps a logged-in idle connection around while `primary` is alive, the
+    /// task yields the standby connection once `primary` is closed
+    fn maintain_standby_in_background(
+        &self,
+        index: usize,
+        login_info: LoginInfo,
+        primary: Connection,
+        retry_policy: RetryPolicy,
+    ) -> JoinHandle<Option<Connection>> {
+        let this = self.clone();
+        tokio::spawn(async move {
+            loop {
+                let connect = || this.login_auxiliary(index, &login_info, "standby ");
+                let conn = connect
+                    .retry(retry_policy.backoff(usize::MAX))
+                    .when(|_| !this.should_quit() && primary.close_reason().is_none())
+                    .sleep(tokio::time::sleep)
+                    .notify(|err: &anyhow::Error, dur: Duration| {
+                        warn!("{index}:will retry standby after {dur:?}, err: {err:?}");
+                    })
+                    .await
+                    .ok()?;
+
+                tokio::select! {
+                    _ = primary.closed() => return Some(conn),
+                    reason = conn.closed() => {
+                        warn!("{index}:standby connection closed: {reason}");
+                    }
+                }
+            }
+        })
+    }
+
+    fn post_reconnect_backoff(
+        &self,
+        index: usize,
+        attempt: u32,
+        delay: Duration,
+        retry_policy: &RetryPolicy,
+        err: &anyhow::Error,
+    ) {
+        let computed = retry_policy.computed_delay(attempt);
+        let capped = computed.min(Duration::from_millis(retry_policy.max_delay_ms));
+        warn!(
+            "{index}:will retry after {delay:?} (attempt:{attempt}, computed:{computed:?}, capped:{capped:?}), err: {err:?}"
+        );
+
+        let error = format!("{err:#}");
+        let mut state = self.inner_state.lock().unwrap();
+        state.tunnel_records.entry(index).or_default().last_error = Some(error.clone());
+        state.post_tunnel_info(TunnelInfo::new(
+            TunnelInfoType::ReconnectBackoff,
+            Box::new(ReconnectBackoff {
+                index,
+                attempt,
+                computed_delay_ms: computed.as_millis() as u64,
+                capped_delay_ms: capped.as_millis() as u64,
+                delay_ms: delay.as_millis() as u64,
+                error,
+            }),
+        ));
+    }
+
+    /// once the server redirects the client, logs in to the new server and drains the current
+    /// connection, the task yields the new connection
+    fn handover_on_redirect(
+        &self,
+        index: usize,
+        login_info: LoginInfo,
+        mut redirected: watch::Receiver<Option<String>>,
+        drain: Arc<Notify>,
+        retry_policy: RetryPolicy,
+    ) -> JoinHandle<Option<Connection>> {
+        let this = self.clone();
+        tokio::spawn(async move {
+            redirected.changed().await.ok()?;
+            let connect = || this.login_auxiliary(index, &login_info, "redirected ");
+            let conn = connect
+                .retry(retry_policy.backoff(usize::MAX))
+                .when(|_| !this.should_quit())
+                .sleep(tokio::time::sleep)
+                .notify(|err: &anyhow::Error, dur: Duration| {
+                    warn!(
+                        "{index}:will retry the redirected connection after {dur:?}, err: {err:?}"
+                    );
+                })
+                .await
+                .ok()?;
+
+            drain.notify_one();
+            Some(conn)
+        })
+    }
+
+    /// logs in with the endpoint of the tunnel, which the primary connection has set up
+    async fn login_auxiliary(
+        &self,
+        index: usize,
+        login_info: &LoginInfo,
+        role: &str,
+    ) -> Result<Connection> {
+        let tunnel_config = match &login_info.tunnel {
+            Tunnel::NetworkBased(cfg) => Some(cfg.as_ref()),
+            _ => None,
+        };
+        let login_cfg = self.prepare_login_config(tunnel_config).await?;
+        let Some(endpoint) = self.tunnel_endpoint(index, self.owns_endpoint(tunnel_config)) else {
+            bail!("{index}:no endpoint for the {role}connection");
+        };
+        self.login(index, &endpoint, login_info, &login_cfg, role)
+            .await
+    }
+
+    /// the server may push messages over the login stream once logged in, servers that
+    /// predate this simply finish the stream
+    fn watch_control_stream(&self, index: usize, mut quic_recv: RecvStream) {
+        let this = self.clone();
+        tokio::spawn(async move {
+            while let Ok(msg) = TunnelMessage::recv(&mut quic_recv).await {
+                let TunnelMessage::Redirect { new_addr } = msg else {
+                    continue;
+                };
+                let redirected = this.server_redirect.send_if_modified(|addr| {
+                    if addr.as_deref() == Some(new_addr.as_str()) {
+                        return false;
+                    }
+                    *addr = Some(new_addr.clone());
+                    true
+                });
+                if redirected {
+                    this.post_tunnel_log(
+                        format!("{index}:redirected to {new_addr} by the server").as_str(),
+                    );
+                }
+            }
+        });
+    }
+
+    async fn login(
+        &self,
+        index: usize,
+        endpoint: &Endpoint,
+        login_info: &LoginInfo,
+        login_cfg: &LoginConfig,
+        role: &str,
+    ) -> Result<Connection> {
+        let remote_addr = &login_cfg.remote_addr;
+        let domain = login_cfg.domain.as_str();
+        // auxiliary connections (e.g. standby) are set up while tunneling, so only the primary
+        // connection (empty role) updates the state
+        let primary = role.is_empty();
+        if primary {
+            self.set_and_post_tunnel_state(ClientState::Connecting);
+        }
+        self.post_tunnel_log(
+            format!(
+                "{index}:{role}{} connecting, idle_timeout:{}, retry_timeout:{}, cipher:{}, threads:{}",
+                login_info.format_with_remote_addr(remote_addr),
+                self.config.quic_timeout_ms,
+                self.config.retry_policy.min_delay_ms,
+                self.config.cipher,
+                self.config.workers,
+            )
+            .as_str(),
+        );
+
+        let credentials = self
+            .auth_provider()
+            .credentials()
+            .await
+            .context("failed to obtain login credentials")?;
+        let password_challenge = self.config.password_challenge
+            && !self
+                .inner_state
+                .lock()
+                .unwrap()
+                .password_challenge_unsupported;
+        let login_info = &LoginInfo {
+            // proven with ChallengeResponse instead if the server asks for it
+            password: if password_challenge {
+                String::new()
+            } else {
+                credentials.password.clone()
+            },
+            ..login_info.clone()
+        };
+
+        let candidates = self.happy_eyeballs_candidates(endpoint, remote_addr);
+        let handshake_started = std::time::Instant::now();
+        let connecting = if candidates.len() > 1 {
+            self.connect_racing(endpoint, &login_cfg.quinn_client_cfg, &candidates, domain)
+                .await
+                .map(|(conn, addr)| ((conn, None), addr))
+        } else {
+            self.connect_once(endpoint, &login_cfg.quinn_client_cfg, *remote_addr, domain)
+                .await
+                .map(|connecting| (connecting, *remote_addr))
+        };
+        let ((conn, zero_rtt_accepted), connected_addr) = match connecting {
+            Ok(connecting) => connecting,
+            Err(e) => match e.downcast_ref::<quinn::ConnectionError>() {
+                Some(err) if Self::is_alpn_rejected(err) => {
+                    let msg = format!(
+                        "no ALPN protocol in common with the server, offered: {:?}",
+                        effective_alpn_protocols(&self.config.alpn_protocols)
+                    );
+                    self.post_login_failure(index, LoginFailureReason::ServerRejected, msg.clone());
+                    bail!(
+                        "{index}:{} {msg}",
+                        login_info.format_with_remote_addr(remote_addr)
+                    );
+                }
+                Some(err) if Self::is_cert_rejected(err) => {
+                    self.post_cert_verification_failure(index, domain, err);
+                    return Err(e);
+                }
+                Some(quinn::ConnectionError::TimedOut) => {
+                    self.post_login_failure(index, LoginFailureReason::Timeout, e.to_string());
+                    return Err(e);
+                }
+                _ => return Err(e),
+            },
+        };
+        // near zero when resuming in 0-RTT, as the connection is usable before the handshake
+        let handshake_ms = handshake_started.elapsed().as_millis() as u64;
+        let login_started = std::time::Instant::now();
+        // the address that won the race if the server resolves to several
+        let remote_addr = &connected_addr;
+        self.check_cert_expiry(index, &conn)?;
+        let (mut quic_send, mut quic_recv) = conn
+            .open_bi()
+            .await
+            .context("open bidirectional connection failed")?;
+
+        if primary {
+            self.set_and_post_tunnel_state(ClientState::Connected);
+        }
+
+        self.post_tunnel_log(
+            format!(
+                "{index}:{role}{} logging in...",
+                login_info.format_with_remote_addr(remote_addr)
+            )
+            .as_str(),
+        );
+
+        let login_msg = TunnelMessage::ReqLogin(login_info.clone());
+        let wants_udp_datagrams =
+            matches!(&login_info.tunnel, Tunnel::NetworkBased(cfg) if cfg.udp_datagrams);
+        let login_ext = LoginReqExt {
+            one_way: matches!(&login_info.tunnel, Tunnel::NetworkBased(cfg) if cfg.one_way),
+            connection_label: self.config.connection_label.clone(),
+            udp_datagrams: wants_udp_datagrams && conn.max_datagram_size().is_some(),
+            proxy_protocol: matches!(&login_info.tunnel, Tunnel::NetworkBased(cfg) if cfg.proxy_protocol),
+            compression: match &login_info.tunnel {
+                Tunnel::NetworkBased(cfg) => cfg.compression,
+                _ => Compression::None,
+            },
+            datagram_heartbeat: self.config.datagram_heartbeat_interval_ms > 0
+                && matches!(&login_info.tunnel, Tunnel::NetworkBased(_))
+                && conn.max_datagram_size().is_some(),
+            password_challenge,
+        };
+        TunnelMessage::send_with_ext(&mut quic_send, &login_msg, &login_ext).await?;
+
+        // the login went out as early data if the connection is still in 0-RTT, the server
+        // never sees it if it declines early data, so it's sent again in 1-RTT
+        let used_0rtt = match zero_rtt_accepted {
+            Some(accepted) => {
+                let accepted = accepted.await;
+                if !accepted {
+                    self.post_tunnel_log(
+                        format!("{index}:{role}0-RTT declined by the server, logging in again")
+                            .as_str(),
+                    );
+                    (quic_send, quic_recv) = conn
+                        .open_bi()
+                        .await
+                        .context("open bidirectional connection failed")?;
+                    TunnelMessage::send_with_ext(&mut quic_send, &login_msg, &login_ext).await?;
+                }
+                accepted
+            }
+            None => false,
+        };
+
+        let on_recv_error = |e: &anyhow::Error| {
+            let reason = if Self::is_timeout_error(e) {
+                LoginFailureReason::Timeout
+            } else {
+                LoginFailureReason::ProtocolError
+            };
+            self.post_login_failure(index, reason, format!("{e:#}"));
+        };
+        let first_resp = TunnelMessage::recv_with_ext::<LoginRespExt>(&mut quic_recv).await;
+        if password_challenge && Self::password_challenge_ignored(&conn, &first_resp) {
+            self.inner_state
+                .lock()
+                .unwrap()
+                .password_challenge_unsupported = true;
+            warn!("the server doesn't support the password challenge, sending the password as is");
+            self.post_tunnel_log(
+                format!(
+                    "{index}:{role}password challenge not supported by the server, the password is sent as is on the next attempt"
+                )
+                .as_str(),
+            );
+            bail!(
+                "{index}:{} password challenge not supported",
+                login_info.format_with_remote_addr(remote_addr)
+            );
+        }
+        let (mut resp, mut resp_ext) = first_resp.inspect_err(on_recv_error)?;
+        if let TunnelMessage::Challenge { nonce } = &resp {
+            let mac = password_hmac(&credentials.password, nonce);
+            TunnelMessage::send(&mut quic_send, &TunnelMessage::ChallengeResponse { mac }).await?;
+            (resp, resp_ext) = TunnelMessage::recv_with_ext::<LoginRespExt>(&mut quic_recv)
+                .await
+                .inspect_err(on_recv_error)?;
+        }
+        if let TunnelMessage::RespFailure(msg) = &resp {
+            let reason = if msg == ERR_BAD_PASSWORD {
+                LoginFailureReason::BadPassword
+            } else {
+                LoginFailureReason::ServerRejected
+            };
+            self.post_login_failure(index, reason, msg.clone());
+            bail!(
+                "{index}:{} failed to login: {msg}",
+                login_info.format_with_remote_addr(remote_addr)
+            );
+        }
+        if !resp.is_resp_success() {
+            self.post_login_failure(index, LoginFailureReason::ProtocolError, resp.to_string());
+            bail!(
+                "{index}:{} unexpected response, failed to login",
+                login_info.format_with_remote_addr(remote_addr)
+            );
+        }
+        TunnelMessage::handle_message(&resp)?;
+        let login_ms = login_started.elapsed().as_millis() as u64;
+        self.watch_control_stream(index, quic_recv);
+        self.check_connection_label(index, resp_ext.as_ref());
+        let udp_datagrams = login_ext.udp_datagrams
+            && resp_ext
+                .as_ref()
+                .is_some_and(|resp_ext| resp_ext.udp_datagrams);
+        let proxy_protocol = resp_ext
+            .as_ref()
+            .is_some_and(|resp_ext| resp_ext.proxy_protocol);
+        let compression = match &resp_ext {
+            Some(resp_ext) if resp_ext.compression == login_ext.compression => {
+                login_ext.compression
+            }
+            _ => Compression::None,
+        };
+        let datagram_heartbeat = resp_ext
+            .as_ref()
+            .is_some_and(|resp_ext| resp_ext.datagram_heartbeat);
+        let domain_destinations = resp_ext
+            .as_ref()
+            .is_some_and(|resp_ext| resp_ext.domain_destinations);
+        self.update_effective_quic_timeout(index, resp_ext);
+        let negotiated_cipher = self
+            .tls_sessions
+            .negotiated_suite(domain)
+            .map(cipher_suite_name);
+        {
+            let mut state = self.inner_state.lock().unwrap();
+            let record = state.tunnel_records.entry(index).or_default();
+            record.used_0rtt = Some(used_0rtt);
+            record.handshake_ms = Some(handshake_ms);
+            record.login_ms = Some(login_ms);
+            record.datagram_heartbeat = datagram_heartbeat;
+            record.domain_destinations = domain_destinations;
+            if wants_udp_datagrams {
+                record.udp_datagrams = Some(udp_datagrams);
+            }
+            if login_ext.proxy_protocol {
+                record.proxy_protocol = Some(proxy_protocol);
+            }
+            if login_ext.compression != Compression::None {
+                record.compression = Some(compression);
+            }
+            record.negotiated_cipher = negotiated_cipher.clone();
+            state.post_tunnel_info(TunnelInfo::new(
+                TunnelInfoType::HandshakeLatency,
+                Box::new(HandshakeLatency {
+                    index,
+                    remote_addr: *remote_addr,
+                    handshake_ms,
+                    login_ms,
+                    total_ms: handshake_ms + login_ms,
+                    used_0rtt,
+                }),
+            ));
+        }
+        if wants_udp_datagrams && !udp_datagrams {
+            let side = if login_ext.udp_datagrams {
+                "the server"
+            } else {
+                "the client"
+            };
+            self.post_tunnel_log(
+                format!("{index}:{role}QUIC datagrams not supported by {side}, UDP falls back to streams")
+                    .as_str(),
+            );
+        }
+        if compression != login_ext.compression {
+            self.post_tunnel_log(
+                format!(
+                    "{index}:{role}{} compression not supported by the server, the streams are sent uncompressed",
+                    login_ext.compression
+                )
+                .as_str(),
+            );
+        }
+        if login_ext.datagram_heartbeat && !datagram_heartbeat {
+            self.post_tunnel_log(
+                format!(
+                    "{index}:{role}the server doesn't echo datagram heartbeats, no RTT is measured"
+                )
+                .as_str(),
+            );
+        }
+        let proxy_tunnel = matches!(&login_info.tunnel, Tunnel::NetworkBased(cfg)
+            if matches!(cfg.upstream.upstream_type, UpstreamType::Socks5 | UpstreamType::HttpConnect));
+        if proxy_tunnel && !domain_destinations {
+            self.post_tunnel_log(
+                format!("{index}:{role}the server doesn't resolve domain names, the destinations are resolved on the client side")
+                    .as_str(),
+            );
+        }
+        if login_ext.proxy_protocol && !proxy_protocol {
+            self.post_tunnel_log(
+                format!("{index}:{role}the server doesn't send the client addresses, PROXY protocol headers carry none")
+                    .as_str(),
+            );
+        }
+        let cipher = match negotiated_cipher {
+            Some(cipher) if cipher == self.config.cipher => cipher,
+            Some(cipher) => format!("{cipher}, fell back from {}", self.config.cipher),
+            None => "cipher unknown".to_string(),
+        };
+        self.post_tunnel_log(
+            format!(
+                "{index}:{role}{} login succeeded! ({}, {cipher}, handshake_ms:{handshake_ms}, login_ms:{login_ms})",
+                login_info.format_with_remote_addr(remote_addr),
+                if used_0rtt { "0-RTT" } else { "1-RTT" }
+            )
+            .as_str(),
+        );
+        Ok(conn)
+    }
+
+    async fn serve_outbound_tcp(
+        &mut self,
+        index: usize,
+        conn: Connection,
+        tunnel_config: &TunnelConfig,
+        pending_request: &mut Option<StreamRequest<TcpStream>>,
+        drain: &Notify,
+    ) -> Result<()> {
+        let mut tcp_server = self.obtain_tcp_server(tunnel_config).await?;
+
+        self.post_tunnel_log(
+            format!(
+                "{index}:TCP_OUT start serving from {} via {}",
+                Self::listen_addrs(&tcp_server, tunnel_config),
+                conn.remote_address()
+            )
+            .as_str(),
+        );
+        self.set_and_post_tunnel_state(ClientState::Tunneling);
+
+        let mut tcp_receiver = tcp_server.take_receiver();
+
+        TcpTunnel::start_serving(
+            true,
+            &conn,
+            &mut tcp_receiver,
+            pending_request,
+            self.config.tcp_timeout_ms,
+            tunnel_config.one_way,
+            tunnel_config.flush_coalesce_ms,
+            &self.rate_limiters,
+            Some(drain),
+            self.idle_streams.as_deref(),
+            false,
+            self.compression(index).unwrap_or_default(),
+        )
+        .await;
+
+        tcp_server.put_receiver(tcp_receiver);
+
+        Ok(())
+    }
+
+    /// Runs the SOCKS5 or HTTP CONNECT handshake with each local client in its own task, then
+    /// tunnels the streams to the destinations they asked for
+    async fn serve_outbound_proxy(
+        &mut self,
+        index: usize,
+        conn: Connection,
+        tunnel_config: &TunnelConfig,
+        pending_request: &mut Option<StreamRequest<BufReader<TcpStream>>>,
+        drain: &Notify,
+    ) -> Result<()> {
+        let mut tcp_server = self.obtain_tcp_server(tunnel_config).await?;
+        let proxy_type = tunnel_config.upstream.upstream_type.clone();
+        let resolve_domains = !inner_state!(self, tunnel_records)
+            .get(&index)
+            .is_some_and(|record| record.domain_destinations);
+
+        self.post_tunnel_log(
+            format!(
+                "{index}:{proxy_type}_OUT start serving from {} via {}",
+                Self::listen_addrs(&tcp_server, tunnel_config),
+                conn.remote_address()
+            )
+            .as_str(),
+        );
+        self.set_and_post_tunnel_state(ClientState::Tunneling);
+
+        let mut tcp_receiver = tcp_server.take_receiver();
+        let (proxy_sender, mut proxy_receiver) = tokio::sync::mpsc::channel(4);
+        let stop_handshaking = Arc::new(Notify::new());
+        let handshake_timeout =
+            Duration::from_millis(match self.config.proxy_handshake_timeout_ms {
+                0 => self.config.tcp_timeout_ms,
+                ms => ms,
+            });
+        let policy = Arc::new(DestinationPolicy::new(
+            &tunnel_config.upstream_allow,
+            &tunnel_config.upstream_deny,
+            self.denied_destinations.clone(),
+        )?);
+
+        let handshaking = {
+            let stop_handshaking = stop_handshaking.clone();
+            tokio::spawn(async move {
+                loop {
+                    let request = tokio::select! {
+                        msg = tcp_receiver.recv() => match msg {
+                            Some(StreamMessage::Request(request)) => request,
+                            _ => {
+                                proxy_sender.send(StreamMessage::Quit).await.ok();
+                                break;
+                            }
+                        },
+                        _ = stop_handshaking.notified() => break,
+                    };
+
+                    let proxy_sender = proxy_sender.clone();
+                    let proxy_type = proxy_type.clone();
+                    let policy = policy.clone();
+                    tokio::spawn(async move {
+                        let mut stream = BufReader::new(request.stream);
+                        let handshake = async {
+                            match proxy_type {
+                                UpstreamType::Socks5 => {
+                                    socks5::accept(&mut stream, &policy, resolve_domains).await
+                                }
+                                _ => {
+                                    http_connect::accept(&mut stream, &policy, resolve_domains)
+                                        .await
+                                }
+                            }
+                        };
+                        match tokio::time::timeout(handshake_timeout, handshake).await {
+                            Ok(Ok(destination)) => {
+                                let request = StreamRequest {
+                                    stream,
+                                    destination: Some(destination),
+                                };
+                                proxy_sender
+                                    .send(StreamMessage::Request(request))
+                                    .await
+                                    .ok();
+                            }
+                            Ok(Err(e)) => debug!("{index}:{proxy_type} handshake failed: {e}"),
+                            Err(_) => debug!("{index}:{proxy_type} handshake timed out"),
+                        }
+                    });
+                }
+                tcp_receiver
+            })
+        };
+
+        TcpTunnel::start_serving(
+            true,
+            &conn,
+            &mut proxy_receiver,
+            pending_request,
+            self.config.tcp_timeout_ms,
+            false,
+            tunnel_config.flush_coalesce_ms,
+            &self.rate_limiters,
+            Some(drain),
+            self.idle_streams.as_deref(),
+            false,
+            self.compression(index).unwrap_or_default(),
+        )
+        .await;
+
+        stop_handshaking.notify_one();
+        tcp_server.put_receiver(handshaking.await?);
+
+        Ok(())
+    }
+
+    async fn obtain_tcp_server(&self, tunnel_config: &TunnelConfig) -> Result<TcpServer> {
+        let local_server_addr = tunnel_config.local_server_addr.unwrap();
+        let tcp_server = {
+            inner_state!(self, tcp_servers)
+                .get(&local_server_addr)
+                .cloned()
+        };
+
+        match tcp_server {
+            Some(server) => Ok(server),
+            None => self.start_tunnel_tcp_server(tunnel_config).await,
+        }
+    }
+
+    async fn serve_outbound_udp(
+        &mut self,
+        index: usize,
+        conn: Connection,
+        tunnel_config: &TunnelConfig,
+        drain: &Notify,
+    ) -> Result<()> {
+        let local_server_addr = tunnel_config.local_server_addr.unwrap();
+        let udp_server = {
+            inner_state!(self, udp_servers)
+                .get(&local_server_addr)
+                .cloned()
+        };
+
+        let mut udp_server = match udp_server {
+            Some(server) => server.clone(),
+            None => {
+                let retry_policy = self.retry_policy(Some(tunnel_config));
+                self.start_udp_server_with_policy(local_server_addr, &retry_policy)
+                    .await?
+            }
+        };
+
+        let datagrams = self.udp_datagrams(index).unwrap_or(false);
+        self.post_tunnel_log(
+            format!(
+                "{index}:UDP_OUT start serving from {} via {} in {}",
+                udp_server.addr(),
+                conn.remote_address(),
+                Self::udp_mode(datagrams)
+            )
+            .as_str(),
+        );
+
+        self.set_and_post_tunnel_state(ClientState::Tunneling);
+
+        let mut udp_receiver = udp_server.take_receiver();
+        let udp_sender = udp_server.clone_sender();
+
+        UdpTunnel::start_serving(
+            &conn,
+            &udp_sender,
+            &mut udp_receiver,
+            self.config.udp_timeout_ms,
+            &self.rate_limiters,
+            Some(drain),
+            datagrams,
+            &self.datagram_heartbeats(index),
+        )
+        .await;
+
+        udp_server.put_receiver(udp_receiver);
+
+        Ok(())
+    }
+
+    async fn serve_inbound_tcp(
+        &mut self,
+        index: usize,
+        conn: Connection,
+        tunnel_config: &TunnelConfig,
+    ) -> Result<()> {
+        let target = match (
+            &tunnel_config.local_server_path,
+            tunnel_config.local_server_addr,
+        ) {
+            #[cfg(unix)]
+            (Some(path), _) => DialTarget::UnixSocket(path.clone()),
+            #[cfg(not(unix))]
+            (Some(_), _) => {
+                log_and_bail!("{index}:Unix sockets are not supported on this platform")
+            }
+            (None, Some(addr)) => DialTarget::Addr(addr),
+            (None, None) => DialTarget::Requested,
+        };
+        self.post_tunnel_log(
+            format!(
+                "{index}:TCP_IN start serving via: {}",
+                conn.remote_address()
+            )
+            .as_str(),
+        );
+
+        self.set_and_post_tunnel_state(ClientState::Tunneling);
+        let (dial_backlog, proxy_header, compression) = {
+            let mut state = self.inner_state.lock().unwrap();
+            let record = state.tunnel_records.entry(index).or_default();
+            let proxy_header = match record.proxy_protocol {
+                None => ProxyHeader::None,
+                Some(false) => ProxyHeader::Local,
+                Some(true) => ProxyHeader::Proxied,
+            };
+            let dial_backlog = record
+                .dial_backlog
+                .get_or_insert_with(Default::default)
+                .clone();
+            (
+                dial_backlog,
+                proxy_header,
+                record.compression.unwrap_or_default(),
+            )
+        };
+        TcpTunnel::start_accepting(
+            &conn,
+            target,
+            self.config.tcp_timeout_ms,
+            tunnel_config.one_way,
+            tunnel_config.flush_coalesce_ms,
+            &self.rate_limiters,
+            Some(&dial_backlog),
+            self.inbound_connection_permits.as_ref(),
+            proxy_header,
+            compression,
+        )
+        .await;
+
+        Ok(())
+    }
+
+    async fn serve_inbound_udp(
+        &mut self,
+        index: usize,
+        conn: Connection,
+        local_server_addr: SocketAddr,
+    ) -> Result<()> {
+        let datagrams = self.udp_datagrams(index).unwrap_or(false);
+        self.post_tunnel_log(
+            format!(
+                "{index}:UDP_IN start serving via: {} in {}",
+                conn.remote_address(),
+                Self::udp_mode(datagrams)
+            )
+            .as_str(),
+        );
+
+        self.set_and_post_tunnel_state(ClientState::Tunneling);
+        UdpTunnel::start_accepting(
+            &conn,
+            Some(local_server_addr),
+            self.config.udp_timeout_ms,
+            &self.rate_limiters,
+            datagrams,
+            Some(&self.udp_sessions),
+            &self.datagram_heartbeats(index),
+        )
+        .await;
+
+        Ok(())
+    }
+
+    fn udp_mode(datagrams: bool) -> &'static str {
+        if datagrams {
+            "datagrams"
+        } else {
+            "streams"
+        }
+    }
+
+    fn should_quit(&self) -> bool {
+        let state = self.get_state();
+        state == ClientState::Stopping || state == ClientState::Terminated
+    }
+
+    fn should_stop_tunnel(&self, fail_together: bool) -> bool {
+        self.should_quit() || (fail_together && *self.tunnel_group_failed.borrow())
+    }
+
+    fn report_traffic_data_in_background(&self) {
+        if self.config.congestion_sample_interval_ms > 0 {
+            self.sample_congestion_data_in_background();
+        }
+
+        let report_interval_secs = self
+            .config
+            .traffic_report_interval_secs
+            .unwrap_or(DEFAULT_TRAFFIC_REPORT_INTERVAL_SECS);
+        if report_interval_secs == 0 {
+            return;
+        }
+
+        let state = self.inner_state.clone();
+        let rate_limiters = self.rate_limiters.clone();
+        let idle_streams = self.idle_streams.clone();
+        let denied_destinations = self.denied_destinations.clone();
+        let udp_sessions = self.udp_sessions.clone();
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(Duration::from_secs(report_interval_secs));
+            interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Skip);
+            let mut last_tunneled = (0, 0);
+            let mut last_per_tunnel = PerTunnelTraffic::default();
+            let mut last_tick = interval.tick().await;
+
+            loop {
+                let tick = interval.tick().await;
+                let elapsed_secs = (tick - last_tick).as_secs_f64();
+                last_tick = tick;
+
+                // rates of the payload carried by the tunnels, the counters above include the
+                // QUIC overhead and the retransmissions
+                let tunneled = (
+                    rate_limiters.tx.transferred_bytes(),
+                    rate_limiters.rx.transferred_bytes(),
+                );
+                let tx_bps = ((tunneled.0 - last_tunneled.0) as f64 * 8.0 / elapsed_secs) as u64;
+                let rx_bps = ((tunneled.1 - last_tunneled.1) as f64 * 8.0 / elapsed_secs) as u64;
+                last_tunneled = tunneled;
+
+                let state = state.lock().unwrap();
+                let (mut data, mut per_tunnel) = state.traffic();
+                for (local_server_addr, traffic) in per_tunnel.tunnels.iter_mut() {
+                    traffic.set_rates_since(
+                        last_per_tunnel.tunnels.get(local_server_addr),
+                        elapsed_secs,
+                    );
+                }
+                data.tx_bps = tx_bps;
+                data.rx_bps = rx_bps;
+                data.reaped_idle_streams = idle_streams.as_ref().map_or(0, |idle| idle.reaped());
+                data.denied_destinations = denied_destinations.load(Ordering::Relaxed);
+                data.udp_sessions = udp_sessions.open();
+                let client_state = state.client_state.clone();
+
+                info!(
+                    "traffic log, rx_bytes:{}, tx_bytes:{}, rx_dgrams:{}, tx_dgrams:{}, rx_bps:{}, tx_bps:{}, reaped_idle_streams:{}, denied_destinations:{}, udp_sessions:{}",
+                    data.rx_bytes, data.tx_bytes, data.rx_dgrams, data.tx_dgrams, data.rx_bps, data.tx_bps, data.reaped_idle_streams, data.denied_destinations, data.udp_sessions
+                );
+                state.post_tunnel_info(TunnelInfo::new(
+                    TunnelInfoType::TunnelTraffic,
+                    Box::new(data),
+                ));
+                for (local_server_addr, traffic) in &per_tunnel.tunnels {
+                    debug!(
+                        "traffic log, addr:{local_server_addr}, rx_bytes:{}, tx_bytes:{}, rx_bps:{}, tx_bps:{}",
+                        traffic.rx_bytes, traffic.tx_bytes, traffic.rx_bps, traffic.tx_bps
+                    );
+                }
+                state.post_tunnel_info(TunnelInfo::new(
+                    TunnelInfoType::PerTunnelTraffic,
+                    Box::new(per_tunnel.clone()),
+                ));
+                for (index, record) in &state.tunnel_records {
+                    let Some(backlog) = &record.dial_backlog else {
+                        continue;
+                    };
+                    let backlog = InboundBacklog {
+                        index: *index,
+                        depth: backlog.depth(),
+                        peak: backlog.peak(),
+                    };
+                    if backlog.depth > 0 {
+                        info!(
+                            "{index}:inbound backlog, depth:{}, peak:{}",
+                            backlog.depth, backlog.peak
+                        );
+                    }
+                    state.post_tunnel_info(TunnelInfo::new(
+                        TunnelInfoType::InboundBacklog,
+                        Box::new(backlog),
+                    ));
+                }
+                last_per_tunnel = per_tunnel;
+
+                if client_state == ClientState::Stopping || client_state == ClientState::Terminated
+                {
+                    break;
+                }
+            }
+        });
+    }
+
+    fn sweep_idle_streams_in_background(&self) {
+        let Some(idle_streams) = self.idle_streams.clone() else {
+            return;
+        };
+        let this = self.clone();
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(idle_streams.sweep_interval());
+            interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Skip);
+            while !this.should_quit() {
+                interval.tick().await;
+                idle_streams.sweep();
+            }
+        });
+    }
+
+    /// Posts a heartbeat on every interval whether or not anything changed, so consumers of
+    /// the events can tell a quiet client from a stalled one by the missing heartbeats
+    fn post_heartbeats_in_background(&self) {
+        if self.config.heartbeat_interval_secs == 0 {
+            return;
+        }
+
+        let state = self.inner_state.clone();
+        let heartbeat_interval = Duration::from_secs(self.config.heartbeat_interval_secs);
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(heartbeat_interval);
+            interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Skip);
+            let mut seq = 0;
+            loop {
+                interval.tick().await;
+                let state = state.lock().unwrap();
+                let client_state = state.client_state.clone();
+                state.post_tunnel_info(TunnelInfo::new(
+                    TunnelInfoType::Heartbeat,
+                    Box::new(Heartbeat {
+                        seq,
+                        timestamp_ms: chrono::Local::now().timestamp_millis(),
+                        state: client_state.clone(),
+                        connections: state.connections.len(),
+                        tunnels: state.tunnel_heartbeats(),
+                    }),
+                ));
+                seq += 1;
+
+                if client_state == ClientState::Stopping || client_state == ClientState::Terminated
+                {
+                    break;
+                }
+            }
+        });
+    }
+
+    /// Number of connections to the SOCKS5 and HTTP tunnels refused for asking for a destination
+    /// their `upstream_allow`/`upstream_deny` patterns don't permit
+    pub fn denied_destinations(&self) -> u64 {
+        self.denied_destinations.load(Ordering::Relaxed)
+    }
+
+    /// Number of TCP streams closed for being idle, see `ClientConfig::stream_idle_timeout_ms`
+    pub fn reaped_idle_streams(&self) -> u64 {
+        self.idle_streams.as_ref().map_or(0, |idle| idle.reaped())
+    }
+
+    /// Number of UDP sessions the inbound UDP tunnels have open to their local servers, see
+    /// `ClientConfig::max_udp_sessions`
+    pub fn udp_sessions(&self) -> usize {
+        self.udp_sessions.open()
+    }
+
+    /// Sends the heartbeats of `ClientConfig::datagram_heartbeat_interval_ms` on the connection
+    /// of the tunnel if the server agreed to echo them. The echoes are read along with the
+    /// packets if it's a UDP tunnel in datagram mode, or by a task of their own otherwise
+    fn start_datagram_heartbeats(&self, state: &mut State, index: usize, conn: &Connection) {
+        let record = state.tunnel_records.entry(index).or_default();
+        record.heartbeat = None;
+        if !record.datagram_heartbeat {
+            return;
+        }
+
+        let probe = Arc::new(HeartbeatProbe::new());
+        record.heartbeat = Some(probe.clone());
+        if record.udp_datagrams != Some(true) {
+            tokio::spawn(DatagramHeartbeats::Measure(probe.clone()).read(conn.clone()));
+        }
+        tokio::spawn(probe.run(
+            conn.clone(),
+            Duration::from_millis(self.config.datagram_heartbeat_interval_ms),
+        ));
+    }
+
+    fn datagram_heartbeats(&self, index: usize) -> DatagramHeartbeats {
+        inner_state!(self, tunnel_records)
+            .get(&index)
+            .and_then(|record| record.heartbeat.clone())
+            .map_or(DatagramHeartbeats::None, DatagramHeartbeats::Measure)
+    }
+
+    /// Serves the counters on `ClientConfig::metrics_addr` until the client stops
+    fn serve_metrics_in_background(&self) {
+        let Some(metrics_addr) = self.config.metrics_addr else {
+            return;
+        };
+
+        #[cfg(feature = "metrics")]
+        {
+            let this = self.clone();
+            let mut state_changes = self.state_changes.subscribe();
+            tokio::spawn(async move {
+                tokio::select! {
+                    result = crate::metrics::serve(metrics_addr, move || this.render_metrics()) => {
+                        if let Err(e) = result {
+                            error!("metrics listener stopped: {e:#}");
+                        }
+                    }
+                    _ = state_changes.wait_for(|state| {
+                        *state == ClientState::Stopping || *state == ClientState::Terminated
+                    }) => {}
+                }
+            });
+        }
+        #[cfg(not(feature = "metrics"))]
+        warn!("metrics_addr {metrics_addr} is ignored, rstun is built without the metrics feature");
+    }
+
+    /// The traffic is that of `report_traffic_data_in_background`, the per-tunnel counters
+    /// restart from zero when the tunnel reconnects
+    #[cfg(feature = "metrics")]
+    fn render_metrics(&self) -> String {
+        use crate::metrics::MetricsWriter;
+        use std::collections::BTreeMap;
+
+        let state = self.inner_state.lock().unwrap();
+        let (total, per_tunnel) = state.traffic();
+        let records: BTreeMap<_, _> = state.tunnel_records.iter().collect();
+        let mut writer = MetricsWriter::default();
+
+        writer.metric(
+            "rstun_client_state",
+            "gauge",
+            "1 for the current state of the client, 0 for the others",
+            [
+                ClientState::Idle,
+                ClientState::Connecting,
+                ClientState::Connected,
+                ClientState::LoggingIn,
+                ClientState::Tunneling,
+                ClientState::Stopping,
+                ClientState::Terminated,
+            ]
+            .into_iter()
+            .map(|client_state| {
+                let value = if client_state == state.client_state {
+                    1.0
+                } else {
+                    0.0
+                };
+                (vec![("state", client_state.to_string())], value)
+            }),
+        );
+        writer.metric(
+            "rstun_active_connections",
+            "gauge",
+            "connections to the server serving the tunnels",
+            [(vec![], state.connections.len() as f64)],
+        );
+        writer.metric(
+            "rstun_tx_bytes_total",
+            "counter",
+            "bytes sent to the server by all the connections, QUIC overhead included",
+            [(vec![], total.tx_bytes as f64)],
+        );
+        writer.metric(
+            "rstun_rx_bytes_total",
+            "counter",
+            "bytes received from the server by all the connections, QUIC overhead included",
+            [(vec![], total.rx_bytes as f64)],
+        );
+        writer.metric(
+            "rstun_tunnel_tx_bytes_total",
+            "counter",
+            "bytes sent by the current connection of the tunnel",
+            per_tunnel.tunnels.iter().map(|(addr, traffic)| {
+                (vec![("tunnel", addr.to_string())], traffic.tx_bytes as f64)
+            }),
+        );
+        writer.metric(
+            "rstun_tunnel_rx_bytes_total",
+            "counter",
+            "bytes received by the current connection of the tunnel",
+            per_tunnel.tunnels.iter().map(|(addr, traffic)| {
+                (vec![("tunnel", addr.to_string())], traffic.rx_bytes as f64)
+            }),
+        );
+        writer.metric(
+            "rstun_tunnel_reconnects_total",
+            "counter",
+            "times the tunnel reconnected after its connection closed",
+            records.iter().map(|(index, record)| {
+                (vec![("index", index.to_string())], record.reconnects as f64)
+            }),
+        );
+        writer.metric(
+            "rstun_tunnel_handshake_seconds",
+            "gauge",
+            "duration of the QUIC handshake of the latest connection of the tunnel",
+            records.iter().filter_map(|(index, record)| {
+                let handshake_ms = record.handshake_ms?;
+                Some((
+                    vec![("index", index.to_string())],
+                    handshake_ms as f64 / 1000.0,
+                ))
+            }),
+        );
+        writer.metric(
+            "rstun_tunnel_login_seconds",
+            "gauge",
+            "duration of the login exchange of the latest connection of the tunnel, after the handshake",
+            records.iter().filter_map(|(index, record)| {
+                let login_ms = record.login_ms?;
+                Some((vec![("index", index.to_string())], login_ms as f64 / 1000.0))
+            }),
+        );
+        writer.metric(
+            "rstun_tunnel_heartbeat_rtt_seconds",
+            "gauge",
+            "RTT of the latest echo of the datagram heartbeats of the tunnel",
+            records.iter().filter_map(|(index, record)| {
+                let rtt_ms = record.heartbeat.as_ref()?.rtt_ms()?;
+                Some((vec![("index", index.to_string())], rtt_ms as f64 / 1000.0))
+            }),
+        );
+        writer.metric(
+            "rstun_migrations_total",
+            "counter",
+            "endpoints rebound to a new local address",
+            [(vec![], state.migrations as f64)],
+        );
+        writer.metric(
+            "rstun_udp_sessions",
+            "gauge",
+            "UDP sessions the inbound UDP tunnels have open to their local servers",
+            [(vec![], self.udp_sessions.open() as f64)],
+        );
+        writer.into_text()
+    }
+
+    fn sample_congestion_data_in_background(&self) {
+        let state = self.inner_state.clone();
+        let sample_interval = self.config.congestion_sample_interval_ms;
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(Duration::from_millis(sample_interval));
+            interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Skip);
+
+            loop {
+                interval.tick().await;
+
+                let state = state.lock().unwrap();
+                let timestamp_ms = chrono::Local::now().timestamp_millis();
+                for (local_server_addr, conn) in &state.connections {
+                    let path = conn.stats().path;
+                    let rtt_us = path.rtt.as_micros() as u64;
+                    let pacing_rate_bps = path
+                        .cwnd
+                        .saturating_mul(8_000_000)
+                        .checked_div(rtt_us)
+                        .unwrap_or(0);
+
+                    let sample = CongestionSample {
+                        local_server_addr: *local_server_addr,
+                        timestamp_ms,
+                        rtt_ms: path.rtt.as_millis() as u64,
+                        cwnd: path.cwnd,
+                        pacing_rate_bps,
+                        congestion_events: path.congestion_events,
+                    };
+                    debug!(
+                        "congestion sample, addr:{local_server_addr}, rtt:{}ms, cwnd:{}, pacing_rate:{}bps",
+                        sample.rtt_ms, sample.cwnd, sample.pacing_rate_bps
+                    );
+                    state.post_tunnel_info(TunnelInfo::new(
+                        TunnelInfoType::CongestionSample,
+                        Box::new(sample),
+                    ));
+                }
+
+                if state.client_state == ClientState::Stopping
+                    || state.client_state == ClientState::Terminated
+                {
+                    break;
+                }
+            }
+        });
+    }
+
+    fn get_crypto_provider(&self, cipher: &SupportedCipherSuite) -> Arc<CryptoProvider> {
+        let default_provider = rustls::crypto::ring::default_provider();
+        let mut cipher_suites = vec![*cipher];
+        // Quinn assumes that the cipher suites contain this one
+        cipher_suites.push(cipher_suite::TLS13_AES_128_GCM_SHA256);
+        Arc::new(rustls::crypto::CryptoProvider {
+            cipher_suites,
+            ..default_provider
+        })
+    }
+
+    /// the windows of `overrides` take precedence, the zero (unset) ones fall back to the defaults
+    fn create_client_config_builder(
+        &self,
+        cipher: &SupportedCipherSuite,
+    ) -> std::result::Result<
+        rustls::ConfigBuilder<rustls::ClientConfig, rustls::WantsVerifier>,
+        rustls::Error,
+    > {
+        let cfg_builder =
+            rustls::ClientConfig::builder_with_provider(self.get_crypto_provider(cipher))
+                .with_protocol_versions(&[&rustls::version::TLS13])
+                .unwrap();
+        Ok(cfg_builder)
+    }
+
+    fn parse_client_config_and_domain(&self) -> Result<(rustls::ClientConfig, String)> {
+        let cipher = *SelectedCipherSuite::from_str(&self.config.cipher).map_err(|_| {
+            rustls::Error::General(format!("invalid cipher: {}", self.config.cipher))
+        })?;
+
+        if !self.config.pinned_spki_sha256.is_empty() {
+            let pinned = PinnedCertVerifier::new(
+                &self.config.pinned_spki_sha256,
+                self.get_crypto_provider(&cipher),
+            )?;
+            let client_config = self.with_client_auth(
+                self.create_client_config_builder(&cipher)?
+                    .dangerous()
+                    .with_custom_certificate_verifier(self.recording_verifier(Arc::new(pinned))),
+            )?;
+            return Ok((client_config, self.server_name()));
+        }
+
+        if !self.has_cert() {
+            if !Self::is_ip_addr(&self.config.server_addr)
+                || self.config.server_name_override.is_some()
+            {
+                let client_config = self.with_client_auth(
+                    self.create_client_config_builder(&cipher)?
+                        .dangerous()
+                        .with_custom_certificate_verifier(self.recording_verifier(
+                            self.pkix_verifier(self.get_crypto_provider(&cipher))?,
+                        )),
+                )?;
+
+                return Ok((client_config, self.server_name()));
+            }
+
+            if !self.config.allow_insecure {
+                log_and_bail!(
+                    "no cert and insecure not allowed, set cert_path, pinned_spki_sha256 or server_name_override to verify the server at {}, or allow_insecure to skip the verification",
+                    self.config.server_addr
+                );
+            }
+
+            let client_config = self.with_client_auth(
+                self.create_client_config_builder(&cipher)?
+                    .dangerous()
+                    .with_custom_certificate_verifier(Arc::new(InsecureCertVerifier::new(
+                        self.get_crypto_provider(&cipher),
+                    ))),
+            )?;
+
+            static ONCE: Once = Once::new();
+            ONCE.call_once(|| {
+                warn!(
+                    "Insecure mode, the server certificate isn't verified, domain \"localhost\" is assumed"
+                );
+            });
+            return Ok((client_config, "localhost".to_string()));
+        }
+
+        // for self-signed certificates, generating IP-based TLS certificates is not difficult
+        let domain_or_ip = self.server_name();
+
+        Ok((
+            self.with_client_auth(
+                self.create_client_config_builder(&cipher)?
+                    .dangerous()
+                    .with_custom_certificate_verifier(self.recording_verifier(
+                        self.pkix_verifier(self.get_crypto_provider(&cipher))?,
+                    )),
+            )?,
+            domain_or_ip,
+        ))
+    }
+
+    /// the name sent as SNI and verified against the certificate, `server_name_override` if
+    /// set, or the domain or IP of `server_addr`
+    fn server_name(&self) -> String {
+        if let Some(server_name) = &self.config.server_name_override {
+            return server_name.clone();
+        }
+        // without the brackets of IPv6 and the zone of a link-local address
+        if let Ok(Some(addr)) = Self::parse_ip_addr(&self.config.server_addr) {
+            return addr.ip().to_string();
+        }
+        match self.config.server_addr.rfind(':') {
+            Some(colon_index) => self.config.server_addr[0..colon_index].to_string(),
+            None => self.config.server_addr.to_string(),
+        }
+    }
+
+    /// verifies against the provided certificate if any, or the platform's trust store, and
+    /// caps the length of the chain if `max_cert_chain_len` is set
+    fn pkix_verifier(&self, provider: Arc<CryptoProvider>) -> Result<Arc<dyn ServerCertVerifier>> {
+        let verifier: Arc<dyn ServerCertVerifier> = if !self.has_cert() {
+            Arc::new(rustls_platform_verifier::Verifier::new(provider)?)
+        } else {
+            WebPkiServerVerifier::builder_with_provider(
+                Arc::new(self.load_root_certificates()?),
+                provider,
+            )
+            .build()?
+        };
+
+        Ok(match self.config.max_cert_chain_len {
+            Some(0) => log_and_bail!("max_cert_chain_len must be at least 1"),
+            Some(max_len) => Arc::new(ChainLengthVerifier {
+                inner: verifier,
+                max_len,
+            }),
+            None => verifier,
+        })
+    }
+
+    pub fn get_state(&self) -> ClientState {
+        inner_state!(self, client_state).clone()
+    }
+
+    /// The configuration the client runs with as pretty-printed JSON, to be saved and loaded
+    /// again with `ClientConfig::from_json`, the tunnels are in the --tcp-mappings /
+    /// --udp-mappings form. `redact_secrets` replaces the password and the client key path,
+    /// `auth_provider` is never exported
+    pub fn export_config(&self, redact_secrets: bool) -> String {
+        let mut config = serde_json::to_value(&self.config).unwrap_or_default();
+        if redact_secrets {
+            for key in ["password", "client_key_path"] {
+                if config[key].as_str().is_some_and(|s| !s.is_empty()) {
+                    config[key] = REDACTED.into();
+                }
+            }
+        }
+        serde_json::to_string_pretty(&config).unwrap_or_default()
+    }
+
+    /// Current stats of each active connection keyed by the local server address of its tunnel,
+    /// the closed ones are left out
+    pub fn get_connection_stats(&self) -> Vec<ConnectionStats> {
+        inner_state!(self, connections)
+            .iter()
+            .filter(|(_, conn)| conn.close_reason().is_none())
+            .map(|(local_server_addr, conn)| {
+                let stats = conn.stats();
+                ConnectionStats {
+                    local_server_addr: *local_server_addr,
+                    rtt_ms: stats.path.rtt.as_millis() as u64,
+                    cwnd: stats.path.cwnd,
+                    congestion_events: stats.path.congestion_events,
+                    sent_packets: stats.path.sent_packets,
+                    lost_packets: stats.path.lost_packets,
+                    lost_bytes: stats.path.lost_bytes,
+                    tx_bytes: stats.udp_tx.bytes,
+                    rx_bytes: stats.udp_rx.bytes,
+                }
+            })
+            .collect()
+    }
+
+    /// presents the client certificate if configured, for servers that require mutual TLS
+    fn with_client_auth(
+        &self,
+        builder: rustls::ConfigBuilder<rustls::ClientConfig, rustls::client::WantsClientCert>,
+    ) -> Result<rustls::ClientConfig> {
+        let cert_path = self.config.client_cert_path.as_str();
+        let key_path = self.config.client_key_path.as_str();
+        match (cert_path.is_empty(), key_path.is_empty()) {
+            (true, true) => return Ok(builder.with_no_client_auth()),
+            (false, false) => {}
+            _ => log_and_bail!(
+                "both client_cert_path and client_key_path are required for mutual TLS"
+            ),
+        }
+
+        let certs = pem_util::load_certificates_from_pem(cert_path)
+            .context(format!("failed to read client cert file: {cert_path}"))?;
+        if certs.is_empty() {
+            log_and_bail!("No certificates found in provided file: {cert_path}");
+        }
+        let key = pem_util::load_private_key_from_pem(key_path)
+            .context(format!("failed to read client key file: {key_path}"))?;
+        Ok(builder.with_client_auth_cert(certs, key)?)
+    }
+
+    /// whether a certificate to verify the server against is provided, in memory or as a file
+    fn has_cert(&self) -> bool {
+        self.config.cert_pem.is_some() || !self.config.cert_path.is_empty()
+    }
+
+    /// whether `parse_client_config_and_domain` falls back to the insecure verification, which
+    /// accepts any certificate
+    fn skips_cert_verification(&self) -> bool {
+        self.config.pinned_spki_sha256.is_empty()
+            && !self.has_cert()
+            && Self::is_ip_addr(&self.config.server_addr)
+            && self.config.server_name_override.is_none()
+    }
+
+    fn load_root_certificates(&self) -> Result<RootCertStore> {
+        let (certs, source) = match &self.config.cert_pem {
+            Some(pem) => (
+                pem_util::parse_certificates_from_pem(pem.as_bytes()),
+                "cert_pem".to_string(),
+            ),
+            None => (
+                pem_util::load_certificates_from_pem(self.config.cert_path.as_str())
+                    .context("failed to read from cert file")?,
+                format!("file: {}", self.config.cert_path),
+            ),
+        };
+        if certs.is_empty() {
+            log_and_bail!("No certificates found in provided {source}");
+        }
+        let mut roots = RootCertStore::empty();
+        // save all certificates in the certificate chain to the trust list
+        for cert in &certs {
+            roots
+                .add(cert.clone())
+                .context(format!("failed to add certificate from {source}"))?;
+        }
+        Ok(roots)
+    }
+
+    /// Replaces the certificate verifier with one checking the TLSA records of the server,
+    /// falling back to the CA (or provided certificate) verification for the PKIX usages
+    #[cfg(feature = "dane")]
+    async fn enable_dane(
+        &self,
+        tls_client_cfg: &mut rustls::ClientConfig,
+        domain: &str,
+        port: u16,
+    ) -> Result<()> {
+        if Self::is_ip_addr(&self.config.server_addr) {
+            log_and_bail!("DANE verification requires the server address to be a domain");
+        }
+
+        let records = crate::dane::lookup_tlsa(
+            domain,
+            port,
+            &self.config.dot_servers,
+            &self.config.dns_servers,
+        )
+        .await?;
+        if records.is_empty() {
+            log_and_bail!("no DNSSEC-validated TLSA records found for {domain}:{port}");
+        }
+
+        let provider = tls_client_cfg.crypto_provider().clone();
+        let pkix = self.pkix_verifier(provider.clone())?;
+
+        tls_client_cfg
+            .dangerous()
+            .set_certificate_verifier(self.recording_verifier(Arc::new(
+                crate::dane::DaneCertVerifier::new(records, pkix, provider),
+            )));
+        Ok(())
+    }
+
+    #[cfg(not(feature = "dane"))]
+    async fn enable_dane(
+        &self,
+        _tls_client_cfg: &mut rustls::ClientConfig,
+        _domain: &str,
+        _port: u16,
+    ) -> Result<()> {
+        log_and_bail!("DANE verification requires rstun to be built with the dane feature");
+    }
+
+    /// records the certificates `verifier` rejects, see `post_cert_verification_failure`
+    fn recording_verifier(
+        &self,
+        verifier: Arc<dyn ServerCertVerifier>,
+    ) -> Arc<dyn ServerCertVerifier> {
+        Arc::new(RecordingCertVerifier {
+            inner: verifier,
+            rejected: self.rejected_cert.clone(),
+        })
+    }
+
+    /// the certificate alerts this side sent, i.e. the server certificate failed verification,
+    /// the same alerts from the server are about the client certificate
+    fn is_cert_rejected(e: &quinn::ConnectionError) -> bool {
+        let quinn::ConnectionError::TransportError(err) = e else {
+            return false;
+        };
+        [
+            rustls::AlertDescription::BadCertificate,
+            rustls::AlertDescription::UnsupportedCertificate,
+            rustls::AlertDescription::CertificateRevoked,
+            rustls::AlertDescription::CertificateExpired,
+            rustls::AlertDescription::CertificateUnknown,
+            rustls::AlertDescription::UnknownCA,
+            rustls::AlertDescription::AccessDenied,
+            rustls::AlertDescription::DecryptError,
+        ]
+        .into_iter()
+        .any(|alert| err.code == quinn::TransportErrorCode::crypto(u8::from(alert)))
+    }
+
+    fn is_cert_rejected_error(e: &anyhow::Error) -> bool {
+        e.downcast_ref::<quinn::ConnectionError>()
+            .is_some_and(Self::is_cert_rejected)
+    }
+
+    /// tells which check the server certificate failed and how to fix it, along with the names
+    /// and the validity of the certificate if the verifier recorded it
+    fn post_cert_verification_failure(
+        &self,
+        index: usize,
+        domain: &str,
+        e: &quinn::ConnectionError,
+    ) {
+        let rejected = self.rejected_cert.lock().unwrap().take();
+        let (check, guidance) = match rejected.as_ref().map(|rejected| &rejected.error) {
+            Some(rustls::Error::InvalidCertificate(err)) => match err {
+                rustls::CertificateError::Expired | rustls::CertificateError::ExpiredContext { .. } => (
+                    "expired",
+                    "the server certificate has expired, renew it on the server".to_string(),
+                ),
+                rustls::CertificateError::NotValidYet
+                | rustls::CertificateError::NotValidYetContext { .. } => (
+                    "not_yet_valid",
+                    "the server certificate isn't valid yet, check the clocks of both hosts"
+                        .to_string(),
+                ),
+                rustls::CertificateError::NotValidForName
+                | rustls::CertificateError::NotValidForNameContext { .. } => (
+                    "name_mismatch",
+                    format!("the server certificate isn't issued for {domain}, connect with one of its names or reissue it to include {domain}"),
+                ),
+                rustls::CertificateError::UnknownIssuer => (
+                    "unknown_issuer",
+                    "the server certificate isn't issued by a trusted CA, pass the CA certificate, or the self-signed server certificate, as the certificate to verify with".to_string(),
+                ),
+                rustls::CertificateError::Revoked => (
+                    "revoked",
+                    "the server certificate has been revoked, reissue it".to_string(),
+                ),
+                rustls::CertificateError::ApplicationVerificationFailure => (
+                    "rejected",
+                    "the server certificate doesn't match the pinned key or the TLSA records, check them against the server".to_string(),
+                ),
+                _ => (
+                    "invalid",
+                    "the server certificate is invalid, check it on the server".to_string(),
+                ),
+            },
+            _ => (
+                "invalid",
+                "the server certificate was rejected, check it on the server".to_string(),
+            ),
+        };
+
+        let (subject_alt_names, not_before, not_after) = rejected
+            .as_ref()
+            .and_then(|rejected| X509Certificate::from_der(rejected.end_entity.as_ref()).ok())
+            .map(|(_, cert)| {
+                let names = match cert.subject_alternative_name() {
+                    Ok(Some(san)) => san
+                        .value
+                        .general_names
+                        .iter()
+                        .map(|name| match name {
+                            GeneralName::DNSName(name) => name.to_string(),
+                            GeneralName::IPAddress(ip) => match ip.len() {
+                                4 => IpAddr::from(<[u8; 4]>::try_from(*ip).unwrap()).to_string(),
+                                16 => IpAddr::from(<[u8; 16]>::try_from(*ip).unwrap()).to_string(),
+                                _ => name.to_string(),
+                            },
+                            _ => name.to_string(),
+                        })
+                        .collect(),
+                    _ => vec![],
+                };
+                let validity = cert.validity();
+                (
+                    names,
+                    Some(validity.not_before.to_string()),
+                    Some(validity.not_after.to_string()),
+                )
+            })
+            .unwrap_or_default();
+
+        error!(
+            "{index}:server certificate failed verification ({check}): {e}, {guidance}, names:{subject_alt_names:?}, not_before:{}, not_after:{}",
+            not_before.as_deref().unwrap_or("?"),
+            not_after.as_deref().unwrap_or("?"),
+        );
+        self.inner_state
+            .lock()
+            .unwrap()
+            .post_tunnel_info(TunnelInfo::new(
+                TunnelInfoType::CertVerificationFailed,
+                Box::new(CertVerificationFailure {
+                    index,
+                    check: check.to_string(),
+                    message: e.to_string(),
+                    guidance,
+                    subject_alt_names,
+                    not_before,
+                    not_after,
+                }),
+            ));
+    }
+
+    /// Posts CertExpiryWarning if the certificate of the server `conn` is connected to expires
+    /// within `cert_expiry_warning_days`, and closes `conn` if it expired already and
+    /// `reject_expired_cert` is set
+    fn check_cert_expiry(&self, index: usize, conn: &Connection) -> Result<()> {
+        let warning_days = self.config.cert_expiry_warning_days;
+        if warning_days == 0 && !self.config.reject_expired_cert {
+            return Ok(());
+        }
+        // quinn exposes the certificate presented by the server even if it wasn't verified
+        let Some(end_entity) = conn
+            .peer_identity()
+            .and_then(|identity| {
+                identity
+                    .downcast::<Vec<rustls::pki_types::CertificateDer<'static>>>()
+                    .ok()
+            })
+            .and_then(|certs| certs.into_iter().next())
+        else {
+            return Ok(());
+        };
+        let Ok((_, cert)) = X509Certificate::from_der(end_entity.as_ref()) else {
+            return Ok(());
+        };
+
+        let not_after = cert.validity().not_after;
+        let secs_left = not_after.timestamp() - chrono::Utc::now().timestamp();
+        let expired = secs_left <= 0;
+        let rejected = expired && self.config.reject_expired_cert;
+        if !expired && secs_left >= i64::from(warning_days) * 86400 {
+            return Ok(());
+        }
+
+        let days_left = secs_left.div_euclid(86400);
+        let subject = cert.subject().to_string();
+        let verified = !self.skips_cert_verification();
+        warn!(
+            "{index}:{}server certificate of {subject} expires at {not_after}, days left:{days_left}",
+            if verified { "" } else { "unverified " }
+        );
+        self.inner_state
+            .lock()
+            .unwrap()
+            .post_tunnel_info(TunnelInfo::new(
+                TunnelInfoType::CertExpiryWarning,
+                Box::new(CertExpiryWarning {
+                    index,
+                    subject,
+                    not_after: not_after.to_string(),
+                    days_left,
+                    rejected,
+                    verified,
+                }),
+            ));
+        if rejected {
+            conn.close(VarInt::from_u32(0), b"certificate expired");
+            log_and_bail!("{index}:server certificate expired at {not_after}, connection closed");
+        }
+        Ok(())
+    }
+
+    /// the connection is made with `client_cfg` rather than the default config of the endpoint,
+    /// which may be shared by tunnels with different transport settings
+    async fn connect_once(
+        &self,
+        endpoint: &Endpoint,
+        client_cfg: &quinn::ClientConfig,
+        remote_addr: SocketAddr,
+        domain: &str,
+    ) -> Result<(Connection, Option<ZeroRttAccepted>)> {
+        if self.config.enable_0rtt {
+            self.transport()
+                .connect_0rtt(endpoint, client_cfg.clone(), remote_addr, domain)
+                .await
+        } else {
+            self.transport()
+                .connect(endpoint, client_cfg.clone(), remote_addr, domain)
+                .await
+                .map(|conn| (conn, None))
+        }
+    }
 
-                        self.handle_network_based_tunnel(
-                            index,
-                            conn.clone(),
-                            tunnel_config,
-                            &mut pending_network_based_stream,
-                        )
+    /// Happy Eyeballs (RFC 8305), the addresses are tried in turn, each one once the previous
+    /// attempt failed or `HAPPY_EYEBALLS_DELAY_MS` later, while the earlier ones keep connecting.
+    /// The first to complete the handshake wins and the others are dropped, so a dead address
+    /// doesn't hold up the connect. The error of the last attempt is returned if all fail.
+    /// 0-RTT isn't used, as a connection is usable before its handshake completes then, and the
+    /// first address with a session ticket would always win, reachable or not.
+    async fn connect_racing(
+        &self,
+        endpoint: &Endpoint,
+        client_cfg: &quinn::ClientConfig,
+        remote_addrs: &[SocketAddr],
+        domain: &str,
+    ) -> Result<(Connection, SocketAddr)> {
+        let mut remaining = remote_addrs.iter().copied();
+        let mut attempts = FuturesUnordered::new();
+        let mut last_err = None;
+        loop {
+            match remaining.next() {
+                Some(addr) => attempts.push(async move {
+                    let result = self
+                        .transport()
+                        .connect(endpoint, client_cfg.clone(), addr, domain)
                         .await;
+                    (addr, result)
+                }),
+                None if attempts.is_empty() => {
+                    return Err(last_err.unwrap_or_else(|| anyhow::anyhow!("no server address")));
+                }
+                None => {}
+            }
 
-                        inner_state!(self, connections).remove(&local_server_addr);
+            let delay = tokio::time::sleep(Duration::from_millis(HAPPY_EYEBALLS_DELAY_MS));
+            tokio::select! {
+                Some((addr, result)) = attempts.next() => match result {
+                    Ok(conn) => return Ok((conn, addr)),
+                    Err(e) => {
+                        debug!("failed to connect to {addr}, err: {e}");
+                        last_err = Some(e);
                     }
-                    Tunnel::ChannelBased(upstream_type) => match upstream_type {
-                        UpstreamType::Tcp => {
-                            self.post_tunnel_log(
-                                format!(
-                                    "{index}:STREAM_OUT start serving via {}",
-                                    conn.remote_address()
-                                )
-                                .as_str(),
-                            );
-                            self.set_and_post_tunnel_state(ClientState::Tunneling);
-
-                            let stream_receiver = stream_receiver.as_mut().unwrap();
-                            TcpTunnel::start_serving(
-                                true,
-                                &conn,
-                                stream_receiver,
-                                &mut pending_channel_based_stream,
-                                self.config.tcp_timeout_ms,
-                            )
-                            .await;
-                        }
-
-                        UpstreamType::Udp => {
-                            self.post_tunnel_log(
-                                format!(
-                                    "{index}:UDP_OUT start serving via {}",
-                                    conn.remote_address()
-                                )
-                                .as_str(),
-                            );
-                            self.set_and_post_tunnel_state(ClientState::Tunneling);
-
-                            let ch = ch.as_mut().unwrap();
-                            UdpTunnel::start_serving(
-                                &conn,
-                                &ch.0,
-                                &mut ch.1,
-                                self.config.udp_timeout_ms,
-                            )
-                            .await;
-                        }
-                    },
                 },
-
-                Err(e) => {
-                    error!("{e}");
-                    info!(
-                        "[{login_info}] quit after having retried for {} times",
-                        usize::MAX
-                    );
-                    break;
-                }
-            };
-
-            if self.should_quit() {
-                break;
+                _ = delay, if remaining.len() > 0 => {}
             }
         }
-        self.post_tunnel_log(format!("[{login_info}] quit").as_str());
     }
 
-    async fn handle_network_based_tunnel(
-        &mut self,
-        index: usize,
-        conn: Connection,
-        tunnel_config: &TunnelConfig,
-        pending_request: &mut Option<StreamRequest<TcpStream>>,
-    ) {
-        let upstream_type = &tunnel_config.upstream.upstream_type;
-        let local_server_addr = tunnel_config.local_server_addr.unwrap();
+    /// All the addresses the server resolved to, if `remote_addr` was selected from them, in the
+    /// order they are to be tried. Those the endpoint can't reach are left out, an IPv4 endpoint
+    /// can't connect to IPv6 addresses, while an IPv6 one reaches IPv4 through mapped addresses.
+    fn happy_eyeballs_candidates(
+        &self,
+        endpoint: &Endpoint,
+        remote_addr: &SocketAddr,
+    ) -> Vec<SocketAddr> {
+        let endpoint_ipv6 = endpoint
+            .local_addr()
+            .map_or(remote_addr.is_ipv6(), |addr| addr.is_ipv6());
+        match &inner_state!(self, server_resolution) {
+            Some(resolution) if resolution.selected == remote_addr.ip() => resolution
+                .addresses
+                .iter()
+                .filter(|ip| endpoint_ipv6 || ip.is_ipv4())
+                .map(|ip| SocketAddr::new(*ip, remote_addr.port()))
+                .collect(),
+            _ => vec![*remote_addr],
+        }
+    }
 
-        if tunnel_config.mode == TunnelMode::Out {
-            match upstream_type {
-                UpstreamType::Tcp => {
-                    self.serve_outbound_tcp(
-                        index,
-                        conn.clone(),
-                        local_server_addr,
-                        pending_request,
-                    )
-                    .await
-                    .ok();
-                }
-                UpstreamType::Udp => {
-                    self.serve_outbound_udp(index, conn.clone(), local_server_addr)
-                        .await
-                        .ok();
-                }
+    /// the TLS no_application_protocol alert, sent by whichever side found no protocol in common
+    fn is_alpn_rejected(e: &quinn::ConnectionError) -> bool {
+        let code = match e {
+            quinn::ConnectionError::ConnectionClosed(close) => close.error_code,
+            quinn::ConnectionError::TransportError(err) => err.code,
+            _ => return false,
+        };
+        code == quinn::TransportErrorCode::crypto(u8::from(
+            rustls::AlertDescription::NoApplicationProtocol,
+        ))
+    }
+
+    fn is_ip_addr(addr: &str) -> bool {
+        !matches!(Self::parse_ip_addr(addr), Ok(None))
+    }
+
+    /// `addr` as an IP address, None if it's a domain. Link-local IPv6 addresses take a zone,
+    /// e.g. [fe80::1%eth0]:3515, or fe80::1%eth0 for the default port, which std only parses
+    /// with the numeric scope id. The scope id is kept in the address, so the packets leave
+    /// through the interface the zone names. Other addresses drop the zone, as the packets
+    /// received from them carry none, which wouldn't match the address connected to
+    fn parse_ip_addr(addr: &str) -> Result<Option<SocketAddr>> {
+        if let Ok(addr) = addr.parse::<SocketAddr>() {
+            return Ok(Some(addr));
+        }
+        let (host, port) = match addr.strip_prefix('[') {
+            Some(rest) => {
+                let Some((host, port)) = rest.split_once(']') else {
+                    return Ok(None);
+                };
+                let port = match port.strip_prefix(':') {
+                    Some(port) => port
+                        .parse()
+                        .with_context(|| format!("invalid address: {addr}"))?,
+                    None if port.is_empty() => DEFAULT_SERVER_PORT,
+                    None => bail!("invalid address: {addr}"),
+                };
+                (host, port)
             }
+            None => (addr, DEFAULT_SERVER_PORT),
+        };
+        let Some((ip, zone)) = host.split_once('%') else {
+            return Ok(None);
+        };
+        let Ok(ip) = ip.parse::<Ipv6Addr>() else {
+            return Ok(None);
+        };
+        let scope_id = match zone.parse() {
+            Ok(scope_id) => scope_id,
+            Err(_) => interface_index(zone)
+                .with_context(|| format!("unknown interface '{zone}' in address: {addr}"))?,
+        };
+        let scope_id = if ip.is_unicast_link_local() {
+            scope_id
         } else {
-            match upstream_type {
-                UpstreamType::Tcp => {
-                    self.serve_inbound_tcp(index, conn.clone(), local_server_addr)
-                        .await
-                        .ok();
+            0
+        };
+        Ok(Some(SocketAddr::V6(SocketAddrV6::new(
+            ip, port, 0, scope_id,
+        ))))
+    }
+
+    async fn parse_server_addr(&self) -> Result<SocketAddr> {
+        let redirect = self.server_redirect.borrow().clone();
+        let addr = redirect
+            .as_deref()
+            .unwrap_or(self.config.server_addr.as_str());
+        self.resolve_server_addr(addr).await
+    }
+
+    async fn resolve_server_addr(&self, addr: &str) -> Result<SocketAddr> {
+        if let Some(sock_addr) = Self::parse_ip_addr(addr)? {
+            return Ok(sock_addr);
+        }
+
+        let ttl = Duration::from_secs(
+            self.config
+                .dns_cache_ttl_secs
+                .unwrap_or(DEFAULT_DNS_CACHE_TTL_SECS),
+        );
+        if let Some(cached) = self.cached_server_addr(addr, ttl) {
+            debug!("resolved {addr} to {cached} from the cache");
+            return Ok(cached);
+        }
+
+        match self.lookup_server_addr(addr).await {
+            Ok(sock_addr) => {
+                if !ttl.is_zero() {
+                    inner_state!(self, dns_cache).insert(
+                        addr.to_string(),
+                        CachedServerAddr {
+                            addr: sock_addr,
+                            resolved_at: std::time::Instant::now(),
+                        },
+                    );
                 }
-                UpstreamType::Udp => {
-                    self.serve_inbound_udp(index, conn.clone(), local_server_addr)
-                        .await
-                        .ok();
+                Ok(sock_addr)
+            }
+            Err(e) => {
+                // a transient DNS outage shouldn't take down a server that's still reachable
+                let grace = Duration::from_secs(self.config.dns_stale_grace_secs);
+                match self.cached_server_addr(addr, ttl + grace) {
+                    Some(stale) if !grace.is_zero() => {
+                        warn!("failed to resolve {addr}, using the stale address {stale}: {e}");
+                        Ok(stale)
+                    }
+                    _ => Err(e),
                 }
             }
         }
+    }
 
-        let stats = conn.stats();
-        let data = &mut inner_state!(self, total_traffic_data);
-        data.rx_bytes += stats.udp_rx.bytes;
-        data.tx_bytes += stats.udp_tx.bytes;
-        data.rx_dgrams += stats.udp_rx.datagrams;
-        data.tx_dgrams += stats.udp_tx.datagrams;
+    /// the cached address if resolved within `max_age`
+    fn cached_server_addr(&self, addr: &str, max_age: Duration) -> Option<SocketAddr> {
+        inner_state!(self, dns_cache)
+            .get(addr)
+            .filter(|cached| cached.resolved_at.elapsed() < max_age)
+            .map(|cached| cached.addr)
     }
 
-    async fn prepare_login_config(&self) -> Result<LoginConfig> {
-        let mut transport_cfg = TransportConfig::default();
-        transport_cfg.stream_receive_window(quinn::VarInt::from_u32(1024 * 1024));
-        transport_cfg.receive_window(quinn::VarInt::from_u32(1024 * 1024 * 2));
-        transport_cfg.send_window(1024 * 1024 * 2);
-        transport_cfg.congestion_controller_factory(Arc::new(congestion::BbrConfig::default()));
-        transport_cfg.max_concurrent_bidi_streams(VarInt::from_u32(1024));
+    async fn lookup_server_addr(&self, addr: &str) -> Result<SocketAddr> {
+        let mut domain = addr;
+        let mut port = DEFAULT_SERVER_PORT;
+        let pos = addr.rfind(':');
+        if let Some(pos) = pos {
+            port = addr[(pos + 1)..]
+                .parse()
+                .with_context(|| format!("invalid address: {}", addr))?;
+            domain = &addr[..pos];
+        }
 
-        if self.config.quic_timeout_ms > 0 {
-            let timeout = IdleTimeout::from(VarInt::from_u32(self.config.quic_timeout_ms as u32));
-            transport_cfg.max_idle_timeout(Some(timeout));
-            transport_cfg.keep_alive_interval(Some(Duration::from_millis(
-                self.config.quic_timeout_ms * 2 / 3,
-            )));
+        for doh in self.config.doh_servers.iter().filter(|doh| !doh.is_empty()) {
+            match self.lookup_server_ip_with_doh(domain, doh).await {
+                Ok(ip) => return Ok(SocketAddr::new(ip, port)),
+                Err(e) => warn!("failed to resolve {domain} via {doh}: {e:#}"),
+            }
         }
 
-        let (tls_client_cfg, domain) = self.parse_client_config_and_domain()?;
-        let quic_client_cfg = Arc::new(QuicClientConfig::try_from(tls_client_cfg)?);
-        let mut client_cfg = quinn::ClientConfig::new(quic_client_cfg);
-        client_cfg.transport_config(Arc::new(transport_cfg));
+        for dot in &self.config.dot_servers {
+            if let Ok(ip) = self.lookup_server_ip(domain, dot, vec![]).await {
+                return Ok(SocketAddr::new(ip, port));
+            }
+        }
 
-        let remote_addr = self.parse_server_addr().await?;
-        let local_addr = socket_addr_with_unspecified_ip_port(remote_addr.is_ipv6());
-        Ok(LoginConfig {
-            local_addr,
-            remote_addr,
-            quinn_client_cfg: client_cfg,
-            domain,
-        })
+        if let Ok(ip) = self
+            .lookup_server_ip(domain, "", self.config.dns_servers.clone())
+            .await
+        {
+            return Ok(SocketAddr::new(ip, port));
+        }
+
+        if let Ok(ip) = self.lookup_server_ip(domain, "", vec![]).await {
+            return Ok(SocketAddr::new(ip, port));
+        }
+
+        bail!("failed to resolve domain: {domain}");
     }
 
-    async fn login(
+    async fn lookup_server_ip(
         &self,
-        index: usize,
-        endpoint: &Endpoint,
-        login_info: &LoginInfo,
-        remote_addr: &SocketAddr,
         domain: &str,
-    ) -> Result<Connection> {
-        self.set_and_post_tunnel_state(ClientState::Connecting);
-        self.post_tunnel_log(
-            format!(
-                "{index}:{} connecting, idle_timeout:{}, retry_timeout:{}, cipher:{}, threads:{}",
-                login_info.format_with_remote_addr(remote_addr),
-                self.config.quic_timeout_ms,
-                self.config.wait_before_retry_ms,
-                self.config.cipher,
-                self.config.workers,
+        dot_server: &str,
+        name_servers: Vec<String>,
+    ) -> Result<IpAddr> {
+        let dns_config = DNSResolverConfig {
+            strategy: match self.config.dns_strategy {
+                DnsStrategy::Ipv4Only => DNSResolverLookupIpStrategy::Ipv4Only,
+                DnsStrategy::Ipv6Only => DNSResolverLookupIpStrategy::Ipv6Only,
+                DnsStrategy::Ipv4AndIpv6 => DNSResolverLookupIpStrategy::Ipv4AndIpv6,
+                DnsStrategy::Ipv4thenIpv6 => DNSResolverLookupIpStrategy::Ipv4thenIpv6,
+                DnsStrategy::Ipv6thenIpv4 => DNSResolverLookupIpStrategy::Ipv6thenIpv4,
+            },
+            num_conccurent_reqs: 3,
+            ordering: match self.config.dns_query_ordering {
+                crate::DnsQueryOrdering::QueryStatistics => DNSQueryOrdering::QueryStatistics,
+                crate::DnsQueryOrdering::UserProvidedOrder => DNSQueryOrdering::UserProvidedOrder,
+            },
+        };
+
+        let (resolver, resolver_name) = if !dot_server.is_empty() {
+            (
+                dns::resolver2(dot_server, vec![], dns_config),
+                format!("dot:{dot_server}"),
             )
-            .as_str(),
-        );
+        } else if !name_servers.is_empty() {
+            let resolver_name = format!("dns:{}", name_servers.join(","));
+            (dns::resolver2("", name_servers, dns_config), resolver_name)
+        } else {
+            (dns::resolver2("", vec![], dns_config), "system".to_string())
+        };
 
-        let conn = endpoint.connect(*remote_addr, domain)?.await?;
-        let (mut quic_send, mut quic_recv) = conn
-            .open_bi()
-            .await
-            .context("open bidirectional connection failed")?;
+        let addresses = resolver.await.lookup(domain).await?;
+        self.select_server_ip(domain, resolver_name, addresses)
+    }
 
-        self.set_and_post_tunnel_state(ClientState::Connected);
+    #[cfg(feature = "doh")]
+    async fn lookup_server_ip_with_doh(&self, domain: &str, doh_server: &str) -> Result<IpAddr> {
+        let addresses = crate::doh::lookup_ip(doh_server, domain, self.config.dns_strategy).await?;
+        self.select_server_ip(domain, format!("doh:{doh_server}"), addresses)
+    }
 
-        self.post_tunnel_log(
-            format!(
-                "{index}:{} logging in...",
-                login_info.format_with_remote_addr(remote_addr)
-            )
-            .as_str(),
-        );
+    #[cfg(not(feature = "doh"))]
+    async fn lookup_server_ip_with_doh(&self, _domain: &str, _doh_server: &str) -> Result<IpAddr> {
+        bail!("DNS-over-HTTPS requires rstun to be built with the doh feature");
+    }
 
-        let login_msg = TunnelMessage::ReqLogin(login_info.clone());
-        TunnelMessage::send(&mut quic_send, &login_msg).await?;
+    /// the first address is connected to, and the resolution recorded, the others are raced
+    /// against it if it doesn't connect quickly, see `connect_racing`
+    fn select_server_ip(
+        &self,
+        domain: &str,
+        resolver_name: String,
+        addresses: Vec<IpAddr>,
+    ) -> Result<IpAddr> {
+        let prefer_ipv4 = self.config.dns_strategy == DnsStrategy::Ipv4thenIpv6;
+        let addresses = Self::interleave_address_families(addresses, prefer_ipv4);
+        let Some(ip) = addresses.first().copied() else {
+            bail!("no address found for {domain}");
+        };
 
-        let resp = TunnelMessage::recv(&mut quic_recv).await?;
-        if let TunnelMessage::RespFailure(msg) = resp {
-            bail!(
-                "{index}:{} failed to login: {msg}",
-                login_info.format_with_remote_addr(remote_addr)
+        let resolution = ServerResolution {
+            domain: domain.to_string(),
+            resolver: resolver_name,
+            addresses,
+            selected: ip,
+        };
+        if self.config.report_dns_resolution {
+            info!(
+                "resolved {domain} to {:?} via {}, selected {ip}",
+                resolution.addresses, resolution.resolver
             );
+        } else {
+            info!("resolved {domain} to {ip}");
         }
-        if !resp.is_resp_success() {
-            bail!(
-                "{index}:{} unexpected response, failed to login",
-                login_info.format_with_remote_addr(remote_addr)
-            );
+
+        let mut state = self.inner_state.lock().unwrap();
+        if self.config.report_dns_resolution {
+            state.post_tunnel_info(TunnelInfo::new(
+                TunnelInfoType::ServerResolution,
+                Box::new(resolution.clone()),
+            ));
         }
-        TunnelMessage::handle_message(&resp)?;
-        self.post_tunnel_log(
-            format!(
-                "{index}:{} login succeeded!",
-                login_info.format_with_remote_addr(remote_addr)
-            )
-            .as_str(),
-        );
-        Ok(conn)
+        state.server_resolution = Some(resolution);
+        Ok(ip)
     }
 
-    async fn serve_outbound_tcp(
-        &mut self,
-        index: usize,
-        conn: Connection,
-        local_server_addr: SocketAddr,
-        pending_request: &mut Option<StreamRequest<TcpStream>>,
-    ) -> Result<()> {
-        let tcp_server = {
-            inner_state!(self, tcp_servers)
-                .get(&local_server_addr)
-                .cloned()
+    /// alternates the address families, IPv6 first unless `prefer_ipv4`, the order RFC 8305
+    /// tries the addresses in
+    fn interleave_address_families(addresses: Vec<IpAddr>, prefer_ipv4: bool) -> Vec<IpAddr> {
+        let mut sorted = Vec::with_capacity(addresses.len());
+        let (first, second): (Vec<_>, Vec<_>) = addresses
+            .into_iter()
+            .partition(|ip| ip.is_ipv4() == prefer_ipv4);
+        let (mut first, mut second) = (first.into_iter(), second.into_iter());
+        loop {
+            match (first.next(), second.next()) {
+                (None, None) => return sorted,
+                (a, b) => sorted.extend(a.into_iter().chain(b)),
+            }
+        }
+    }
+
+    /// the result of the latest successful resolution of the server domain, None if the server
+    /// address is an IP or the domain hasn't been resolved yet
+    pub fn server_resolution(&self) -> Option<ServerResolution> {
+        inner_state!(self, server_resolution).clone()
+    }
+
+    /// servers that predate connection labels don't echo them back, and don't log them either
+    fn check_connection_label(&self, index: usize, resp_ext: Option<&LoginRespExt>) {
+        let Some(label) = &self.config.connection_label else {
+            return;
         };
+        match resp_ext.and_then(|ext| ext.connection_label.as_ref()) {
+            Some(echoed) if echoed == label => {
+                debug!("{index}:server confirmed the connection label: {label}")
+            }
+            Some(echoed) => warn!("{index}:server echoed connection label {echoed}, sent {label}"),
+            None => warn!("{index}:server didn't confirm the connection label: {label}"),
+        }
+    }
 
-        let mut tcp_server = match tcp_server {
-            Some(server) => server.clone(),
-            None => self.start_tcp_server(local_server_addr).await?,
+    /// The idle timeout is negotiated to the lower of both peers' values, warn if the server
+    /// enforces a shorter one than requested, which explains otherwise unexpected disconnects
+    fn update_effective_quic_timeout(&self, index: usize, resp_ext: Option<LoginRespExt>) {
+        let Some(resp_ext) = resp_ext else {
+            debug!("{index}:server didn't report its idle timeout");
+            return;
         };
 
-        self.post_tunnel_log(
-            format!(
-                "{index}:TCP_OUT start serving from {} via {}",
-                tcp_server.addr(),
-                conn.remote_address()
-            )
-            .as_str(),
-        );
-        self.set_and_post_tunnel_state(ClientState::Tunneling);
+        let requested = self.config.quic_timeout_ms;
+        let effective = match (requested, resp_ext.quic_timeout_ms) {
+            (0, timeout) | (timeout, 0) => timeout,
+            (requested, server) => requested.min(server),
+        };
 
-        let mut tcp_receiver = tcp_server.take_receiver();
+        if effective != requested {
+            warn!(
+                "{index}:server clamped the idle timeout to {effective}ms, requested:{requested}ms"
+            );
+        } else {
+            info!("{index}:effective idle timeout:{effective}ms");
+        }
 
-        TcpTunnel::start_serving(
-            true,
-            &conn,
-            &mut tcp_receiver,
-            pending_request,
-            self.config.tcp_timeout_ms,
-        )
-        .await;
+        inner_state!(self, tunnel_records)
+            .entry(index)
+            .or_default()
+            .effective_quic_timeout_ms = Some(effective);
+    }
 
-        tcp_server.put_receiver(tcp_receiver);
+    /// The idle timeout in effect for the tunnel's connection, None if the tunnel hasn't logged
+    /// in yet or the server doesn't report its idle timeout
+    pub fn effective_quic_timeout_ms(&self, index: usize) -> Option<u64> {
+        inner_state!(self, tunnel_records)
+            .get(&index)
+            .and_then(|record| record.effective_quic_timeout_ms)
+    }
 
-        Ok(())
+    /// Streams of an inbound TCP tunnel waiting for its upstream to be dialed, None for the
+    /// other tunnels and before the first connection
+    pub fn inbound_backlog(&self, index: usize) -> Option<InboundBacklog> {
+        inner_state!(self, tunnel_records)
+            .get(&index)
+            .and_then(|record| record.dial_backlog.as_ref())
+            .map(|backlog| InboundBacklog {
+                index,
+                depth: backlog.depth(),
+                peak: backlog.peak(),
+            })
     }
 
-    async fn serve_outbound_udp(
-        &mut self,
+    fn connection_event(
         index: usize,
-        conn: Connection,
         local_server_addr: SocketAddr,
-    ) -> Result<()> {
-        let udp_server = {
-            inner_state!(self, udp_servers)
-                .get(&local_server_addr)
-                .cloned()
+        conn: &Connection,
+        event: ConnectionEventKind,
+        reason: Option<String>,
+    ) -> TunnelInfo<ConnectionEvent> {
+        TunnelInfo::new(
+            TunnelInfoType::ConnectionEvent,
+            Box::new(ConnectionEvent {
+                index,
+                local_server_addr,
+                remote_addr: conn.remote_address(),
+                event,
+                reason,
+            }),
+        )
+    }
+
+    /// Counts the reconnect the tunnel is about to make, false if that exceeds
+    /// `max_lifetime_reconnects`, in which case the tunnel is to stop rather than keep flapping
+    fn record_reconnect(&self, index: usize) -> bool {
+        let limit = self.config.max_lifetime_reconnects;
+        let reconnects = {
+            let mut state = self.inner_state.lock().unwrap();
+            let record = state.tunnel_records.entry(index).or_default();
+            record.reconnects += 1;
+            let reconnects = record.reconnects;
+            if limit > 0 && reconnects > limit {
+                state.post_tunnel_info(TunnelInfo::new(
+                    TunnelInfoType::ReconnectLimitReached,
+                    Box::new(ReconnectLimitReached {
+                        index,
+                        reconnects: reconnects - 1,
+                        limit,
+                    }),
+                ));
+            }
+            reconnects
         };
 
-        let mut udp_server = match udp_server {
-            Some(server) => server.clone(),
-            None => self.start_udp_server(local_server_addr).await?,
-        };
+        if limit > 0 && reconnects > limit {
+            self.post_tunnel_log(
+                format!("{index}:reconnected {limit} times, stopping the tunnel for investigation")
+                    .as_str(),
+            );
+            return false;
+        }
+        true
+    }
 
-        self.post_tunnel_log(
-            format!(
-                "{index}:UDP_OUT start serving from {} via {}",
-                udp_server.addr(),
-                conn.remote_address()
-            )
-            .as_str(),
-        );
+    /// Why the latest connect attempt of the tunnel failed, e.g. to tell the user the server
+    /// certificate expired or the connection was refused, None once it connects
+    pub fn last_error(&self, index: usize) -> Option<String> {
+        inner_state!(self, tunnel_records)
+            .get(&index)
+            .and_then(|record| record.last_error.clone())
+    }
 
-        self.set_and_post_tunnel_state(ClientState::Tunneling);
+    /// Times the tunnel reconnected after its connection closed, see
+    /// `ClientConfig::max_lifetime_reconnects`
+    pub fn lifetime_reconnects(&self, index: usize) -> u64 {
+        inner_state!(self, tunnel_records)
+            .get(&index)
+            .map_or(0, |record| record.reconnects)
+    }
 
-        let mut udp_receiver = udp_server.take_receiver();
-        let udp_sender = udp_server.clone_sender();
+    /// How long the tunnel has been connected and how often it reconnected, along with the state of
+    /// the client, None if the tunnel hasn't tried to connect yet
+    pub fn tunnel_stats(&self, index: usize) -> Option<TunnelStats> {
+        let state = self.inner_state.lock().unwrap();
+        let record = state.tunnel_records.get(&index)?;
+        Some(TunnelStats {
+            index,
+            state: state.client_state.clone(),
+            uptime_ms: record
+                .connected_since
+                .map(|since| since.elapsed().as_millis() as u64),
+            reconnects: record.reconnects,
+            heartbeat_rtt_ms: record.heartbeat.as_ref().and_then(|probe| probe.rtt_ms()),
+            unanswered_heartbeats: record
+                .heartbeat
+                .as_ref()
+                .map_or(0, |probe| probe.unanswered()),
+        })
+    }
 
-        UdpTunnel::start_serving(
-            &conn,
-            &udp_sender,
-            &mut udp_receiver,
-            self.config.udp_timeout_ms,
-        )
-        .await;
+    /// Whether the latest login of the tunnel sent early data in 0-RTT, and thus could have been
+    /// replayed, None if the tunnel hasn't logged in yet. See `ClientConfig::enable_0rtt`
+    pub fn used_0rtt(&self, index: usize) -> Option<bool> {
+        inner_state!(self, tunnel_records)
+            .get(&index)
+            .and_then(|record| record.used_0rtt)
+    }
 
-        udp_server.put_receiver(udp_receiver);
+    /// The TLS cipher suite the latest connection of the tunnel negotiated, named as
+    /// `ClientConfig::cipher`, which it differs from if the server fell back to aes-128-gcm.
+    /// None until the server sent a session ticket, which rstund does right after the handshake
+    pub fn negotiated_cipher(&self, index: usize) -> Option<String> {
+        inner_state!(self, tunnel_records)
+            .get(&index)
+            .and_then(|record| record.negotiated_cipher.clone())
+    }
 
-        Ok(())
+    /// Whether the latest login of a UDP tunnel with `TunnelConfig::udp_datagrams` set carries
+    /// the packets in QUIC datagrams, false if it fell back to streams, None if it hasn't
+    /// logged in yet or didn't ask for datagrams
+    pub fn udp_datagrams(&self, index: usize) -> Option<bool> {
+        inner_state!(self, tunnel_records)
+            .get(&index)
+            .and_then(|record| record.udp_datagrams)
     }
 
-    async fn serve_inbound_tcp(
-        &mut self,
-        index: usize,
-        conn: Connection,
-        local_server_addr: SocketAddr,
-    ) -> Result<()> {
-        self.post_tunnel_log(
-            format!(
-                "{index}:TCP_IN start serving via: {}",
-                conn.remote_address()
-            )
-            .as_str(),
-        );
+    /// The compression the latest login of a TCP tunnel with `TunnelConfig::compression` set
+    /// agreed on, `Compression::None` if the server doesn't support it, None if it hasn't
+    /// logged in yet or didn't ask for compression
+    pub fn compression(&self, index: usize) -> Option<Compression> {
+        inner_state!(self, tunnel_records)
+            .get(&index)
+            .and_then(|record| record.compression)
+    }
 
-        self.set_and_post_tunnel_state(ClientState::Tunneling);
-        TcpTunnel::start_accepting(&conn, Some(local_server_addr), self.config.tcp_timeout_ms)
-            .await;
+    /// Sends an ICMP echo request to `target` from the server and returns the round-trip time
+    /// measured there. It requires a server that allows ICMP (`ServerConfig::allow_icmp`), the
+    /// requests are carried by a connection of their own, which is set up on first use.
+    pub async fn ping(&self, target: IpAddr, seq: u16, timeout: Duration) -> Result<Duration> {
+        let conn = self.icmp_channel().await?;
+        let (mut quic_send, mut quic_recv) = conn.open_bi().await?;
+
+        let req = PingRequest {
+            target,
+            seq,
+            payload_len: PING_PAYLOAD_LEN,
+            timeout_ms: timeout.as_millis() as u64,
+        };
+        TunnelMessage::send(&mut quic_send, &TunnelMessage::ReqPing(req)).await?;
+        quic_send.finish()?;
 
-        Ok(())
+        // the server gives up after timeout, allow for the round trip to the server on top
+        let resp = tokio::time::timeout(timeout * 2, TunnelMessage::recv(&mut quic_recv))
+            .await
+            .with_context(|| format!("ping {target} timed out"))??;
+        match resp {
+            TunnelMessage::RespPing(reply) => Ok(Duration::from_micros(reply.rtt_us)),
+            TunnelMessage::RespFailure(msg) => bail!("ping {target} failed: {msg}"),
+            msg => bail!("ping {target} failed, unexpected response: {msg}"),
+        }
     }
 
-    async fn serve_inbound_udp(
-        &mut self,
-        index: usize,
-        conn: Connection,
-        local_server_addr: SocketAddr,
-    ) -> Result<()> {
+    async fn icmp_channel(&self) -> Result<Connection> {
+        let conn = inner_state!(self, icmp_conn).clone();
+        if let Some(conn) = conn.filter(|conn| conn.close_reason().is_none()) {
+            return Ok(conn);
+        }
+
+        let login_cfg = self.prepare_login_config(None).await?;
+        let endpoint = inner_state!(self, endpoint).clone();
+        let endpoint = match endpoint {
+            Some(endpoint) => endpoint,
+            None => {
+                self.bind_with_source_ip(login_cfg.remote_addr.is_ipv6(), quinn::Endpoint::client)?
+            }
+        };
+
+        let login_info = LoginInfo {
+            password: self.config.password.clone(),
+            tunnel: Tunnel::IcmpChannel,
+        };
+        // indexed after the network based tunnels
+        let conn = self
+            .login(
+                self.config.tunnels.len(),
+                &endpoint,
+                &login_info,
+                &login_cfg,
+                "icmp ",
+            )
+            .await?;
+        inner_state!(self, icmp_conn) = Some(conn.clone());
+        Ok(conn)
+    }
+
+    fn record_connect_result(&self, index: usize, succeeded: bool) {
+        let failover = {
+            let mut state = self.inner_state.lock().unwrap();
+            state
+                .tunnel_records
+                .entry(index)
+                .or_default()
+                .record_connect_result(succeeded);
+            self.track_server_health(&mut state, succeeded)
+        };
+
+        let Some((prev_index, server_index)) = failover else {
+            return;
+        };
+        let server_addr = self.server_addr_at(server_index);
         self.post_tunnel_log(
             format!(
-                "{index}:UDP_IN start serving via: {}",
-                conn.remote_address()
+                "{index}:failing over from {} to {server_addr}",
+                self.server_addr_at(prev_index)
             )
             .as_str(),
         );
+        // the tunnels still connected to the previous server hand over as if redirected
+        self.server_redirect
+            .send_replace(Some(server_addr.to_string()));
+        if prev_index == 0 {
+            self.probe_primary_server_in_background();
+        }
+    }
 
-        self.set_and_post_tunnel_state(ClientState::Tunneling);
-        UdpTunnel::start_accepting(&conn, Some(local_server_addr), self.config.udp_timeout_ms)
-            .await;
+    /// Moves on to the next server once `failover_after_attempts` connect attempts of the
+    /// tunnels failed in a row, returns the indices of the previous and the next server
+    fn track_server_health(&self, state: &mut State, succeeded: bool) -> Option<(usize, usize)> {
+        if succeeded || self.config.fallback_server_addrs.is_empty() {
+            state.connect_failures = 0;
+            return None;
+        }
 
-        Ok(())
+        state.connect_failures += 1;
+        let failover_after_attempts = match self.config.failover_after_attempts {
+            0 => DEFAULT_FAILOVER_AFTER_ATTEMPTS,
+            attempts => attempts,
+        };
+        if state.connect_failures < failover_after_attempts {
+            return None;
+        }
+
+        state.connect_failures = 0;
+        let prev_index = state.server_index;
+        state.server_index = (prev_index + 1) % (self.config.fallback_server_addrs.len() + 1);
+        Some((prev_index, state.server_index))
     }
 
-    fn should_quit(&self) -> bool {
-        let state = self.get_state();
-        state == ClientState::Stopping || state == ClientState::Terminated
+    fn server_addr_at(&self, server_index: usize) -> &str {
+        match server_index {
+            0 => self.config.server_addr.as_str(),
+            index => self.config.fallback_server_addrs[index - 1].as_str(),
+        }
     }
 
-    fn report_traffic_data_in_background(&self) {
-        let state = self.inner_state.clone();
+    /// Returns to the primary server once it completes a handshake again, while one of the
+    /// fallback servers is in use
+    fn probe_primary_server_in_background(&self) {
+        let this = self.clone();
         tokio::spawn(async move {
             let mut interval =
-                tokio::time::interval(Duration::from_secs(POST_TRAFFIC_DATA_INTERVAL_SECS));
-            interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Skip);
-
+                tokio::time::interval(Duration::from_secs(PRIMARY_SERVER_PROBE_INTERVAL_SECS));
+            interval.tick().await;
             loop {
                 interval.tick().await;
+                if this.should_quit() || inner_state!(this, server_index) == 0 {
+                    break;
+                }
 
-                let mut rx_bytes = 0;
-                let mut tx_bytes = 0;
-                let mut rx_dgrams = 0;
-                let mut tx_dgrams = 0;
-
-                {
-                    let connections = &state.lock().unwrap().connections;
-                    for conn in connections.values() {
-                        let stats = conn.stats();
-                        rx_bytes += stats.udp_rx.bytes;
-                        tx_bytes += stats.udp_tx.bytes;
-                        rx_dgrams += stats.udp_rx.datagrams;
-                        tx_dgrams += stats.udp_tx.datagrams;
-                    }
+                let primary = this.config.server_addr.as_str();
+                if let Err(e) = this.probe_server(primary).await {
+                    debug!("primary server {primary} is still unavailable, err: {e:#}");
+                    continue;
                 }
 
                 {
-                    let total_traffic_data = &&state.lock().unwrap().total_traffic_data;
-                    rx_bytes += total_traffic_data.rx_bytes;
-                    tx_bytes += total_traffic_data.tx_bytes;
-                    rx_dgrams += total_traffic_data.rx_dgrams;
-                    tx_dgrams += total_traffic_data.tx_dgrams;
+                    let mut state = this.inner_state.lock().unwrap();
+                    state.server_index = 0;
+                    state.connect_failures = 0;
                 }
+                this.post_tunnel_log(format!("returning to primary server {primary}").as_str());
+                this.server_redirect.send_replace(Some(primary.to_string()));
+                break;
+            }
+        });
+    }
 
-                let state = state.lock().unwrap();
-                let client_state = state.client_state.clone();
-                let data = TunnelTraffic {
-                    rx_bytes,
-                    tx_bytes,
-                    rx_dgrams,
-                    tx_dgrams,
-                };
+    /// completes a QUIC handshake with the server, without logging in
+    async fn probe_server(&self, server_addr: &str) -> Result<()> {
+        let remote_addr = self.resolve_server_addr(server_addr).await?;
+        let login_cfg = self.prepare_login_config(None).await?;
+        let endpoint = self.bind_with_source_ip(remote_addr.is_ipv6(), Endpoint::client)?;
+        let conn = self
+            .transport()
+            .connect(
+                &endpoint,
+                login_cfg.quinn_client_cfg,
+                remote_addr,
+                &login_cfg.domain,
+            )
+            .await?;
+        conn.close(VarInt::from_u32(0), b"probed");
+        endpoint.wait_idle().await;
+        Ok(())
+    }
 
-                info!("traffic log, rx_bytes:{rx_bytes}, tx_bytes:{tx_bytes}, rx_dgrams:{rx_dgrams}, tx_dgrams:{tx_dgrams}");
-                state.post_tunnel_info(TunnelInfo::new(
-                    TunnelInfoType::TunnelTraffic,
-                    Box::new(data),
-                ));
+    /// Ratio of the successful ones among the last 20 connection attempts of the tunnel, None
+    /// if the tunnel hasn't attempted to connect yet
+    pub fn connect_success_rate(&self, index: usize) -> Option<f64> {
+        let state = self.inner_state.lock().unwrap();
+        let results = &state.tunnel_records.get(&index)?.connect_results;
+        if results.is_empty() {
+            return None;
+        }
+        let succeeded = results.iter().filter(|succeeded| **succeeded).count();
+        Some(succeeded as f64 / results.len() as f64)
+    }
 
-                if client_state == ClientState::Stopping || client_state == ClientState::Terminated
-                {
-                    break;
-                }
+    fn auth_provider(&self) -> Arc<dyn AuthProvider> {
+        match &self.config.auth_provider {
+            Some(provider) => provider.clone(),
+            None => Arc::new(StaticPasswordProvider::new(&self.config.password)),
+        }
+    }
+
+    fn transport(&self) -> Arc<dyn Transport> {
+        match &self.config.transport {
+            Some(transport) => transport.clone(),
+            None => Arc::new(QuicTransport),
+        }
+    }
+
+    /// Servers predating the password challenge check the empty password sent in its place.
+    /// Those predating the login extensions close the connection on it without an answer, the
+    /// later ones answer RespFailure(ERR_BAD_PASSWORD) without a LoginRespExt
+    fn password_challenge_ignored(
+        conn: &Connection,
+        resp: &Result<(TunnelMessage, Option<LoginRespExt>)>,
+    ) -> bool {
+        match resp {
+            Ok((TunnelMessage::RespFailure(msg), None)) => msg == ERR_BAD_PASSWORD,
+            Ok(_) => false,
+            Err(e) => {
+                matches!(
+                    conn.close_reason(),
+                    Some(quinn::ConnectionError::ApplicationClosed(_))
+                ) || e.chain().any(|cause| {
+                    cause
+                        .downcast_ref::<std::io::Error>()
+                        .is_some_and(|e| e.kind() == std::io::ErrorKind::UnexpectedEof)
+                })
             }
-        });
+        }
     }
 
-    fn get_crypto_provider(&self, cipher: &SupportedCipherSuite) -> Arc<CryptoProvider> {
-        let default_provider = rustls::crypto::ring::default_provider();
-        let mut cipher_suites = vec![*cipher];
-        // Quinn assumes that the cipher suites contain this one
-        cipher_suites.push(cipher_suite::TLS13_AES_128_GCM_SHA256);
-        Arc::new(rustls::crypto::CryptoProvider {
-            cipher_suites,
-            ..default_provider
+    fn is_timeout_error(e: &anyhow::Error) -> bool {
+        e.chain().any(|cause| {
+            matches!(
+                cause.downcast_ref::<quinn::ConnectionError>(),
+                Some(quinn::ConnectionError::TimedOut)
+            ) || cause
+                .downcast_ref::<tokio::time::error::Elapsed>()
+                .is_some()
         })
     }
 
-    fn create_client_config_builder(
-        &self,
-        cipher: &SupportedCipherSuite,
-    ) -> std::result::Result<
-        rustls::ConfigBuilder<rustls::ClientConfig, rustls::WantsVerifier>,
-        rustls::Error,
-    > {
-        let cfg_builder =
-            rustls::ClientConfig::builder_with_provider(self.get_crypto_provider(cipher))
-                .with_protocol_versions(&[&rustls::version::TLS13])
-                .unwrap();
-        Ok(cfg_builder)
+    fn post_login_failure(&self, index: usize, reason: LoginFailureReason, message: String) {
+        warn!("{index}:login failed, reason:{reason:?}, message:{message}");
+        let state = self.inner_state.lock().unwrap();
+        state.post_tunnel_info(TunnelInfo::new(
+            TunnelInfoType::LoginFailed,
+            Box::new(LoginFailure {
+                index,
+                reason,
+                message,
+            }),
+        ));
     }
 
-    fn parse_client_config_and_domain(&self) -> Result<(rustls::ClientConfig, String)> {
-        let cipher = *SelectedCipherSuite::from_str(&self.config.cipher).map_err(|_| {
-            rustls::Error::General(format!("invalid cipher: {}", self.config.cipher))
-        })?;
+    fn post_tunnel_log(&self, msg: &str) {
+        info!("{msg}");
+        let state = self.inner_state.lock().unwrap();
+        if self.config.structured_tunnel_log {
+            state.post_tunnel_info(TunnelInfo::new(
+                TunnelInfoType::TunnelLog,
+                Box::new(TunnelLogRecord::new(msg)),
+            ));
+            return;
+        }
+        state.post_tunnel_info(TunnelInfo::new(
+            TunnelInfoType::TunnelLog,
+            Box::new(format!(
+                "{} {msg}",
+                chrono::Local::now().format(TIME_FORMAT)
+            )),
+        ));
+    }
 
-        if self.config.cert_path.is_empty() {
-            if !Self::is_ip_addr(&self.config.server_addr) {
-                let domain = match self.config.server_addr.rfind(':') {
-                    Some(colon_index) => self.config.server_addr[0..colon_index].to_string(),
-                    None => self.config.server_addr.to_string(),
-                };
+    fn set_and_post_tunnel_state(&self, client_state: ClientState) {
+        let state_listener = {
+            let mut state = self.inner_state.lock().unwrap();
+            state.client_state = client_state.clone();
+            state.post_tunnel_info(TunnelInfo::new(
+                TunnelInfoType::TunnelState,
+                Box::new(client_state.clone()),
+            ));
+            state.state_listener.clone()
+        };
+        self.state_changes.send_replace(client_state.clone());
+        // called without holding the state, so the listener may query the client
+        if let Some(listener) = state_listener {
+            listener.lock().unwrap()(client_state);
+        }
+    }
 
-                let client_config = self
-                    .create_client_config_builder(&cipher)?
-                    .with_platform_verifier()?
-                    .with_no_client_auth();
+    /// Called with the new state on each transition, alongside the TunnelState info posted to
+    /// the info listener. Unlike the latter, it doesn't depend on `set_enable_on_info_report`
+    pub fn set_on_state_listener(&self, callback: impl FnMut(ClientState) + 'static + Send + Sync) {
+        inner_state!(self, state_listener) = Some(Arc::new(Mutex::new(callback)));
+    }
 
-                return Ok((client_config, domain));
-            }
+    pub fn set_on_info_listener(&self, callback: impl FnMut(&str) + 'static + Send + Sync) {
+        inner_state!(self, tunnel_info_bridge).set_listener(callback);
+    }
 
-            let client_config = self
-                .create_client_config_builder(&cipher)?
-                .dangerous()
-                .with_custom_certificate_verifier(Arc::new(InsecureCertVerifier::new(
-                    self.get_crypto_provider(&cipher),
-                )))
-                .with_no_client_auth();
+    /// Like `set_on_info_listener`, with the events encoded in `format`, e.g. MessagePack to
+    /// forward them over the network compactly, it replaces the listener set before
+    pub fn set_on_encoded_info_listener(
+        &self,
+        format: InfoFormat,
+        callback: impl FnMut(&[u8]) + 'static + Send + Sync,
+    ) -> Result<()> {
+        inner_state!(self, tunnel_info_bridge).set_encoded_listener(format, callback)
+    }
 
-            static ONCE: Once = Once::new();
-            ONCE.call_once(|| {
-                warn!(
-                    "No certificate is provided for verification, domain \"localhost\" is assumed"
-                );
-            });
-            return Ok((client_config, "localhost".to_string()));
-        }
+    pub fn has_on_info_listener(&self) -> bool {
+        inner_state!(self, tunnel_info_bridge).has_listener()
+    }
 
-        // when client config provides a certificate
-        let certs = pem_util::load_certificates_from_pem(self.config.cert_path.as_str())
-            .context("failed to read from cert file")?;
-        if certs.is_empty() {
-            log_and_bail!(
-                "No certificates found in provided file: {}",
-                self.config.cert_path
-            );
+    pub fn set_enable_on_info_report(&self, enable: bool) {
+        info!("set_enable_on_info_report, enable:{enable}");
+        inner_state!(self, on_info_report_enabled) = enable;
+    }
+}
+
+/// Accepts the server certificate only if the SHA-256 of its SubjectPublicKeyInfo matches the
+/// pinned one, name and expiry are not checked, as with a self-signed certificate
+#[derive(Debug)]
+struct PinnedCertVerifier {
+    spki_sha256: Vec<u8>,
+    crypto: Arc<CryptoProvider>,
+}
+
+impl PinnedCertVerifier {
+    fn new(spki_sha256_hex: &str, crypto: Arc<CryptoProvider>) -> Result<Self> {
+        let hex: String = spki_sha256_hex
+            .chars()
+            .filter(|c| *c != ':')
+            .collect::<String>()
+            .to_lowercase();
+        if hex.len() != 64 || !hex.chars().all(|c| c.is_ascii_hexdigit()) {
+            log_and_bail!("invalid SPKI SHA-256 '{spki_sha256_hex}', expected 64 hex digits");
         }
-        let mut roots = RootCertStore::empty();
-        // save all certificates in the certificate chain to the trust list
-        for cert in &certs {
-            roots.add(cert.clone()).context(format!(
-                "failed to add certificate from file: {}",
-                self.config.cert_path
-            ))?;
+        let spki_sha256 = (0..hex.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&hex[i..i + 2], 16))
+            .collect::<std::result::Result<Vec<u8>, _>>()?;
+        Ok(Self {
+            spki_sha256,
+            crypto,
+        })
+    }
+}
+
+impl rustls::client::danger::ServerCertVerifier for PinnedCertVerifier {
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &rustls::pki_types::CertificateDer<'_>,
+        dss: &rustls::DigitallySignedStruct,
+    ) -> std::prelude::v1::Result<rustls::client::danger::HandshakeSignatureValid, rustls::Error>
+    {
+        rustls::crypto::verify_tls12_signature(
+            message,
+            cert,
+            dss,
+            &self.crypto.signature_verification_algorithms,
+        )
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &rustls::pki_types::CertificateDer<'_>,
+        dss: &rustls::DigitallySignedStruct,
+    ) -> std::prelude::v1::Result<rustls::client::danger::HandshakeSignatureValid, rustls::Error>
+    {
+        rustls::crypto::verify_tls13_signature(
+            message,
+            cert,
+            dss,
+            &self.crypto.signature_verification_algorithms,
+        )
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<rustls::SignatureScheme> {
+        self.crypto
+            .signature_verification_algorithms
+            .supported_schemes()
+    }
+
+    fn verify_server_cert(
+        &self,
+        end_entity: &rustls::pki_types::CertificateDer<'_>,
+        _intermediates: &[rustls::pki_types::CertificateDer<'_>],
+        _server_name: &rustls::pki_types::ServerName<'_>,
+        _ocsp_response: &[u8],
+        _now: rustls::pki_types::UnixTime,
+    ) -> std::prelude::v1::Result<ServerCertVerified, rustls::Error> {
+        let (_, cert) = X509Certificate::from_der(end_entity.as_ref()).map_err(|_| {
+            rustls::Error::InvalidCertificate(rustls::CertificateError::BadEncoding)
+        })?;
+        let spki = cert.tbs_certificate.subject_pki.raw;
+        if ring::digest::digest(&ring::digest::SHA256, spki).as_ref() != self.spki_sha256 {
+            return Err(rustls::Error::InvalidCertificate(
+                rustls::CertificateError::ApplicationVerificationFailure,
+            ));
         }
+        Ok(ServerCertVerified::assertion())
+    }
+}
+
+/// stops the client once dropped, unless disarmed, see `Client::spawn`
+struct StopOnAbort(Option<Client>);
+
+impl StopOnAbort {
+    fn disarm(&mut self) {
+        self.0 = None;
+    }
+}
 
-        // for self-signed certificates, generating IP-based TLS certificates is not difficult
-        let domain_or_ip = match self.config.server_addr.rfind(':') {
-            Some(colon_index) => self.config.server_addr[0..colon_index].to_string(),
-            None => self.config.server_addr.to_string(),
+impl Drop for StopOnAbort {
+    fn drop(&mut self) {
+        let Some(client) = self.0.take() else {
+            return;
         };
+        if let Ok(handle) = tokio::runtime::Handle::try_current() {
+            handle.spawn(async move { client.stop_async().await });
+        }
+    }
+}
 
-        Ok((
-            self.create_client_config_builder(&cipher)?
-                .with_root_certificates(roots)
-                .with_no_client_auth(),
-            domain_or_ip,
-        ))
+struct RejectedCert {
+    error: rustls::Error,
+    end_entity: rustls::pki_types::CertificateDer<'static>,
+}
+
+/// Records the end-entity certificate `inner` rejects and why, which the handshake error
+/// reports only as an alert
+#[derive(Debug)]
+struct RecordingCertVerifier {
+    inner: Arc<dyn ServerCertVerifier>,
+    rejected: Arc<Mutex<Option<RejectedCert>>>,
+}
+
+impl std::fmt::Debug for RejectedCert {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "RejectedCert({})", self.error)
     }
+}
 
-    pub fn get_state(&self) -> ClientState {
-        inner_state!(self, client_state).clone()
+impl rustls::client::danger::ServerCertVerifier for RecordingCertVerifier {
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &rustls::pki_types::CertificateDer<'_>,
+        dss: &rustls::DigitallySignedStruct,
+    ) -> std::prelude::v1::Result<rustls::client::danger::HandshakeSignatureValid, rustls::Error>
+    {
+        self.inner.verify_tls12_signature(message, cert, dss)
     }
 
-    fn is_ip_addr(addr: &str) -> bool {
-        addr.parse::<SocketAddr>().is_ok()
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &rustls::pki_types::CertificateDer<'_>,
+        dss: &rustls::DigitallySignedStruct,
+    ) -> std::prelude::v1::Result<rustls::client::danger::HandshakeSignatureValid, rustls::Error>
+    {
+        self.inner.verify_tls13_signature(message, cert, dss)
     }
 
-    async fn parse_server_addr(&self) -> Result<SocketAddr> {
-        let addr = self.config.server_addr.as_str();
-        let sock_addr: Result<SocketAddr> = addr.parse().context("error will be ignored");
+    fn supported_verify_schemes(&self) -> Vec<rustls::SignatureScheme> {
+        self.inner.supported_verify_schemes()
+    }
 
-        if sock_addr.is_ok() {
-            return sock_addr;
-        }
+    fn verify_server_cert(
+        &self,
+        end_entity: &rustls::pki_types::CertificateDer<'_>,
+        intermediates: &[rustls::pki_types::CertificateDer<'_>],
+        server_name: &rustls::pki_types::ServerName<'_>,
+        ocsp_response: &[u8],
+        now: rustls::pki_types::UnixTime,
+    ) -> std::prelude::v1::Result<ServerCertVerified, rustls::Error> {
+        self.inner
+            .verify_server_cert(end_entity, intermediates, server_name, ocsp_response, now)
+            .inspect_err(|error| {
+                *self.rejected.lock().unwrap() = Some(RejectedCert {
+                    error: error.clone(),
+                    end_entity: end_entity.clone().into_owned(),
+                });
+            })
+    }
+}
 
-        let mut domain = addr;
-        let mut port = DEFAULT_SERVER_PORT;
-        let pos = addr.rfind(':');
-        if let Some(pos) = pos {
-            port = addr[(pos + 1)..]
-                .parse()
-                .with_context(|| format!("invalid address: {}", addr))?;
-            domain = &addr[..pos];
-        }
+/// Keeps the session tickets in `inner`, noting the cipher suite of the latest ticket of each
+/// server, as the connections don't expose the suite their handshake negotiated
+#[derive(Debug)]
+struct RecordingSessionStore {
+    inner: ClientSessionMemoryCache,
+    suites: Mutex<HashMap<String, rustls::CipherSuite>>,
+}
 
-        for dot in &self.config.dot_servers {
-            if let Ok(ip) = Self::lookup_server_ip(domain, dot, vec![]).await {
-                return Ok(SocketAddr::new(ip, port));
-            }
+impl RecordingSessionStore {
+    fn new(size: usize) -> Self {
+        Self {
+            inner: ClientSessionMemoryCache::new(size),
+            suites: Mutex::new(HashMap::new()),
         }
+    }
 
-        if let Ok(ip) = Self::lookup_server_ip(domain, "", self.config.dns_servers.clone()).await {
-            return Ok(SocketAddr::new(ip, port));
-        }
+    /// None if the server hasn't sent a ticket
+    fn negotiated_suite(&self, server_name: &str) -> Option<rustls::CipherSuite> {
+        let server_name = rustls::pki_types::ServerName::try_from(server_name).ok()?;
+        self.suites
+            .lock()
+            .unwrap()
+            .get(server_name.to_str().as_ref())
+            .copied()
+    }
+}
 
-        if let Ok(ip) = Self::lookup_server_ip(domain, "", vec![]).await {
-            return Ok(SocketAddr::new(ip, port));
-        }
+impl ClientSessionStore for RecordingSessionStore {
+    fn set_kx_hint(
+        &self,
+        server_name: rustls::pki_types::ServerName<'static>,
+        group: rustls::NamedGroup,
+    ) {
+        self.inner.set_kx_hint(server_name, group)
+    }
 
-        bail!("failed to resolve domain: {domain}");
+    fn kx_hint(
+        &self,
+        server_name: &rustls::pki_types::ServerName<'_>,
+    ) -> Option<rustls::NamedGroup> {
+        self.inner.kx_hint(server_name)
     }
 
-    async fn lookup_server_ip(
-        domain: &str,
-        dot_server: &str,
-        name_servers: Vec<String>,
-    ) -> Result<IpAddr> {
-        let dns_config = DNSResolverConfig {
-            strategy: DNSResolverLookupIpStrategy::Ipv6thenIpv4,
-            num_conccurent_reqs: 3,
-            ordering: DNSQueryOrdering::QueryStatistics,
-        };
+    fn set_tls12_session(
+        &self,
+        server_name: rustls::pki_types::ServerName<'static>,
+        value: rustls::client::Tls12ClientSessionValue,
+    ) {
+        self.inner.set_tls12_session(server_name, value)
+    }
 
-        let resolver = if !dot_server.is_empty() {
-            dns::resolver2(dot_server, vec![], dns_config)
-        } else if !name_servers.is_empty() {
-            dns::resolver2("", name_servers, dns_config)
-        } else {
-            dns::resolver2("", vec![], dns_config)
-        };
+    fn tls12_session(
+        &self,
+        server_name: &rustls::pki_types::ServerName<'_>,
+    ) -> Option<rustls::client::Tls12ClientSessionValue> {
+        self.inner.tls12_session(server_name)
+    }
 
-        let ip = resolver.await.lookup_first(domain).await?;
-        info!("resolved {domain} to {ip}");
-        Ok(ip)
+    fn remove_tls12_session(&self, server_name: &rustls::pki_types::ServerName<'static>) {
+        self.inner.remove_tls12_session(server_name)
     }
 
-    fn post_tunnel_log(&self, msg: &str) {
-        info!("{msg}");
-        let state = self.inner_state.lock().unwrap();
-        state.post_tunnel_info(TunnelInfo::new(
-            TunnelInfoType::TunnelLog,
-            Box::new(format!(
-                "{} {msg}",
-                chrono::Local::now().format(TIME_FORMAT)
-            )),
-        ));
+    fn insert_tls13_ticket(
+        &self,
+        server_name: rustls::pki_types::ServerName<'static>,
+        value: rustls::client::Tls13ClientSessionValue,
+    ) {
+        self.suites.lock().unwrap().insert(
+            server_name.to_str().into_owned(),
+            value.suite().common.suite,
+        );
+        self.inner.insert_tls13_ticket(server_name, value)
     }
 
-    fn set_and_post_tunnel_state(&self, client_state: ClientState) {
-        let mut state = self.inner_state.lock().unwrap();
-        state.client_state = client_state.clone();
-        state.post_tunnel_info(TunnelInfo::new(
-            TunnelInfoType::TunnelState,
-            Box::new(client_state),
-        ));
+    fn take_tls13_ticket(
+        &self,
+        server_name: &rustls::pki_types::ServerName<'static>,
+    ) -> Option<rustls::client::Tls13ClientSessionValue> {
+        self.inner.take_tls13_ticket(server_name)
     }
+}
 
-    pub fn set_on_info_listener(&self, callback: impl FnMut(&str) + 'static + Send + Sync) {
-        inner_state!(self, tunnel_info_bridge).set_listener(callback);
+/// the name `ClientConfig::cipher` takes for the suite, e.g. aes-128-gcm
+fn cipher_suite_name(suite: rustls::CipherSuite) -> String {
+    SUPPORTED_CIPHER_SUITE_STRS
+        .iter()
+        .find(|name| {
+            SelectedCipherSuite::from_str(name).is_ok_and(|selected| selected.suite() == suite)
+        })
+        .map_or_else(|| format!("{suite:?}"), |name| name.to_string())
+}
+
+/// Rejects chains of more than `max_len` certificates, the end entity included, before
+/// handing them over to `inner`
+#[derive(Debug)]
+struct ChainLengthVerifier {
+    inner: Arc<dyn ServerCertVerifier>,
+    max_len: usize,
+}
+
+impl rustls::client::danger::ServerCertVerifier for ChainLengthVerifier {
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &rustls::pki_types::CertificateDer<'_>,
+        dss: &rustls::DigitallySignedStruct,
+    ) -> std::prelude::v1::Result<rustls::client::danger::HandshakeSignatureValid, rustls::Error>
+    {
+        self.inner.verify_tls12_signature(message, cert, dss)
     }
 
-    pub fn has_on_info_listener(&self) -> bool {
-        inner_state!(self, tunnel_info_bridge).has_listener()
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &rustls::pki_types::CertificateDer<'_>,
+        dss: &rustls::DigitallySignedStruct,
+    ) -> std::prelude::v1::Result<rustls::client::danger::HandshakeSignatureValid, rustls::Error>
+    {
+        self.inner.verify_tls13_signature(message, cert, dss)
     }
 
-    pub fn set_enable_on_info_report(&self, enable: bool) {
-        info!("set_enable_on_info_report, enable:{enable}");
-        inner_state!(self, on_info_report_enabled) = enable;
+    fn supported_verify_schemes(&self) -> Vec<rustls::SignatureScheme> {
+        self.inner.supported_verify_schemes()
+    }
+
+    fn verify_server_cert(
+        &self,
+        end_entity: &rustls::pki_types::CertificateDer<'_>,
+        intermediates: &[rustls::pki_types::CertificateDer<'_>],
+        server_name: &rustls::pki_types::ServerName<'_>,
+        ocsp_response: &[u8],
+        now: rustls::pki_types::UnixTime,
+    ) -> std::prelude::v1::Result<ServerCertVerified, rustls::Error> {
+        let chain_len = 1 + intermediates.len();
+        if chain_len > self.max_len {
+            return Err(rustls::Error::General(format!(
+                "server certificate chain of {chain_len} certificates exceeds the max length of {}",
+                self.max_len
+            )));
+        }
+        self.inner
+            .verify_server_cert(end_entity, intermediates, server_name, ocsp_response, now)
     }
 }
 
@@ -1032,7 +5060,7 @@ impl rustls::client::danger::ServerCertVerifier for InsecureCertVerifier {
         dss: &rustls::DigitallySignedStruct,
     ) -> std::prelude::v1::Result<rustls::client::danger::HandshakeSignatureValid, rustls::Error>
     {
-        rustls::crypto::verify_tls12_signature(
+        rustls::crypto::verify_tls13_signature(
             message,
             cert,
             dss,
@@ -1062,3 +5090,449 @@ impl rustls::client::danger::ServerCertVerifier for InsecureCertVerifier {
         Ok(ServerCertVerified::assertion())
     }
 }
+
+/// `interval_ms` ± a random offset of up to `jitter_percent` of it, never less than 1ms
+fn jittered_interval_ms(interval_ms: u64, jitter_percent: u8) -> u64 {
+    let max_jitter = interval_ms * u64::from(jitter_percent.min(100)) / 100;
+    if max_jitter == 0 {
+        return interval_ms;
+    }
+
+    let mut bytes = [0u8; 8];
+    if ring::rand::SecureRandom::fill(&ring::rand::SystemRandom::new(), &mut bytes).is_err() {
+        return interval_ms;
+    }
+    let offset = u64::from_le_bytes(bytes) % (2 * max_jitter + 1);
+    (interval_ms - max_jitter + offset).max(1)
+}
+
+/// index of the network interface named `name`, e.g. eth0, the scope id of its link-local
+/// addresses
+#[cfg(unix)]
+fn interface_index(name: &str) -> Option<u32> {
+    let name = std::ffi::CString::new(name).ok()?;
+    let index = unsafe { libc::if_nametoindex(name.as_ptr()) };
+    (index != 0).then_some(index)
+}
+
+/// interface names are only resolved on Unix, the numeric scope id works everywhere
+#[cfg(not(unix))]
+fn interface_index(_name: &str) -> Option<u32> {
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use quinn::crypto::rustls::QuicServerConfig;
+    use rustls::pki_types::{CertificateDer, PrivateKeyDer, PrivatePkcs8KeyDer};
+
+    /// a bare QUIC server accepting any connection, enough for the handshakes to complete
+    fn quic_server() -> Endpoint {
+        quic_server_with(|conn| async move {
+            conn.closed().await;
+        })
+    }
+
+    /// a server predating the password challenge and the login extensions, which drops the
+    /// connection if the password doesn't match, and answers RespSuccess alone otherwise
+    fn legacy_server(password: &'static str) -> Endpoint {
+        quic_server_with(move |conn| async move {
+            let Ok((mut quic_send, mut quic_recv)) = conn.accept_bi().await else {
+                return;
+            };
+            let Ok(TunnelMessage::ReqLogin(login_info)) = TunnelMessage::recv(&mut quic_recv).await
+            else {
+                return;
+            };
+            if login_info.password != password {
+                return;
+            }
+            TunnelMessage::send(&mut quic_send, &TunnelMessage::RespSuccess)
+                .await
+                .ok();
+            conn.closed().await;
+        })
+    }
+
+    /// a QUIC server with a self-signed certificate, handing each connection to `handler`
+    fn quic_server_with<F, Fut>(handler: F) -> Endpoint
+    where
+        F: Fn(Connection) -> Fut + Send + Sync + 'static,
+        Fut: std::future::Future<Output = ()> + Send + 'static,
+    {
+        let cert = rcgen::generate_simple_self_signed(vec!["localhost".into()]).unwrap();
+        let key = PrivatePkcs8KeyDer::from(cert.signing_key.serialize_der());
+        let mut tls_cfg = rustls::ServerConfig::builder_with_provider(Arc::new(
+            rustls::crypto::ring::default_provider(),
+        ))
+        .with_protocol_versions(&[&rustls::version::TLS13])
+        .unwrap()
+        .with_no_client_auth()
+        .with_single_cert(
+            vec![CertificateDer::from(cert.cert)],
+            PrivateKeyDer::Pkcs8(key),
+        )
+        .unwrap();
+        tls_cfg.alpn_protocols = effective_alpn_protocols(&None)
+            .into_iter()
+            .map(String::into_bytes)
+            .collect();
+        let server_cfg = quinn::ServerConfig::with_crypto(Arc::new(
+            QuicServerConfig::try_from(tls_cfg).unwrap(),
+        ));
+        let endpoint = Endpoint::server(server_cfg, "127.0.0.1:0".parse().unwrap()).unwrap();
+
+        let accepting = endpoint.clone();
+        tokio::spawn(async move {
+            while let Some(incoming) = accepting.accept().await {
+                if let Ok(conn) = incoming.await {
+                    tokio::spawn(handler(conn));
+                }
+            }
+        });
+        endpoint
+    }
+
+    /// a port of 127.0.0.1 free for both UDP and TCP at the time of the call
+    fn free_port() -> u16 {
+        loop {
+            let udp = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+            let port = udp.local_addr().unwrap().port();
+            if std::net::TcpListener::bind(("127.0.0.1", port)).is_ok() {
+                return port;
+            }
+        }
+    }
+
+    /// an rstun server with a self-signed certificate, serving in the background
+    fn rstun_server(password: &str) -> SocketAddr {
+        let addr = SocketAddr::from(([127, 0, 0, 1], free_port()));
+        let mut server = crate::Server::new(crate::ServerConfig {
+            addr: addr.to_string(),
+            password: password.to_string(),
+            cert_path: String::new(),
+            key_path: String::new(),
+            quic_timeout_ms: 30000,
+            tcp_timeout_ms: 30000,
+            udp_timeout_ms: 5000,
+            default_tcp_upstream: None,
+            default_udp_upstream: None,
+            dashboard_server: String::new(),
+            dashboard_server_credential: String::new(),
+            allow_icmp: false,
+            client_ca_path: String::new(),
+            alpn_protocols: None,
+            accept_0rtt: false,
+        });
+        server.bind().unwrap();
+        tokio::spawn(async move { server.serve().await });
+        addr
+    }
+
+    /// logs in the tunnel at `index` of `client` once, on an endpoint of its own
+    async fn login_once(client: &Client, index: usize) -> Result<Connection> {
+        let tunnel_config = client.config.tunnels.get(index);
+        let login_cfg = client.prepare_login_config(tunnel_config).await?;
+        let endpoint = client.bind_with_source_ip(false, Endpoint::client)?;
+        let login_info = LoginInfo {
+            password: client.config.password.clone(),
+            tunnel: Tunnel::NetworkBased(Box::new(tunnel_config.unwrap().clone())),
+        };
+        client
+            .login(index, &endpoint, &login_info, &login_cfg, "")
+            .await
+    }
+
+    #[tokio::test]
+    async fn password_challenge_falls_back_to_plaintext_with_legacy_servers() {
+        let server = legacy_server("pw");
+        let mut config = ClientConfig::builder()
+            .server_addr(&server.local_addr().unwrap().to_string())
+            .password("pw")
+            .add_mappings("OUT^127.0.0.1:0^127.0.0.1:80", UpstreamType::Tcp)
+            .unwrap()
+            .build()
+            .unwrap();
+        config.allow_insecure = true;
+        config.password_challenge = true;
+        let client = Client::new(config);
+
+        // the empty password sent in place of the challenge is rejected by closing the connection
+        assert!(login_once(&client, 0).await.is_err());
+        assert!(inner_state!(client, password_challenge_unsupported));
+        let conn = login_once(&client, 0).await.unwrap();
+        conn.close(VarInt::from_u32(0), b"");
+    }
+
+    #[tokio::test]
+    async fn password_challenge_is_answered_by_current_servers() {
+        let server_addr = rstun_server("pw");
+        let mut config = ClientConfig::builder()
+            .server_addr(&server_addr.to_string())
+            .password("pw")
+            .add_mappings(
+                &format!("OUT^127.0.0.1:0^127.0.0.1:{}", free_port()),
+                UpstreamType::Tcp,
+            )
+            .unwrap()
+            .build()
+            .unwrap();
+        config.allow_insecure = true;
+        config.password_challenge = true;
+        let client = Client::new(config);
+
+        let conn = login_once(&client, 0).await.unwrap();
+        assert!(!inner_state!(client, password_challenge_unsupported));
+        conn.close(VarInt::from_u32(0), b"");
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn login_with_an_inbound_tunnel_to_a_unix_socket() {
+        let server_addr = rstun_server("pw");
+        let mapping = format!("IN^unix:/tmp/rstun-test.sock^{}", free_port());
+        let mut config = ClientConfig::builder()
+            .server_addr(&server_addr.to_string())
+            .password("pw")
+            .add_mappings(&mapping, UpstreamType::Tcp)
+            .unwrap()
+            .build()
+            .unwrap();
+        config.allow_insecure = true;
+        let client = Client::new(config);
+
+        let conn = login_once(&client, 0).await.unwrap();
+        conn.close(VarInt::from_u32(0), b"");
+    }
+
+    #[test]
+    fn worker_threads_fall_back_to_the_cpus_and_are_capped() {
+        let cpus = num_cpus::get();
+        assert_eq!(Client::worker_threads(0), cpus);
+        assert_eq!(Client::worker_threads(1), 1);
+        assert_eq!(Client::worker_threads(cpus), cpus);
+        assert_eq!(
+            Client::worker_threads(cpus * MAX_WORKERS_PER_CPU),
+            cpus * MAX_WORKERS_PER_CPU
+        );
+        assert_eq!(
+            Client::worker_threads(cpus * MAX_WORKERS_PER_CPU + 1),
+            cpus * MAX_WORKERS_PER_CPU
+        );
+        assert_eq!(
+            Client::worker_threads(usize::MAX),
+            cpus * MAX_WORKERS_PER_CPU
+        );
+    }
+
+    #[test]
+    fn exported_config_loads_back() {
+        let mut config = ClientConfig::builder()
+            .server_addr("example.com:3515")
+            .password("pw")
+            .retry_policy(RetryPolicy {
+                max_times: 5,
+                ..RetryPolicy::default()
+            })
+            .add_mappings(
+                "OUT^127.0.0.1:9000^ANY^CC=cubic^RECV_WINDOW=4194304^CONNECT_TIMEOUT=3000^RETRY_FOR=60000,\
+                 OUT^127.0.0.1:1080^SOCKS5^DENY=10.0.0.0/8^ALLOW=*.example.com^HOP=10000,\
+                 IN^127.0.0.1:8080^9080^PROXY_PROTOCOL^GROUP,\
+                 OUT^127.0.0.1:5140^10.0.0.5:514^ONEWAY^COALESCE=5,\
+                 OUT^localhost:9100^ANY",
+                UpstreamType::Tcp,
+            )
+            .unwrap()
+            .add_mappings("OUT^127.0.0.1:5353^10.0.0.53:53^DATAGRAM", UpstreamType::Udp)
+            .unwrap()
+            .build()
+            .unwrap();
+        config.fallback_server_addrs = vec!["backup.example.com:3515".to_string()];
+        config.dns_strategy = DnsStrategy::Ipv4thenIpv6;
+        config.migration_port_range = Some((20000, 20100));
+        config.metrics_addr = Some("127.0.0.1:9100".parse().unwrap());
+        config.receive_window = 8388608;
+
+        let exported = Client::new(config.clone()).export_config(false);
+        let loaded = ClientConfig::from_json(&exported).unwrap();
+        assert_eq!(loaded.tunnels, config.tunnels);
+        assert_eq!(Client::new(loaded).export_config(false), exported);
+
+        let redacted = Client::new(config).export_config(true);
+        let loaded = ClientConfig::from_json(&redacted).unwrap();
+        assert_eq!(loaded.password, REDACTED);
+
+        // the fields left out take their defaults
+        let loaded = ClientConfig::from_json(r#"{"server_addr": "127.0.0.1:3515"}"#).unwrap();
+        assert_eq!(loaded.retry_policy, RetryPolicy::default());
+        assert!(loaded.tunnels.is_empty());
+        assert!(ClientConfig::from_json(r#"{"tunnels": {"tcp_mappings": "OUT^9000"}}"#).is_err());
+    }
+
+    #[test]
+    fn conflicting_flow_control_windows_fail_the_config() {
+        let create = |tcp_mappings: &str| {
+            ClientConfig::create(
+                "3515",
+                "pw",
+                "",
+                "",
+                tcp_mappings,
+                "",
+                "",
+                "",
+                1,
+                1000,
+                0,
+                0,
+                0,
+                0,
+            )
+        };
+        assert!(create("OUT^9000^ANY^STREAM_WINDOW=4194304^RECV_WINDOW=1048576").is_err());
+        assert!(create("OUT^9000^ANY^STREAM_WINDOW=4194304^RECV_WINDOW=0").is_err());
+        assert!(create("OUT^9000^ANY^STREAM_WINDOW=1048576^RECV_WINDOW=4194304").is_ok());
+
+        // the client-wide receive window may still be raised to fit
+        let mut config = create("OUT^9000^ANY^STREAM_WINDOW=4194304").unwrap();
+        assert!(config.check_flow_control_windows().is_err());
+        config.receive_window = 8388608;
+        assert!(config.check_flow_control_windows().is_ok());
+        config.stream_receive_window = 16777216;
+        assert!(config.check_flow_control_windows().is_err());
+
+        let builder = || {
+            ClientConfig::builder()
+                .server_addr("3515")
+                .add_mappings("OUT^9000^ANY^STREAM_WINDOW=4194304", UpstreamType::Tcp)
+                .unwrap()
+        };
+        assert!(builder().build().is_err());
+        assert!(builder()
+            .flow_control_windows(0, 8388608, 0)
+            .build()
+            .is_ok());
+        assert!(builder()
+            .flow_control_windows(0, 1048576, 0)
+            .build()
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn tunnels_sharing_an_endpoint_keep_their_transport_overrides() {
+        let server = quic_server();
+        let mut config = ClientConfig::builder()
+            .server_addr(&server.local_addr().unwrap().to_string())
+            .add_mappings(
+                "OUT^127.0.0.1:0^127.0.0.1:80^CC=cubic,OUT^127.0.0.1:0^127.0.0.1:81^CC=newreno",
+                UpstreamType::Tcp,
+            )
+            .unwrap()
+            .build()
+            .unwrap();
+        config.allow_insecure = true;
+        let client = Client::new(config);
+        let endpoint = client.bind_with_source_ip(false, Endpoint::client).unwrap();
+
+        let mut controllers = Vec::new();
+        for tunnel in &client.config.tunnels {
+            let login_cfg = client.prepare_login_config(Some(tunnel)).await.unwrap();
+            let (conn, _) = client
+                .connect_once(
+                    &endpoint,
+                    &login_cfg.quinn_client_cfg,
+                    login_cfg.remote_addr,
+                    &login_cfg.domain,
+                )
+                .await
+                .unwrap();
+            controllers.push(conn.congestion_state().into_any());
+        }
+        assert!(controllers[0].is::<congestion::Cubic>());
+        assert!(controllers[1].is::<congestion::NewReno>());
+    }
+
+    #[tokio::test]
+    async fn migration_answers_are_checked_on_the_connections_of_the_endpoint() {
+        let server = quic_server();
+        let mut config = ClientConfig::builder()
+            .server_addr(&server.local_addr().unwrap().to_string())
+            .build()
+            .unwrap();
+        config.allow_insecure = true;
+        let client = Client::new(config);
+        let login_cfg = client.prepare_login_config(None).await.unwrap();
+        let mut conns = Vec::new();
+        for _ in 0..2 {
+            let endpoint = client.bind_with_source_ip(false, Endpoint::client).unwrap();
+            let (conn, _) = client
+                .connect_once(
+                    &endpoint,
+                    &login_cfg.quinn_client_cfg,
+                    login_cfg.remote_addr,
+                    &login_cfg.domain,
+                )
+                .await
+                .unwrap();
+            conns.push((endpoint, conn));
+        }
+
+        let (shared, owned) = (&conns[0], &conns[1]);
+        {
+            let mut state = client.inner_state.lock().unwrap();
+            state.endpoint = Some(shared.0.clone());
+            state.tunnel_endpoints.insert(1, owned.0.clone());
+            for (index, (_, conn)) in conns.iter().enumerate() {
+                let local_server_addr = SocketAddr::from(([127, 0, 0, 1], 9000 + index as u16));
+                state.tunnel_local_addrs.insert(index, local_server_addr);
+                state.connections.insert(local_server_addr, conn.clone());
+            }
+        }
+
+        let state = client.inner_state.lock().unwrap();
+        for (endpoint, conn) in [shared, owned] {
+            let endpoint_conns = state.endpoint_connections(endpoint);
+            assert_eq!(endpoint_conns.len(), 1);
+            assert_eq!(endpoint_conns[0].stable_id(), conn.stable_id());
+        }
+    }
+
+    #[tokio::test]
+    async fn reconnects_after_an_mtu_black_hole_stick_to_the_minimum_mtu() {
+        let server = quic_server();
+        let mut config = ClientConfig::builder()
+            .server_addr(&server.local_addr().unwrap().to_string())
+            .build()
+            .unwrap();
+        config.allow_insecure = true;
+        let client = Client::new(config);
+        let endpoint = client.bind_with_source_ip(false, Endpoint::client).unwrap();
+        let connect = || async {
+            let login_cfg = client.prepare_login_config(None).await.unwrap();
+            let (conn, _) = client
+                .connect_once(
+                    &endpoint,
+                    &login_cfg.quinn_client_cfg,
+                    login_cfg.remote_addr,
+                    &login_cfg.domain,
+                )
+                .await
+                .unwrap();
+            conn
+        };
+
+        let probing = connect().await;
+        inner_state!(client, mtu_clamped) = true;
+        let clamped = connect().await;
+        for _ in 0..50 {
+            if probing.stats().path.current_mtu > 1200 {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(100)).await;
+        }
+        tokio::time::sleep(Duration::from_millis(500)).await;
+        assert!(probing.stats().path.current_mtu > 1200);
+        assert_eq!(clamped.stats().path.current_mtu, 1200);
+    }
+}
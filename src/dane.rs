@@ -0,0 +1,246 @@
+//! DANE (RFC 6698/7671) verification of the server certificate against the TLSA records of
+//! `_PORT._udp.DOMAIN`. Only records proven secure by DNSSEC are trusted, which the resolver
+//! validates itself, so the configured DoT/DNS servers need not be trusted for the records.
+
+use anyhow::{Context, Result};
+use hickory_resolver::{
+    config::{NameServerConfig, NameServerConfigGroup, ResolverConfig},
+    name_server::TokioConnectionProvider,
+    proto::{
+        rr::{
+            rdata::tlsa::{CertUsage, Matching, Selector},
+            rdata::TLSA,
+            RData, RecordType,
+        },
+        xfer::Protocol,
+    },
+    Resolver,
+};
+use log::{debug, warn};
+use ring::digest;
+use rustls::{
+    client::{
+        danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier},
+        WebPkiServerVerifier,
+    },
+    crypto::CryptoProvider,
+    pki_types::{CertificateDer, ServerName, UnixTime},
+    DigitallySignedStruct, RootCertStore,
+};
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Arc;
+use x509_parser::prelude::{FromDer, X509Certificate};
+
+const DOT_PORT: u16 = 853;
+const DNS_PORT: u16 = 53;
+
+/// looks up the DNSSEC-validated TLSA records of the QUIC service at `domain:port`, DoT servers
+/// take precedence over DNS servers, and the system configuration is used if neither is set
+pub(crate) async fn lookup_tlsa(
+    domain: &str,
+    port: u16,
+    dot_servers: &[String],
+    dns_servers: &[String],
+) -> Result<Vec<TLSA>> {
+    let mut name_servers = NameServerConfigGroup::new();
+    for dot in dot_servers.iter().filter(|dot| !dot.is_empty()) {
+        let ips: Vec<IpAddr> = match tokio::net::lookup_host((dot.as_str(), DOT_PORT)).await {
+            Ok(addrs) => addrs.map(|addr| addr.ip()).collect(),
+            Err(e) => {
+                warn!("failed to resolve DoT server {dot}: {e}");
+                continue;
+            }
+        };
+        name_servers.merge(NameServerConfigGroup::from_ips_tls(
+            &ips,
+            DOT_PORT,
+            dot.clone(),
+            true,
+        ));
+    }
+
+    if name_servers.is_empty() {
+        let servers: Vec<NameServerConfig> = dns_servers
+            .iter()
+            .filter_map(|dns| {
+                dns.parse::<SocketAddr>()
+                    .or_else(|_| {
+                        dns.parse::<IpAddr>()
+                            .map(|ip| SocketAddr::new(ip, DNS_PORT))
+                    })
+                    .ok()
+            })
+            .flat_map(|addr| {
+                [
+                    NameServerConfig::new(addr, Protocol::Udp),
+                    NameServerConfig::new(addr, Protocol::Tcp),
+                ]
+            })
+            .collect();
+        name_servers = servers.into();
+    }
+
+    let mut builder = if name_servers.is_empty() {
+        Resolver::builder_tokio().context("failed to read the system DNS configuration")?
+    } else {
+        Resolver::builder_with_config(
+            ResolverConfig::from_parts(None, vec![], name_servers),
+            TokioConnectionProvider::default(),
+        )
+    };
+    builder.options_mut().validate = true;
+    let resolver = builder.build();
+
+    let name = format!("_{port}._udp.{domain}.");
+    let lookup = resolver
+        .lookup(name.as_str(), RecordType::TLSA)
+        .await
+        .with_context(|| format!("failed to look up TLSA records of {name}"))?;
+
+    let records: Vec<TLSA> = lookup
+        .dnssec_iter()
+        .filter_map(|rdata| match rdata.into_parts() {
+            (proof, RData::TLSA(tlsa)) if proof.is_secure() => Some(tlsa.clone()),
+            _ => None,
+        })
+        .collect();
+    debug!("found {} secure TLSA records of {name}", records.len());
+    Ok(records)
+}
+
+/// Accepts the server certificate if it matches one of the TLSA records, the PKIX usages
+/// additionally require the certificate to pass `pkix`, which is the verifier otherwise in use
+#[derive(Debug)]
+pub(crate) struct DaneCertVerifier {
+    records: Vec<TLSA>,
+    pkix: Arc<dyn ServerCertVerifier>,
+    provider: Arc<CryptoProvider>,
+}
+
+impl DaneCertVerifier {
+    pub(crate) fn new(
+        records: Vec<TLSA>,
+        pkix: Arc<dyn ServerCertVerifier>,
+        provider: Arc<CryptoProvider>,
+    ) -> Self {
+        Self {
+            records,
+            pkix,
+            provider,
+        }
+    }
+
+    fn matches(record: &TLSA, cert: &CertificateDer<'_>) -> bool {
+        let selected = match record.selector() {
+            Selector::Full => cert.as_ref(),
+            Selector::Spki => match X509Certificate::from_der(cert.as_ref()) {
+                Ok((_, x509)) => x509.tbs_certificate.subject_pki.raw,
+                Err(_) => return false,
+            },
+            _ => return false,
+        };
+
+        match record.matching() {
+            Matching::Raw => selected == record.cert_data(),
+            Matching::Sha256 => {
+                digest::digest(&digest::SHA256, selected).as_ref() == record.cert_data()
+            }
+            Matching::Sha512 => {
+                digest::digest(&digest::SHA512, selected).as_ref() == record.cert_data()
+            }
+            _ => false,
+        }
+    }
+}
+
+impl ServerCertVerifier for DaneCertVerifier {
+    fn verify_server_cert(
+        &self,
+        end_entity: &CertificateDer<'_>,
+        intermediates: &[CertificateDer<'_>],
+        server_name: &ServerName<'_>,
+        ocsp_response: &[u8],
+        now: UnixTime,
+    ) -> std::result::Result<ServerCertVerified, rustls::Error> {
+        let mut last_err = None;
+        for record in &self.records {
+            let result = match record.cert_usage() {
+                // the certificate itself is the trust anchor, name and expiry are ignored
+                CertUsage::DaneEe if Self::matches(record, end_entity) => {
+                    Ok(ServerCertVerified::assertion())
+                }
+                CertUsage::PkixEe if Self::matches(record, end_entity) => self
+                    .pkix
+                    .verify_server_cert(end_entity, intermediates, server_name, ocsp_response, now),
+                CertUsage::DaneTa => {
+                    let Some(anchor) = intermediates.iter().find(|c| Self::matches(record, c))
+                    else {
+                        continue;
+                    };
+                    let mut roots = RootCertStore::empty();
+                    roots.add(anchor.clone().into_owned())?;
+                    WebPkiServerVerifier::builder_with_provider(
+                        Arc::new(roots),
+                        self.provider.clone(),
+                    )
+                    .build()
+                    .map_err(|e| rustls::Error::General(e.to_string()))?
+                    .verify_server_cert(
+                        end_entity,
+                        intermediates,
+                        server_name,
+                        ocsp_response,
+                        now,
+                    )
+                }
+                CertUsage::PkixTa if intermediates.iter().any(|c| Self::matches(record, c)) => self
+                    .pkix
+                    .verify_server_cert(end_entity, intermediates, server_name, ocsp_response, now),
+                _ => continue,
+            };
+
+            match result {
+                Ok(verified) => return Ok(verified),
+                Err(e) => last_err = Some(e),
+            }
+        }
+
+        Err(last_err.unwrap_or_else(|| {
+            rustls::Error::General("server certificate matches no TLSA record".to_string())
+        }))
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> std::result::Result<HandshakeSignatureValid, rustls::Error> {
+        rustls::crypto::verify_tls12_signature(
+            message,
+            cert,
+            dss,
+            &self.provider.signature_verification_algorithms,
+        )
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> std::result::Result<HandshakeSignatureValid, rustls::Error> {
+        rustls::crypto::verify_tls13_signature(
+            message,
+            cert,
+            dss,
+            &self.provider.signature_verification_algorithms,
+        )
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<rustls::SignatureScheme> {
+        self.provider
+            .signature_verification_algorithms
+            .supported_schemes()
+    }
+}
@@ -0,0 +1,77 @@
+//! Resolving the server domain through DNS-over-HTTPS (RFC 8484) servers, for networks that
+//! block DoT on port 853 but let HTTPS through.
+
+use crate::DnsStrategy;
+use anyhow::{bail, Context, Result};
+use hickory_resolver::{
+    config::{LookupIpStrategy, NameServerConfig, ResolverConfig},
+    name_server::TokioConnectionProvider,
+    proto::xfer::Protocol,
+    Resolver,
+};
+use std::net::IpAddr;
+
+const DOH_PORT: u16 = 443;
+const DEFAULT_DOH_PATH: &str = "/dns-query";
+
+/// looks up the addresses of `domain` through the DoH server at `url`, which is in the form
+/// `https://HOST[:PORT][/PATH]`, the path defaults to /dns-query
+pub(crate) async fn lookup_ip(
+    url: &str,
+    domain: &str,
+    strategy: DnsStrategy,
+) -> Result<Vec<IpAddr>> {
+    let Some(rest) = url.strip_prefix("https://") else {
+        bail!("invalid DoH server URL: {url}, expected https://HOST[:PORT][/PATH]");
+    };
+    let (authority, path) = match rest.find('/') {
+        Some(pos) => (&rest[..pos], &rest[pos..]),
+        None => (rest, DEFAULT_DOH_PATH),
+    };
+    let (host, port) = match authority.rsplit_once(':') {
+        // not the end of a bracketed IPv6 address
+        Some((host, port)) if !port.contains(']') => (
+            host,
+            port.parse()
+                .with_context(|| format!("invalid port in DoH server URL: {url}"))?,
+        ),
+        _ => (authority, DOH_PORT),
+    };
+    let host = host.trim_start_matches('[').trim_end_matches(']');
+    if host.is_empty() {
+        bail!("invalid DoH server URL: {url}, no host");
+    }
+
+    // the host of the DoH server itself can only be resolved by the system resolver
+    let name_servers: Vec<NameServerConfig> = tokio::net::lookup_host((host, port))
+        .await
+        .with_context(|| format!("failed to resolve DoH server {host}"))?
+        .map(|socket_addr| NameServerConfig {
+            socket_addr,
+            protocol: Protocol::Https,
+            tls_dns_name: Some(host.to_string()),
+            http_endpoint: Some(path.to_string()),
+            trust_negative_responses: true,
+            bind_addr: None,
+        })
+        .collect();
+
+    let mut builder = Resolver::builder_with_config(
+        ResolverConfig::from_parts(None, vec![], name_servers),
+        TokioConnectionProvider::default(),
+    );
+    builder.options_mut().ip_strategy = match strategy {
+        DnsStrategy::Ipv4Only => LookupIpStrategy::Ipv4Only,
+        DnsStrategy::Ipv6Only => LookupIpStrategy::Ipv6Only,
+        DnsStrategy::Ipv4AndIpv6 => LookupIpStrategy::Ipv4AndIpv6,
+        DnsStrategy::Ipv4thenIpv6 => LookupIpStrategy::Ipv4thenIpv6,
+        DnsStrategy::Ipv6thenIpv4 => LookupIpStrategy::Ipv6thenIpv4,
+    };
+    let resolver = builder.build();
+
+    let lookup = resolver
+        .lookup_ip(domain)
+        .await
+        .with_context(|| format!("failed to look up {domain} via {url}"))?;
+    Ok(lookup.iter().collect())
+}
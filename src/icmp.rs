@@ -0,0 +1,119 @@
+//! ICMP echo sent by the server on behalf of the clients, see `Client::ping`. An unprivileged
+//! ping socket (`net.ipv4.ping_group_range` on Linux) is tried first, then a raw socket, which
+//! requires CAP_NET_RAW.
+
+use anyhow::{bail, Context, Result};
+use socket2::{Domain, Protocol, SockAddr, Socket, Type};
+use std::io::{ErrorKind, Read};
+use std::net::{IpAddr, SocketAddr};
+use std::time::{Duration, Instant};
+
+const ICMPV4_ECHO_REQUEST: u8 = 8;
+const ICMPV4_ECHO_REPLY: u8 = 0;
+const ICMPV6_ECHO_REQUEST: u8 = 128;
+const ICMPV6_ECHO_REPLY: u8 = 129;
+const ECHO_IDENTIFIER: u16 = 0x5253;
+const ECHO_HEADER_SIZE: usize = 8;
+
+/// Sends an echo request to `target` and returns the round-trip time
+pub(crate) async fn echo(
+    target: IpAddr,
+    seq: u16,
+    payload_len: u16,
+    timeout: Duration,
+) -> Result<Duration> {
+    tokio::task::spawn_blocking(move || echo_blocking(target, seq, payload_len, timeout)).await?
+}
+
+fn open_socket(target: &IpAddr) -> Result<(Socket, bool)> {
+    let (domain, protocol) = if target.is_ipv4() {
+        (Domain::IPV4, Protocol::ICMPV4)
+    } else {
+        (Domain::IPV6, Protocol::ICMPV6)
+    };
+
+    if let Ok(socket) = Socket::new(domain, Type::DGRAM, Some(protocol)) {
+        return Ok((socket, false));
+    }
+
+    let socket = Socket::new(domain, Type::RAW, Some(protocol))
+        .context("not permitted to send ICMP, CAP_NET_RAW or net.ipv4.ping_group_range needed")?;
+    Ok((socket, true))
+}
+
+fn echo_blocking(
+    target: IpAddr,
+    seq: u16,
+    payload_len: u16,
+    timeout: Duration,
+) -> Result<Duration> {
+    let (mut socket, raw) = open_socket(&target)?;
+    socket.connect(&SockAddr::from(SocketAddr::new(target, 0)))?;
+
+    let (request_type, reply_type) = if target.is_ipv4() {
+        (ICMPV4_ECHO_REQUEST, ICMPV4_ECHO_REPLY)
+    } else {
+        (ICMPV6_ECHO_REQUEST, ICMPV6_ECHO_REPLY)
+    };
+
+    let mut packet = vec![0u8; ECHO_HEADER_SIZE + payload_len as usize];
+    packet[0] = request_type;
+    packet[4..6].copy_from_slice(&ECHO_IDENTIFIER.to_be_bytes());
+    packet[6..8].copy_from_slice(&seq.to_be_bytes());
+    // the kernel fills in the checksum for ICMPv6
+    if target.is_ipv4() {
+        let checksum = checksum(&packet);
+        packet[2..4].copy_from_slice(&checksum.to_be_bytes());
+    }
+
+    let started_at = Instant::now();
+    socket.send(&packet)?;
+
+    let mut buf = vec![0u8; 1500.max(packet.len() + 60)];
+    loop {
+        let Some(remaining) = timeout
+            .checked_sub(started_at.elapsed())
+            .filter(|remaining| !remaining.is_zero())
+        else {
+            bail!("timed out waiting for the echo reply from {target}");
+        };
+        socket.set_read_timeout(Some(remaining))?;
+
+        let len = match socket.read(&mut buf) {
+            Ok(len) => len,
+            Err(e) if matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => continue,
+            Err(e) => return Err(e.into()),
+        };
+
+        // raw IPv4 sockets receive the IP header as well
+        let reply = if raw && target.is_ipv4() {
+            let header_len = (buf[0] & 0x0f) as usize * 4;
+            buf.get(header_len..len)
+        } else {
+            buf.get(..len)
+        };
+        let Some(reply) = reply.filter(|reply| reply.len() >= ECHO_HEADER_SIZE) else {
+            continue;
+        };
+
+        // ping sockets rewrite the identifier, while raw sockets see the replies to other
+        // processes as well
+        if reply[0] == reply_type
+            && reply[6..8] == seq.to_be_bytes()
+            && (!raw || reply[4..6] == ECHO_IDENTIFIER.to_be_bytes())
+        {
+            return Ok(started_at.elapsed());
+        }
+    }
+}
+
+fn checksum(data: &[u8]) -> u16 {
+    let mut sum: u32 = data
+        .chunks(2)
+        .map(|word| u16::from_be_bytes([word[0], *word.get(1).unwrap_or(&0)]) as u32)
+        .sum();
+    while sum >> 16 != 0 {
+        sum = (sum & 0xffff) + (sum >> 16);
+    }
+    !(sum as u16)
+}
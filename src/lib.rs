@@ -1,13 +1,27 @@
+mod auth;
 mod client;
+#[cfg(feature = "dane")]
+mod dane;
+#[cfg(feature = "doh")]
+mod doh;
+#[cfg(feature = "icmp")]
+mod icmp;
+#[cfg(feature = "metrics")]
+mod metrics;
+mod otel;
 mod pem_util;
+#[cfg(unix)]
+mod privilege;
 mod server;
 mod tcp;
+mod transport;
 mod tunnel_info_bridge;
 mod tunnel_message;
 mod udp;
 mod util;
 
 use anyhow::{Context, Result};
+pub use auth::{AuthCredentials, AuthProvider, StaticPasswordProvider};
 use byte_pool::BytePool;
 pub use client::Client;
 pub use client::ClientState;
@@ -22,9 +36,16 @@ use std::fmt::Display;
 use std::net::IpAddr;
 use std::net::Ipv4Addr;
 use std::net::Ipv6Addr;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
 use std::{net::SocketAddr, ops::Deref};
 pub use tcp::tcp_server::TcpServer;
 pub use tcp::{AsyncStream, StreamMessage, StreamReceiver, StreamRequest, StreamSender};
+pub use transport::{QuicTransport, Transport};
+pub use tunnel_info_bridge::{
+    ConnectionStats, InboundBacklog, InfoFormat, ServerResolution, TunnelStats,
+};
 use tunnel_message::LoginInfo;
 use udp::udp_server::UdpServer;
 pub use udp::{UdpMessage, UdpPacket, UdpReceiver, UdpSender};
@@ -35,6 +56,10 @@ extern crate pretty_env_logger;
 pub const TUNNEL_MODE_IN: &str = "IN";
 pub const TUNNEL_MODE_OUT: &str = "OUT";
 pub const UDP_PACKET_SIZE: usize = 1500;
+pub const DEFAULT_ALPN_PROTOCOL: &str = "rstun";
+const DEFAULT_STREAM_RECEIVE_WINDOW: u64 = 1024 * 1024;
+const DEFAULT_RECEIVE_WINDOW: u64 = 1024 * 1024 * 2;
+const DEFAULT_SEND_WINDOW: u64 = 1024 * 1024 * 2;
 
 lazy_static! {
     static ref BUFFER_POOL: BytePool::<Vec<u8>> = BytePool::<Vec<u8>>::new();
@@ -107,24 +132,31 @@ impl Deref for SelectedCipherSuite {
 pub struct TcpTunnelOutInfo {
     conn: quinn::Connection,
     upstream_addr: SocketAddr,
+    one_way: bool,
+    compression: Compression,
 }
 
 #[derive(Debug)]
 pub struct TcpTunnelInInfo {
     conn: quinn::Connection,
     tcp_server: TcpServer,
+    one_way: bool,
+    proxy_protocol: bool,
+    compression: Compression,
 }
 
 #[derive(Debug)]
 pub struct UdpTunnelOutInfo {
     conn: quinn::Connection,
     upstream_addr: SocketAddr,
+    datagrams: bool,
 }
 
 #[derive(Debug)]
 pub struct UdpTunnelInInfo {
     conn: quinn::Connection,
     udp_server: UdpServer,
+    datagrams: bool,
 }
 
 #[derive(Debug)]
@@ -133,8 +165,9 @@ pub enum TunnelType {
     TcpIn(TcpTunnelInInfo),
     UdpOut(UdpTunnelOutInfo),
     UdpIn(UdpTunnelInInfo),
-    DynamicUpstreamTcpOut(quinn::Connection),
+    DynamicUpstreamTcpOut(quinn::Connection, Compression),
     DynamicUpstreamUdpOut(quinn::Connection),
+    IcmpChannel(quinn::Connection),
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
@@ -156,6 +189,18 @@ impl Display for TunnelMode {
 pub enum UpstreamType {
     Tcp,
     Udp,
+    /// outbound TCP whose local server speaks SOCKS5, the server connects each stream to the
+    /// destination the local client asked for
+    Socks5,
+    /// as Socks5, but the local server is an HTTP CONNECT proxy
+    HttpConnect,
+}
+
+impl UpstreamType {
+    /// whether the local server is a proxy taking the destination from each local client
+    pub(crate) fn is_proxy(&self) -> bool {
+        matches!(self, Self::Socks5 | Self::HttpConnect)
+    }
 }
 
 impl Display for UpstreamType {
@@ -163,6 +208,154 @@ impl Display for UpstreamType {
         match self {
             Self::Tcp => write!(f, "TCP"),
             Self::Udp => write!(f, "UDP"),
+            Self::Socks5 => write!(f, "SOCKS5"),
+            Self::HttpConnect => write!(f, "HTTP"),
+        }
+    }
+}
+
+/// congestion controller of the client connections, BBR copes poorly with high-loss links
+#[derive(Serialize, Deserialize, Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum CongestionControl {
+    #[default]
+    Bbr,
+    Cubic,
+    NewReno,
+}
+
+impl std::str::FromStr for CongestionControl {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "bbr" => Ok(Self::Bbr),
+            "cubic" => Ok(Self::Cubic),
+            "newreno" | "new-reno" => Ok(Self::NewReno),
+            _ => {
+                log_and_bail!("invalid congestion controller: {s}, expected bbr, cubic or newreno")
+            }
+        }
+    }
+}
+
+impl Display for CongestionControl {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Bbr => write!(f, "bbr"),
+            Self::Cubic => write!(f, "cubic"),
+            Self::NewReno => write!(f, "newreno"),
+        }
+    }
+}
+
+/// address families the server domain is resolved to, the `then` strategies only look up the
+/// second family if the first has no address. Both are looked up by default, for Happy Eyeballs
+#[derive(Serialize, Deserialize, Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum DnsStrategy {
+    Ipv4Only,
+    Ipv6Only,
+    #[default]
+    Ipv4AndIpv6,
+    Ipv4thenIpv6,
+    Ipv6thenIpv4,
+}
+
+impl std::str::FromStr for DnsStrategy {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "ipv4" => Ok(Self::Ipv4Only),
+            "ipv6" => Ok(Self::Ipv6Only),
+            "both" => Ok(Self::Ipv4AndIpv6),
+            "ipv4-then-ipv6" => Ok(Self::Ipv4thenIpv6),
+            "ipv6-then-ipv4" => Ok(Self::Ipv6thenIpv4),
+            _ => log_and_bail!(
+                "invalid DNS strategy: {s}, expected ipv4, ipv6, both, ipv4-then-ipv6 or ipv6-then-ipv4"
+            ),
+        }
+    }
+}
+
+impl Display for DnsStrategy {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Ipv4Only => write!(f, "ipv4"),
+            Self::Ipv6Only => write!(f, "ipv6"),
+            Self::Ipv4AndIpv6 => write!(f, "both"),
+            Self::Ipv4thenIpv6 => write!(f, "ipv4-then-ipv6"),
+            Self::Ipv6thenIpv4 => write!(f, "ipv6-then-ipv4"),
+        }
+    }
+}
+
+/// the order the DNS servers are queried in, by their response times so far or as configured
+#[derive(Serialize, Deserialize, Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum DnsQueryOrdering {
+    #[default]
+    QueryStatistics,
+    UserProvidedOrder,
+}
+
+impl std::str::FromStr for DnsQueryOrdering {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "statistics" => Ok(Self::QueryStatistics),
+            "configured" => Ok(Self::UserProvidedOrder),
+            _ => {
+                log_and_bail!("invalid DNS query ordering: {s}, expected statistics or configured")
+            }
+        }
+    }
+}
+
+impl Display for DnsQueryOrdering {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::QueryStatistics => write!(f, "statistics"),
+            Self::UserProvidedOrder => write!(f, "configured"),
+        }
+    }
+}
+
+/// compression of the bytes tunneled through the streams of a TCP tunnel, which requires the
+/// compression feature on both peers
+#[derive(Serialize, Deserialize, Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum Compression {
+    #[default]
+    None,
+    Zstd,
+    Lz4,
+}
+
+impl Compression {
+    /// whether this build can compress and decompress with it
+    pub(crate) fn is_supported(&self) -> bool {
+        *self == Self::None || cfg!(feature = "compression")
+    }
+}
+
+impl std::str::FromStr for Compression {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "none" => Ok(Self::None),
+            "zstd" => Ok(Self::Zstd),
+            "lz4" => Ok(Self::Lz4),
+            _ => log_and_bail!("invalid compression: {s}, expected none, zstd or lz4"),
+        }
+    }
+}
+
+impl Display for Compression {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::None => write!(f, "none"),
+            Self::Zstd => write!(f, "zstd"),
+            Self::Lz4 => write!(f, "lz4"),
         }
     }
 }
@@ -186,29 +379,351 @@ impl Display for Upstream {
 pub struct TunnelConfig {
     pub mode: TunnelMode,
     pub local_server_addr: Option<SocketAddr>,
+    /// HOST:PORT to bind the local server to, resolved to an address assigned to this host
+    /// when the tunnel starts, local_server_addr is None until then. OUT only
+    #[serde(skip)]
+    pub local_server_host: Option<String>,
+    /// more addresses the local server listens on besides local_server_addr, e.g. [::1]:8080
+    /// next to 127.0.0.1:8080 for apps using either stack, the connections accepted on all of
+    /// them go through the same tunnel. OUT TCP, SOCKS5 and HTTP only
+    #[serde(skip)]
+    pub extra_local_server_addrs: Vec<SocketAddr>,
+    /// Unix socket the upstream of an inbound TCP tunnel listens on, in place of
+    /// local_server_addr, which is None then. IN only, Unix only
+    #[serde(skip)]
+    pub local_server_path: Option<PathBuf>,
     pub upstream: Upstream,
+    /// data only flows from local_server_addr to the upstream, so unidirectional streams
+    /// are used, TCP only. it is sent with the login request, see `LoginReqExt`
+    #[serde(skip)]
+    pub one_way: bool,
+    /// tunnels with this flag set form a group, which stops as a whole once any of them fails
+    /// fatally, e.g. the local server host can't be resolved
+    #[serde(skip)]
+    pub fail_together: bool,
+    /// coalesce the data read from the TCP streams on this side for up to this many ms before
+    /// sending it, which suits bulk transfers, 0 sends each read right away (interactive)
+    #[serde(skip)]
+    pub flush_coalesce_ms: u64,
+    /// overrides `ClientConfig::retry_policy` for this tunnel
+    #[serde(skip)]
+    pub retry_policy: Option<RetryPolicy>,
+    /// overrides the client-wide transport settings for the connections of this tunnel, boxed
+    /// as it's rarely set
+    #[serde(skip)]
+    pub transport: Option<Box<TransportOverrides>>,
+    /// destinations the local clients of a SOCKS5 or HTTP tunnel may connect to, host
+    /// patterns (example.com, *.example.com) or CIDRs, all are allowed if empty
+    #[serde(skip)]
+    pub upstream_allow: Vec<String>,
+    /// destinations they may not connect to, taking precedence over upstream_allow
+    #[serde(skip)]
+    pub upstream_deny: Vec<String>,
+    /// overrides `ClientConfig::hop_interval_ms` for this tunnel, 0 disables the migration,
+    /// the tunnel then owns its endpoint to be migrated on its own schedule
+    #[serde(skip)]
+    pub hop_interval_ms: Option<u64>,
+    /// carry the UDP payloads in QUIC datagrams instead of streams, so lost packets aren't
+    /// retransmitted, UDP only. falls back to streams if either side doesn't support them
+    #[serde(skip)]
+    pub udp_datagrams: bool,
+    /// prepend a PROXY protocol v2 header to each stream sent to the upstream, carrying the
+    /// address of the client the server accepted, TCP IN only. the header has no addresses if
+    /// the server doesn't send them, the upstream then sees the rstun client as the client
+    #[serde(skip)]
+    pub proxy_protocol: bool,
+    /// compress the bytes tunneled through the streams, TCP, SOCKS5 and HTTP only. falls back
+    /// to no compression if the server doesn't support it
+    #[serde(skip)]
+    pub compression: Compression,
+}
+
+impl TunnelConfig {
+    /// the address the connection of the tunnel is tracked and reported by, the server port of
+    /// an inbound tunnel to a Unix socket, as it has no local server address
+    pub(crate) fn tracking_addr(&self) -> Option<SocketAddr> {
+        match &self.local_server_path {
+            Some(_) => self.upstream.upstream_addr,
+            None => self.local_server_addr,
+        }
+    }
+}
+
+/// Transport settings of a tunnel taking precedence over those of `ClientConfig`, the unset
+/// ones fall back to the client-wide settings
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct TransportOverrides {
+    pub congestion: Option<CongestionControl>,
+    /// QUIC flow-control windows in bytes, 0 means the defaults
+    pub stream_receive_window: Option<u64>,
+    pub receive_window: Option<u64>,
+    pub send_window: Option<u64>,
+    /// interval of the keep-alive pings, 0 disables them, by default 2/3 of `quic_timeout_ms`
+    pub keep_alive_interval_ms: Option<u64>,
+}
+
+/// How a tunnel retries connecting, and binding its local server
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct RetryPolicy {
+    /// timeout of the first connect attempt, doubled on each retry up to
+    /// max_connect_timeout_ms (0 means no cap), 0 leaves it to the QUIC handshake
+    pub connect_timeout_ms: u64,
+    pub max_connect_timeout_ms: u64,
+    /// the delay between retries grows from min_delay_ms by backoff_factor up to max_delay_ms,
+    /// `ClientConfig::create` sets min_delay_ms from wait_before_retry_ms
+    pub min_delay_ms: u64,
+    pub max_delay_ms: u64,
+    pub backoff_factor: u32,
+    /// randomizes the delays, so tunnels failing together don't retry in lockstep
+    pub jitter: bool,
+    /// 0 means unlimited for connecting, and 10 for binding the local server
+    pub max_times: usize,
+    /// give up once retrying for this long, 0 means never
+    pub max_elapsed_ms: u64,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            connect_timeout_ms: 0,
+            max_connect_timeout_ms: 0,
+            min_delay_ms: 1000,
+            max_delay_ms: 10000,
+            backoff_factor: 2,
+            jitter: false,
+            max_times: 0,
+            max_elapsed_ms: 0,
+        }
+    }
+}
+
+impl RetryPolicy {
+    pub(crate) fn backoff(&self, default_max_times: usize) -> backon::ExponentialBuilder {
+        let backoff = backon::ExponentialBuilder::default()
+            // a zero delay would never grow, retrying in a busy loop
+            .with_min_delay(Duration::from_millis(self.min_delay_ms.max(1)))
+            .with_max_delay(Duration::from_millis(self.max_delay_ms))
+            .with_factor(self.backoff_factor.max(1) as f32)
+            .with_max_times(if self.max_times > 0 {
+                self.max_times
+            } else {
+                default_max_times
+            });
+        if self.jitter {
+            backoff.with_jitter()
+        } else {
+            backoff
+        }
+    }
+
+    /// delay before retrying after `attempt` failed attempts as the backoff curve goes, before
+    /// max_delay_ms caps it and jitter is added
+    pub(crate) fn computed_delay(&self, attempt: u32) -> Duration {
+        let delay_ms = self.min_delay_ms.max(1) as f64
+            * (self.backoff_factor.max(1) as f64).powi(attempt.saturating_sub(1) as i32);
+        Duration::from_millis(delay_ms.min(u64::MAX as f64) as u64)
+    }
+
+    /// timeout of the connect attempt following `retries` failed ones, None if unlimited
+    pub(crate) fn connect_timeout(&self, retries: u32) -> Option<Duration> {
+        if self.connect_timeout_ms == 0 {
+            return None;
+        }
+        let timeout = self
+            .connect_timeout_ms
+            .saturating_mul(1u64.checked_shl(retries).unwrap_or(u64::MAX));
+        let timeout = match self.max_connect_timeout_ms {
+            0 => timeout,
+            max => timeout.min(max),
+        };
+        Some(Duration::from_millis(timeout))
+    }
+
+    pub(crate) fn is_expired(&self, started_at: std::time::Instant) -> bool {
+        self.max_elapsed_ms > 0
+            && started_at.elapsed() >= Duration::from_millis(self.max_elapsed_ms)
+    }
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
 pub(crate) enum Tunnel {
-    NetworkBased(TunnelConfig),
+    /// boxed as TunnelConfig is much larger than the other variants
+    NetworkBased(Box<TunnelConfig>),
     ChannelBased(UpstreamType),
+    /// carries ICMP echo requests for the server to send, see `Client::ping`
+    IcmpChannel,
 }
 
-#[derive(Debug, Default, Clone)]
+#[derive(Serialize, Deserialize, Debug, Default, Clone)]
+#[serde(default)]
 pub struct ClientConfig {
     pub cert_path: String,
+    /// PEM of the certificates to verify the server against, taking precedence over
+    /// `cert_path`, for applications embedding the certificate or fetching it from a keychain
+    pub cert_pem: Option<String>,
+    /// certificate and key presented to servers requiring mutual TLS, both or neither are set
+    pub client_cert_path: String,
+    pub client_key_path: String,
     pub cipher: String,
     pub server_addr: String,
+    /// the name the server certificate is verified against and sent as SNI, for connecting to
+    /// a server by IP whose certificate is issued for a domain, None means the domain or IP
+    /// of `server_addr`, which is still the address dialed
+    pub server_name_override: Option<String>,
+    /// servers to fail over to in order once `failover_after_attempts` connect attempts in a
+    /// row failed, `server_addr` is probed in the background meanwhile and returned to
+    pub fallback_server_addrs: Vec<String>,
+    /// 0 means 3
+    pub failover_after_attempts: u32,
     pub password: String,
+    /// log in with an HMAC of a nonce the server sends rather than the password, the password
+    /// is sent as is on the next attempt if the server doesn't support it
+    pub password_challenge: bool,
+    /// sent with the login request and logged by the server alongside the connection, e.g.
+    /// hostname + tunnel name, to correlate both sides' logs
+    pub connection_label: Option<String>,
+    /// obtains the login credentials on each connect, `password` is used if not set
+    #[serde(skip)]
+    pub auth_provider: Option<Arc<dyn AuthProvider>>,
+    /// establishes the connections to the server, [QuicTransport] is used if not set
+    #[serde(skip)]
+    pub transport: Option<Arc<dyn Transport>>,
     pub wait_before_retry_ms: u64,
+    /// max number of tunnels attempting to (re)connect at the same time, 0 means unlimited
+    pub max_concurrent_reconnects: usize,
+    /// max number of connections the inbound TCP tunnels have open to their local servers
+    /// at once, the streams the server opens beyond it are rejected, 0 means unlimited
+    pub max_inbound_connections: usize,
+    /// max number of UDP sessions, i.e. the local peers, the inbound UDP tunnels relay to their
+    /// local servers at once, the new ones beyond it are rejected, 0 means unlimited. Sessions
+    /// idle for `udp_timeout_ms` are evicted
+    pub max_udp_sessions: usize,
+    /// a tunnel that reconnected this many times after its connection closed is stopped, and
+    /// the ReconnectLimitReached event posted, rather than keep flapping, 0 means unlimited
+    pub max_lifetime_reconnects: u64,
+    /// max number of endpoints migrating at the same time, e.g. on a network change, 0 means
+    /// unlimited
+    pub max_concurrent_migrations: usize,
     pub quic_timeout_ms: u64,
+    /// RTT assumed until the first sample, e.g. 600 for satellite links, 0 means quinn's
+    /// default (333ms)
+    pub initial_rtt_ms: u64,
     pub tcp_timeout_ms: u64,
     pub udp_timeout_ms: u64,
+    /// the local clients of the SOCKS5 and HTTP proxies not completing the handshake within
+    /// this are dropped, 0 means tcp_timeout_ms
+    pub proxy_handshake_timeout_ms: u64,
+    /// the TCP streams opened by the client without activity in either direction for this long
+    /// are closed, freeing their stream credit while the connection stays up, 0 disables it
+    pub stream_idle_timeout_ms: u64,
     pub hop_interval_ms: u64,
+    /// each migration fires at hop_interval_ms ± a random offset of up to this percent of it,
+    /// so the migrations aren't periodic, 0 disables it
+    pub hop_jitter_percent: u8,
+    /// inclusive range of the local ports migrations move to, cycling through it, e.g. for
+    /// firewalls only letting out a range of source ports, None means random ports
+    pub migration_port_range: Option<(u16, u16)>,
+    /// how long a migrated endpoint waits for the connections to hear from the server on the
+    /// new path before moving back to the old local address, 0 disables the fallback
+    pub migration_fallback_grace_ms: u64,
+    /// migrate an endpoint once the packets its connections lose over a sampling window reach
+    /// this percent of those sent, in addition to the hops of hop_interval_ms, 0 disables it
+    pub migrate_on_loss_threshold_percent: u8,
+    /// likewise once the RTT of any of its connections reaches this, 0 disables it
+    pub migrate_on_rtt_threshold_ms: u64,
+    pub congestion: CongestionControl,
+    /// QUIC flow-control windows in bytes, 0 means the defaults (1MB, 2MB and 2MB), the
+    /// receive_window must not be smaller than the stream_receive_window
+    pub stream_receive_window: u64,
+    pub receive_window: u64,
+    pub send_window: u64,
+    /// max number of streams the server may have open at once, None means 1024, and 0 means
+    /// quinn's default (100)
+    pub max_concurrent_bidi_streams: Option<u64>,
+    pub max_concurrent_uni_streams: Option<u64>,
+    /// interval for sampling cwnd and pacing rate of each connection, 0 to disable
+    pub congestion_sample_interval_ms: u64,
+    /// interval for reporting the traffic data, None means 30 seconds, and 0 disables it
+    pub traffic_report_interval_secs: Option<u64>,
+    /// interval for posting the Heartbeat event, a liveness signal sent even when nothing
+    /// changes, 0 disables it
+    pub heartbeat_interval_secs: u64,
+    /// interval for sending a heartbeat in a QUIC datagram on the connection of each tunnel,
+    /// which the server echoes, to measure the RTT the tunnels see, reported in
+    /// `Client::tunnel_stats` and the Heartbeat event, 0 disables it
+    pub datagram_heartbeat_interval_ms: u64,
+    /// post the TunnelLog events as records of ts, level, tunnel_index, event_type, msg and
+    /// fields for log pipelines, rather than as timestamped strings
+    pub structured_tunnel_log: bool,
+    /// address of the HTTP listener serving the counters of the client on `/metrics` for
+    /// Prometheus to scrape, needs the metrics feature, None disables it
+    pub metrics_addr: Option<SocketAddr>,
+    /// overall deadline for stop() and stop_async() to wait for the shutdown tasks, 0 means 3000ms
+    pub shutdown_timeout_ms: u64,
+    /// on stop, the local servers stop accepting first, then the TCP streams in flight get up to
+    /// this long to finish before the connections are closed, 0 closes them right away
+    pub drain_timeout_ms: u64,
+    /// retry policy of the tunnels that don't set their own
+    pub retry_policy: RetryPolicy,
+    /// keep a second logged-in idle connection per outbound tunnel to fail over to
+    pub warm_standby: bool,
+    /// send the login as 0-RTT early data when reconnecting to a server whose session ticket
+    /// is cached, saving a round trip. off by default, as early data can be replayed by an
+    /// attacker, the login is sent again in 1-RTT if the server declines it
+    pub enable_0rtt: bool,
+    /// caps on the traffic sent into / received from all the tunnels in bits/s, 0 means unlimited
+    pub max_tx_bps: u64,
+    pub max_rx_bps: u64,
+    /// exported as the --tcp-mappings and --udp-mappings strings, as the serde form of
+    /// TunnelConfig is what's sent with the login request, `ClientConfig::from_json` parses them
+    #[serde(serialize_with = "serialize_addr_mappings", skip_deserializing)]
     pub tunnels: Vec<TunnelConfig>,
+    /// local IPs to originate the connections from, rotated per connection and per migration
+    pub source_ip_pool: Vec<IpAddr>,
+    /// local IP to originate the connections from on a multi-homed host, so they go out through
+    /// the interface it's assigned to, the migrations included. Unused if source_ip_pool is set
+    pub local_bind_addr: Option<IpAddr>,
+    /// on Unix, the user and group to switch to once the local servers of the outbound tunnels
+    /// are bound, so privileged ports can be bound without tunneling as root
+    pub run_as_uid: Option<u32>,
+    pub run_as_gid: Option<u32>,
+    /// DoH server URLs, e.g. https://dns.google/dns-query, tried before the DoT and DNS servers,
+    /// requires the `doh` feature
+    pub doh_servers: Vec<String>,
     pub dot_servers: Vec<String>,
     pub dns_servers: Vec<String>,
+    pub dns_strategy: DnsStrategy,
+    /// the order the --dot and --dns servers are queried in
+    pub dns_query_ordering: DnsQueryOrdering,
+    /// log all the addresses the server domain resolves to and post them as an event
+    pub report_dns_resolution: bool,
+    /// how long the address the server domain resolved to is reused for before resolving it
+    /// again, None means 60 seconds, and 0 disables the cache
+    pub dns_cache_ttl_secs: Option<u64>,
+    /// how long past its TTL a cached address is still used if resolving the domain fails,
+    /// 0 means it isn't
+    pub dns_stale_grace_secs: u64,
+    /// verify the server certificate against the DNSSEC-validated TLSA records of its domain
+    pub verify_dane: bool,
+    /// hex SHA-256 of the server certificate's SubjectPublicKeyInfo, accepts the certificate
+    /// by its key alone, taking precedence over `cert_path` and the platform verifier
+    pub pinned_spki_sha256: String,
+    /// skip the verification of the server certificate when connecting by IP without a
+    /// certificate, for testing against the self-signed certificate of a server, such a
+    /// client is refused otherwise
+    pub allow_insecure: bool,
+    /// max number of certificates in the server's chain, the end entity included, longer
+    /// chains are rejected by the CA (or provided certificate) verification
+    pub max_cert_chain_len: Option<usize>,
+    /// post the CertExpiryWarning event on connecting to a server whose certificate expires
+    /// within this many days, 0 disables it
+    pub cert_expiry_warning_days: u32,
+    /// close the connections to a server whose certificate has expired, which the pinned key
+    /// verification doesn't check
+    pub reject_expired_cert: bool,
+    /// ALPN protocols offered to the server, None means [DEFAULT_ALPN_PROTOCOL], an empty list
+    /// offers none, which servers predating ALPN require
+    pub alpn_protocols: Option<Vec<String>>,
     pub workers: usize,
 }
 
@@ -230,6 +745,17 @@ pub struct ServerConfig {
     pub dashboard_server: String,
     /// user:password
     pub dashboard_server_credential: String,
+
+    /// send ICMP echo requests on behalf of the clients, requires the `icmp` feature
+    pub allow_icmp: bool,
+    /// CA certificates the clients' certificates must chain to, mutual TLS is off if empty
+    pub client_ca_path: String,
+    /// ALPN protocols accepted from the clients, None means [DEFAULT_ALPN_PROTOCOL], an empty
+    /// list accepts only the clients predating ALPN, which offer none
+    pub alpn_protocols: Option<Vec<String>>,
+    /// accept the logins sent as 0-RTT early data, see `ClientConfig::enable_0rtt`, they're
+    /// declined otherwise and the clients log in again once the handshake completes
+    pub accept_0rtt: bool,
 }
 
 impl ClientConfig {
@@ -263,11 +789,7 @@ impl ClientConfig {
         if udp_timeout_ms == 0 {
             udp_timeout_ms = 5000;
         }
-        if hop_interval_ms != 0 && hop_interval_ms < 5000 {
-            warn!("Endpoint migration interval: {hop_interval_ms} ms is too low and has been forcibly set to 5000 ms to prevent potential network failures due to excessive port or NAT resource exhaustion."
-                    );
-            hop_interval_ms = 5000;
-        }
+        hop_interval_ms = clamp_hop_interval_ms(hop_interval_ms);
 
         let mut config = ClientConfig {
             cert_path: cert.to_string(),
@@ -284,6 +806,10 @@ impl ClientConfig {
                 num_cpus::get()
             },
             wait_before_retry_ms,
+            retry_policy: RetryPolicy {
+                min_delay_ms: wait_before_retry_ms,
+                ..RetryPolicy::default()
+            },
             quic_timeout_ms,
             tcp_timeout_ms,
             udp_timeout_ms,
@@ -293,16 +819,318 @@ impl ClientConfig {
             ..ClientConfig::default()
         };
 
-        parse_addr_mappings(tcp_addr_mappings, UpstreamType::Tcp, &mut config.tunnels)?;
-        parse_addr_mappings(udp_addr_mappings, UpstreamType::Udp, &mut config.tunnels)?;
+        parse_addr_mappings(
+            tcp_addr_mappings,
+            UpstreamType::Tcp,
+            &config.retry_policy,
+            &mut config.tunnels,
+        )?;
+        parse_addr_mappings(
+            udp_addr_mappings,
+            UpstreamType::Udp,
+            &config.retry_policy,
+            &mut config.tunnels,
+        )?;
+
+        Ok(config)
+    }
+
+    /// Fails if the receive window of the client or of a tunnel is smaller than its stream
+    /// receive window. `ClientConfigBuilder::build` checks it, and `create` the mappings setting
+    /// both, so callers setting the client-wide windows afterwards should check it
+    pub fn check_flow_control_windows(&self) -> Result<()> {
+        self.check_flow_control_windows_of(&TransportOverrides::default())?;
+        for (index, tunnel) in self.tunnels.iter().enumerate() {
+            if let Some(overrides) = &tunnel.transport {
+                self.check_flow_control_windows_of(overrides)
+                    .with_context(|| format!("tunnel {index}"))?;
+            }
+        }
+        Ok(())
+    }
+
+    pub(crate) fn check_flow_control_windows_of(
+        &self,
+        overrides: &TransportOverrides,
+    ) -> Result<()> {
+        let (stream_receive_window, receive_window, _) = self.flow_control_windows(overrides);
+        if receive_window < stream_receive_window {
+            log_and_bail!(
+                "receive_window ({receive_window}) must not be smaller than stream_receive_window ({stream_receive_window})"
+            );
+        }
+        Ok(())
+    }
+
+    /// the stream receive, receive and send windows of a tunnel, its overrides take precedence
+    /// over the client-wide windows
+    pub(crate) fn flow_control_windows(&self, overrides: &TransportOverrides) -> (u64, u64, u64) {
+        let or_default = |window: u64, default: u64| if window > 0 { window } else { default };
+        (
+            or_default(
+                overrides
+                    .stream_receive_window
+                    .unwrap_or(self.stream_receive_window),
+                DEFAULT_STREAM_RECEIVE_WINDOW,
+            ),
+            or_default(
+                overrides.receive_window.unwrap_or(self.receive_window),
+                DEFAULT_RECEIVE_WINDOW,
+            ),
+            or_default(
+                overrides.send_window.unwrap_or(self.send_window),
+                DEFAULT_SEND_WINDOW,
+            ),
+        )
+    }
+
+    /// Loads the configuration `Client::export_config` exported, the fields left out take their
+    /// defaults, and the tunnels overriding the retry policy the rest of `retry_policy`. A
+    /// redacted password or client key path has to be set again before connecting
+    pub fn from_json(json: &str) -> Result<ClientConfig> {
+        #[derive(Deserialize, Default)]
+        #[serde(default)]
+        struct AddrMappings {
+            tcp_mappings: String,
+            udp_mappings: String,
+        }
 
+        let mut json: serde_json::Value = serde_json::from_str(json).context("invalid JSON")?;
+        let mappings: AddrMappings = match json.get_mut("tunnels") {
+            Some(tunnels) => serde_json::from_value(tunnels.take()).context("invalid tunnels")?,
+            None => AddrMappings::default(),
+        };
+        let mut config: ClientConfig =
+            serde_json::from_value(json).context("invalid client config")?;
+        parse_addr_mappings(
+            &mappings.tcp_mappings,
+            UpstreamType::Tcp,
+            &config.retry_policy,
+            &mut config.tunnels,
+        )?;
+        parse_addr_mappings(
+            &mappings.udp_mappings,
+            UpstreamType::Udp,
+            &config.retry_policy,
+            &mut config.tunnels,
+        )?;
+        config.check_flow_control_windows()?;
         Ok(config)
     }
+
+    /// builds the config field by field rather than from the command-line style arguments of
+    /// `ClientConfig::create`
+    pub fn builder() -> ClientConfigBuilder {
+        ClientConfigBuilder::default()
+    }
+}
+
+/// Sets the fields of a `ClientConfig` starting from the defaults of rstunc, the ones not
+/// covered by a setter can be changed on the config `build` returns
+pub struct ClientConfigBuilder {
+    config: ClientConfig,
+}
+
+impl Default for ClientConfigBuilder {
+    fn default() -> Self {
+        Self {
+            config: ClientConfig {
+                cipher: SUPPORTED_CIPHER_SUITE_STRS[0].to_string(),
+                workers: num_cpus::get(),
+                wait_before_retry_ms: 5000,
+                retry_policy: RetryPolicy {
+                    min_delay_ms: 5000,
+                    ..RetryPolicy::default()
+                },
+                quic_timeout_ms: 30000,
+                tcp_timeout_ms: 30000,
+                udp_timeout_ms: 5000,
+                ..ClientConfig::default()
+            },
+        }
+    }
+}
+
+impl ClientConfigBuilder {
+    /// [domain|ip]:port, or only the port for 127.0.0.1
+    pub fn server_addr(mut self, server_addr: &str) -> Self {
+        self.config.server_addr = server_addr.to_string();
+        self
+    }
+
+    pub fn password(mut self, password: &str) -> Self {
+        self.config.password = password.to_string();
+        self
+    }
+
+    pub fn auth_provider(mut self, auth_provider: Arc<dyn AuthProvider>) -> Self {
+        self.config.auth_provider = Some(auth_provider);
+        self
+    }
+
+    pub fn cert_path(mut self, cert_path: &str) -> Self {
+        self.config.cert_path = cert_path.to_string();
+        self
+    }
+
+    /// one of `SUPPORTED_CIPHER_SUITE_STRS`
+    pub fn cipher(mut self, cipher: &str) -> Self {
+        self.config.cipher = cipher.to_string();
+        self
+    }
+
+    pub fn add_tunnel(mut self, tunnel: TunnelConfig) -> Self {
+        self.config.tunnels.push(tunnel);
+        self
+    }
+
+    /// adds the tunnels of mappings in the `--tcp-mappings`/`--udp-mappings` form
+    pub fn add_mappings(mut self, mappings: &str, upstream_type: UpstreamType) -> Result<Self> {
+        parse_addr_mappings(
+            mappings,
+            upstream_type,
+            &self.config.retry_policy,
+            &mut self.config.tunnels,
+        )?;
+        Ok(self)
+    }
+
+    /// 0 uses all logical CPUs
+    pub fn workers(mut self, workers: usize) -> Self {
+        self.config.workers = if workers > 0 {
+            workers
+        } else {
+            num_cpus::get()
+        };
+        self
+    }
+
+    /// also the min delay of the retry policy, set it before adding mappings overriding it
+    pub fn wait_before_retry_ms(mut self, wait_before_retry_ms: u64) -> Self {
+        self.config.wait_before_retry_ms = wait_before_retry_ms;
+        self.config.retry_policy.min_delay_ms = wait_before_retry_ms;
+        self
+    }
+
+    pub fn retry_policy(mut self, retry_policy: RetryPolicy) -> Self {
+        self.config.retry_policy = retry_policy;
+        self
+    }
+
+    /// 0 means the default, as for the timeouts below
+    pub fn quic_timeout_ms(mut self, quic_timeout_ms: u64) -> Self {
+        self.config.quic_timeout_ms = if quic_timeout_ms > 0 {
+            quic_timeout_ms
+        } else {
+            30000
+        };
+        self
+    }
+
+    pub fn tcp_timeout_ms(mut self, tcp_timeout_ms: u64) -> Self {
+        self.config.tcp_timeout_ms = if tcp_timeout_ms > 0 {
+            tcp_timeout_ms
+        } else {
+            30000
+        };
+        self
+    }
+
+    pub fn udp_timeout_ms(mut self, udp_timeout_ms: u64) -> Self {
+        self.config.udp_timeout_ms = if udp_timeout_ms > 0 {
+            udp_timeout_ms
+        } else {
+            5000
+        };
+        self
+    }
+
+    /// 0 disables the migration, intervals below 5000 ms are raised to it
+    pub fn hop_interval_ms(mut self, hop_interval_ms: u64) -> Self {
+        self.config.hop_interval_ms = clamp_hop_interval_ms(hop_interval_ms);
+        self
+    }
+
+    pub fn dot_servers(mut self, dot_servers: Vec<String>) -> Self {
+        self.config.dot_servers = dot_servers;
+        self
+    }
+
+    pub fn dns_servers(mut self, dns_servers: Vec<String>) -> Self {
+        self.config.dns_servers = dns_servers;
+        self
+    }
+
+    pub fn connection_label(mut self, connection_label: &str) -> Self {
+        self.config.connection_label = Some(connection_label.to_string());
+        self
+    }
+
+    /// the client-wide QUIC flow-control windows in bytes, 0 means the defaults
+    pub fn flow_control_windows(
+        mut self,
+        stream_receive_window: u64,
+        receive_window: u64,
+        send_window: u64,
+    ) -> Self {
+        self.config.stream_receive_window = stream_receive_window;
+        self.config.receive_window = receive_window;
+        self.config.send_window = send_window;
+        self
+    }
+
+    /// fails if the server address is empty, a tunnel has nowhere to listen or connect to
+    /// locally, or the flow-control windows conflict, so the mistake shows before any connection
+    /// is attempted
+    pub fn build(mut self) -> Result<ClientConfig> {
+        let server_addr = self.config.server_addr.trim();
+        if server_addr.is_empty() {
+            log_and_bail!("server_addr must not be empty");
+        }
+        if !server_addr.contains(':') {
+            self.config.server_addr = format!("127.0.0.1:{server_addr}");
+        }
+
+        for (index, tunnel) in self.config.tunnels.iter().enumerate() {
+            if tunnel.local_server_addr.is_none()
+                && tunnel.local_server_host.is_none()
+                && tunnel.local_server_path.is_none()
+            {
+                log_and_bail!(
+                    "tunnel {index} ({}_{}) has no local_server_addr",
+                    tunnel.upstream.upstream_type,
+                    tunnel.mode
+                );
+            }
+        }
+        self.config.check_flow_control_windows()?;
+
+        Ok(self.config)
+    }
+}
+
+fn clamp_hop_interval_ms(hop_interval_ms: u64) -> u64 {
+    if hop_interval_ms != 0 && hop_interval_ms < 5000 {
+        warn!("Endpoint migration interval: {hop_interval_ms} ms is too low and has been forcibly set to 5000 ms to prevent potential network failures due to excessive port or NAT resource exhaustion."
+                    );
+        return 5000;
+    }
+    hop_interval_ms
+}
+
+/// QUIC requires both sides to agree on ALPN, so a side offering none only talks to one
+/// offering none either
+pub(crate) fn effective_alpn_protocols(alpn_protocols: &Option<Vec<String>>) -> Vec<String> {
+    match alpn_protocols {
+        Some(protocols) => protocols.clone(),
+        None => vec![DEFAULT_ALPN_PROTOCOL.to_string()],
+    }
 }
 
 fn parse_addr_mappings(
     mappings: &str,
     upstream_type: UpstreamType,
+    retry_policy: &RetryPolicy,
     v: &mut Vec<TunnelConfig>,
 ) -> Result<()> {
     if mappings.is_empty() {
@@ -311,14 +1139,138 @@ fn parse_addr_mappings(
 
     for mapping in mappings.split(',') {
         let parts: Vec<&str> = mapping.split('^').collect();
-        if parts.len() != 3 {
-            log_and_bail!("Invalid mapping format, expected TYPE^SRC^DEST");
+        if parts.len() < 3 {
+            log_and_bail!("Invalid mapping format, expected TYPE^SRC^DEST[^OPTION...]");
+        }
+
+        let mut one_way = false;
+        let mut fail_together = false;
+        let mut flush_coalesce_ms = 0;
+        let mut tunnel_retry_policy = None;
+        let mut transport: Option<Box<TransportOverrides>> = None;
+        let mut upstream_allow = Vec::new();
+        let mut upstream_deny = Vec::new();
+        let mut hop_interval_ms = None;
+        let mut udp_datagrams = false;
+        let mut proxy_protocol = false;
+        let mut compression = Compression::None;
+        let mut extra_local_server_addrs = Vec::new();
+        for option in &parts[3..] {
+            if *option == "ONEWAY" {
+                one_way = true;
+                continue;
+            }
+            if *option == "DATAGRAM" {
+                udp_datagrams = true;
+                continue;
+            }
+            if *option == "PROXY_PROTOCOL" {
+                proxy_protocol = true;
+                continue;
+            }
+            if *option == "GROUP" {
+                fail_together = true;
+                continue;
+            }
+
+            let Some((key, value)) = option.split_once('=') else {
+                log_and_bail!(
+                    "Invalid mapping option '{option}', expected ONEWAY, DATAGRAM, PROXY_PROTOCOL, GROUP or KEY=VALUE"
+                );
+            };
+            let number = |unit: &str| -> Result<u64> {
+                value
+                    .parse()
+                    .with_context(|| format!("Invalid {key} '{value}', expected {unit}"))
+            };
+            match key {
+                "COALESCE" => flush_coalesce_ms = number("milliseconds")?,
+                "CONNECT_TIMEOUT" => {
+                    tunnel_retry_policy
+                        .get_or_insert_with(|| retry_policy.clone())
+                        .connect_timeout_ms = number("milliseconds")?
+                }
+                "RETRY_FOR" => {
+                    tunnel_retry_policy
+                        .get_or_insert_with(|| retry_policy.clone())
+                        .max_elapsed_ms = number("milliseconds")?
+                }
+                "CC" => transport.get_or_insert_default().congestion = Some(value.parse()?),
+                "STREAM_WINDOW" => {
+                    transport.get_or_insert_default().stream_receive_window = Some(number("bytes")?)
+                }
+                "RECV_WINDOW" => {
+                    transport.get_or_insert_default().receive_window = Some(number("bytes")?)
+                }
+                "SEND_WINDOW" => {
+                    transport.get_or_insert_default().send_window = Some(number("bytes")?)
+                }
+                "KEEPALIVE" => {
+                    transport.get_or_insert_default().keep_alive_interval_ms =
+                        Some(number("milliseconds")?)
+                }
+                "ALLOW" => upstream_allow.push(value.to_string()),
+                "DENY" => upstream_deny.push(value.to_string()),
+                "HOP" => hop_interval_ms = Some(clamp_hop_interval_ms(number("milliseconds")?)),
+                "COMPRESS" => compression = value.parse()?,
+                "ALSO" => extra_local_server_addrs.push(value.parse().with_context(|| {
+                    format!("Invalid ALSO address '{value}', expected IP:PORT")
+                })?),
+                _ => log_and_bail!("Invalid mapping option '{option}', expected ONEWAY, DATAGRAM, PROXY_PROTOCOL, GROUP, COALESCE=MS, CONNECT_TIMEOUT=MS, RETRY_FOR=MS, CC=NAME, STREAM_WINDOW=BYTES, RECV_WINDOW=BYTES, SEND_WINDOW=BYTES, KEEPALIVE=MS, ALLOW=PATTERN, DENY=PATTERN, HOP=MS, COMPRESS=zstd|lz4 or ALSO=IP:PORT"),
+            }
+        }
+        if flush_coalesce_ms > 0 && upstream_type != UpstreamType::Tcp {
+            log_and_bail!("COALESCE is only supported for TCP mappings");
+        }
+        if one_way && upstream_type != UpstreamType::Tcp {
+            log_and_bail!("ONEWAY is only supported for TCP mappings");
+        }
+        if udp_datagrams && upstream_type != UpstreamType::Udp {
+            log_and_bail!("DATAGRAM is only supported for UDP mappings");
+        }
+        if compression != Compression::None && upstream_type != UpstreamType::Tcp {
+            log_and_bail!("COMPRESS is only supported for TCP mappings");
+        }
+        if !compression.is_supported() {
+            log_and_bail!("COMPRESS={compression} requires building with the compression feature");
+        }
+        // a window left unset falls back to the client-wide one, checked along with the config
+        if let Some(overrides) = transport.as_deref().filter(|overrides| {
+            overrides.stream_receive_window.is_some() && overrides.receive_window.is_some()
+        }) {
+            ClientConfig::default().check_flow_control_windows_of(overrides)?;
         }
 
         let tunnel_mode = parts[0];
         if tunnel_mode != "OUT" && tunnel_mode != "IN" {
             log_and_bail!("Invalid tunnel type, expected OUT or IN");
         }
+        if proxy_protocol && (upstream_type != UpstreamType::Tcp || tunnel_mode != "IN") {
+            log_and_bail!("PROXY_PROTOCOL is only supported for IN TCP mappings");
+        }
+        if !extra_local_server_addrs.is_empty()
+            && (upstream_type != UpstreamType::Tcp || tunnel_mode != "OUT")
+        {
+            log_and_bail!("ALSO is only supported for OUT TCP mappings");
+        }
+
+        let proxy_type = match parts[2] {
+            "SOCKS5" => Some(UpstreamType::Socks5),
+            "HTTP" => Some(UpstreamType::HttpConnect),
+            _ => None,
+        };
+        if let Some(proxy_type) = &proxy_type {
+            if upstream_type != UpstreamType::Tcp || tunnel_mode != "OUT" {
+                log_and_bail!("{proxy_type} is only supported for OUT TCP mappings");
+            }
+            if one_way {
+                log_and_bail!("ONEWAY is not supported for {proxy_type} mappings");
+            }
+        } else if !upstream_allow.is_empty() || !upstream_deny.is_empty() {
+            log_and_bail!("ALLOW and DENY are only supported for SOCKS5 and HTTP mappings");
+        }
+        tcp::destination_policy::parse_patterns(&upstream_allow)?;
+        tcp::destination_policy::parse_patterns(&upstream_deny)?;
 
         let parse_addr = |addr: &str| -> Result<Option<SocketAddr>> {
             if addr == "ANY" {
@@ -340,11 +1292,43 @@ fn parse_addr_mappings(
             })?))
         };
 
-        let local_server_addr = parse_addr(parts[1])?;
-        if local_server_addr.is_none() {
+        let upstream_addr = if proxy_type.is_some() {
+            None
+        } else {
+            parse_addr(parts[2])?
+        };
+        let local_server_path = parts[1].strip_prefix("unix:").map(PathBuf::from);
+        if let Some(path) = &local_server_path {
+            if !cfg!(unix) {
+                log_and_bail!("Unix sockets are not supported on this platform");
+            }
+            if tunnel_mode != "IN" || upstream_type != UpstreamType::Tcp {
+                log_and_bail!("unix:PATH is only supported for IN TCP mappings");
+            }
+            if path.as_os_str().is_empty() {
+                log_and_bail!("Invalid Unix socket path '{}'", parts[1]);
+            }
+            if upstream_addr.is_none() {
+                log_and_bail!("'ANY' is not allowed as the server port of unix:PATH mappings");
+            }
+        }
+
+        let (local_server_addr, local_server_host) = match parse_addr(parts[1]) {
+            _ if local_server_path.is_some() => (None, None),
+            Ok(addr) => (addr, None),
+            Err(e) => match parts[1].rsplit_once(':') {
+                Some((host, port))
+                    if tunnel_mode == "OUT" && !host.is_empty() && port.parse::<u16>().is_ok() =>
+                {
+                    (None, Some(parts[1].to_string()))
+                }
+                _ => return Err(e),
+            },
+        };
+        if local_server_addr.is_none() && local_server_host.is_none() && local_server_path.is_none()
+        {
             log_and_bail!("'ANY' is not allowed as local_server_addr");
         }
-        let upstream_addr = parse_addr(parts[2])?;
 
         v.push(TunnelConfig {
             mode: if tunnel_mode == "IN" {
@@ -354,15 +1338,126 @@ fn parse_addr_mappings(
             },
             upstream: Upstream {
                 upstream_addr,
-                upstream_type: upstream_type.clone(),
+                upstream_type: proxy_type.unwrap_or_else(|| upstream_type.clone()),
             },
             local_server_addr,
+            local_server_host,
+            extra_local_server_addrs,
+            local_server_path,
+            one_way,
+            fail_together,
+            flush_coalesce_ms,
+            retry_policy: tunnel_retry_policy,
+            transport,
+            upstream_allow,
+            upstream_deny,
+            hop_interval_ms,
+            udp_datagrams,
+            proxy_protocol,
+            compression,
         });
     }
 
     Ok(())
 }
 
+/// the inverse of `parse_addr_mappings`, TYPE^SRC^DEST[^OPTION...]
+fn format_addr_mapping(tunnel: &TunnelConfig) -> String {
+    let src = match (&tunnel.local_server_host, tunnel.local_server_addr) {
+        (Some(host), _) => host.clone(),
+        (None, Some(addr)) => addr.to_string(),
+        (None, None) => match &tunnel.local_server_path {
+            Some(path) => format!("unix:{}", path.display()),
+            None => "ANY".to_string(),
+        },
+    };
+    let dest = match (
+        &tunnel.upstream.upstream_type,
+        tunnel.upstream.upstream_addr,
+    ) {
+        (UpstreamType::Socks5, _) => "SOCKS5".to_string(),
+        (UpstreamType::HttpConnect, _) => "HTTP".to_string(),
+        (_, Some(addr)) => addr.to_string(),
+        (_, None) => "ANY".to_string(),
+    };
+
+    let mut parts = vec![tunnel.mode.to_string(), src, dest];
+    if tunnel.one_way {
+        parts.push("ONEWAY".to_string());
+    }
+    if tunnel.udp_datagrams {
+        parts.push("DATAGRAM".to_string());
+    }
+    if tunnel.proxy_protocol {
+        parts.push("PROXY_PROTOCOL".to_string());
+    }
+    if tunnel.fail_together {
+        parts.push("GROUP".to_string());
+    }
+    if tunnel.flush_coalesce_ms > 0 {
+        parts.push(format!("COALESCE={}", tunnel.flush_coalesce_ms));
+    }
+    if let Some(retry_policy) = &tunnel.retry_policy {
+        parts.push(format!(
+            "CONNECT_TIMEOUT={}",
+            retry_policy.connect_timeout_ms
+        ));
+        parts.push(format!("RETRY_FOR={}", retry_policy.max_elapsed_ms));
+    }
+    if let Some(transport) = &tunnel.transport {
+        if let Some(congestion) = transport.congestion {
+            parts.push(format!("CC={congestion}"));
+        }
+        let windows = [
+            ("STREAM_WINDOW", transport.stream_receive_window),
+            ("RECV_WINDOW", transport.receive_window),
+            ("SEND_WINDOW", transport.send_window),
+            ("KEEPALIVE", transport.keep_alive_interval_ms),
+        ];
+        for (key, value) in windows {
+            if let Some(value) = value {
+                parts.push(format!("{key}={value}"));
+            }
+        }
+    }
+    for pattern in &tunnel.upstream_allow {
+        parts.push(format!("ALLOW={pattern}"));
+    }
+    for pattern in &tunnel.upstream_deny {
+        parts.push(format!("DENY={pattern}"));
+    }
+    if let Some(hop_interval_ms) = tunnel.hop_interval_ms {
+        parts.push(format!("HOP={hop_interval_ms}"));
+    }
+    if tunnel.compression != Compression::None {
+        parts.push(format!("COMPRESS={}", tunnel.compression));
+    }
+    for addr in &tunnel.extra_local_server_addrs {
+        parts.push(format!("ALSO={addr}"));
+    }
+    parts.join("^")
+}
+
+fn serialize_addr_mappings<S: serde::Serializer>(
+    tunnels: &[TunnelConfig],
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    let mappings = |udp: bool| {
+        tunnels
+            .iter()
+            .filter(|t| (t.upstream.upstream_type == UpstreamType::Udp) == udp)
+            .map(format_addr_mapping)
+            .collect::<Vec<_>>()
+            .join(",")
+    };
+
+    use serde::ser::SerializeStruct;
+    let mut state = serializer.serialize_struct("AddrMappings", 2)?;
+    state.serialize_field("tcp_mappings", &mappings(false))?;
+    state.serialize_field("udp_mappings", &mappings(true))?;
+    state.end()
+}
+
 pub fn socket_addr_with_unspecified_ip_port(ipv6: bool) -> SocketAddr {
     if ipv6 {
         SocketAddr::new(IpAddr::V6(Ipv6Addr::UNSPECIFIED), 0)
@@ -510,6 +1605,20 @@ pub mod android {
         });
     }
 
+    #[no_mangle]
+    pub unsafe extern "C" fn Java_net_neevek_omnip_RsTunc_nativeNotifyNetworkChanged(
+        _env: JNIEnv,
+        _: JClass,
+        client_ptr: jlong,
+    ) {
+        if client_ptr == 0 {
+            return;
+        }
+
+        let client = &*(client_ptr as *mut Client);
+        client.notify_network_changed();
+    }
+
     #[no_mangle]
     pub unsafe extern "C" fn Java_net_neevek_omnip_RsTunc_nativeGetState(
         env: JNIEnv,
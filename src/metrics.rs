@@ -0,0 +1,116 @@
+//! The counters of the client in the Prometheus text format
+//! (https://prometheus.io/docs/instrumenting/exposition_formats/), served on `/metrics` by a
+//! minimal HTTP/1.1 listener, as a scrape is a single GET.
+
+use anyhow::{Context, Result};
+use log::{debug, info};
+use std::fmt::Write;
+use std::net::SocketAddr;
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+
+/// the request line and headers of a scrape are short, anything longer is refused
+const MAX_REQUEST_HEADER_SIZE: usize = 8192;
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// renders the samples of each metric after its HELP and TYPE lines
+#[derive(Default)]
+pub(crate) struct MetricsWriter {
+    text: String,
+}
+
+impl MetricsWriter {
+    /// `kind` is counter or gauge, the samples are the label pairs and the value
+    pub(crate) fn metric<'a>(
+        &mut self,
+        name: &str,
+        kind: &str,
+        help: &str,
+        samples: impl IntoIterator<Item = (Vec<(&'a str, String)>, f64)>,
+    ) {
+        let _ = writeln!(self.text, "# HELP {name} {help}");
+        let _ = writeln!(self.text, "# TYPE {name} {kind}");
+        for (labels, value) in samples {
+            self.text.push_str(name);
+            if !labels.is_empty() {
+                let labels: Vec<_> = labels
+                    .iter()
+                    .map(|(key, value)| format!("{key}=\"{}\"", escape_label_value(value)))
+                    .collect();
+                let _ = write!(self.text, "{{{}}}", labels.join(","));
+            }
+            let _ = writeln!(self.text, " {value}");
+        }
+    }
+
+    pub(crate) fn into_text(self) -> String {
+        self.text
+    }
+}
+
+fn escape_label_value(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+/// serves `render()` on `/metrics` until the listener fails, other paths get 404
+pub(crate) async fn serve(
+    addr: SocketAddr,
+    render: impl Fn() -> String + Clone + Send + Sync + 'static,
+) -> Result<()> {
+    let listener = TcpListener::bind(addr)
+        .await
+        .with_context(|| format!("failed to bind the metrics listener at {addr}"))?;
+    info!("serving metrics at http://{addr}/metrics");
+    loop {
+        let (mut stream, peer_addr) = listener.accept().await?;
+        let render = render.clone();
+        tokio::spawn(async move {
+            let respond = async {
+                let path = read_request_path(&mut stream).await?;
+                let response = if path == "/metrics" {
+                    let body = render();
+                    format!(
+                        "HTTP/1.1 200 OK\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}",
+                        body.len()
+                    )
+                } else {
+                    "HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\nConnection: close\r\n\r\n"
+                        .to_string()
+                };
+                stream.write_all(response.as_bytes()).await?;
+                stream.shutdown().await?;
+                Ok::<_, anyhow::Error>(())
+            };
+            match tokio::time::timeout(REQUEST_TIMEOUT, respond).await {
+                Ok(Ok(())) => {}
+                Ok(Err(e)) => debug!("failed to serve metrics to {peer_addr}: {e}"),
+                Err(_) => debug!("timeout serving metrics to {peer_addr}"),
+            }
+        });
+    }
+}
+
+/// the path of a GET request, the headers are read and ignored
+async fn read_request_path(stream: &mut TcpStream) -> Result<String> {
+    let mut header = Vec::with_capacity(256);
+    while !header.ends_with(b"\r\n\r\n") {
+        if header.len() == MAX_REQUEST_HEADER_SIZE {
+            anyhow::bail!("request header exceeds {MAX_REQUEST_HEADER_SIZE} bytes");
+        }
+        header.push(stream.read_u8().await?);
+    }
+    let request_line = header
+        .split(|b| *b == b'\n')
+        .next()
+        .and_then(|line| std::str::from_utf8(line).ok())
+        .unwrap_or_default()
+        .trim_end();
+    match request_line.split(' ').collect::<Vec<_>>()[..] {
+        ["GET", path, _] => Ok(path.split('?').next().unwrap_or_default().to_string()),
+        _ => anyhow::bail!("unsupported request: {request_line:?}"),
+    }
+}
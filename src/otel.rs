@@ -0,0 +1,75 @@
+//! OpenTelemetry spans for the connection lifecycle, reported to the tracer provider installed
+//! with `opentelemetry::global`. Without the `otel` feature `OtelSpan` is a zero-sized no-op.
+
+use std::fmt::Display;
+use std::future::Future;
+
+#[cfg(feature = "otel")]
+use opentelemetry::{
+    global,
+    trace::{FutureExt, Status, TraceContextExt, Tracer},
+    Context, KeyValue,
+};
+
+pub(crate) struct OtelSpan {
+    #[cfg(feature = "otel")]
+    cx: Context,
+}
+
+impl OtelSpan {
+    /// starts a span, which is a child of the span of the instrumented future being polled, if any
+    #[inline]
+    pub(crate) fn start(name: &'static str) -> Self {
+        #[cfg(not(feature = "otel"))]
+        let _ = name;
+        Self {
+            #[cfg(feature = "otel")]
+            cx: Context::current_with_span(global::tracer("rstun").start(name)),
+        }
+    }
+
+    #[inline]
+    pub(crate) fn set_attr(&self, key: &'static str, value: impl Display) {
+        #[cfg(feature = "otel")]
+        self.cx
+            .span()
+            .set_attribute(KeyValue::new(key, value.to_string()));
+        #[cfg(not(feature = "otel"))]
+        let _ = (key, value);
+    }
+
+    #[inline]
+    pub(crate) fn set_u64(&self, key: &'static str, value: u64) {
+        #[cfg(feature = "otel")]
+        self.cx
+            .span()
+            .set_attribute(KeyValue::new(key, value.min(i64::MAX as u64) as i64));
+        #[cfg(not(feature = "otel"))]
+        let _ = (key, value);
+    }
+
+    #[inline]
+    pub(crate) fn set_error(&self, err: impl Display) {
+        #[cfg(feature = "otel")]
+        self.cx.span().set_status(Status::error(err.to_string()));
+        #[cfg(not(feature = "otel"))]
+        let _ = err;
+    }
+
+    /// makes the spans started while polling `fut` children of this span
+    #[inline]
+    pub(crate) fn instrument<F: Future>(&self, fut: F) -> impl Future<Output = F::Output> {
+        #[cfg(feature = "otel")]
+        return fut.with_context(self.cx.clone());
+        #[cfg(not(feature = "otel"))]
+        fut
+    }
+}
+
+impl Drop for OtelSpan {
+    #[inline]
+    fn drop(&mut self) {
+        #[cfg(feature = "otel")]
+        self.cx.span().end();
+    }
+}
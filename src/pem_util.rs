@@ -11,9 +11,13 @@ use std::{fs::File, io::BufReader};
 pub fn load_certificates_from_pem(path: &str) -> Result<Vec<CertificateDer<'static>>> {
     let file_path = PathBuf::from(path);
     let cert_buf = fs::read(file_path).context("reading cert failed")?;
-    let cert_buf = &mut cert_buf.as_ref();
-    let certs = rustls_pemfile::certs(cert_buf);
-    Ok(certs.filter_map(Result::ok).collect())
+    Ok(parse_certificates_from_pem(&cert_buf))
+}
+
+/// the certificates in PEM data held in memory, e.g. embedded in the application
+pub fn parse_certificates_from_pem(mut pem: &[u8]) -> Vec<CertificateDer<'static>> {
+    let certs = rustls_pemfile::certs(&mut pem);
+    certs.filter_map(Result::ok).collect()
 }
 
 pub fn load_private_key_from_pem(path: &str) -> Result<PrivateKeyDer<'static>> {
@@ -0,0 +1,33 @@
+//! Switching to an unprivileged user once the local servers are bound, so binding privileged
+//! ports (e.g. 443) doesn't require running the tunnels as root.
+
+use anyhow::{bail, Result};
+use std::io;
+
+/// Switches the process to `gid`/`uid`, the group first, as changing it is no longer permitted
+/// once the user isn't root. glibc and musl apply both to all the threads of the process.
+pub(crate) fn drop_privileges(uid: Option<u32>, gid: Option<u32>) -> Result<()> {
+    if let Some(gid) = gid {
+        let gid = gid as libc::gid_t;
+        // leaves the supplementary groups of root as well
+        if unsafe { libc::setgroups(1, &gid) } != 0 {
+            bail!("setgroups({gid}) failed: {}", io::Error::last_os_error());
+        }
+        if unsafe { libc::setgid(gid) } != 0 {
+            bail!("setgid({gid}) failed: {}", io::Error::last_os_error());
+        }
+    }
+
+    if let Some(uid) = uid {
+        let uid = uid as libc::uid_t;
+        if unsafe { libc::setuid(uid) } != 0 {
+            bail!("setuid({uid}) failed: {}", io::Error::last_os_error());
+        }
+        // make sure root can't be regained
+        if uid != 0 && unsafe { libc::setuid(0) } == 0 {
+            bail!("root privileges could be regained after setuid({uid})");
+        }
+    }
+
+    Ok(())
+}
@@ -1,21 +1,29 @@
+use crate::tcp::proxy_protocol::ProxyHeader;
 use crate::tcp::tcp_tunnel::TcpTunnel;
-use crate::tcp::{StreamMessage, StreamSender};
-use crate::tunnel_message::TunnelMessage;
+use crate::tcp::{DialTarget, StreamMessage, StreamSender};
+use crate::tunnel_message::{
+    verify_password_hmac, LoginReqExt, LoginRespExt, PingRequest, TunnelMessage, ERR_BAD_PASSWORD,
+};
 use crate::udp::udp_server::{UdpMessage, UdpSender};
 use crate::udp::{udp_server::UdpServer, udp_tunnel::UdpTunnel};
+use crate::util::heartbeat::DatagramHeartbeats;
+use crate::util::rate_limiter::RateLimiters;
 use crate::{
-    pem_util, ServerConfig, TcpServer, TcpTunnelInInfo, TcpTunnelOutInfo, Tunnel, TunnelConfig,
-    TunnelMode, TunnelType, UdpTunnelInInfo, UdpTunnelOutInfo, UpstreamType,
-    SUPPORTED_CIPHER_SUITES,
+    effective_alpn_protocols, pem_util, Compression, ServerConfig, TcpServer, TcpTunnelInInfo,
+    TcpTunnelOutInfo, Tunnel, TunnelConfig, TunnelMode, TunnelType, UdpTunnelInInfo,
+    UdpTunnelOutInfo, UpstreamType, SUPPORTED_CIPHER_SUITES,
 };
 use anyhow::{Context, Result};
 use log::{debug, error, info, warn};
 use quinn::crypto::rustls::QuicServerConfig;
 use quinn::IdleTimeout;
 use quinn::VarInt;
-use quinn::{congestion, Connection, Endpoint, SendStream, TransportConfig};
+use quinn::{congestion, Connection, Endpoint, RecvStream, SendStream, TransportConfig};
+use ring::rand::{SecureRandom, SystemRandom};
 use rs_utilities::log_and_bail;
 use rustls::pki_types::{CertificateDer, PrivateKeyDer, PrivatePkcs8KeyDer};
+use rustls::server::WebPkiClientVerifier;
+use rustls::RootCertStore;
 use std::net::SocketAddr;
 use std::sync::{Arc, Mutex, Once};
 use tokio::net::TcpStream;
@@ -33,12 +41,20 @@ struct ConnectedUdpInSession {
     sender: UdpSender,
 }
 
+/// the login stream of an authenticated connection, kept open to push messages to the client
+#[derive(Debug)]
+struct ControlSession {
+    conn: Connection,
+    quic_send: SendStream,
+}
+
 #[derive(Debug)]
 struct State {
     config: ServerConfig,
     endpoint: Option<Endpoint>,
     tcp_sessions: Vec<ConnectedTcpInSession>,
     udp_sessions: Vec<ConnectedUdpInSession>,
+    control_sessions: Vec<ControlSession>,
 }
 
 impl State {
@@ -48,6 +64,7 @@ impl State {
             endpoint: None,
             tcp_sessions: Vec::new(),
             udp_sessions: Vec::new(),
+            control_sessions: Vec::new(),
         }
     }
 }
@@ -120,12 +137,36 @@ impl Server {
             ..default_provider
         };
 
-        let tls_server_cfg = rustls::ServerConfig::builder_with_provider(provider.into())
+        let provider = Arc::new(provider);
+        let client_cert_verifier = if config.client_ca_path.is_empty() {
+            WebPkiClientVerifier::no_client_auth()
+        } else {
+            let mut roots = RootCertStore::empty();
+            for cert in
+                pem_util::load_certificates_from_pem(config.client_ca_path.as_str()).context(
+                    format!("failed to read client CA file: {}", config.client_ca_path),
+                )?
+            {
+                roots.add(cert)?;
+            }
+            WebPkiClientVerifier::builder_with_provider(Arc::new(roots), provider.clone())
+                .build()?
+        };
+
+        let mut tls_server_cfg = rustls::ServerConfig::builder_with_provider(provider)
             .with_protocol_versions(&[&rustls::version::TLS13])
             .unwrap()
-            .with_no_client_auth()
+            .with_client_cert_verifier(client_cert_verifier)
             .with_single_cert(certs, key)
             .unwrap();
+        tls_server_cfg.alpn_protocols = effective_alpn_protocols(&config.alpn_protocols)
+            .into_iter()
+            .map(String::into_bytes)
+            .collect();
+        if config.accept_0rtt {
+            // QUIC only allows 0 or u32::MAX
+            tls_server_cfg.max_early_data_size = u32::MAX;
+        }
 
         let mut transport_cfg = TransportConfig::default();
         transport_cfg.stream_receive_window(VarInt::from_u32(1024 * 1024));
@@ -139,6 +180,7 @@ impl Server {
                 .keep_alive_interval(Some(Duration::from_millis(config.quic_timeout_ms * 2 / 3)));
         }
         transport_cfg.max_concurrent_bidi_streams(VarInt::from_u32(1024));
+        transport_cfg.max_concurrent_uni_streams(VarInt::from_u32(1024));
 
         let quic_server_cfg = Arc::new(QuicServerConfig::try_from(tls_server_cfg)?);
         let mut quinn_server_cfg = quinn::ServerConfig::with_crypto(quic_server_cfg);
@@ -162,14 +204,26 @@ impl Server {
             let config = inner_state!(self, config).clone();
             tokio::spawn(async move {
                 let client_conn = client_conn.await?;
-                let tun_type = Self::authenticate_connection(&config, client_conn).await?;
+                let (tun_type, quic_send) =
+                    Self::authenticate_connection(&config, client_conn.clone()).await?;
+                state.lock().unwrap().control_sessions.push(ControlSession {
+                    conn: client_conn,
+                    quic_send,
+                });
 
                 match tun_type {
                     TunnelType::TcpOut(info) => {
                         TcpTunnel::start_accepting(
                             &info.conn,
-                            Some(info.upstream_addr),
+                            DialTarget::Addr(info.upstream_addr),
                             config.tcp_timeout_ms,
+                            info.one_way,
+                            0,
+                            &RateLimiters::default(),
+                            None,
+                            None,
+                            ProxyHeader::None,
+                            info.compression,
                         )
                         .await;
                     }
@@ -179,6 +233,10 @@ impl Server {
                             &info.conn,
                             Some(info.upstream_addr),
                             config.udp_timeout_ms,
+                            &RateLimiters::default(),
+                            info.datagrams,
+                            None,
+                            &DatagramHeartbeats::Echo,
                         )
                         .await
                     }
@@ -201,6 +259,13 @@ impl Server {
                             &mut tcp_receiver,
                             &mut None,
                             config.tcp_timeout_ms,
+                            info.one_way,
+                            0,
+                            &RateLimiters::default(),
+                            None,
+                            None,
+                            info.proxy_protocol,
+                            info.compression,
                         )
                         .await;
 
@@ -225,17 +290,43 @@ impl Server {
                             &udp_sender,
                             &mut udp_receiver,
                             config.udp_timeout_ms,
+                            &RateLimiters::default(),
+                            None,
+                            info.datagrams,
+                            &DatagramHeartbeats::Echo,
                         )
                         .await;
 
                         info.udp_server.shutdown().await.ok();
                     }
-                    TunnelType::DynamicUpstreamTcpOut(conn) => {
-                        TcpTunnel::start_accepting(&conn, None, config.tcp_timeout_ms).await;
+                    TunnelType::DynamicUpstreamTcpOut(conn, compression) => {
+                        TcpTunnel::start_accepting(
+                            &conn,
+                            DialTarget::Requested,
+                            config.tcp_timeout_ms,
+                            false,
+                            0,
+                            &RateLimiters::default(),
+                            None,
+                            None,
+                            ProxyHeader::None,
+                            compression,
+                        )
+                        .await;
                     }
                     TunnelType::DynamicUpstreamUdpOut(conn) => {
-                        UdpTunnel::start_accepting(&conn, None, config.udp_timeout_ms).await
+                        UdpTunnel::start_accepting(
+                            &conn,
+                            None,
+                            config.udp_timeout_ms,
+                            &RateLimiters::default(),
+                            false,
+                            None,
+                            &DatagramHeartbeats::Echo,
+                        )
+                        .await
                     }
+                    TunnelType::IcmpChannel(conn) => Self::serve_icmp_channel(&conn).await,
                 }
 
                 Ok::<(), anyhow::Error>(())
@@ -246,10 +337,73 @@ impl Server {
         Ok(())
     }
 
+    /// Asks all the connected clients to reconnect to `new_addr`, e.g. before shutting down for
+    /// maintenance. Clients set up the new connection before leaving this one, so the streams
+    /// in flight can finish. Returns the number of connections notified
+    pub async fn redirect_clients(&self, new_addr: &str) -> usize {
+        let sessions = std::mem::take(&mut inner_state!(self, control_sessions));
+        let msg = TunnelMessage::Redirect {
+            new_addr: new_addr.to_string(),
+        };
+
+        let mut notified = 0;
+        for mut sess in sessions {
+            let remote_addr = sess.conn.remote_address();
+            match TunnelMessage::send(&mut sess.quic_send, &msg).await {
+                Ok(_) => {
+                    info!("redirected {remote_addr} to {new_addr}");
+                    notified += 1;
+                }
+                Err(e) => warn!("failed to redirect {remote_addr}, err: {e}"),
+            }
+            sess.quic_send.finish().ok();
+        }
+        notified
+    }
+
+    /// each echo request comes in a bi stream of its own, the reply is sent on the same stream
+    async fn serve_icmp_channel(conn: &quinn::Connection) {
+        let remote_addr = conn.remote_address();
+        info!("start serving ICMP channel: {remote_addr}");
+
+        while let Ok((mut quic_send, mut quic_recv)) = conn.accept_bi().await {
+            tokio::spawn(async move {
+                let resp = match TunnelMessage::recv(&mut quic_recv).await? {
+                    TunnelMessage::ReqPing(req) => Self::ping(&req).await,
+                    msg => TunnelMessage::RespFailure(format!("unexpected message: {msg}")),
+                };
+                TunnelMessage::send(&mut quic_send, &resp).await?;
+                quic_send.finish()?;
+                Ok::<(), anyhow::Error>(())
+            });
+        }
+
+        debug!("ICMP channel closed: {remote_addr}");
+    }
+
+    #[cfg(feature = "icmp")]
+    async fn ping(req: &PingRequest) -> TunnelMessage {
+        const MAX_PING_TIMEOUT_MS: u64 = 10000;
+        let timeout = Duration::from_millis(req.timeout_ms.clamp(1, MAX_PING_TIMEOUT_MS));
+        match crate::icmp::echo(req.target, req.seq, req.payload_len, timeout).await {
+            Ok(rtt) => TunnelMessage::RespPing(crate::tunnel_message::PingReply {
+                rtt_us: rtt.as_micros() as u64,
+            }),
+            Err(e) => TunnelMessage::RespFailure(format!("{e:#}")),
+        }
+    }
+
+    #[cfg(not(feature = "icmp"))]
+    async fn ping(_req: &PingRequest) -> TunnelMessage {
+        TunnelMessage::RespFailure("ICMP is not supported".to_string())
+    }
+
+    /// returns the tunnel and the send half of the login stream, which serves as the control
+    /// channel of the connection
     async fn authenticate_connection(
         config: &ServerConfig,
         conn: quinn::Connection,
-    ) -> Result<TunnelType> {
+    ) -> Result<(TunnelType, SendStream)> {
         let remote_addr = &conn.remote_address();
 
         info!("authenticating connection, addr:{remote_addr}");
@@ -259,26 +413,99 @@ impl Server {
             .context(format!("login request not received in time: {remote_addr}"))?;
 
         info!("received bi_stream request: {remote_addr}");
-        match TunnelMessage::recv(&mut quic_recv).await? {
-            TunnelMessage::ReqLogin(login_info) => {
+        match TunnelMessage::recv_with_ext::<LoginReqExt>(&mut quic_recv).await? {
+            (TunnelMessage::ReqLogin(mut login_info), req_ext) => {
                 info!("received ReqLogin request: {remote_addr}");
 
-                Self::check_password(config.password.as_str(), login_info.password.as_str())?;
+                let req_ext = req_ext.unwrap_or_default();
+                let checked = if req_ext.password_challenge {
+                    Self::check_password_challenge(config, &mut quic_send, &mut quic_recv).await
+                } else {
+                    Self::check_password(config.password.as_str(), login_info.password.as_str())
+                };
+                if let Err(e) = checked {
+                    TunnelMessage::send_failure(&mut quic_send, ERR_BAD_PASSWORD.to_string())
+                        .await
+                        .ok();
+                    return Err(e);
+                }
+
+                let label = req_ext.connection_label.as_deref().unwrap_or("-");
+                info!("login from {remote_addr}, label: {label}, tunnel: {login_info}");
+                let mut udp_datagrams = false;
+                let mut proxy_protocol = false;
+                let mut compression = Compression::None;
+                if let Tunnel::NetworkBased(tunnel_config) = &mut login_info.tunnel {
+                    tunnel_config.one_way = req_ext.one_way;
+                    udp_datagrams = req_ext.udp_datagrams
+                        && tunnel_config.upstream.upstream_type == UpstreamType::Udp
+                        && conn.max_datagram_size().is_some();
+                    tunnel_config.udp_datagrams = udp_datagrams;
+                    proxy_protocol = req_ext.proxy_protocol
+                        && tunnel_config.upstream.upstream_type == UpstreamType::Tcp
+                        && tunnel_config.mode == TunnelMode::In;
+                    tunnel_config.proxy_protocol = proxy_protocol;
+                    if req_ext.compression.is_supported()
+                        && tunnel_config.upstream.upstream_type != UpstreamType::Udp
+                    {
+                        compression = req_ext.compression;
+                    }
+                    tunnel_config.compression = compression;
+                }
+                let datagram_heartbeat =
+                    req_ext.datagram_heartbeat && conn.max_datagram_size().is_some();
+                let resp_ext = Self::login_resp_ext(
+                    config,
+                    req_ext.connection_label.clone(),
+                    udp_datagrams,
+                    proxy_protocol,
+                    compression,
+                    datagram_heartbeat,
+                );
+                // the UDP tunnels in datagram mode echo them along with the packets
+                if datagram_heartbeat && !udp_datagrams {
+                    tokio::spawn(DatagramHeartbeats::Echo.read(conn.clone()));
+                }
 
                 let tunnel_type = match login_info.tunnel {
                     Tunnel::NetworkBased(tunnel_config) => {
-                        Self::derive_tunnel_type(conn, &mut quic_send, &tunnel_config, config)
-                            .await?
+                        Self::derive_tunnel_type(
+                            conn,
+                            &mut quic_send,
+                            &tunnel_config,
+                            config,
+                            &resp_ext,
+                        )
+                        .await?
                     }
                     Tunnel::ChannelBased(upstream_type) => match upstream_type {
-                        UpstreamType::Tcp => TunnelType::DynamicUpstreamTcpOut(conn),
+                        UpstreamType::Tcp | UpstreamType::Socks5 | UpstreamType::HttpConnect => {
+                            TunnelType::DynamicUpstreamTcpOut(conn, Compression::None)
+                        }
                         UpstreamType::Udp => TunnelType::DynamicUpstreamUdpOut(conn),
                     },
+                    Tunnel::IcmpChannel => {
+                        if !config.allow_icmp || cfg!(not(feature = "icmp")) {
+                            TunnelMessage::send_failure(
+                                &mut quic_send,
+                                "ICMP is not allowed".to_string(),
+                            )
+                            .await
+                            .ok();
+                            log_and_bail!("rejected ICMP channel: {remote_addr}");
+                        }
+                        TunnelType::IcmpChannel(conn)
+                    }
                 };
 
-                TunnelMessage::send(&mut quic_send, &TunnelMessage::RespSuccess).await?;
-                info!("connection authenticated! addr: {remote_addr}");
-                Ok(tunnel_type)
+                TunnelMessage::send_with_ext(
+                    &mut quic_send,
+                    &TunnelMessage::RespSuccess,
+                    &resp_ext,
+                )
+                .await?;
+                info!("connection authenticated! addr: {remote_addr}, label: {label}");
+                Ok((tunnel_type, quic_send))
             }
 
             _ => {
@@ -292,6 +519,7 @@ impl Server {
         quic_send: &mut SendStream,
         tunnel_config: &TunnelConfig,
         config: &ServerConfig,
+        resp_ext: &LoginRespExt,
     ) -> Result<TunnelType> {
         let upstream_addr = match tunnel_config.upstream.upstream_type {
             UpstreamType::Tcp => {
@@ -300,18 +528,43 @@ impl Server {
             UpstreamType::Udp => {
                 Self::obtain_upstream_addr(tunnel_config, &config.default_udp_upstream)?
             }
+            // the client sends the destination of each stream along with it
+            UpstreamType::Socks5 | UpstreamType::HttpConnect
+                if tunnel_config.mode == TunnelMode::Out =>
+            {
+                return Ok(TunnelType::DynamicUpstreamTcpOut(
+                    conn,
+                    tunnel_config.compression,
+                ));
+            }
+            UpstreamType::Socks5 | UpstreamType::HttpConnect => {
+                let upstream_type = &tunnel_config.upstream.upstream_type;
+                TunnelMessage::send_failure(
+                    quic_send,
+                    format!("{upstream_type} is only supported for outbound tunnels"),
+                )
+                .await?;
+                log_and_bail!("{upstream_type}_IN login rejected");
+            }
         };
         let tunnel_type = match tunnel_config.mode {
             TunnelMode::Out => match tunnel_config.upstream.upstream_type {
                 UpstreamType::Tcp => TunnelType::TcpOut(TcpTunnelOutInfo {
                     conn,
                     upstream_addr,
+                    one_way: tunnel_config.one_way,
+                    compression: tunnel_config.compression,
                 }),
 
                 UpstreamType::Udp => TunnelType::UdpOut(UdpTunnelOutInfo {
                     conn,
                     upstream_addr,
+                    datagrams: tunnel_config.udp_datagrams,
                 }),
+
+                UpstreamType::Socks5 | UpstreamType::HttpConnect => {
+                    unreachable!("proxy tunnels have no fixed upstream")
+                }
             },
 
             TunnelMode::In => match tunnel_config.upstream.upstream_type {
@@ -328,8 +581,15 @@ impl Server {
                         }
                     };
 
-                    TunnelMessage::send(quic_send, &TunnelMessage::RespSuccess).await?;
-                    TunnelType::TcpIn(TcpTunnelInInfo { conn, tcp_server })
+                    TunnelMessage::send_with_ext(quic_send, &TunnelMessage::RespSuccess, resp_ext)
+                        .await?;
+                    TunnelType::TcpIn(TcpTunnelInInfo {
+                        conn,
+                        tcp_server,
+                        one_way: tunnel_config.one_way,
+                        proxy_protocol: tunnel_config.proxy_protocol,
+                        compression: tunnel_config.compression,
+                    })
                 }
 
                 UpstreamType::Udp => {
@@ -345,8 +605,17 @@ impl Server {
                         }
                     };
 
-                    TunnelMessage::send(quic_send, &TunnelMessage::RespSuccess).await?;
-                    TunnelType::UdpIn(UdpTunnelInInfo { conn, udp_server })
+                    TunnelMessage::send_with_ext(quic_send, &TunnelMessage::RespSuccess, resp_ext)
+                        .await?;
+                    TunnelType::UdpIn(UdpTunnelInInfo {
+                        conn,
+                        udp_server,
+                        datagrams: tunnel_config.udp_datagrams,
+                    })
+                }
+
+                UpstreamType::Socks5 | UpstreamType::HttpConnect => {
+                    unreachable!("proxy tunnels have no fixed upstream")
                 }
             },
         };
@@ -354,6 +623,25 @@ impl Server {
         Ok(tunnel_type)
     }
 
+    fn login_resp_ext(
+        config: &ServerConfig,
+        connection_label: Option<String>,
+        udp_datagrams: bool,
+        proxy_protocol: bool,
+        compression: Compression,
+        datagram_heartbeat: bool,
+    ) -> LoginRespExt {
+        LoginRespExt {
+            quic_timeout_ms: config.quic_timeout_ms,
+            connection_label,
+            udp_datagrams,
+            proxy_protocol,
+            compression,
+            datagram_heartbeat,
+            domain_destinations: true,
+        }
+    }
+
     fn obtain_upstream_addr(
         tunnel_config: &TunnelConfig,
         default_upstream: &Option<SocketAddr>,
@@ -391,6 +679,9 @@ impl Server {
 
     fn clear_expired_sessions(state: Arc<Mutex<State>>) {
         let mut state = state.lock().unwrap();
+        state
+            .control_sessions
+            .retain(|sess| sess.conn.close_reason().is_none());
         state.udp_sessions.retain(|sess| {
             if sess.conn.close_reason().is_some() {
                 let sess = sess.clone();
@@ -453,4 +744,33 @@ impl Server {
         }
         Ok(())
     }
+
+    /// sends a random nonce and checks the HMAC of it the client answers with, so the password
+    /// never goes over the wire
+    async fn check_password_challenge(
+        config: &ServerConfig,
+        quic_send: &mut SendStream,
+        quic_recv: &mut RecvStream,
+    ) -> Result<()> {
+        let mut nonce = vec![0; 32];
+        SystemRandom::new()
+            .fill(&mut nonce)
+            .map_err(|_| anyhow::anyhow!("failed to generate the challenge nonce"))?;
+        TunnelMessage::send(
+            quic_send,
+            &TunnelMessage::Challenge {
+                nonce: nonce.clone(),
+            },
+        )
+        .await?;
+        match TunnelMessage::recv(quic_recv).await? {
+            TunnelMessage::ChallengeResponse { mac } => {
+                if !verify_password_hmac(config.password.as_str(), &nonce, &mac) {
+                    log_and_bail!("password challenge failed!");
+                }
+                Ok(())
+            }
+            msg => log_and_bail!("unexpected message in reply to the challenge: {msg}"),
+        }
+    }
 }
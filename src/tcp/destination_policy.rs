@@ -0,0 +1,266 @@
+//! The destinations the local clients of a SOCKS5 or HTTP CONNECT tunnel may ask to connect to,
+//! checked before the stream is tunneled.
+
+use super::Destination;
+use anyhow::{Context, Result};
+use log::info;
+use rs_utilities::log_and_bail;
+use std::net::IpAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+
+/// `example.com` matches the host alone, `*.example.com` its subdomains, and `10.0.0.0/8` or
+/// `::1` the addresses the destination resolves to
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum DestinationPattern {
+    Host(String),
+    Subdomains(String),
+    Cidr(IpAddr, u8),
+}
+
+impl std::str::FromStr for DestinationPattern {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if let Some((ip, prefix_len)) = s.split_once('/') {
+            let ip: IpAddr = ip
+                .parse()
+                .with_context(|| format!("invalid CIDR '{s}', expected IP/PREFIX_LEN"))?;
+            let max_prefix_len = if ip.is_ipv4() { 32 } else { 128 };
+            match prefix_len.parse::<u8>() {
+                Ok(prefix_len) if prefix_len <= max_prefix_len => {
+                    return Ok(Self::Cidr(ip, prefix_len))
+                }
+                _ => log_and_bail!("invalid CIDR '{s}', prefix length exceeds {max_prefix_len}"),
+            }
+        }
+        if let Ok(ip) = s.parse::<IpAddr>() {
+            return Ok(Self::Cidr(ip, if ip.is_ipv4() { 32 } else { 128 }));
+        }
+
+        let (subdomains, host) = match s.strip_prefix("*.") {
+            Some(host) => (true, host),
+            None => (false, s),
+        };
+        if host.is_empty() || host.contains(['*', ':', '/']) {
+            log_and_bail!("invalid destination pattern '{s}', expected HOST, *.DOMAIN or CIDR");
+        }
+        let host = host.trim_end_matches('.').to_lowercase();
+        Ok(if subdomains {
+            Self::Subdomains(host)
+        } else {
+            Self::Host(host)
+        })
+    }
+}
+
+impl DestinationPattern {
+    /// `ip` is None for a domain name left for the server to resolve, which no CIDR matches
+    fn matches(&self, host: Option<&str>, ip: Option<IpAddr>) -> bool {
+        match self {
+            Self::Host(pattern) => host.is_some_and(|host| host == pattern),
+            Self::Subdomains(pattern) => host.is_some_and(|host| {
+                host.strip_suffix(pattern.as_str())
+                    .is_some_and(|sub| sub.len() > 1 && sub.ends_with('.'))
+            }),
+            Self::Cidr(network, prefix_len) => match (network, ip) {
+                (IpAddr::V4(network), Some(IpAddr::V4(ip))) => {
+                    let mask = u32::MAX.checked_shl(32 - *prefix_len as u32).unwrap_or(0);
+                    u32::from(*network) & mask == u32::from(ip) & mask
+                }
+                (IpAddr::V6(network), Some(IpAddr::V6(ip))) => {
+                    let mask = u128::MAX.checked_shl(128 - *prefix_len as u32).unwrap_or(0);
+                    u128::from(*network) & mask == u128::from(ip) & mask
+                }
+                _ => false,
+            },
+        }
+    }
+}
+
+/// what `DestinationPolicy::check` made of a destination
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) enum Checked {
+    /// to be tunneled, resolved to an address if the domain name had to be resolved locally
+    Permitted(Destination),
+    Denied,
+    Unresolved,
+}
+
+/// The deny patterns take precedence, and if there are allow patterns, the destination must
+/// match one of them
+#[derive(Debug)]
+pub(crate) struct DestinationPolicy {
+    allow: Vec<DestinationPattern>,
+    deny: Vec<DestinationPattern>,
+    /// shared by the tunnels of the client
+    denied: Arc<AtomicU64>,
+}
+
+impl DestinationPolicy {
+    pub(crate) fn new(allow: &[String], deny: &[String], denied: Arc<AtomicU64>) -> Result<Self> {
+        Ok(Self {
+            allow: parse_patterns(allow)?,
+            deny: parse_patterns(deny)?,
+            denied,
+        })
+    }
+
+    /// A domain name is resolved here only if `resolve_domains`, for servers that can't resolve
+    /// it, or if CIDR patterns need its address, in which case the address checked is the one
+    /// tunneled. The denied destinations are logged and counted
+    pub(crate) async fn check(&self, destination: Destination, resolve_domains: bool) -> Checked {
+        let (host, destination) = match destination {
+            Destination::Domain(domain, port) if resolve_domains || self.matches_addresses() => {
+                match tokio::net::lookup_host((domain.as_str(), port))
+                    .await
+                    .ok()
+                    .and_then(|mut addrs| addrs.next())
+                {
+                    Some(addr) => (Some(domain), Destination::Addr(addr)),
+                    None => return Checked::Unresolved,
+                }
+            }
+            Destination::Domain(domain, port) => {
+                (Some(domain.clone()), Destination::Domain(domain, port))
+            }
+            destination => (None, destination),
+        };
+
+        if self.permits(host.as_deref(), &destination) {
+            return Checked::Permitted(destination);
+        }
+        self.denied.fetch_add(1, Ordering::Relaxed);
+        match (host, &destination) {
+            (Some(host), Destination::Addr(addr)) => info!("denied destination {host} ({addr})"),
+            _ => info!("denied destination {destination}"),
+        }
+        Checked::Denied
+    }
+
+    fn permits(&self, host: Option<&str>, destination: &Destination) -> bool {
+        let host = host.map(|host| host.trim_end_matches('.').to_lowercase());
+        let ip = match destination {
+            Destination::Addr(addr) => Some(addr.ip()),
+            Destination::Domain(..) => None,
+        };
+        let matches = |pattern: &DestinationPattern| pattern.matches(host.as_deref(), ip);
+        !self.deny.iter().any(matches) && (self.allow.is_empty() || self.allow.iter().any(matches))
+    }
+
+    fn matches_addresses(&self) -> bool {
+        self.allow
+            .iter()
+            .chain(&self.deny)
+            .any(|pattern| matches!(pattern, DestinationPattern::Cidr(..)))
+    }
+}
+
+/// validates the patterns of a mapping up front, so a typo fails the config instead of the tunnel
+pub(crate) fn parse_patterns(patterns: &[String]) -> Result<Vec<DestinationPattern>> {
+    patterns.iter().map(|pattern| pattern.parse()).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pattern(s: &str) -> DestinationPattern {
+        s.parse().unwrap()
+    }
+
+    fn ip(s: &str) -> Option<IpAddr> {
+        Some(s.parse().unwrap())
+    }
+
+    #[test]
+    fn prefix_lengths_cover_the_whole_family_down_to_a_single_address() {
+        let any_v4 = pattern("0.0.0.0/0");
+        assert!(any_v4.matches(None, ip("1.2.3.4")));
+        assert!(any_v4.matches(None, ip("255.255.255.255")));
+        let any_v6 = pattern("::/0");
+        assert!(any_v6.matches(None, ip("2001:db8::1")));
+        assert!(any_v6.matches(None, ip("::")));
+
+        assert_eq!(pattern("10.0.0.1"), pattern("10.0.0.1/32"));
+        assert!(pattern("10.0.0.1/32").matches(None, ip("10.0.0.1")));
+        assert!(!pattern("10.0.0.1/32").matches(None, ip("10.0.0.2")));
+
+        assert_eq!(pattern("::1"), pattern("::1/128"));
+        assert!(pattern("::1/128").matches(None, ip("::1")));
+        assert!(!pattern("::1/128").matches(None, ip("::2")));
+
+        assert!(pattern("10.0.0.0/8").matches(None, ip("10.255.0.1")));
+        assert!(!pattern("10.0.0.0/8").matches(None, ip("11.0.0.1")));
+        assert!(pattern("2001:db8::/32").matches(None, ip("2001:db8:ffff::1")));
+        assert!(!pattern("2001:db8::/32").matches(None, ip("2001:db9::1")));
+    }
+
+    #[test]
+    fn cidrs_only_match_their_own_family() {
+        assert!(!pattern("0.0.0.0/0").matches(None, ip("::1")));
+        assert!(!pattern("::/0").matches(None, ip("127.0.0.1")));
+        assert!(!pattern("10.0.0.0/8").matches(None, ip("::ffff:10.0.0.1")));
+        // nor domain names left for the server to resolve
+        assert!(!pattern("0.0.0.0/0").matches(Some("example.com"), None));
+    }
+
+    #[test]
+    fn subdomain_patterns_leave_out_the_domain_itself() {
+        let subdomains = pattern("*.example.com");
+        assert!(subdomains.matches(Some("www.example.com"), None));
+        assert!(subdomains.matches(Some("a.b.example.com"), None));
+        assert!(!subdomains.matches(Some("example.com"), None));
+        assert!(!subdomains.matches(Some("badexample.com"), None));
+        assert!(!subdomains.matches(Some(".example.com"), None));
+        assert!(!subdomains.matches(None, ip("93.184.216.34")));
+
+        let host = pattern("example.com");
+        assert!(host.matches(Some("example.com"), None));
+        assert!(!host.matches(Some("www.example.com"), None));
+    }
+
+    #[test]
+    fn hosts_are_matched_regardless_of_case_and_trailing_dots() {
+        assert_eq!(
+            pattern("Example.COM."),
+            DestinationPattern::Host("example.com".to_string())
+        );
+        assert_eq!(
+            pattern("*.Example.com."),
+            DestinationPattern::Subdomains("example.com".to_string())
+        );
+
+        let policy = DestinationPolicy::new(
+            &[],
+            &["*.EXAMPLE.com".to_string()],
+            Arc::new(AtomicU64::new(0)),
+        )
+        .unwrap();
+        let destination = Destination::Domain("WWW.Example.com.".to_string(), 443);
+        assert!(!policy.permits(Some("WWW.Example.com."), &destination));
+        let destination = Destination::Domain("www.example.org".to_string(), 443);
+        assert!(policy.permits(Some("www.example.org"), &destination));
+    }
+
+    #[test]
+    fn invalid_patterns_are_refused() {
+        for s in [
+            "",
+            "*",
+            "*.",
+            "**.example.com",
+            "www.*.com",
+            "example.com:443",
+            "ex/ample",
+            "10.0.0.0/33",
+            "::/129",
+            "10.0.0.0/",
+            "10.0.0.0/-1",
+            "1.2.3/8",
+        ] {
+            assert!(s.parse::<DestinationPattern>().is_err(), "{s:?}");
+        }
+        assert!(parse_patterns(&["example.com".to_string(), "10.0.0.0/33".to_string()]).is_err());
+    }
+}
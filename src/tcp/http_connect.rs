@@ -0,0 +1,241 @@
+//! The proxy side of HTTP CONNECT (RFC 9110 §9.3.6) the local server of an HTTP tunnel runs with
+//! the local clients, which is all that's needed for HTTPS and other TLS traffic.
+
+use super::destination_policy::{Checked, DestinationPolicy};
+use super::Destination;
+use anyhow::{bail, Result};
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::net::TcpStream;
+
+/// the request line and headers of a CONNECT request are short, anything longer is refused
+const MAX_REQUEST_HEADER_SIZE: usize = 8192;
+/// the longest domain name the server can be sent
+const MAX_HOST_LEN: usize = 255;
+
+/// Reads the CONNECT request and returns the destination it asked for, a domain name is resolved
+/// by the server unless `resolve_domains`. The response is sent right away, as the server
+/// connects to the destination only once the stream is tunneled and doesn't report back whether
+/// it could. Destinations the policy doesn't permit are refused with 403. Any data the client
+/// sent after the header stays buffered in `stream` for the tunnel.
+pub(crate) async fn accept(
+    stream: &mut BufReader<TcpStream>,
+    policy: &DestinationPolicy,
+    resolve_domains: bool,
+) -> Result<Destination> {
+    let mut header = Vec::with_capacity(256);
+    while !header.ends_with(b"\r\n\r\n") {
+        let remaining = MAX_REQUEST_HEADER_SIZE - header.len();
+        if remaining == 0 {
+            respond(stream, "431 Request Header Fields Too Large").await?;
+            bail!("request header exceeds {MAX_REQUEST_HEADER_SIZE} bytes");
+        }
+        let len = (&mut *stream)
+            .take(remaining as u64)
+            .read_until(b'\n', &mut header)
+            .await?;
+        if len == 0 {
+            bail!("connection closed within the request header");
+        }
+    }
+
+    let request_line = header
+        .split(|b| *b == b'\n')
+        .next()
+        .and_then(|line| std::str::from_utf8(line).ok())
+        .unwrap_or_default()
+        .trim_end();
+    let mut parts = request_line.split(' ');
+    let (Some(method), Some(authority), Some(version), None) =
+        (parts.next(), parts.next(), parts.next(), parts.next())
+    else {
+        respond(stream, "400 Bad Request").await?;
+        bail!("malformed request line: {request_line:?}");
+    };
+    if !version.starts_with("HTTP/1.") {
+        respond(stream, "400 Bad Request").await?;
+        bail!("malformed request line: {request_line:?}");
+    }
+    if method != "CONNECT" {
+        respond(stream, "405 Method Not Allowed").await?;
+        bail!("unsupported method: {method}");
+    }
+    // host:port, or [IPv6]:port
+    let Some((host, port)) = authority
+        .rsplit_once(':')
+        .and_then(|(host, port)| Some((host, port.parse::<u16>().ok()?)))
+        .map(|(host, port)| (host.trim_start_matches('[').trim_end_matches(']'), port))
+        .filter(|(host, _)| !host.is_empty() && host.len() <= MAX_HOST_LEN)
+    else {
+        respond(stream, "400 Bad Request").await?;
+        bail!("malformed authority: {authority:?}");
+    };
+
+    let destination = Destination::from_host(host.to_string(), port);
+    match policy.check(destination, resolve_domains).await {
+        Checked::Permitted(destination) => {
+            respond(stream, "200 Connection Established").await?;
+            Ok(destination)
+        }
+        Checked::Denied => {
+            respond(stream, "403 Forbidden").await?;
+            bail!("destination not allowed: {authority}");
+        }
+        Checked::Unresolved => {
+            respond(stream, "502 Bad Gateway").await?;
+            bail!("failed to resolve {authority}");
+        }
+    }
+}
+
+async fn respond(stream: &mut BufReader<TcpStream>, status: &str) -> Result<()> {
+    stream
+        .write_all(format!("HTTP/1.1 {status}\r\n\r\n").as_bytes())
+        .await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::AtomicU64;
+    use std::sync::Arc;
+    use tokio::net::TcpListener;
+
+    /// runs the handshake of a local client sending `request`, returns its result, the data
+    /// left buffered after the header and the response
+    async fn handshake(
+        request: &[u8],
+        deny: &[&str],
+        resolve_domains: bool,
+    ) -> (Result<Destination>, Vec<u8>, String) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let mut client = TcpStream::connect(listener.local_addr().unwrap())
+            .await
+            .unwrap();
+        let (stream, _) = listener.accept().await.unwrap();
+
+        let deny: Vec<String> = deny.iter().map(|pattern| pattern.to_string()).collect();
+        let policy = DestinationPolicy::new(&[], &deny, Arc::new(AtomicU64::new(0))).unwrap();
+        let accepting = tokio::spawn(async move {
+            let mut stream = BufReader::new(stream);
+            let result = accept(&mut stream, &policy, resolve_domains).await;
+            let buffered = stream.buffer().to_vec();
+            // what's left unread of a refused request would reset the connection
+            tokio::io::copy(&mut stream, &mut tokio::io::sink())
+                .await
+                .ok();
+            (result, buffered)
+        });
+
+        client.write_all(request).await.unwrap();
+        client.shutdown().await.unwrap();
+        let (result, buffered) = accepting.await.unwrap();
+        let mut response = String::new();
+        client.read_to_string(&mut response).await.unwrap();
+        (result, buffered, response)
+    }
+
+    #[tokio::test]
+    async fn domain_names_are_left_for_the_server_to_resolve() {
+        let request = b"CONNECT example.com:443 HTTP/1.1\r\nHost: example.com:443\r\n\r\nearly";
+        let (result, buffered, response) = handshake(request, &[], false).await;
+        assert_eq!(
+            result.unwrap(),
+            Destination::Domain("example.com".to_string(), 443)
+        );
+        assert_eq!(buffered, b"early");
+        assert_eq!(response, "HTTP/1.1 200 Connection Established\r\n\r\n");
+    }
+
+    #[tokio::test]
+    async fn addresses_are_taken_as_such() {
+        let (result, _, _) = handshake(b"CONNECT [::1]:443 HTTP/1.1\r\n\r\n", &[], false).await;
+        assert_eq!(
+            result.unwrap(),
+            Destination::Addr("[::1]:443".parse().unwrap())
+        );
+
+        let (result, _, _) = handshake(b"CONNECT 10.0.0.1:80 HTTP/1.0\r\n\r\n", &[], true).await;
+        assert_eq!(
+            result.unwrap(),
+            Destination::Addr("10.0.0.1:80".parse().unwrap())
+        );
+    }
+
+    #[tokio::test]
+    async fn malformed_requests_are_bad() {
+        for request in [
+            "CONNECT example.com:443\r\n\r\n",
+            "CONNECT example.com:443 HTTP/2 extra\r\n\r\n",
+            "CONNECT example.com:443 SPDY/3\r\n\r\n",
+            "CONNECT example.com HTTP/1.1\r\n\r\n",
+            "CONNECT :443 HTTP/1.1\r\n\r\n",
+            "CONNECT example.com:65536 HTTP/1.1\r\n\r\n",
+        ] {
+            let (result, _, response) = handshake(request.as_bytes(), &[], false).await;
+            assert!(result.is_err(), "{request:?}");
+            assert_eq!(response, "HTTP/1.1 400 Bad Request\r\n\r\n", "{request:?}");
+        }
+
+        let request = format!(
+            "CONNECT {}.com:443 HTTP/1.1\r\n\r\n",
+            "a".repeat(MAX_HOST_LEN)
+        );
+        let (result, _, response) = handshake(request.as_bytes(), &[], false).await;
+        assert!(result.is_err());
+        assert_eq!(response, "HTTP/1.1 400 Bad Request\r\n\r\n");
+    }
+
+    #[tokio::test]
+    async fn other_methods_are_not_allowed() {
+        let request = b"GET http://example.com/ HTTP/1.1\r\nHost: example.com\r\n\r\n";
+        let (result, _, response) = handshake(request, &[], false).await;
+        assert!(result.is_err());
+        assert_eq!(response, "HTTP/1.1 405 Method Not Allowed\r\n\r\n");
+    }
+
+    #[tokio::test]
+    async fn oversized_headers_are_refused() {
+        let mut request = b"CONNECT example.com:443 HTTP/1.1\r\n".to_vec();
+        while request.len() <= MAX_REQUEST_HEADER_SIZE {
+            request.extend_from_slice(b"X-Padding: 0123456789abcdef\r\n");
+        }
+        request.extend_from_slice(b"\r\n");
+        let (result, _, response) = handshake(&request, &[], false).await;
+        assert!(result.is_err());
+        assert_eq!(
+            response,
+            "HTTP/1.1 431 Request Header Fields Too Large\r\n\r\n"
+        );
+
+        // a single line without an end is bounded too
+        let request = vec![b'a'; MAX_REQUEST_HEADER_SIZE * 2];
+        let (result, _, response) = handshake(&request, &[], false).await;
+        assert!(result.is_err());
+        assert_eq!(
+            response,
+            "HTTP/1.1 431 Request Header Fields Too Large\r\n\r\n"
+        );
+    }
+
+    #[tokio::test]
+    async fn denied_destinations_are_forbidden() {
+        let request = b"CONNECT www.example.com:443 HTTP/1.1\r\n\r\n";
+        let (result, _, response) = handshake(request, &["*.example.com"], false).await;
+        assert!(result.is_err());
+        assert_eq!(response, "HTTP/1.1 403 Forbidden\r\n\r\n");
+
+        let request = b"CONNECT 10.1.2.3:443 HTTP/1.1\r\n\r\n";
+        let (result, _, response) = handshake(request, &["10.0.0.0/8"], false).await;
+        assert!(result.is_err());
+        assert_eq!(response, "HTTP/1.1 403 Forbidden\r\n\r\n");
+    }
+
+    #[tokio::test]
+    async fn unresolved_domains_are_a_bad_gateway() {
+        let request = b"CONNECT rstun.invalid:443 HTTP/1.1\r\n\r\n";
+        let (result, _, response) = handshake(request, &[], true).await;
+        assert!(result.is_err());
+        assert_eq!(response, "HTTP/1.1 502 Bad Gateway\r\n\r\n");
+    }
+}
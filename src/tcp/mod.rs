@@ -1,24 +1,171 @@
-use std::net::SocketAddr;
-use tokio::io::{AsyncRead, AsyncWrite};
+use std::net::{IpAddr, SocketAddr};
+#[cfg(unix)]
+use std::path::PathBuf;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, Weak};
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncRead, AsyncWrite, BufReader, ReadBuf};
 use tokio::net::TcpStream;
 use tokio::sync::mpsc::{Receiver, Sender};
+use tokio::sync::{Notify, OwnedSemaphorePermit};
 
+pub(crate) mod destination_policy;
+pub(crate) mod http_connect;
+pub(crate) mod proxy_protocol;
+pub(crate) mod socks5;
 pub mod tcp_server;
 pub mod tcp_tunnel;
 
 pub trait AsyncStream: AsyncRead + AsyncWrite + Unpin + Send + 'static {
     fn peer_addr(&self) -> std::io::Result<SocketAddr>;
+
+    /// the address the stream was accepted at, sent along with `peer_addr` for the PROXY
+    /// protocol header, see `TunnelConfig::proxy_protocol`
+    fn local_addr(&self) -> std::io::Result<SocketAddr> {
+        Err(std::io::ErrorKind::Unsupported.into())
+    }
 }
 
 impl AsyncStream for TcpStream {
     fn peer_addr(&self) -> std::io::Result<SocketAddr> {
         TcpStream::peer_addr(self)
     }
+
+    fn local_addr(&self) -> std::io::Result<SocketAddr> {
+        TcpStream::local_addr(self)
+    }
+}
+
+/// the local clients of SOCKS5 and HTTP CONNECT tunnels, whose bytes buffered along with the
+/// handshake are read first once the stream is tunneled
+impl AsyncStream for BufReader<TcpStream> {
+    fn peer_addr(&self) -> std::io::Result<SocketAddr> {
+        self.get_ref().peer_addr()
+    }
+
+    fn local_addr(&self) -> std::io::Result<SocketAddr> {
+        self.get_ref().local_addr()
+    }
+}
+
+#[cfg(unix)]
+impl AsyncStream for tokio::net::UnixStream {
+    /// Unix sockets have no IP address, the unspecified one stands in for it in the logs
+    fn peer_addr(&self) -> std::io::Result<SocketAddr> {
+        Ok(crate::socket_addr_with_unspecified_ip_port(false))
+    }
+}
+
+/// where the streams accepted from the peer are dialed to
+#[derive(Debug, Clone)]
+pub(crate) enum DialTarget {
+    Addr(SocketAddr),
+    /// a domain name the peer sent, resolved when dialing
+    Domain(String, u16),
+    #[cfg(unix)]
+    UnixSocket(PathBuf),
+    /// the address the peer sends ahead of each stream
+    Requested,
+}
+
+impl std::fmt::Display for DialTarget {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Addr(addr) => write!(f, "{addr}"),
+            Self::Domain(domain, port) => write!(f, "{domain}:{port}"),
+            #[cfg(unix)]
+            Self::UnixSocket(path) => write!(f, "unix:{}", path.display()),
+            Self::Requested => f.write_str("ANY"),
+        }
+    }
+}
+
+/// A stream dialed for the peer, holding its share of the connection limit until both
+/// directions are done with it
+pub(crate) struct PermittedStream {
+    stream: Box<dyn AsyncStream>,
+    _permit: Option<OwnedSemaphorePermit>,
+}
+
+impl PermittedStream {
+    pub(crate) fn new(stream: Box<dyn AsyncStream>, permit: Option<OwnedSemaphorePermit>) -> Self {
+        Self {
+            stream,
+            _permit: permit,
+        }
+    }
+}
+
+impl AsyncRead for PermittedStream {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.stream).poll_read(cx, buf)
+    }
+}
+
+impl AsyncWrite for PermittedStream {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        Pin::new(&mut self.stream).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.stream).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.stream).poll_shutdown(cx)
+    }
+}
+
+impl AsyncStream for PermittedStream {
+    fn peer_addr(&self) -> std::io::Result<SocketAddr> {
+        self.stream.peer_addr()
+    }
+
+    fn local_addr(&self) -> std::io::Result<SocketAddr> {
+        self.stream.local_addr()
+    }
+}
+
+/// the destination a local client of a SOCKS5 or HTTP CONNECT tunnel asks for, a domain name is
+/// resolved by the server
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Destination {
+    Addr(SocketAddr),
+    Domain(String, u16),
+}
+
+impl Destination {
+    /// an IP address given as the host is taken as such
+    pub(crate) fn from_host(host: String, port: u16) -> Self {
+        match host.parse::<IpAddr>() {
+            Ok(ip) => Self::Addr(SocketAddr::new(ip, port)),
+            Err(_) => Self::Domain(host, port),
+        }
+    }
+}
+
+impl std::fmt::Display for Destination {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Addr(addr) => write!(f, "{addr}"),
+            Self::Domain(domain, port) => write!(f, "{domain}:{port}"),
+        }
+    }
 }
 
 pub struct StreamRequest<S: AsyncStream> {
     pub stream: S,
-    pub dst_addr: Option<SocketAddr>,
+    pub destination: Option<Destination>,
 }
 
 pub enum StreamMessage<S: AsyncStream> {
@@ -28,3 +175,133 @@ pub enum StreamMessage<S: AsyncStream> {
 
 pub type StreamSender<S> = Sender<StreamMessage<S>>;
 pub type StreamReceiver<S> = Receiver<StreamMessage<S>>;
+
+/// Streams accepted from the peer that wait for the upstream to be dialed, a growing depth
+/// means the upstream can't keep up with what the tunnel delivers
+#[derive(Debug, Default)]
+pub(crate) struct DialBacklog {
+    depth: AtomicUsize,
+    peak: AtomicUsize,
+}
+
+impl DialBacklog {
+    /// counts the stream until the guard is dropped
+    pub(crate) fn enter(self: &Arc<Self>) -> DialBacklogGuard {
+        let depth = self.depth.fetch_add(1, Ordering::Relaxed) + 1;
+        self.peak.fetch_max(depth, Ordering::Relaxed);
+        DialBacklogGuard(self.clone())
+    }
+
+    pub(crate) fn depth(&self) -> usize {
+        self.depth.load(Ordering::Relaxed)
+    }
+
+    pub(crate) fn peak(&self) -> usize {
+        self.peak.load(Ordering::Relaxed)
+    }
+}
+
+pub(crate) struct DialBacklogGuard(Arc<DialBacklog>);
+
+impl Drop for DialBacklogGuard {
+    fn drop(&mut self) {
+        self.0.depth.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// Streams swept periodically to close the ones without activity in either direction for
+/// `idle_timeout`, which frees their share of the stream credit while the connection stays up.
+/// A zero `idle_timeout` only tracks them, to count those in flight
+#[derive(Debug)]
+pub(crate) struct IdleStreams {
+    idle_timeout: Duration,
+    streams: Mutex<Vec<Weak<StreamActivity>>>,
+    reaped: AtomicU64,
+}
+
+impl IdleStreams {
+    pub(crate) fn new(idle_timeout: Duration) -> Self {
+        Self {
+            idle_timeout,
+            streams: Mutex::new(Vec::new()),
+            reaped: AtomicU64::new(0),
+        }
+    }
+
+    /// the activity of a new stream, which is tracked until dropped
+    pub(crate) fn track(&self) -> Arc<StreamActivity> {
+        let activity = Arc::new(StreamActivity {
+            started_at: Instant::now(),
+            last_active_ms: AtomicU64::new(0),
+            reaped: AtomicBool::new(false),
+            reap: Notify::new(),
+        });
+        self.streams.lock().unwrap().push(Arc::downgrade(&activity));
+        activity
+    }
+
+    /// signals the streams idle for too long to close, and forgets the closed ones
+    pub(crate) fn sweep(&self) {
+        self.streams.lock().unwrap().retain(|stream| {
+            let Some(activity) = stream.upgrade() else {
+                return false;
+            };
+            if !self.idle_timeout.is_zero() && activity.idle_for() >= self.idle_timeout {
+                if !activity.reaped.swap(true, Ordering::Relaxed) {
+                    self.reaped.fetch_add(1, Ordering::Relaxed);
+                }
+                // on every sweep, in case a direction wasn't waiting for it the last time
+                activity.reap.notify_waiters();
+            }
+            true
+        });
+    }
+
+    pub(crate) fn sweep_interval(&self) -> Duration {
+        if self.idle_timeout.is_zero() {
+            // only forgetting the closed streams
+            return Duration::from_secs(10);
+        }
+        (self.idle_timeout / 2).max(Duration::from_millis(1))
+    }
+
+    /// number of the tracked streams not closed yet
+    pub(crate) fn in_flight(&self) -> usize {
+        let mut streams = self.streams.lock().unwrap();
+        streams.retain(|stream| stream.strong_count() > 0);
+        streams.len()
+    }
+
+    /// number of streams closed for being idle so far
+    pub(crate) fn reaped(&self) -> u64 {
+        self.reaped.load(Ordering::Relaxed)
+    }
+}
+
+#[derive(Debug)]
+pub(crate) struct StreamActivity {
+    started_at: Instant,
+    /// since started_at
+    last_active_ms: AtomicU64,
+    reaped: AtomicBool,
+    reap: Notify,
+}
+
+impl StreamActivity {
+    pub(crate) fn touch(&self) {
+        let elapsed_ms = self.started_at.elapsed().as_millis() as u64;
+        self.last_active_ms.store(elapsed_ms, Ordering::Relaxed);
+    }
+
+    /// resolves once the sweep finds the stream idle
+    pub(crate) async fn reaped(&self) {
+        if !self.reaped.load(Ordering::Relaxed) {
+            self.reap.notified().await;
+        }
+    }
+
+    fn idle_for(&self) -> Duration {
+        let last_active = Duration::from_millis(self.last_active_ms.load(Ordering::Relaxed));
+        self.started_at.elapsed().saturating_sub(last_active)
+    }
+}
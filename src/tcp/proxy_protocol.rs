@@ -0,0 +1,118 @@
+//! The PROXY protocol v2 header (https://www.haproxy.org/download/2.9/doc/proxy-protocol.txt)
+//! sent to the upstream of an inbound TCP tunnel ahead of the tunneled bytes, so the upstream
+//! sees the address of the client the server accepted rather than that of the rstun client.
+
+use std::net::{IpAddr, SocketAddr};
+
+const SIGNATURE: [u8; 12] = *b"\r\n\r\n\0\r\nQUIT\n";
+const VERSION_2_LOCAL: u8 = 0x20;
+const VERSION_2_PROXY: u8 = 0x21;
+const FAMILY_UNSPEC: u8 = 0x00;
+const FAMILY_TCP4: u8 = 0x11;
+const FAMILY_TCP6: u8 = 0x21;
+
+/// the header sent to the upstream of each stream, see `TunnelConfig::proxy_protocol`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum ProxyHeader {
+    None,
+    /// the LOCAL command, for servers that don't send the addresses of their clients, the
+    /// upstream then takes the addresses of the connection itself
+    Local,
+    /// the PROXY command with the addresses the server sends ahead of each stream
+    Proxied,
+}
+
+/// the LOCAL header if `addrs` is None, or the PROXY header with the source and destination
+/// addresses, IPv4 ones are mapped to IPv6 if the other is IPv6
+pub(crate) fn encode_v2(addrs: Option<(SocketAddr, SocketAddr)>) -> Vec<u8> {
+    let mut header = Vec::with_capacity(SIGNATURE.len() + 4 + 36);
+    header.extend_from_slice(&SIGNATURE);
+
+    let Some((src, dst)) = addrs else {
+        header.extend_from_slice(&[VERSION_2_LOCAL, FAMILY_UNSPEC, 0, 0]);
+        return header;
+    };
+
+    header.push(VERSION_2_PROXY);
+    match (src.ip(), dst.ip()) {
+        (IpAddr::V4(src_ip), IpAddr::V4(dst_ip)) => {
+            header.push(FAMILY_TCP4);
+            header.extend_from_slice(&12u16.to_be_bytes());
+            header.extend_from_slice(&src_ip.octets());
+            header.extend_from_slice(&dst_ip.octets());
+        }
+        (src_ip, dst_ip) => {
+            let to_v6 = |ip: IpAddr| match ip {
+                IpAddr::V4(ip) => ip.to_ipv6_mapped(),
+                IpAddr::V6(ip) => ip,
+            };
+            header.push(FAMILY_TCP6);
+            header.extend_from_slice(&36u16.to_be_bytes());
+            header.extend_from_slice(&to_v6(src_ip).octets());
+            header.extend_from_slice(&to_v6(dst_ip).octets());
+        }
+    }
+    header.extend_from_slice(&src.port().to_be_bytes());
+    header.extend_from_slice(&dst.port().to_be_bytes());
+    header
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// the signature spelled out as in section 2.2 of the spec
+    const SIG: [u8; 12] = [
+        0x0d, 0x0a, 0x0d, 0x0a, 0x00, 0x0d, 0x0a, 0x51, 0x55, 0x49, 0x54, 0x0a,
+    ];
+
+    fn header(rest: &[u8]) -> Vec<u8> {
+        [&SIG[..], rest].concat()
+    }
+
+    #[test]
+    fn local_header_carries_no_addresses() {
+        assert_eq!(encode_v2(None), header(&[0x20, 0x00, 0x00, 0x00]));
+    }
+
+    #[test]
+    fn ipv4_addresses_are_sent_as_tcp4() {
+        let src = "192.0.2.1:56324".parse().unwrap();
+        let dst = "198.51.100.7:443".parse().unwrap();
+        #[rustfmt::skip]
+        let expected = header(&[
+            0x21, 0x11, 0x00, 0x0c,
+            192, 0, 2, 1,
+            198, 51, 100, 7,
+            0xdc, 0x04,
+            0x01, 0xbb,
+        ]);
+        assert_eq!(encode_v2(Some((src, dst))), expected);
+    }
+
+    #[test]
+    fn mixed_families_are_sent_as_tcp6_with_the_ipv4_address_mapped() {
+        let src = "192.0.2.1:56324".parse().unwrap();
+        let dst = "[2001:db8::7]:443".parse().unwrap();
+        #[rustfmt::skip]
+        let expected = header(&[
+            0x21, 0x21, 0x00, 0x24,
+            0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0xff, 0xff, 192, 0, 2, 1,
+            0x20, 0x01, 0x0d, 0xb8, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0x07,
+            0xdc, 0x04,
+            0x01, 0xbb,
+        ]);
+        assert_eq!(encode_v2(Some((src, dst))), expected);
+
+        // either way round
+        #[rustfmt::skip]
+        let expected = header(&[
+            0x21, 0x21, 0x00, 0x24,
+            0x20, 0x01, 0x0d, 0xb8, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0x07,
+            0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0xff, 0xff, 192, 0, 2, 1,
+            0x01, 0xbb,
+            0xdc, 0x04,
+        ]);
+        assert_eq!(encode_v2(Some((dst, src))), expected);
+    }
+}
@@ -0,0 +1,262 @@
+//! The server side of the SOCKS5 (RFC 1928) handshake the local server of a SOCKS5 tunnel runs
+//! with the local clients, only CONNECT without authentication is supported.
+
+use super::destination_policy::{Checked, DestinationPolicy};
+use super::Destination;
+use anyhow::{bail, Context, Result};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use tokio::io::{AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::net::TcpStream;
+
+const VERSION: u8 = 5;
+const METHOD_NO_AUTH: u8 = 0x00;
+const METHOD_NOT_ACCEPTABLE: u8 = 0xff;
+const CMD_CONNECT: u8 = 0x01;
+const ATYP_IPV4: u8 = 0x01;
+const ATYP_DOMAIN: u8 = 0x03;
+const ATYP_IPV6: u8 = 0x04;
+const REP_SUCCEEDED: u8 = 0x00;
+const REP_NOT_ALLOWED: u8 = 0x02;
+const REP_HOST_UNREACHABLE: u8 = 0x04;
+const REP_COMMAND_NOT_SUPPORTED: u8 = 0x07;
+const REP_ADDRESS_TYPE_NOT_SUPPORTED: u8 = 0x08;
+
+/// Negotiates with the local client and returns the destination it asked to connect to, a
+/// domain name is resolved by the server unless `resolve_domains`. Success is replied right away,
+/// as the server connects to the destination only once the stream is tunneled and doesn't report
+/// back whether it could. Destinations the policy doesn't permit are refused as not allowed by
+/// the ruleset.
+pub(crate) async fn accept(
+    stream: &mut BufReader<TcpStream>,
+    policy: &DestinationPolicy,
+    resolve_domains: bool,
+) -> Result<Destination> {
+    let mut header = [0u8; 2];
+    stream.read_exact(&mut header).await?;
+    if header[0] != VERSION {
+        bail!("unsupported SOCKS version: {}", header[0]);
+    }
+    let mut methods = vec![0u8; header[1] as usize];
+    stream.read_exact(&mut methods).await?;
+    if !methods.contains(&METHOD_NO_AUTH) {
+        stream.write_all(&[VERSION, METHOD_NOT_ACCEPTABLE]).await?;
+        bail!("no supported authentication method in {methods:?}");
+    }
+    stream.write_all(&[VERSION, METHOD_NO_AUTH]).await?;
+
+    let mut request = [0u8; 4];
+    stream.read_exact(&mut request).await?;
+    if request[1] != CMD_CONNECT {
+        reply(stream, REP_COMMAND_NOT_SUPPORTED).await?;
+        bail!("unsupported SOCKS command: {}", request[1]);
+    }
+
+    let destination = match request[3] {
+        ATYP_IPV4 => {
+            let mut ip = [0u8; 4];
+            stream.read_exact(&mut ip).await?;
+            let port = stream.read_u16().await?;
+            Destination::Addr(SocketAddr::new(IpAddr::V4(Ipv4Addr::from(ip)), port))
+        }
+        ATYP_IPV6 => {
+            let mut ip = [0u8; 16];
+            stream.read_exact(&mut ip).await?;
+            let port = stream.read_u16().await?;
+            Destination::Addr(SocketAddr::new(IpAddr::V6(Ipv6Addr::from(ip)), port))
+        }
+        ATYP_DOMAIN => {
+            let mut domain = vec![0u8; stream.read_u8().await? as usize];
+            stream.read_exact(&mut domain).await?;
+            let domain = String::from_utf8(domain).context("invalid domain name")?;
+            Destination::from_host(domain, stream.read_u16().await?)
+        }
+        atyp => {
+            reply(stream, REP_ADDRESS_TYPE_NOT_SUPPORTED).await?;
+            bail!("unsupported SOCKS address type: {atyp}");
+        }
+    };
+
+    match policy.check(destination.clone(), resolve_domains).await {
+        Checked::Permitted(destination) => {
+            reply(stream, REP_SUCCEEDED).await?;
+            Ok(destination)
+        }
+        Checked::Denied => {
+            reply(stream, REP_NOT_ALLOWED).await?;
+            bail!("destination not allowed: {destination}");
+        }
+        Checked::Unresolved => {
+            reply(stream, REP_HOST_UNREACHABLE).await?;
+            bail!("failed to resolve {destination}");
+        }
+    }
+}
+
+/// the bound address is left unspecified, it's the server that connects to the destination
+async fn reply(stream: &mut BufReader<TcpStream>, rep: u8) -> Result<()> {
+    stream
+        .write_all(&[VERSION, rep, 0, ATYP_IPV4, 0, 0, 0, 0, 0, 0])
+        .await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicU64, Ordering};
+    use std::sync::Arc;
+    use tokio::net::TcpListener;
+
+    const GREETING: [u8; 3] = [VERSION, 1, METHOD_NO_AUTH];
+    const NEGOTIATED: [u8; 2] = [VERSION, METHOD_NO_AUTH];
+
+    /// runs the handshake of a local client sending `request`, returns its result, the bytes
+    /// replied and the number of destinations denied
+    async fn handshake(
+        request: &[u8],
+        deny: &[&str],
+        resolve_domains: bool,
+    ) -> (Result<Destination>, Vec<u8>, u64) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let mut client = TcpStream::connect(listener.local_addr().unwrap())
+            .await
+            .unwrap();
+        let (stream, _) = listener.accept().await.unwrap();
+
+        let denied = Arc::new(AtomicU64::new(0));
+        let deny: Vec<String> = deny.iter().map(|pattern| pattern.to_string()).collect();
+        let policy = DestinationPolicy::new(&[], &deny, denied.clone()).unwrap();
+        let accepting = tokio::spawn(async move {
+            let mut stream = BufReader::new(stream);
+            accept(&mut stream, &policy, resolve_domains).await
+        });
+
+        client.write_all(request).await.unwrap();
+        let result = accepting.await.unwrap();
+        let mut replies = Vec::new();
+        client.read_to_end(&mut replies).await.unwrap();
+        (result, replies, denied.load(Ordering::Relaxed))
+    }
+
+    fn connect_request(atyp: u8, addr: &[u8], port: u16) -> Vec<u8> {
+        let mut request = GREETING.to_vec();
+        request.extend_from_slice(&[VERSION, CMD_CONNECT, 0, atyp]);
+        request.extend_from_slice(addr);
+        request.extend_from_slice(&port.to_be_bytes());
+        request
+    }
+
+    fn domain_request(domain: &str, port: u16) -> Vec<u8> {
+        let mut addr = vec![domain.len() as u8];
+        addr.extend_from_slice(domain.as_bytes());
+        connect_request(ATYP_DOMAIN, &addr, port)
+    }
+
+    fn replied(rep: u8) -> Vec<u8> {
+        let mut replies = NEGOTIATED.to_vec();
+        replies.extend_from_slice(&[VERSION, rep, 0, ATYP_IPV4, 0, 0, 0, 0, 0, 0]);
+        replies
+    }
+
+    #[tokio::test]
+    async fn clients_without_the_no_auth_method_are_refused() {
+        let (result, replies, _) = handshake(&[VERSION, 2, 0x01, 0x02], &[], false).await;
+        assert!(result.is_err());
+        assert_eq!(replies, [VERSION, METHOD_NOT_ACCEPTABLE]);
+
+        let (result, replies, _) = handshake(&[4, 1, METHOD_NO_AUTH], &[], false).await;
+        assert!(result.is_err());
+        assert!(replies.is_empty());
+    }
+
+    #[tokio::test]
+    async fn connects_to_an_ipv4_address() {
+        let request = connect_request(ATYP_IPV4, &[10, 0, 0, 1], 8080);
+        let (result, replies, _) = handshake(&request, &[], false).await;
+        assert_eq!(
+            result.unwrap(),
+            Destination::Addr("10.0.0.1:8080".parse().unwrap())
+        );
+        assert_eq!(replies, replied(REP_SUCCEEDED));
+    }
+
+    #[tokio::test]
+    async fn connects_to_an_ipv6_address() {
+        let request = connect_request(ATYP_IPV6, &Ipv6Addr::LOCALHOST.octets(), 443);
+        let (result, replies, _) = handshake(&request, &[], false).await;
+        assert_eq!(
+            result.unwrap(),
+            Destination::Addr("[::1]:443".parse().unwrap())
+        );
+        assert_eq!(replies, replied(REP_SUCCEEDED));
+    }
+
+    #[tokio::test]
+    async fn domain_names_are_left_for_the_server_to_resolve() {
+        let (result, replies, _) = handshake(&domain_request("example.com", 443), &[], false).await;
+        assert_eq!(
+            result.unwrap(),
+            Destination::Domain("example.com".to_string(), 443)
+        );
+        assert_eq!(replies, replied(REP_SUCCEEDED));
+
+        // an address sent as a domain name is taken as such
+        let (result, _, _) = handshake(&domain_request("10.0.0.1", 80), &[], false).await;
+        assert_eq!(
+            result.unwrap(),
+            Destination::Addr("10.0.0.1:80".parse().unwrap())
+        );
+    }
+
+    #[tokio::test]
+    async fn domain_names_are_resolved_for_servers_that_cant() {
+        let (result, replies, _) = handshake(&domain_request("localhost", 80), &[], true).await;
+        match result.unwrap() {
+            Destination::Addr(addr) => {
+                assert!(addr.ip().is_loopback());
+                assert_eq!(addr.port(), 80);
+            }
+            destination => panic!("unresolved destination: {destination}"),
+        }
+        assert_eq!(replies, replied(REP_SUCCEEDED));
+
+        let (result, replies, _) = handshake(&domain_request("rstun.invalid", 80), &[], true).await;
+        assert!(result.is_err());
+        assert_eq!(replies, replied(REP_HOST_UNREACHABLE));
+    }
+
+    #[tokio::test]
+    async fn unsupported_commands_and_address_types_are_replied() {
+        let mut request = GREETING.to_vec();
+        request.extend_from_slice(&[VERSION, 0x02, 0, ATYP_IPV4, 10, 0, 0, 1, 0, 80]);
+        let (result, replies, _) = handshake(&request, &[], false).await;
+        assert!(result.is_err());
+        assert_eq!(replies, replied(REP_COMMAND_NOT_SUPPORTED));
+
+        let request = connect_request(0x05, &[10, 0, 0, 1], 80);
+        let (result, replies, _) = handshake(&request, &[], false).await;
+        assert!(result.is_err());
+        assert_eq!(replies, replied(REP_ADDRESS_TYPE_NOT_SUPPORTED));
+    }
+
+    #[tokio::test]
+    async fn denied_destinations_are_not_allowed() {
+        let request = connect_request(ATYP_IPV4, &[10, 1, 2, 3], 80);
+        let (result, replies, denied) = handshake(&request, &["10.0.0.0/8"], false).await;
+        assert!(result.is_err());
+        assert_eq!(replies, replied(REP_NOT_ALLOWED));
+        assert_eq!(denied, 1);
+
+        let request = domain_request("www.example.com", 443);
+        let (result, replies, denied) = handshake(&request, &["*.example.com"], false).await;
+        assert!(result.is_err());
+        assert_eq!(replies, replied(REP_NOT_ALLOWED));
+        assert_eq!(denied, 1);
+
+        // resolved on the client side for the CIDR to be checked
+        let request = domain_request("localhost", 80);
+        let (result, replies, _) = handshake(&request, &["127.0.0.0/8", "::1/128"], false).await;
+        assert!(result.is_err());
+        assert_eq!(replies, replied(REP_NOT_ALLOWED));
+    }
+}
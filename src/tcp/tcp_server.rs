@@ -16,6 +16,8 @@ pub struct TcpServer {
 #[derive(Debug)]
 struct State {
     addr: SocketAddr,
+    /// see `TcpServer::listen_also`
+    extra_addrs: Vec<SocketAddr>,
     tcp_sender: StreamSender<TcpStream>,
     tcp_receiver: Option<StreamReceiver<TcpStream>>,
     active: bool,
@@ -30,13 +32,37 @@ impl TcpServer {
         let (tcp_sender, tcp_receiver) = channel(4);
         let state = Arc::new(Mutex::new(State {
             addr,
+            extra_addrs: Vec::new(),
             tcp_sender: tcp_sender.clone(),
             tcp_receiver: Some(tcp_receiver),
             active: false,
             terminated: false,
         }));
-        let state_clone = state.clone();
+        Self::accept_in_background(tcp_listener, addr, state.clone(), tcp_sender);
 
+        Ok(Self { state })
+    }
+
+    /// Listens on `addr` as well, e.g. [::1]:8080 next to 127.0.0.1:8080, the connections
+    /// accepted there are received along with the others
+    pub async fn listen_also(&self, addr: SocketAddr) -> Result<SocketAddr> {
+        let tcp_listener = TcpListener::bind(addr).await?;
+        let addr = tcp_listener.local_addr().unwrap();
+        let tcp_sender = {
+            let mut state = self.state.lock().unwrap();
+            state.extra_addrs.push(addr);
+            state.tcp_sender.clone()
+        };
+        Self::accept_in_background(tcp_listener, addr, self.state.clone(), tcp_sender);
+        Ok(addr)
+    }
+
+    fn accept_in_background(
+        tcp_listener: TcpListener,
+        addr: SocketAddr,
+        state: Arc<Mutex<State>>,
+        tcp_sender: StreamSender<TcpStream>,
+    ) {
         tokio::spawn(async move {
             loop {
                 match tcp_listener.accept().await {
@@ -64,7 +90,7 @@ impl TcpServer {
                             .send_timeout(
                                 StreamMessage::Request(StreamRequest {
                                     stream,
-                                    dst_addr: None,
+                                    destination: None,
                                 }),
                                 Duration::from_millis(3000),
                             )
@@ -90,18 +116,20 @@ impl TcpServer {
             }
             info!("tcp server quit: {addr}");
         });
-
-        Ok(Self { state: state_clone })
     }
 
     pub async fn shutdown(&mut self) -> Result<()> {
-        let addr = {
+        let addrs = {
             let mut state = self.state.lock().unwrap();
             state.terminated = true;
-            state.addr
+            let mut addrs = vec![state.addr];
+            addrs.extend_from_slice(&state.extra_addrs);
+            addrs
         };
-        // initiate a new connection to wake up the accept() loop
-        TcpStream::connect(addr).await?;
+        // initiate a new connection to wake up the accept() loops
+        for addr in addrs {
+            TcpStream::connect(addr).await?;
+        }
         Ok(())
     }
 
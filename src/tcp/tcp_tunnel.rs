@@ -1,50 +1,132 @@
+use crate::socket_addr_with_unspecified_ip_port;
+use crate::tcp::proxy_protocol::{self, ProxyHeader};
 use crate::tcp::StreamMessage;
-use crate::tcp::{AsyncStream, StreamReceiver, StreamRequest};
-use crate::util::stream_util::StreamUtil;
-use log::{debug, error, info};
+use crate::tcp::{
+    AsyncStream, Destination, DialBacklog, DialTarget, IdleStreams, PermittedStream,
+    StreamReceiver, StreamRequest,
+};
+use crate::util::compression::{QuicRecv, QuicSend};
+use crate::util::rate_limiter::RateLimiters;
+use crate::util::stream_util::{StreamUtil, TransferError};
+use crate::Compression;
+use log::{debug, error, info, warn};
+use quinn::VarInt;
 use std::borrow::BorrowMut;
-use std::net::SocketAddr;
+use std::sync::Arc;
 use std::time::Duration;
+use tokio::io::AsyncWriteExt;
 use tokio::net::TcpStream;
+use tokio::sync::{Notify, Semaphore};
 
 pub struct TcpTunnel;
 
 impl TcpTunnel {
+    /// `drain` stops taking new streams while letting the ones in flight finish on `conn`,
+    /// `idle_streams` tracks the streams for the idle sweep, `send_client_addrs` sends the peer
+    /// and local address of each stream for the PROXY protocol header the peer sends upstream,
+    /// `compression` as agreed on at login compresses the bytes following the addresses
+    #[allow(clippy::too_many_arguments)]
     pub async fn start_serving<S: AsyncStream>(
         tunnel_out: bool,
         conn: &quinn::Connection,
         stream_receiver: &mut StreamReceiver<S>,
         pending_request: &mut Option<StreamRequest<S>>,
         stream_timeout_ms: u64,
+        one_way: bool,
+        flush_coalesce_ms: u64,
+        limiters: &RateLimiters,
+        drain: Option<&Notify>,
+        idle_streams: Option<&IdleStreams>,
+        send_client_addrs: bool,
+        compression: Compression,
     ) {
         loop {
             let request = match pending_request.take() {
                 Some(request) => request,
-                None => match stream_receiver.borrow_mut().recv().await {
-                    Some(StreamMessage::Request(request)) => request,
-                    _ => break,
-                },
+                None => {
+                    let msg = match drain {
+                        Some(drain) => tokio::select! {
+                            msg = stream_receiver.borrow_mut().recv() => msg,
+                            _ = drain.notified() => {
+                                debug!("draining connection: {}", conn.remote_address());
+                                break;
+                            }
+                        },
+                        None => stream_receiver.borrow_mut().recv().await,
+                    };
+                    match msg {
+                        Some(StreamMessage::Request(request)) => request,
+                        _ => break,
+                    }
+                }
             };
 
-            match conn.open_bi().await {
+            let result = if one_way {
+                conn.open_uni().await.map(|quic_send| (quic_send, None))
+            } else {
+                conn.open_bi()
+                    .await
+                    .map(|(quic_send, quic_recv)| (quic_send, Some(quic_recv)))
+            };
+
+            let tag = if tunnel_out { "OUT" } else { "IN" };
+            match result {
                 Ok((mut quic_send, quic_recv)) => {
-                    if let Err(e) =
-                        StreamUtil::write_socket_addr(&mut quic_send, &request.dst_addr, false)
-                            .await
-                    {
-                        error!("failed to send dst addr: {e}");
+                    let sent = match &request.destination {
+                        Some(destination) => {
+                            StreamUtil::write_destination(&mut quic_send, destination).await
+                        }
+                        None => Ok(()),
+                    };
+                    if let Err(e) = sent {
+                        error!("failed to send destination: {e}");
                         *pending_request = Some(request);
                         continue;
                     }
-                    StreamUtil::start_flowing(
-                        if tunnel_out { "OUT" } else { "IN" },
-                        request.stream,
-                        (quic_send, quic_recv),
-                        stream_timeout_ms,
-                    )
+                    if send_client_addrs {
+                        let src = request
+                            .stream
+                            .peer_addr()
+                            .unwrap_or_else(|_| socket_addr_with_unspecified_ip_port(false));
+                        let dst = request.stream.local_addr().unwrap_or_else(|_| {
+                            socket_addr_with_unspecified_ip_port(src.is_ipv6())
+                        });
+                        let sent = async {
+                            StreamUtil::write_socket_addr(&mut quic_send, &Some(src), false)
+                                .await?;
+                            StreamUtil::write_socket_addr(&mut quic_send, &Some(dst), false).await
+                        };
+                        if let Err(e) = sent.await {
+                            error!("failed to send client addrs: {e}");
+                            *pending_request = Some(request);
+                            continue;
+                        }
+                    }
+                    let activity = idle_streams.map(IdleStreams::track);
+                    let quic_send = QuicSend::new(quic_send, compression);
+                    match quic_recv {
+                        Some(quic_recv) => StreamUtil::start_flowing(
+                            tag,
+                            request.stream,
+                            (quic_send, QuicRecv::new(quic_recv, compression)),
+                            stream_timeout_ms,
+                            flush_coalesce_ms,
+                            limiters.clone(),
+                            activity,
+                        ),
+                        None => StreamUtil::start_sending(
+                            tag,
+                            request.stream,
+                            quic_send,
+                            stream_timeout_ms,
+                            flush_coalesce_ms,
+                            limiters.tx.clone(),
+                            activity,
+                        ),
+                    }
                 }
                 Err(e) => {
-                    error!("failed to open_bi, will retry: {e}");
+                    error!("failed to open stream, will retry: {e}");
                     *pending_request = Some(request);
                     break;
                 }
@@ -53,16 +135,38 @@ impl TcpTunnel {
         // the tcp server will be reused when tunnel reconnects
     }
 
+    /// `backlog` counts the streams waiting for the upstream to be dialed
+    /// `connection_limit` caps the upstream connections open at once, the streams accepted
+    /// beyond it are reset
+    /// `proxy_header` is written to the upstream ahead of the tunneled bytes
+    /// `compression` as for `start_serving`
+    #[allow(clippy::too_many_arguments)]
     pub async fn start_accepting(
         conn: &quinn::Connection,
-        upstream_addr: Option<SocketAddr>,
+        target: DialTarget,
         stream_timeout_ms: u64,
+        one_way: bool,
+        flush_coalesce_ms: u64,
+        limiters: &RateLimiters,
+        backlog: Option<&Arc<DialBacklog>>,
+        connection_limit: Option<&Arc<Semaphore>>,
+        proxy_header: ProxyHeader,
+        compression: Compression,
     ) {
         let remote_addr = &conn.remote_address();
-        info!("start tcp streaming, {remote_addr} ↔  {upstream_addr:?}");
+        info!("start tcp streaming, {remote_addr} ↔  {target}");
 
         loop {
-            match conn.accept_bi().await {
+            let result = if one_way {
+                conn.accept_uni().await.map(|quic_recv| (None, quic_recv))
+            } else {
+                conn.accept_bi()
+                    .await
+                    .map(|(quic_send, quic_recv)| (Some(quic_send), quic_recv))
+            };
+
+            let limiters = limiters.clone();
+            match result {
                 Err(quinn::ConnectionError::TimedOut) => {
                     info!("connection timeout: {remote_addr}");
                     break;
@@ -72,42 +176,133 @@ impl TcpTunnel {
                     break;
                 }
                 Err(e) => {
-                    error!("failed to open accept_bi: {remote_addr}, err: {e}");
+                    error!("failed to accept stream: {remote_addr}, err: {e}");
                     break;
                 }
-                Ok((quic_send, mut quic_recv)) => tokio::spawn(async move {
-                    let dst_addr = match upstream_addr {
-                        Some(dst_addr) => dst_addr,
-                        None => {
-                            match StreamUtil::read_socket_addr(&mut quic_recv, stream_timeout_ms)
-                                .await
-                            {
-                                Ok(dst_addr) => dst_addr,
-                                Err(e) => {
-                                    log::error!("failed to read dst address: {e}");
-                                    return;
+                Ok((mut quic_send, mut quic_recv)) => {
+                    let permit = match connection_limit {
+                        Some(limit) => {
+                            match limit.clone().try_acquire_owned() {
+                                Ok(permit) => Some(permit),
+                                Err(_) => {
+                                    warn!("connection limit reached, rejected stream from {remote_addr}");
+                                    if let Some(quic_send) = quic_send.as_mut() {
+                                        quic_send.reset(VarInt::from_u32(0)).ok();
+                                    }
+                                    quic_recv.stop(VarInt::from_u32(0)).ok();
+                                    continue;
                                 }
                             }
                         }
+                        None => None,
                     };
+                    let backlog_guard = backlog.map(|backlog| backlog.enter());
+                    let target = target.clone();
+                    tokio::spawn(async move {
+                        let target = match target {
+                            DialTarget::Requested => {
+                                match StreamUtil::read_destination(
+                                    &mut quic_recv,
+                                    stream_timeout_ms,
+                                )
+                                .await
+                                {
+                                    Ok(Destination::Addr(dst_addr)) => DialTarget::Addr(dst_addr),
+                                    Ok(Destination::Domain(domain, port)) => {
+                                        DialTarget::Domain(domain, port)
+                                    }
+                                    Err(e) => {
+                                        log::error!("failed to read destination: {e}");
+                                        return;
+                                    }
+                                }
+                            }
+                            target => target,
+                        };
+                        let header = match proxy_header {
+                            ProxyHeader::None => None,
+                            ProxyHeader::Local => Some(proxy_protocol::encode_v2(None)),
+                            ProxyHeader::Proxied => {
+                                let client_addrs = async {
+                                    let src = StreamUtil::read_socket_addr(
+                                        &mut quic_recv,
+                                        stream_timeout_ms,
+                                    )
+                                    .await?;
+                                    let dst = StreamUtil::read_socket_addr(
+                                        &mut quic_recv,
+                                        stream_timeout_ms,
+                                    )
+                                    .await?;
+                                    Ok::<_, TransferError>((src, dst))
+                                };
+                                match client_addrs.await {
+                                    Ok(client_addrs) => {
+                                        Some(proxy_protocol::encode_v2(Some(client_addrs)))
+                                    }
+                                    Err(e) => {
+                                        error!("failed to read client addrs: {e}");
+                                        return;
+                                    }
+                                }
+                            }
+                        };
 
-                    match tokio::time::timeout(
-                        Duration::from_secs(5),
-                        TcpStream::connect(&dst_addr),
-                    )
-                    .await
-                    {
-                        Ok(Ok(request)) => StreamUtil::start_flowing(
-                            "OUT",
-                            request,
-                            (quic_send, quic_recv),
-                            stream_timeout_ms,
-                        ),
-                        Ok(Err(e)) => error!("failed to connect to {dst_addr}, err: {e}"),
-                        Err(_) => error!("timeout connecting to {dst_addr}"),
-                    }
-                }),
+                        let dialed = tokio::time::timeout(
+                            Duration::from_secs(5),
+                            Self::dial(&target, header),
+                        )
+                        .await;
+                        drop(backlog_guard);
+                        match dialed {
+                            Ok(Ok(request)) => match quic_send {
+                                // the permit is released once both directions are done
+                                Some(quic_send) => StreamUtil::start_flowing(
+                                    "OUT",
+                                    PermittedStream::new(request, permit),
+                                    (
+                                        QuicSend::new(quic_send, compression),
+                                        QuicRecv::new(quic_recv, compression),
+                                    ),
+                                    stream_timeout_ms,
+                                    flush_coalesce_ms,
+                                    limiters,
+                                    None,
+                                ),
+                                None => StreamUtil::start_receiving(
+                                    "OUT",
+                                    PermittedStream::new(request, permit),
+                                    QuicRecv::new(quic_recv, compression),
+                                    stream_timeout_ms,
+                                    limiters.rx,
+                                ),
+                            },
+                            Ok(Err(e)) => error!("failed to connect to {target}, err: {e}"),
+                            Err(_) => error!("timeout connecting to {target}"),
+                        }
+                    })
+                }
             };
         }
     }
+
+    /// connects to the upstream and writes the PROXY protocol header ahead of the tunneled bytes
+    async fn dial(
+        target: &DialTarget,
+        header: Option<Vec<u8>>,
+    ) -> std::io::Result<Box<dyn AsyncStream>> {
+        let mut upstream: Box<dyn AsyncStream> = match target {
+            DialTarget::Addr(dst_addr) => Box::new(TcpStream::connect(dst_addr).await?),
+            DialTarget::Domain(domain, port) => {
+                Box::new(TcpStream::connect((domain.as_str(), *port)).await?)
+            }
+            #[cfg(unix)]
+            DialTarget::UnixSocket(path) => Box::new(tokio::net::UnixStream::connect(path).await?),
+            DialTarget::Requested => unreachable!("the requested address is read before dialing"),
+        };
+        if let Some(header) = header {
+            upstream.write_all(&header).await?;
+        }
+        Ok(upstream)
+    }
 }
@@ -0,0 +1,80 @@
+use anyhow::Result;
+use futures_util::future::BoxFuture;
+use quinn::{ClientConfig, Connection, Endpoint, ZeroRttAccepted};
+use std::fmt::Debug;
+use std::net::SocketAddr;
+
+/// Establishes the connections to the server, the logins and the tunneled traffic then go over
+/// the returned connection. It is called for every connection the client makes (tunnels,
+/// standbys, redirects and probes), so tests can observe or fail the connects and embedders can
+/// reach the server some other way, as long as it ends up in a QUIC connection.
+pub trait Transport: Debug + Send + Sync {
+    /// `endpoint` is bound by the client and may be shared by tunnels with different transport
+    /// settings, so the connection is to be made with `client_cfg` (`Endpoint::connect_with`).
+    /// Errors of the QUIC handshake are to be returned as `quinn::ConnectionError`, which the
+    /// client inspects to report why the connect failed
+    fn connect<'a>(
+        &'a self,
+        endpoint: &'a Endpoint,
+        client_cfg: ClientConfig,
+        remote_addr: SocketAddr,
+        server_name: &'a str,
+    ) -> BoxFuture<'a, Result<Connection>>;
+
+    /// Like `connect`, but returns the connection before the handshake completes if a session
+    /// ticket of the server is cached, so the login goes out as 0-RTT early data, along with
+    /// the future telling whether the server accepted it. The handshake is completed first
+    /// otherwise, and by transports that don't support 0-RTT
+    fn connect_0rtt<'a>(
+        &'a self,
+        endpoint: &'a Endpoint,
+        client_cfg: ClientConfig,
+        remote_addr: SocketAddr,
+        server_name: &'a str,
+    ) -> BoxFuture<'a, Result<(Connection, Option<ZeroRttAccepted>)>> {
+        Box::pin(async move {
+            let conn = self
+                .connect(endpoint, client_cfg, remote_addr, server_name)
+                .await?;
+            Ok((conn, None))
+        })
+    }
+}
+
+/// The default transport, which connects over the client's endpoint directly.
+#[derive(Debug, Clone, Default)]
+pub struct QuicTransport;
+
+impl Transport for QuicTransport {
+    fn connect<'a>(
+        &'a self,
+        endpoint: &'a Endpoint,
+        client_cfg: ClientConfig,
+        remote_addr: SocketAddr,
+        server_name: &'a str,
+    ) -> BoxFuture<'a, Result<Connection>> {
+        Box::pin(async move {
+            Ok(endpoint
+                .connect_with(client_cfg, remote_addr, server_name)?
+                .await?)
+        })
+    }
+
+    fn connect_0rtt<'a>(
+        &'a self,
+        endpoint: &'a Endpoint,
+        client_cfg: ClientConfig,
+        remote_addr: SocketAddr,
+        server_name: &'a str,
+    ) -> BoxFuture<'a, Result<(Connection, Option<ZeroRttAccepted>)>> {
+        Box::pin(async move {
+            match endpoint
+                .connect_with(client_cfg, remote_addr, server_name)?
+                .into_0rtt()
+            {
+                Ok((conn, accepted)) => Ok((conn, Some(accepted))),
+                Err(connecting) => Ok((connecting.await?, None)),
+            }
+        })
+    }
+}
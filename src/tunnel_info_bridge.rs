@@ -1,4 +1,9 @@
+use crate::ClientState;
+use anyhow::Result;
+use rs_utilities::log_and_bail;
 use serde::Serialize;
+use std::collections::BTreeMap;
+use std::net::{IpAddr, SocketAddr};
 use std::sync::{Arc, Mutex};
 
 #[derive(Serialize, Default, Clone)]
@@ -7,6 +12,309 @@ pub(crate) struct TunnelTraffic {
     pub tx_bytes: u64,
     pub tx_dgrams: u64,
     pub rx_dgrams: u64,
+    /// tunneled payload rates over the last report interval, left out of `add`
+    pub tx_bps: u64,
+    pub rx_bps: u64,
+    /// streams closed by the idle sweep over the lifetime of the client, left out of `add`
+    pub reaped_idle_streams: u64,
+    /// destinations refused by the allow/deny patterns of the proxy tunnels, left out of `add`
+    pub denied_destinations: u64,
+    /// UDP sessions the inbound tunnels have open, left out of `add`
+    pub udp_sessions: usize,
+}
+
+impl TunnelTraffic {
+    /// saturates instead of wrapping around, the totals of every closed connection are
+    /// added up for the lifetime of the client
+    pub fn add(&mut self, other: &TunnelTraffic) {
+        self.rx_bytes = self.rx_bytes.saturating_add(other.rx_bytes);
+        self.tx_bytes = self.tx_bytes.saturating_add(other.tx_bytes);
+        self.tx_dgrams = self.tx_dgrams.saturating_add(other.tx_dgrams);
+        self.rx_dgrams = self.rx_dgrams.saturating_add(other.rx_dgrams);
+    }
+
+    /// sets the rates from the bytes transferred since `last`, the report of the previous
+    /// interval. The counters restart from zero when the tunnel reconnects, so all the bytes
+    /// are new if they went down
+    pub fn set_rates_since(&mut self, last: Option<&TunnelTraffic>, elapsed_secs: f64) {
+        let since = |current: u64, last: u64| current.checked_sub(last).unwrap_or(current);
+        let (last_tx, last_rx) = last.map_or((0, 0), |last| (last.tx_bytes, last.rx_bytes));
+        self.tx_bps = (since(self.tx_bytes, last_tx) as f64 * 8.0 / elapsed_secs) as u64;
+        self.rx_bps = (since(self.rx_bytes, last_rx) as f64 * 8.0 / elapsed_secs) as u64;
+    }
+}
+
+impl From<&quinn::ConnectionStats> for TunnelTraffic {
+    fn from(stats: &quinn::ConnectionStats) -> Self {
+        Self {
+            rx_bytes: stats.udp_rx.bytes,
+            tx_bytes: stats.udp_tx.bytes,
+            tx_dgrams: stats.udp_tx.datagrams,
+            rx_dgrams: stats.udp_rx.datagrams,
+            ..Default::default()
+        }
+    }
+}
+
+/// traffic of the current connection of each tunnel keyed by its local server address, the
+/// rates are of the QUIC traffic here, as the tunneled payload is only counted per client
+#[derive(Serialize, Default, Clone)]
+pub(crate) struct PerTunnelTraffic {
+    pub tunnels: BTreeMap<SocketAddr, TunnelTraffic>,
+}
+
+#[derive(Serialize, Clone)]
+pub(crate) struct CongestionSample {
+    pub local_server_addr: SocketAddr,
+    pub timestamp_ms: i64,
+    pub rtt_ms: u64,
+    pub cwnd: u64,
+    /// estimated as cwnd / rtt, quinn doesn't expose the pacer's rate directly
+    pub pacing_rate_bps: u64,
+    pub congestion_events: u64,
+}
+
+/// a migration moved the connection to a path that drops the packets larger than its MTU
+#[derive(Serialize, Clone)]
+pub(crate) struct MtuBlackHole {
+    pub local_server_addr: SocketAddr,
+    pub current_mtu: u16,
+    pub sent_packets: u64,
+    pub lost_packets: u64,
+    /// whether new connections are clamped to the minimum MTU, which is unnecessary
+    /// if quinn already detected the black hole and fell back on its own
+    pub clamped: bool,
+}
+
+/// addresses the server domain resolved to, of which `selected` is connected to
+#[derive(Serialize, Clone, Debug)]
+pub struct ServerResolution {
+    pub domain: String,
+    /// "doh:URL", "dot:SERVER", "dns:SERVERS" or "system"
+    pub resolver: String,
+    pub addresses: Vec<IpAddr>,
+    pub selected: IpAddr,
+}
+
+/// streams of an inbound TCP tunnel waiting for the upstream to be dialed, and the most there
+/// have been at once
+#[derive(Serialize, Clone, Debug)]
+pub struct InboundBacklog {
+    pub index: usize,
+    pub depth: usize,
+    pub peak: usize,
+}
+
+/// snapshot of the QUIC stats of the connection serving a tunnel, see
+/// `Client::get_connection_stats`
+#[derive(Serialize, Clone, Debug)]
+pub struct ConnectionStats {
+    pub local_server_addr: SocketAddr,
+    pub rtt_ms: u64,
+    pub cwnd: u64,
+    pub congestion_events: u64,
+    pub sent_packets: u64,
+    pub lost_packets: u64,
+    pub lost_bytes: u64,
+    /// UDP payload bytes, the QUIC framing included
+    pub tx_bytes: u64,
+    pub rx_bytes: u64,
+}
+
+/// health of a tunnel, see `Client::tunnel_stats`
+#[derive(Serialize, Clone)]
+pub struct TunnelStats {
+    pub index: usize,
+    pub state: ClientState,
+    /// how long the current connection has been up, None while the tunnel is reconnecting
+    pub uptime_ms: Option<u64>,
+    /// times the tunnel reconnected after its connection closed
+    pub reconnects: u64,
+    /// RTT of the latest echo of the datagram heartbeats, see
+    /// `ClientConfig::datagram_heartbeat_interval_ms`
+    pub heartbeat_rtt_ms: Option<u64>,
+    /// heartbeats sent since the latest echo, a tunnel that keeps piling them up is dead even
+    /// if its connection hasn't timed out yet
+    pub unanswered_heartbeats: u64,
+}
+
+/// hopping was suspended for `duration_ms` by `Client::suspend_migration`, paused (0) by
+/// `Client::pause_migration`, or resumed
+#[derive(Serialize, Clone)]
+pub(crate) struct MigrationSuspension {
+    pub suspended: bool,
+    pub duration_ms: u64,
+}
+
+/// the server didn't answer on the new path of `unanswered_migrations` migrations in a row,
+/// the last from `last_local_addr`, so it's taken not to support migration, which is stopped
+#[derive(Serialize, Clone)]
+pub(crate) struct MigrationUnsupported {
+    pub unanswered_migrations: u32,
+    pub last_local_addr: SocketAddr,
+}
+
+/// a tunnel logged in, `handshake_ms` is the QUIC handshake, near zero if resumed in 0-RTT,
+/// and `login_ms` the login exchange up to RespSuccess, which includes waiting for the
+/// handshake to complete in 0-RTT
+#[derive(Serialize, Clone)]
+pub(crate) struct HandshakeLatency {
+    pub index: usize,
+    pub remote_addr: SocketAddr,
+    pub handshake_ms: u64,
+    pub login_ms: u64,
+    pub total_ms: u64,
+    pub used_0rtt: bool,
+}
+
+/// a connect attempt failed, `computed_delay_ms` is where the backoff curve is at, which
+/// `max_delay_ms` caps to `capped_delay_ms`, and `delay_ms` is the delay after jitter
+#[derive(Serialize, Clone)]
+pub(crate) struct ReconnectBackoff {
+    pub index: usize,
+    pub attempt: u32,
+    pub computed_delay_ms: u64,
+    pub capped_delay_ms: u64,
+    pub delay_ms: u64,
+    pub error: String,
+}
+
+/// the tunnel reconnected `limit` times and was stopped instead of reconnecting again, as it
+/// likely has a persistent problem worth investigating
+#[derive(Serialize, Clone)]
+pub(crate) struct ReconnectLimitReached {
+    pub index: usize,
+    pub reconnects: u64,
+    pub limit: u64,
+}
+
+#[derive(Serialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum ConnectionEventKind {
+    Opened,
+    Closed,
+}
+
+/// a connection of a network based tunnel started or stopped serving, for counting the
+/// reconnects and measuring the uptime, `reason` is why it closed, None once opened
+#[derive(Serialize, Clone)]
+pub(crate) struct ConnectionEvent {
+    pub index: usize,
+    pub local_server_addr: SocketAddr,
+    pub remote_addr: SocketAddr,
+    pub event: ConnectionEventKind,
+    pub reason: Option<String>,
+}
+
+#[derive(Serialize, Clone, Debug, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum LoginFailureReason {
+    BadPassword,
+    ProtocolError,
+    ServerRejected,
+    Timeout,
+}
+
+#[derive(Serialize, Clone)]
+pub(crate) struct LoginFailure {
+    pub index: usize,
+    pub reason: LoginFailureReason,
+    pub message: String,
+}
+
+/// the server certificate failed verification repeatedly, which retrying won't fix
+#[derive(Serialize, Clone)]
+pub(crate) struct CertVerificationFailure {
+    pub index: usize,
+    /// expired, not_yet_valid, name_mismatch, unknown_issuer, revoked, rejected or invalid
+    pub check: String,
+    pub message: String,
+    pub guidance: String,
+    /// of the server certificate, empty and None if it wasn't captured
+    pub subject_alt_names: Vec<String>,
+    pub not_before: Option<String>,
+    pub not_after: Option<String>,
+}
+
+/// the server certificate expires within `ClientConfig::cert_expiry_warning_days`, or has
+/// expired already, in which case `days_left` is negative
+#[derive(Serialize, Clone)]
+pub(crate) struct CertExpiryWarning {
+    pub index: usize,
+    pub subject: String,
+    pub not_after: String,
+    pub days_left: i64,
+    /// the connection was closed, see `ClientConfig::reject_expired_cert`
+    pub rejected: bool,
+    /// false with `ClientConfig::allow_insecure`, the certificate is then whatever the server
+    /// presented, not verified against anything
+    pub verified: bool,
+}
+
+/// a TunnelLog message as a record for log pipelines, see `ClientConfig::structured_tunnel_log`
+#[derive(Serialize, Clone)]
+pub(crate) struct TunnelLogRecord {
+    /// RFC 3339 with milliseconds
+    pub ts: String,
+    pub level: &'static str,
+    /// None for the messages about the client as a whole, e.g. its migrations
+    pub tunnel_index: Option<usize>,
+    pub event_type: &'static str,
+    pub msg: String,
+    /// the key:value parts of the message, e.g. idle_timeout:30000
+    pub fields: BTreeMap<String, String>,
+}
+
+impl TunnelLogRecord {
+    /// the `{index}:` prefix of `msg` is taken as the tunnel index
+    pub(crate) fn new(msg: &str) -> Self {
+        let (tunnel_index, msg) = match msg.split_once(':') {
+            Some((index, rest)) => match index.parse() {
+                Ok(index) => (Some(index), rest),
+                Err(_) => (None, msg),
+            },
+            None => (None, msg),
+        };
+        let fields = msg
+            .split(", ")
+            .filter_map(|part| part.split_once(':'))
+            .filter(|(key, value)| {
+                !key.is_empty()
+                    && key.bytes().all(|b| b.is_ascii_lowercase() || b == b'_')
+                    && !value.is_empty()
+                    && !value.contains(' ')
+            })
+            .map(|(key, value)| (key.to_string(), value.to_string()))
+            .collect();
+        Self {
+            ts: chrono::Local::now().to_rfc3339_opts(chrono::SecondsFormat::Millis, false),
+            level: "info",
+            tunnel_index,
+            event_type: "TunnelLog",
+            msg: msg.to_string(),
+            fields,
+        }
+    }
+}
+
+/// posted every `ClientConfig::heartbeat_interval_secs` regardless of activity, `seq` counts up
+/// from 0, so a gap means heartbeats were lost and none arriving means the client stalled
+#[derive(Serialize, Clone)]
+pub(crate) struct Heartbeat {
+    pub seq: u64,
+    pub timestamp_ms: i64,
+    pub state: ClientState,
+    /// number of tunnels connected to the server
+    pub connections: usize,
+    /// the tunnels measuring their RTT with datagram heartbeats
+    pub tunnels: Vec<TunnelHeartbeat>,
+}
+
+#[derive(Serialize, Clone)]
+pub(crate) struct TunnelHeartbeat {
+    pub index: usize,
+    pub rtt_ms: Option<u64>,
+    pub unanswered: u64,
 }
 
 #[derive(Serialize)]
@@ -14,6 +322,21 @@ pub(crate) enum TunnelInfoType {
     TunnelState,
     TunnelLog,
     TunnelTraffic,
+    CongestionSample,
+    LoginFailed,
+    MtuBlackHole,
+    MigrationSuspension,
+    ServerResolution,
+    ReconnectBackoff,
+    PerTunnelTraffic,
+    InboundBacklog,
+    CertVerificationFailed,
+    Heartbeat,
+    ReconnectLimitReached,
+    ConnectionEvent,
+    CertExpiryWarning,
+    MigrationUnsupported,
+    HandshakeLatency,
 }
 
 #[derive(Serialize)]
@@ -34,18 +357,50 @@ where
     }
 }
 
+/// encoding of the events posted to the info listener
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum InfoFormat {
+    #[default]
+    Json,
+    /// with the field names as in JSON, requires the `msgpack` feature
+    MessagePack,
+}
+
+type InfoListener = Arc<Mutex<dyn FnMut(&[u8]) + 'static + Send + Sync>>;
+
 #[derive(Clone)]
 pub(crate) struct TunnelInfoBridge {
-    listener: Option<Arc<Mutex<dyn FnMut(&str) + 'static + Send + Sync>>>,
+    listener: Option<InfoListener>,
+    format: InfoFormat,
 }
 
 impl TunnelInfoBridge {
     pub(crate) fn new() -> Self {
-        TunnelInfoBridge { listener: None }
+        TunnelInfoBridge {
+            listener: None,
+            format: InfoFormat::Json,
+        }
     }
 
-    pub(crate) fn set_listener(&mut self, listener: impl FnMut(&str) + 'static + Send + Sync) {
+    pub(crate) fn set_listener(&mut self, mut listener: impl FnMut(&str) + 'static + Send + Sync) {
+        self.listener = Some(Arc::new(Mutex::new(move |data: &[u8]| {
+            // JSON is always valid UTF-8
+            listener(std::str::from_utf8(data).unwrap_or_default())
+        })));
+        self.format = InfoFormat::Json;
+    }
+
+    pub(crate) fn set_encoded_listener(
+        &mut self,
+        format: InfoFormat,
+        listener: impl FnMut(&[u8]) + 'static + Send + Sync,
+    ) -> Result<()> {
+        if format == InfoFormat::MessagePack && !cfg!(feature = "msgpack") {
+            log_and_bail!("MessagePack events require rstun to be built with the msgpack feature");
+        }
         self.listener = Some(Arc::new(Mutex::new(listener)));
+        self.format = format;
+        Ok(())
     }
 
     pub(crate) fn has_listener(&self) -> bool {
@@ -57,9 +412,102 @@ impl TunnelInfoBridge {
         T: ?Sized + Serialize,
     {
         if let Some(ref listener) = self.listener {
-            if let Ok(json) = serde_json::to_string(&data) {
-                listener.lock().unwrap()(json.as_str());
+            if let Some(encoded) = Self::encode(self.format, &data) {
+                listener.lock().unwrap()(&encoded);
             }
         }
     }
+
+    fn encode<T>(format: InfoFormat, data: &TunnelInfo<T>) -> Option<Vec<u8>>
+    where
+        T: ?Sized + Serialize,
+    {
+        match format {
+            InfoFormat::Json => serde_json::to_vec(data).ok(),
+            #[cfg(feature = "msgpack")]
+            InfoFormat::MessagePack => rmp_serde::to_vec_named(data).ok(),
+            #[cfg(not(feature = "msgpack"))]
+            InfoFormat::MessagePack => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn traffic(tx_bytes: u64, rx_bytes: u64) -> TunnelTraffic {
+        TunnelTraffic {
+            tx_bytes,
+            rx_bytes,
+            tx_dgrams: tx_bytes / 1000,
+            rx_dgrams: rx_bytes / 1000,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn add_saturates_near_u64_max() {
+        let mut total = traffic(u64::MAX - 10, u64::MAX - 10);
+        total.add(&traffic(100, 5));
+        assert_eq!(total.tx_bytes, u64::MAX);
+        assert_eq!(total.rx_bytes, u64::MAX - 5);
+
+        total.add(&traffic(u64::MAX, u64::MAX));
+        assert_eq!(total.tx_bytes, u64::MAX);
+        assert_eq!(total.rx_bytes, u64::MAX);
+
+        let mut dgrams = TunnelTraffic {
+            tx_dgrams: u64::MAX - 1,
+            rx_dgrams: u64::MAX,
+            ..Default::default()
+        };
+        dgrams.add(&dgrams.clone());
+        assert_eq!(dgrams.tx_dgrams, u64::MAX);
+        assert_eq!(dgrams.rx_dgrams, u64::MAX);
+    }
+
+    #[test]
+    fn add_keeps_the_totals_of_closed_connections() {
+        // what the client adds up as each connection of a tunnel closes and it reconnects
+        let mut closed = TunnelTraffic::default();
+        closed.add(&traffic(5 << 40, 7 << 40));
+        closed.add(&traffic(3 << 40, 1 << 40));
+
+        let mut total = closed.clone();
+        total.add(&traffic(1000, 2000));
+        assert_eq!(total.tx_bytes, (8 << 40) + 1000);
+        assert_eq!(total.rx_bytes, (8 << 40) + 2000);
+    }
+
+    #[test]
+    fn rates_rebase_on_reconnect() {
+        let last = traffic(10_000, 20_000);
+
+        let mut current = traffic(12_000, 30_000);
+        current.set_rates_since(Some(&last), 2.0);
+        assert_eq!(current.tx_bps, 8_000);
+        assert_eq!(current.rx_bps, 40_000);
+
+        // a new connection counts from zero, all its bytes were sent in the interval
+        let mut reconnected = traffic(1_000, 500);
+        reconnected.set_rates_since(Some(&last), 1.0);
+        assert_eq!(reconnected.tx_bps, 8_000);
+        assert_eq!(reconnected.rx_bps, 4_000);
+
+        let mut first = traffic(1_000, 500);
+        first.set_rates_since(None, 1.0);
+        assert_eq!(first.tx_bps, 8_000);
+        assert_eq!(first.rx_bps, 4_000);
+    }
+
+    #[test]
+    fn rates_near_u64_max_dont_wrap_around() {
+        let last = traffic(u64::MAX - 1000, 0);
+        let mut current = traffic(u64::MAX, u64::MAX);
+        current.set_rates_since(Some(&last), 1.0);
+        assert_eq!(current.tx_bps, 8_000);
+        // beyond u64 in bits per second, the conversion saturates
+        assert_eq!(current.rx_bps, u64::MAX);
+    }
 }
@@ -1,21 +1,53 @@
-use crate::{Tunnel, TunnelMode};
+use crate::{Compression, Tunnel, TunnelMode};
 use anyhow::Result;
 use anyhow::{bail, Context};
 use bincode::config::{self, Configuration};
 use enum_as_inner::EnumAsInner;
+use log::debug;
 use quinn::{RecvStream, SendStream};
-use serde::{Deserialize, Serialize};
+use ring::hmac;
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
 use std::fmt::Display;
-use std::net::SocketAddr;
+use std::net::{IpAddr, SocketAddr};
 use std::time::Duration;
 use tokio::io::{AsyncReadExt, AsyncWriteExt};
 
+/// sent with RespFailure when the password doesn't match, so the client can tell it apart
+pub(crate) const ERR_BAD_PASSWORD: &str = "bad password";
+
+/// proves the password to the server without sending it, see `TunnelMessage::Challenge`
+pub(crate) fn password_hmac(password: &str, nonce: &[u8]) -> Vec<u8> {
+    let key = hmac::Key::new(hmac::HMAC_SHA256, password.as_bytes());
+    hmac::sign(&key, nonce).as_ref().to_vec()
+}
+
+/// compares in constant time, so the timing doesn't tell how much of `mac` is right
+pub(crate) fn verify_password_hmac(password: &str, nonce: &[u8], mac: &[u8]) -> bool {
+    let key = hmac::Key::new(hmac::HMAC_SHA256, password.as_bytes());
+    hmac::verify(&key, nonce, mac).is_ok()
+}
+
 #[derive(EnumAsInner, Serialize, Deserialize, Debug, Clone)]
 pub enum TunnelMessage {
     ReqLogin(LoginInfo),
     ReqUdpStart(UdpPeerAddr),
     RespFailure(String),
     RespSuccess,
+    ReqPing(PingRequest),
+    RespPing(PingReply),
+    /// pushed by the server over the login stream, asks the client to reconnect to `new_addr`
+    Redirect {
+        new_addr: String,
+    },
+    /// sent by the server in reply to a login asking for it, the client answers with
+    /// ChallengeResponse rather than putting the password in ReqLogin
+    Challenge {
+        nonce: Vec<u8>,
+    },
+    /// HMAC-SHA256 of the nonce keyed with the password
+    ChallengeResponse {
+        mac: Vec<u8>,
+    },
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
@@ -30,6 +62,7 @@ impl LoginInfo {
             Tunnel::ChannelBased(upstream_type) => {
                 format!("{upstream_type}_ChannelBased →  {remote_addr}")
             }
+            Tunnel::IcmpChannel => format!("ICMP_Channel →  {remote_addr}"),
             Tunnel::NetworkBased(cfg) => {
                 let upstream = &cfg.upstream;
                 let upstream_str = if let Some(upstream) = upstream.upstream_addr {
@@ -38,23 +71,30 @@ impl LoginInfo {
                     } else {
                         format!("{upstream}")
                     }
+                } else if upstream.upstream_type.is_proxy() {
+                    String::from("Requested")
                 } else {
                     String::from("PeerDefault")
                 };
 
+                // an inbound tunnel to a Unix socket has no local server address
+                let local_str = match (&cfg.local_server_path, cfg.local_server_addr) {
+                    (Some(path), _) => format!("unix:{}", path.display()),
+                    (None, Some(addr)) => addr.to_string(),
+                    (None, None) => cfg.local_server_host.clone().unwrap_or_default(),
+                };
+
                 match cfg.mode {
                     TunnelMode::Out => {
                         format!(
-                            "{}_OUT →  {} →  {remote_addr} →  {upstream_str}",
+                            "{}_OUT →  {local_str} →  {remote_addr} →  {upstream_str}",
                             upstream.upstream_type,
-                            cfg.local_server_addr.unwrap()
                         )
                     }
                     TunnelMode::In => {
                         format!(
-                            "{}_IN ←  {} ←  {remote_addr} ←  {upstream_str}",
+                            "{}_IN ←  {local_str} ←  {remote_addr} ←  {upstream_str}",
                             upstream.upstream_type,
-                            cfg.local_server_addr.unwrap()
                         )
                     }
                 }
@@ -66,14 +106,74 @@ impl LoginInfo {
 #[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
 pub struct UdpPeerAddr(pub Option<SocketAddr>);
 
+/// An ICMP echo request to be sent by the server, over the ICMP channel
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub(crate) struct PingRequest {
+    pub target: IpAddr,
+    pub seq: u16,
+    pub payload_len: u16,
+    pub timeout_ms: u64,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub(crate) struct PingReply {
+    /// round-trip time measured by the server
+    pub rtt_us: u64,
+}
+
+/// Appended to the login request by the client, see `TunnelMessage::send_with_ext`,
+/// new fields go last, older peers stop decoding before them
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq, Eq)]
+pub(crate) struct LoginReqExt {
+    /// see `TunnelConfig::one_way`
+    pub one_way: bool,
+    /// see `ClientConfig::connection_label`
+    pub connection_label: Option<String>,
+    /// see `TunnelConfig::udp_datagrams`, only set if the connection supports datagrams
+    pub udp_datagrams: bool,
+    /// see `TunnelConfig::proxy_protocol`
+    pub proxy_protocol: bool,
+    /// see `TunnelConfig::compression`
+    pub compression: Compression,
+    /// see `ClientConfig::datagram_heartbeat_interval_ms`
+    pub datagram_heartbeat: bool,
+    /// see `ClientConfig::password_challenge`, the password in LoginInfo is left empty
+    pub password_challenge: bool,
+}
+
+/// Appended to the login response by the server, see `TunnelMessage::send_with_ext`
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq, Eq)]
+pub(crate) struct LoginRespExt {
+    /// max_idle_timeout of the server, 0 means no idle timeout
+    pub quic_timeout_ms: u64,
+    /// the label received in the login request, echoed back for confirmation
+    pub connection_label: Option<String>,
+    /// the UDP payloads are carried in QUIC datagrams, as the client asked for and the
+    /// connection supports them on the server side too
+    pub udp_datagrams: bool,
+    /// the addresses of the clients the server accepts are sent ahead of each stream
+    pub proxy_protocol: bool,
+    /// the compression the server agreed on, None if it doesn't support the one asked for
+    pub compression: Compression,
+    /// the heartbeats the client sends in QUIC datagrams are echoed back
+    pub datagram_heartbeat: bool,
+    /// the destinations of SOCKS5 and HTTP CONNECT tunnels may be sent as domain names, which the
+    /// server resolves
+    pub domain_destinations: bool,
+}
+
 impl Display for LoginInfo {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match &self.tunnel {
             Tunnel::ChannelBased(upstream_type) => {
                 f.write_str(format!("{upstream_type}_ChannelBased").as_str())
             }
+            Tunnel::IcmpChannel => f.write_str("ICMP_Channel"),
             Tunnel::NetworkBased(cfg) => {
-                f.write_str(format!("{}_{}", cfg.upstream.upstream_type, cfg.mode).as_str())
+                let one_way = if cfg.one_way { "_ONEWAY" } else { "" };
+                f.write_str(
+                    format!("{}_{}{one_way}", cfg.upstream.upstream_type, cfg.mode).as_str(),
+                )
             }
         }
     }
@@ -88,12 +188,25 @@ impl Display for TunnelMessage {
             }
             Self::RespFailure(msg) => f.write_str(format!("fail:{msg}").as_str()),
             Self::RespSuccess => f.write_str("succeeded"),
+            Self::ReqPing(req) => f.write_str(format!("ping:{}#{}", req.target, req.seq).as_str()),
+            Self::RespPing(reply) => f.write_str(format!("pong:{}us", reply.rtt_us).as_str()),
+            Self::Redirect { new_addr } => f.write_str(format!("redirect:{new_addr}").as_str()),
+            Self::Challenge { .. } => f.write_str("challenge"),
+            Self::ChallengeResponse { .. } => f.write_str("challenge_response"),
         }
     }
 }
 
 impl TunnelMessage {
     pub async fn recv(quic_recv: &mut RecvStream) -> Result<TunnelMessage> {
+        Ok(Self::recv_with_ext::<()>(quic_recv).await?.0)
+    }
+
+    /// Receives a message and the extension that may trail it in the same frame, the extension
+    /// is None if the peer doesn't send one (older versions) or it can't be decoded
+    pub async fn recv_with_ext<T: DeserializeOwned>(
+        quic_recv: &mut RecvStream,
+    ) -> Result<(TunnelMessage, Option<T>)> {
         let msg_len = quic_recv.read_u32().await? as usize;
         let mut msg = vec![0; msg_len];
         quic_recv
@@ -101,12 +214,21 @@ impl TunnelMessage {
             .await
             .context("read message failed")?;
 
-        let tun_msg = bincode::serde::decode_from_slice::<TunnelMessage, Configuration>(
+        let (tun_msg, len) = bincode::serde::decode_from_slice::<TunnelMessage, Configuration>(
             &msg,
             config::standard(),
         )
         .context("deserialize message failed")?;
-        Ok(tun_msg.0)
+
+        let ext = if len < msg.len() {
+            bincode::serde::decode_from_slice::<T, Configuration>(&msg[len..], config::standard())
+                .inspect_err(|e| debug!("ignored undecodable message extension: {e}"))
+                .ok()
+                .map(|(ext, _)| ext)
+        } else {
+            None
+        };
+        Ok((tun_msg, ext))
     }
 
     pub async fn send(quic_send: &mut SendStream, msg: &TunnelMessage) -> Result<()> {
@@ -117,6 +239,24 @@ impl TunnelMessage {
         Ok(())
     }
 
+    /// Sends a message with an extension appended in the same frame, peers that don't know about
+    /// the extension simply ignore the trailing bytes, which keeps the protocol compatible
+    pub async fn send_with_ext<T: Serialize>(
+        quic_send: &mut SendStream,
+        msg: &TunnelMessage,
+        ext: &T,
+    ) -> Result<()> {
+        let mut msg = bincode::serde::encode_to_vec(msg, config::standard())
+            .context("serialize message failed")?;
+        msg.extend(
+            bincode::serde::encode_to_vec(ext, config::standard())
+                .context("serialize message extension failed")?,
+        );
+        quic_send.write_u32(msg.len() as u32).await?;
+        quic_send.write_all(&msg).await?;
+        Ok(())
+    }
+
     pub async fn send_failure(quic_send: &mut SendStream, msg: String) -> Result<()> {
         let msg = TunnelMessage::RespFailure(msg);
         Self::send(quic_send, &msg).await?;
@@ -151,3 +291,28 @@ impl TunnelMessage {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{ClientConfig, UpstreamType};
+
+    #[cfg(unix)]
+    #[test]
+    fn format_inbound_tunnel_to_a_unix_socket() {
+        let config = ClientConfig::builder()
+            .server_addr("127.0.0.1:6000")
+            .add_mappings("IN^unix:/run/app.sock^8080", UpstreamType::Tcp)
+            .unwrap()
+            .build()
+            .unwrap();
+        let login_info = LoginInfo {
+            password: String::new(),
+            tunnel: Tunnel::NetworkBased(Box::new(config.tunnels[0].clone())),
+        };
+        assert_eq!(
+            login_info.format_with_remote_addr(&"10.0.0.1:6000".parse().unwrap()),
+            "TCP_IN ←  unix:/run/app.sock ←  10.0.0.1:6000 ←  10.0.0.1:8080"
+        );
+    }
+}
@@ -1,41 +1,172 @@
 use crate::tunnel_message::{TunnelMessage, UdpPeerAddr};
 use crate::udp::{UdpMessage, UdpPacket};
+use crate::util::heartbeat::{self, DatagramHeartbeats};
+use crate::util::rate_limiter::{RateLimiter, RateLimiters};
 use crate::BUFFER_POOL;
 use crate::UDP_PACKET_SIZE;
 use anyhow::{Context, Result};
 use dashmap::DashMap;
 use log::{debug, error, info, warn};
-use quinn::{Connection, RecvStream, SendStream};
+use quinn::{Connection, RecvStream, SendStream, VarInt};
 use rs_utilities::log_and_bail;
 use std::{
     net::{IpAddr, Ipv4Addr, SocketAddr},
+    sync::atomic::{AtomicBool, AtomicU32, AtomicUsize, Ordering},
     sync::Arc,
     time::Duration,
 };
 use tokio::sync::mpsc::{Receiver, Sender};
-use tokio::sync::oneshot;
+use tokio::sync::{oneshot, Notify};
 use tokio::{net::UdpSocket, sync::Mutex};
 
 type TSafe<T> = Arc<tokio::sync::Mutex<T>>;
 
+/// each QUIC datagram starts with the id of the flow it belongs to, i.e. the local peer it's
+/// from or to, as there's no stream per peer to tell them apart
+const FLOW_ID_LEN: usize = 4;
+
+/// a local peer exchanging datagrams through the tunnel, `active` is cleared on every sweep
+/// and set again by its packets
+struct DatagramFlow {
+    local_addr: SocketAddr,
+    active: AtomicBool,
+}
+
+/// the flows of the local peers served by a connection in datagram mode
+#[derive(Default)]
+struct DatagramFlows {
+    ids: DashMap<SocketAddr, u32>,
+    flows: DashMap<u32, DatagramFlow>,
+    next_id: AtomicU32,
+}
+
+impl DatagramFlows {
+    fn id_of(&self, local_addr: SocketAddr) -> u32 {
+        let id = *self.ids.entry(local_addr).or_insert_with(|| {
+            let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+            self.flows.insert(
+                id,
+                DatagramFlow {
+                    local_addr,
+                    active: AtomicBool::new(true),
+                },
+            );
+            id
+        });
+        if let Some(flow) = self.flows.get(&id) {
+            flow.active.store(true, Ordering::Relaxed);
+        }
+        id
+    }
+
+    fn local_addr_of(&self, id: u32) -> Option<SocketAddr> {
+        let flow = self.flows.get(&id)?;
+        flow.active.store(true, Ordering::Relaxed);
+        Some(flow.local_addr)
+    }
+
+    /// drops the flows without packets since the last sweep
+    fn remove_idle(&self) {
+        self.flows
+            .retain(|_, flow| flow.active.swap(false, Ordering::Relaxed));
+        self.ids.retain(|_, id| self.flows.contains_key(id));
+    }
+}
+
+/// the socket connected to the upstream for a flow of the peer, see `DatagramFlow`, `evicted`
+/// is notified by the sweep once neither side sent any packets for `udp_timeout_ms`
+struct UpstreamFlow {
+    udp_socket: UdpSocket,
+    active: AtomicBool,
+    evicted: Notify,
+    _session: Option<UdpSession>,
+}
+
+/// the UDP sessions the inbound tunnels have open to their local servers, i.e. the streams in
+/// stream mode and the flows in datagram mode, shared by the tunnels of the client
+pub(crate) struct UdpSessions {
+    open: AtomicUsize,
+    /// 0 means unlimited
+    max: usize,
+}
+
+impl UdpSessions {
+    pub(crate) fn new(max: usize) -> Self {
+        Self {
+            open: AtomicUsize::new(0),
+            max,
+        }
+    }
+
+    /// None if `max` sessions are open already
+    fn try_open(self: &Arc<Self>) -> Option<UdpSession> {
+        self.open
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |open| {
+                (self.max == 0 || open < self.max).then_some(open + 1)
+            })
+            .ok()?;
+        Some(UdpSession(self.clone()))
+    }
+
+    pub(crate) fn open(&self) -> usize {
+        self.open.load(Ordering::Relaxed)
+    }
+}
+
+/// counted in `UdpSessions` until dropped
+struct UdpSession(Arc<UdpSessions>);
+
+impl Drop for UdpSession {
+    fn drop(&mut self) {
+        self.0.open.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
 pub struct UdpTunnel;
 
 impl UdpTunnel {
+    /// `drain` stops taking new packets, the sessions in flight stay on `conn` until they time out.
+    /// `datagrams` carries the packets in QUIC datagrams, which both sides must have agreed on,
+    /// the heartbeats among them are handled by `heartbeats`
+    #[allow(clippy::too_many_arguments)]
     pub async fn start_serving(
         conn: &quinn::Connection,
         udp_sender: &Sender<UdpMessage>,
         udp_receiver: &mut Receiver<UdpMessage>,
         udp_timeout_ms: u64,
+        limiters: &RateLimiters,
+        drain: Option<&Notify>,
+        datagrams: bool,
+        heartbeats: &DatagramHeartbeats,
     ) {
+        if datagrams {
+            Self::serve_datagrams(
+                conn,
+                udp_sender,
+                udp_receiver,
+                udp_timeout_ms,
+                limiters,
+                drain,
+                heartbeats,
+            )
+            .await;
+            return;
+        }
+
         debug!("start serving udp via: {}", conn.remote_address());
         let stream_map = Arc::new(DashMap::new());
-        while let Some(UdpMessage::Packet(packet)) = udp_receiver.recv().await {
+        loop {
+            let Some(packet) = Self::next_packet(conn, udp_receiver, drain).await else {
+                break;
+            };
+
             let quic_send = match UdpTunnel::open_stream(
                 conn.clone(),
                 udp_sender.clone(),
                 packet.local_addr,
                 stream_map.clone(),
                 udp_timeout_ms,
+                limiters.rx.clone(),
             )
             .await
             {
@@ -51,9 +182,11 @@ impl UdpTunnel {
             };
 
             // send the packet using an async task
+            let tx = limiters.tx.clone();
             tokio::spawn(async move {
-                let mut quic_send = quic_send.lock().await;
                 let payload_len = packet.payload.len();
+                tx.acquire(payload_len).await;
+                let mut quic_send = quic_send.lock().await;
 
                 TunnelMessage::send(
                     &mut quic_send,
@@ -76,12 +209,148 @@ impl UdpTunnel {
         info!("udp server quit");
     }
 
+    /// the next packet from the local peers, None once the server quit or `drain` is notified
+    async fn next_packet(
+        conn: &quinn::Connection,
+        udp_receiver: &mut Receiver<UdpMessage>,
+        drain: Option<&Notify>,
+    ) -> Option<UdpPacket> {
+        let msg = match drain {
+            Some(drain) => tokio::select! {
+                msg = udp_receiver.recv() => msg,
+                _ = drain.notified() => {
+                    debug!("draining connection: {}", conn.remote_address());
+                    return None;
+                }
+            },
+            None => udp_receiver.recv().await,
+        };
+        match msg {
+            Some(UdpMessage::Packet(packet)) => Some(packet),
+            _ => None,
+        }
+    }
+
+    async fn serve_datagrams(
+        conn: &quinn::Connection,
+        udp_sender: &Sender<UdpMessage>,
+        udp_receiver: &mut Receiver<UdpMessage>,
+        udp_timeout_ms: u64,
+        limiters: &RateLimiters,
+        drain: Option<&Notify>,
+        heartbeats: &DatagramHeartbeats,
+    ) {
+        debug!(
+            "start serving udp in datagrams via: {}",
+            conn.remote_address()
+        );
+        let flows = Arc::new(DatagramFlows::default());
+        tokio::spawn(Self::datagrams_to_local_peers(
+            conn.clone(),
+            udp_sender.clone(),
+            flows.clone(),
+            limiters.rx.clone(),
+            heartbeats.clone(),
+        ));
+        tokio::spawn({
+            let conn = conn.clone();
+            let flows = flows.clone();
+            async move {
+                let mut sweep = tokio::time::interval(Duration::from_millis(udp_timeout_ms.max(1)));
+                loop {
+                    tokio::select! {
+                        _ = conn.closed() => break,
+                        _ = sweep.tick() => flows.remove_idle(),
+                    }
+                }
+            }
+        });
+
+        while let Some(packet) = Self::next_packet(conn, udp_receiver, drain).await {
+            let flow_id = flows.id_of(packet.local_addr);
+            limiters.tx.acquire(packet.payload.len()).await;
+            match Self::send_datagram(conn, flow_id, &packet.payload) {
+                Ok(()) => {}
+                Err(quinn::SendDatagramError::ConnectionLost(e)) => {
+                    debug!("connection is closed, will quit, err: {e}");
+                    break;
+                }
+                Err(e) => {
+                    warn!(
+                        "failed to send datagram({}) through the tunnel, err: {e}",
+                        packet.payload.len()
+                    );
+                }
+            }
+        }
+
+        info!("udp server quit");
+    }
+
+    async fn datagrams_to_local_peers(
+        conn: Connection,
+        udp_sender: Sender<UdpMessage>,
+        flows: Arc<DatagramFlows>,
+        rx: RateLimiter,
+        heartbeats: DatagramHeartbeats,
+    ) {
+        while let Ok(datagram) = conn.read_datagram().await {
+            if heartbeat::is_heartbeat(&datagram) {
+                heartbeats.handle(&conn, &datagram);
+                continue;
+            }
+            let Some((flow_id, payload)) = Self::parse_datagram(&datagram) else {
+                continue;
+            };
+            let Some(local_addr) = flows.local_addr_of(flow_id) else {
+                debug!(
+                    "drop the datagram ({}) of expired flow: {flow_id}",
+                    payload.len()
+                );
+                continue;
+            };
+
+            rx.acquire(payload.len()).await;
+            let mut buf = BUFFER_POOL.alloc_and_fill(payload.len());
+            buf.copy_from_slice(payload);
+            let packet = UdpPacket {
+                payload: buf,
+                local_addr,
+                peer_addr: None,
+            };
+            if udp_sender.send(UdpMessage::Packet(packet)).await.is_err() {
+                break;
+            }
+        }
+    }
+
+    fn send_datagram(
+        conn: &Connection,
+        flow_id: u32,
+        payload: &[u8],
+    ) -> Result<(), quinn::SendDatagramError> {
+        let mut datagram = Vec::with_capacity(FLOW_ID_LEN + payload.len());
+        datagram.extend_from_slice(&flow_id.to_be_bytes());
+        datagram.extend_from_slice(payload);
+        conn.send_datagram(datagram.into())
+    }
+
+    fn parse_datagram(datagram: &[u8]) -> Option<(u32, &[u8])> {
+        if datagram.len() < FLOW_ID_LEN {
+            warn!("malformed datagram of {} bytes", datagram.len());
+            return None;
+        }
+        let (flow_id, payload) = datagram.split_at(FLOW_ID_LEN);
+        Some((u32::from_be_bytes(flow_id.try_into().ok()?), payload))
+    }
+
     async fn open_stream(
         conn: Connection,
         udp_sender: Sender<UdpMessage>,
         local_addr: SocketAddr,
         stream_map: Arc<DashMap<SocketAddr, TSafe<SendStream>>>,
         udp_timeout_ms: u64,
+        rx: RateLimiter,
     ) -> Result<TSafe<SendStream>> {
         if let Some(s) = stream_map.get(&local_addr) {
             return Ok((*s).clone());
@@ -111,6 +380,7 @@ impl UdpTunnel {
                         unsafe {
                             payload.set_len(packet_len as usize);
                         }
+                        rx.acquire(packet_len as usize).await;
                         let packet = UdpPacket {
                             payload,
                             local_addr,
@@ -139,12 +409,35 @@ impl UdpTunnel {
         Ok(quic_send)
     }
 
-    pub async fn start_accepting(
+    /// `datagrams` as for `start_serving`, which requires `upstream_addr`. The streams or flows
+    /// beyond what `sessions` permits are rejected, None means unlimited
+    pub(crate) async fn start_accepting(
         conn: &quinn::Connection,
         upstream_addr: Option<SocketAddr>,
         udp_timeout_ms: u64,
+        limiters: &RateLimiters,
+        datagrams: bool,
+        sessions: Option<&Arc<UdpSessions>>,
+        heartbeats: &DatagramHeartbeats,
     ) {
         let remote_addr = &conn.remote_address();
+        if datagrams {
+            match upstream_addr {
+                Some(upstream_addr) => {
+                    Self::accept_datagrams(
+                        conn,
+                        upstream_addr,
+                        udp_timeout_ms,
+                        limiters,
+                        sessions,
+                        heartbeats,
+                    )
+                    .await
+                }
+                None => error!("no upstream_addr to send the datagrams to: {remote_addr}"),
+            }
+            return;
+        }
         info!("start udp stream, {remote_addr} ↔  {upstream_addr:?}");
 
         loop {
@@ -161,8 +454,28 @@ impl UdpTunnel {
                     error!("failed to accept_bi: {remote_addr}, err: {e}");
                     break;
                 }
-                Ok((quic_send, quic_recv)) => tokio::spawn(async move {
-                    Self::process(quic_send, quic_recv, upstream_addr, udp_timeout_ms).await
+                Ok((mut quic_send, mut quic_recv)) => tokio::spawn({
+                    let session = match sessions.map(|sessions| sessions.try_open()) {
+                        Some(None) => {
+                            warn!("max udp sessions reached, reject the stream: {remote_addr}");
+                            quic_send.reset(VarInt::from_u32(0)).ok();
+                            quic_recv.stop(VarInt::from_u32(0)).ok();
+                            continue;
+                        }
+                        session => session.flatten(),
+                    };
+                    let limiters = limiters.clone();
+                    async move {
+                        let _session = session;
+                        Self::process(
+                            quic_send,
+                            quic_recv,
+                            upstream_addr,
+                            udp_timeout_ms,
+                            limiters,
+                        )
+                        .await
+                    }
                 }),
             };
         }
@@ -175,6 +488,7 @@ impl UdpTunnel {
         mut quic_recv: RecvStream,
         upstream_addr: Option<SocketAddr>,
         udp_timeout_ms: u64,
+        limiters: RateLimiters,
     ) -> Result<()> {
         let quic_send = Arc::new(Mutex::new(quic_send));
         let mut udp_socket = None;
@@ -184,6 +498,7 @@ impl UdpTunnel {
                 upstream_addr,
                 quic_send.clone(),
                 udp_timeout_ms,
+                limiters.tx.clone(),
             )
             .await?;
         }
@@ -219,6 +534,7 @@ impl UdpTunnel {
                                     peer_addr,
                                     quic_send.clone(),
                                     udp_timeout_ms,
+                                    limiters.tx.clone(),
                                 )
                                 .await?;
                             }
@@ -230,6 +546,7 @@ impl UdpTunnel {
                         }
                     };
 
+                    limiters.rx.acquire(packet_len as usize).await;
                     udp_socket
                         .as_ref()
                         .unwrap()
@@ -252,34 +569,189 @@ impl UdpTunnel {
         Ok::<(), anyhow::Error>(())
     }
 
-    async fn create_peer_socket_and_exchange_data(
-        addr: SocketAddr,
-        quic_send: Arc<Mutex<SendStream>>,
+    async fn accept_datagrams(
+        conn: &quinn::Connection,
+        upstream_addr: SocketAddr,
         udp_timeout_ms: u64,
-    ) -> Result<Option<(Arc<UdpSocket>, oneshot::Sender<()>)>> {
-        let local_addr = SocketAddr::new(IpAddr::V4(Ipv4Addr::UNSPECIFIED), 0);
-        match UdpSocket::bind(local_addr).await {
-            Ok(udp_socket) => {
-                if let Err(e) = udp_socket.connect(addr).await {
-                    log_and_bail!("failed to connect to upstream: {addr}, err: {e}");
-                };
+        limiters: &RateLimiters,
+        sessions: Option<&Arc<UdpSessions>>,
+        heartbeats: &DatagramHeartbeats,
+    ) {
+        let remote_addr = &conn.remote_address();
+        info!("start udp datagrams, {remote_addr} ↔  {upstream_addr}");
+
+        let upstream_flows: Arc<DashMap<u32, Arc<UpstreamFlow>>> = Arc::new(DashMap::new());
+        tokio::spawn({
+            let conn = conn.clone();
+            let upstream_flows = upstream_flows.clone();
+            async move {
+                let mut sweep = tokio::time::interval(Duration::from_millis(udp_timeout_ms.max(1)));
+                loop {
+                    tokio::select! {
+                        _ = conn.closed() => break,
+                        _ = sweep.tick() => upstream_flows.retain(|_, flow| {
+                            let active = flow.active.swap(false, Ordering::Relaxed);
+                            if !active {
+                                flow.evicted.notify_one();
+                            }
+                            active
+                        }),
+                    }
+                }
+            }
+        });
 
-                let (shutdown_tx, shutdown_rx) = oneshot::channel::<()>();
-                let udp_socket = Arc::new(udp_socket);
+        loop {
+            let datagram = match conn.read_datagram().await {
+                Ok(datagram) => datagram,
+                Err(quinn::ConnectionError::TimedOut) => {
+                    info!("connection timeout: {remote_addr}");
+                    break;
+                }
+                Err(quinn::ConnectionError::ApplicationClosed { .. }) => {
+                    debug!("connection closed: {remote_addr}");
+                    break;
+                }
+                Err(e) => {
+                    error!("failed to read datagram: {remote_addr}, err: {e}");
+                    break;
+                }
+            };
+            if heartbeat::is_heartbeat(&datagram) {
+                heartbeats.handle(conn, &datagram);
+                continue;
+            }
+            let Some((flow_id, payload)) = Self::parse_datagram(&datagram) else {
+                continue;
+            };
 
-                Self::udp_to_quic(
-                    udp_socket.clone(),
-                    quic_send.clone(),
-                    udp_timeout_ms,
-                    shutdown_rx,
-                );
+            let flow = match upstream_flows.get(&flow_id).map(|flow| flow.clone()) {
+                Some(flow) => flow,
+                None => {
+                    let session = match sessions.map(|sessions| sessions.try_open()) {
+                        Some(None) => {
+                            debug!(
+                                "max udp sessions reached, drop the datagram of flow: {flow_id}"
+                            );
+                            continue;
+                        }
+                        session => session.flatten(),
+                    };
+                    let udp_socket = match Self::connect_peer_socket(upstream_addr).await {
+                        Ok(udp_socket) => udp_socket,
+                        Err(e) => {
+                            error!("{e}");
+                            continue;
+                        }
+                    };
+                    let flow = Arc::new(UpstreamFlow {
+                        udp_socket,
+                        active: AtomicBool::new(true),
+                        evicted: Notify::new(),
+                        _session: session,
+                    });
+                    upstream_flows.insert(flow_id, flow.clone());
+                    Self::upstream_to_datagrams(
+                        conn.clone(),
+                        flow_id,
+                        flow.clone(),
+                        upstream_flows.clone(),
+                        limiters.tx.clone(),
+                    );
+                    flow
+                }
+            };
 
-                Ok(Some((udp_socket, shutdown_tx)))
+            flow.active.store(true, Ordering::Relaxed);
+            limiters.rx.acquire(payload.len()).await;
+            flow.udp_socket
+                .send(payload)
+                .await
+                .inspect_err(|e| warn!("failed to send datagram through udp_socket, err: {e}"))
+                .ok();
+        }
+
+        info!("connection for udp datagrams is dropped");
+    }
+
+    /// relays the packets of the upstream until the sweep evicts the flow
+    fn upstream_to_datagrams(
+        conn: Connection,
+        flow_id: u32,
+        flow: Arc<UpstreamFlow>,
+        upstream_flows: Arc<DashMap<u32, Arc<UpstreamFlow>>>,
+        tx: RateLimiter,
+    ) {
+        tokio::spawn(async move {
+            debug!(
+                "start udp flow {flow_id} →  {:?}",
+                flow.udp_socket.peer_addr()
+            );
+            let mut buf = BUFFER_POOL.alloc_and_fill(UDP_PACKET_SIZE);
+            loop {
+                tokio::select! {
+                    _ = conn.closed() => break,
+                    _ = flow.evicted.notified() => break,
+                    result = flow.udp_socket.recv(&mut buf) => {
+                        match result {
+                            Ok(len) => {
+                                flow.active.store(true, Ordering::Relaxed);
+                                tx.acquire(len).await;
+                                if let Err(quinn::SendDatagramError::ConnectionLost(_)) =
+                                    Self::send_datagram(&conn, flow_id, &buf[..len])
+                                {
+                                    break;
+                                }
+                            }
+                            Err(e) => {
+                                warn!("failed to receive datagrams from upstream, err: {e:?}");
+                                break;
+                            }
+                        }
+                    }
+                }
             }
+            // a new flow of the same id may have taken the place of the evicted one
+            upstream_flows.remove_if(&flow_id, |_, other| Arc::ptr_eq(other, &flow));
+            debug!(
+                "dropped udp flow {flow_id} →  {:?}",
+                flow.udp_socket.peer_addr()
+            );
+        });
+    }
+
+    async fn connect_peer_socket(addr: SocketAddr) -> Result<UdpSocket> {
+        let local_addr = SocketAddr::new(IpAddr::V4(Ipv4Addr::UNSPECIFIED), 0);
+        let udp_socket = match UdpSocket::bind(local_addr).await {
+            Ok(udp_socket) => udp_socket,
             Err(e) => {
                 log_and_bail!("failed to bind to localhost, err: {e}");
             }
-        }
+        };
+        if let Err(e) = udp_socket.connect(addr).await {
+            log_and_bail!("failed to connect to upstream: {addr}, err: {e}");
+        };
+        Ok(udp_socket)
+    }
+
+    async fn create_peer_socket_and_exchange_data(
+        addr: SocketAddr,
+        quic_send: Arc<Mutex<SendStream>>,
+        udp_timeout_ms: u64,
+        tx: RateLimiter,
+    ) -> Result<Option<(Arc<UdpSocket>, oneshot::Sender<()>)>> {
+        let udp_socket = Arc::new(Self::connect_peer_socket(addr).await?);
+        let (shutdown_tx, shutdown_rx) = oneshot::channel::<()>();
+
+        Self::udp_to_quic(
+            udp_socket.clone(),
+            quic_send.clone(),
+            udp_timeout_ms,
+            shutdown_rx,
+            tx,
+        );
+
+        Ok(Some((udp_socket, shutdown_tx)))
     }
 
     fn udp_to_quic(
@@ -287,6 +759,7 @@ impl UdpTunnel {
         quic_send: Arc<Mutex<SendStream>>,
         udp_timeout_ms: u64,
         mut shutdown_rx: oneshot::Receiver<()>,
+        tx: RateLimiter,
     ) {
         tokio::spawn(async move {
             debug!("start udp stream →  {:?}", udp_socket.peer_addr());
@@ -305,6 +778,7 @@ impl UdpTunnel {
                     ) => {
                         match result {
                             Ok(Ok(len)) => {
+                                tx.acquire(len).await;
                                 let mut quic_send = quic_send.lock().await;
                                 TunnelMessage::send_raw(&mut quic_send, &buf[..len])
                                     .await
@@ -0,0 +1,138 @@
+//! The QUIC streams of the TCP tunnels, wrapped in a streaming compressor and decompressor if
+//! both peers agreed on `Compression` at login. Only the tunneled bytes are compressed, the
+//! addresses sent ahead of them are not.
+
+use crate::Compression;
+#[cfg(feature = "compression")]
+use async_compression::tokio::{
+    bufread::{Lz4Decoder, ZstdDecoder},
+    write::{Lz4Encoder, ZstdEncoder},
+};
+use quinn::{RecvStream, SendStream, StreamId, VarInt};
+use std::io;
+#[cfg(feature = "compression")]
+use tokio::io::{AsyncReadExt, AsyncWriteExt, BufReader};
+
+pub(crate) enum QuicSend {
+    Plain(SendStream),
+    #[cfg(feature = "compression")]
+    Zstd(ZstdEncoder<SendStream>),
+    #[cfg(feature = "compression")]
+    Lz4(Lz4Encoder<SendStream>),
+}
+
+impl QuicSend {
+    /// sends `quic_send` uncompressed if the compression feature isn't built
+    pub(crate) fn new(quic_send: SendStream, compression: Compression) -> Self {
+        match compression {
+            #[cfg(feature = "compression")]
+            Compression::Zstd => Self::Zstd(ZstdEncoder::new(quic_send)),
+            #[cfg(feature = "compression")]
+            Compression::Lz4 => Self::Lz4(Lz4Encoder::new(quic_send)),
+            _ => Self::Plain(quic_send),
+        }
+    }
+
+    pub(crate) fn id(&self) -> StreamId {
+        match self {
+            Self::Plain(quic_send) => quic_send.id(),
+            #[cfg(feature = "compression")]
+            Self::Zstd(encoder) => encoder.get_ref().id(),
+            #[cfg(feature = "compression")]
+            Self::Lz4(encoder) => encoder.get_ref().id(),
+        }
+    }
+
+    /// the compressor is flushed after each write, so the peer gets the bytes without waiting
+    /// for more, which interactive protocols rely on
+    pub(crate) async fn write_all(&mut self, buf: &[u8]) -> io::Result<()> {
+        match self {
+            Self::Plain(quic_send) => Ok(quic_send.write_all(buf).await?),
+            #[cfg(feature = "compression")]
+            Self::Zstd(encoder) => {
+                encoder.write_all(buf).await?;
+                encoder.flush().await
+            }
+            #[cfg(feature = "compression")]
+            Self::Lz4(encoder) => {
+                encoder.write_all(buf).await?;
+                encoder.flush().await
+            }
+        }
+    }
+
+    /// ends the stream, after the end of the compressed frame if it's compressed
+    pub(crate) async fn finish(&mut self) -> io::Result<()> {
+        match self {
+            Self::Plain(quic_send) => quic_send.finish().map_err(io::Error::other),
+            #[cfg(feature = "compression")]
+            Self::Zstd(encoder) => encoder.shutdown().await,
+            #[cfg(feature = "compression")]
+            Self::Lz4(encoder) => encoder.shutdown().await,
+        }
+    }
+
+    pub(crate) fn reset(&mut self) {
+        let quic_send = match self {
+            Self::Plain(quic_send) => quic_send,
+            #[cfg(feature = "compression")]
+            Self::Zstd(encoder) => encoder.get_mut(),
+            #[cfg(feature = "compression")]
+            Self::Lz4(encoder) => encoder.get_mut(),
+        };
+        quic_send.reset(VarInt::from_u32(0)).ok();
+    }
+}
+
+pub(crate) enum QuicRecv {
+    Plain(RecvStream),
+    #[cfg(feature = "compression")]
+    Zstd(ZstdDecoder<BufReader<RecvStream>>),
+    #[cfg(feature = "compression")]
+    Lz4(Lz4Decoder<BufReader<RecvStream>>),
+}
+
+impl QuicRecv {
+    /// receives `quic_recv` uncompressed if the compression feature isn't built
+    pub(crate) fn new(quic_recv: RecvStream, compression: Compression) -> Self {
+        match compression {
+            #[cfg(feature = "compression")]
+            Compression::Zstd => Self::Zstd(ZstdDecoder::new(BufReader::new(quic_recv))),
+            #[cfg(feature = "compression")]
+            Compression::Lz4 => Self::Lz4(Lz4Decoder::new(BufReader::new(quic_recv))),
+            _ => Self::Plain(quic_recv),
+        }
+    }
+
+    pub(crate) fn id(&self) -> StreamId {
+        match self {
+            Self::Plain(quic_recv) => quic_recv.id(),
+            #[cfg(feature = "compression")]
+            Self::Zstd(decoder) => decoder.get_ref().get_ref().id(),
+            #[cfg(feature = "compression")]
+            Self::Lz4(decoder) => decoder.get_ref().get_ref().id(),
+        }
+    }
+
+    /// None at the end of the stream
+    pub(crate) async fn read(&mut self, buf: &mut [u8]) -> io::Result<Option<usize>> {
+        match self {
+            Self::Plain(quic_recv) => Ok(quic_recv.read(buf).await?),
+            #[cfg(feature = "compression")]
+            Self::Zstd(decoder) => decoder.read(buf).await.map(|len| (len > 0).then_some(len)),
+            #[cfg(feature = "compression")]
+            Self::Lz4(decoder) => decoder.read(buf).await.map(|len| (len > 0).then_some(len)),
+        }
+    }
+
+    pub(crate) fn stop(&mut self) {
+        let quic_recv = match self {
+            Self::Plain(quic_recv) => quic_recv,
+            #[cfg(feature = "compression")]
+            Self::Zstd(decoder) => decoder.get_mut().get_mut(),
+            #[cfg(feature = "compression")]
+            Self::Lz4(decoder) => decoder.get_mut().get_mut(),
+        };
+        quic_recv.stop(VarInt::from_u32(0)).ok();
+    }
+}
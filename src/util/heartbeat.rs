@@ -0,0 +1,122 @@
+//! Heartbeats sent by the client as QUIC datagrams and echoed by the server, which measure the
+//! round trip the tunnels see, apart from the keep-alive pings of the transport. They take a
+//! flow id the UDP tunnels never reach, so a connection carrying UDP packets in datagrams too
+//! tells them apart.
+
+use log::debug;
+use quinn::Connection;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+pub(crate) const HEARTBEAT_FLOW_ID: u32 = u32::MAX;
+const NO_RTT: u64 = u64::MAX;
+
+/// whether `datagram` is a heartbeat rather than a UDP packet
+pub(crate) fn is_heartbeat(datagram: &[u8]) -> bool {
+    datagram.starts_with(&HEARTBEAT_FLOW_ID.to_be_bytes())
+}
+
+/// the heartbeats of a connection, each carries the time it was sent, which comes back in the
+/// echo. `unanswered` counts those sent since the latest echo, so it keeps growing once the
+/// server no longer answers, while a slow but working tunnel only sees the RTT rise
+pub(crate) struct HeartbeatProbe {
+    started: Instant,
+    rtt_us: AtomicU64,
+    unanswered: AtomicU64,
+}
+
+impl HeartbeatProbe {
+    pub(crate) fn new() -> Self {
+        Self {
+            started: Instant::now(),
+            rtt_us: AtomicU64::new(NO_RTT),
+            unanswered: AtomicU64::new(0),
+        }
+    }
+
+    /// sends a heartbeat every `interval` until the connection closes
+    pub(crate) async fn run(self: Arc<Self>, conn: Connection, interval: Duration) {
+        let mut interval = tokio::time::interval(interval);
+        interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Skip);
+        loop {
+            tokio::select! {
+                _ = conn.closed() => break,
+                _ = interval.tick() => {
+                    if let Err(e) = self.send(&conn) {
+                        debug!("failed to send heartbeat: {e}");
+                    }
+                }
+            }
+        }
+    }
+
+    fn send(&self, conn: &Connection) -> Result<(), quinn::SendDatagramError> {
+        let sent_us = self.started.elapsed().as_micros() as u64;
+        let mut datagram = Vec::with_capacity(12);
+        datagram.extend_from_slice(&HEARTBEAT_FLOW_ID.to_be_bytes());
+        datagram.extend_from_slice(&sent_us.to_be_bytes());
+        conn.send_datagram(datagram.into())?;
+        self.unanswered.fetch_add(1, Ordering::Relaxed);
+        Ok(())
+    }
+
+    fn on_echo(&self, datagram: &[u8]) {
+        let Some(sent_us) = datagram
+            .get(4..12)
+            .and_then(|sent_us| sent_us.try_into().ok())
+            .map(u64::from_be_bytes)
+        else {
+            debug!("malformed heartbeat of {} bytes", datagram.len());
+            return;
+        };
+        let now_us = self.started.elapsed().as_micros() as u64;
+        self.rtt_us
+            .store(now_us.saturating_sub(sent_us), Ordering::Relaxed);
+        self.unanswered.store(0, Ordering::Relaxed);
+    }
+
+    /// RTT of the latest echo, None until the first one
+    pub(crate) fn rtt_ms(&self) -> Option<u64> {
+        let rtt_us = self.rtt_us.load(Ordering::Relaxed);
+        (rtt_us != NO_RTT).then_some(rtt_us / 1000)
+    }
+
+    pub(crate) fn unanswered(&self) -> u64 {
+        self.unanswered.load(Ordering::Relaxed)
+    }
+}
+
+/// what the reader of the datagrams of a connection does with the heartbeats among them
+#[derive(Clone)]
+pub(crate) enum DatagramHeartbeats {
+    /// they're dropped, the client didn't ask for them
+    None,
+    /// the server sends them back as they are
+    Echo,
+    /// the client takes the RTT of the echoes
+    Measure(Arc<HeartbeatProbe>),
+}
+
+impl DatagramHeartbeats {
+    pub(crate) fn handle(&self, conn: &Connection, datagram: &[u8]) {
+        match self {
+            Self::None => {}
+            Self::Echo => {
+                if let Err(e) = conn.send_datagram(datagram.to_vec().into()) {
+                    debug!("failed to echo heartbeat: {e}");
+                }
+            }
+            Self::Measure(probe) => probe.on_echo(datagram),
+        }
+    }
+
+    /// reads the datagrams of a connection that carries no UDP packets in them until it closes
+    pub(crate) async fn read(self, conn: Connection) {
+        while let Ok(datagram) = conn.read_datagram().await {
+            if is_heartbeat(&datagram) {
+                self.handle(&conn, &datagram);
+            }
+        }
+    }
+}
@@ -1 +1,4 @@
+pub(crate) mod compression;
+pub(crate) mod heartbeat;
+pub mod rate_limiter;
 pub mod stream_util;
@@ -0,0 +1,87 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::time::Instant;
+
+/// Token bucket shared by all the tunnels of a client in one direction, bursts of up to one
+/// second worth of traffic pass right away. The transferred bytes are counted even if unlimited.
+#[derive(Clone, Default)]
+pub struct RateLimiter {
+    bucket: Option<Arc<Mutex<Bucket>>>,
+    transferred_bytes: Arc<AtomicU64>,
+}
+
+struct Bucket {
+    bytes_per_sec: f64,
+    tokens: f64,
+    refilled_at: Instant,
+}
+
+impl RateLimiter {
+    /// 0 means unlimited
+    pub fn new(max_bps: u64) -> Self {
+        let bucket = (max_bps > 0).then(|| {
+            let bytes_per_sec = max_bps as f64 / 8.0;
+            Arc::new(Mutex::new(Bucket {
+                bytes_per_sec,
+                tokens: bytes_per_sec,
+                refilled_at: Instant::now(),
+            }))
+        });
+
+        Self {
+            bucket,
+            transferred_bytes: Arc::new(AtomicU64::new(0)),
+        }
+    }
+
+    /// waits until `bytes` can be sent without exceeding the limit, the tokens are taken right
+    /// away, so concurrent callers queue up behind each other
+    pub async fn acquire(&self, bytes: usize) {
+        self.transferred_bytes
+            .fetch_add(bytes as u64, Ordering::Relaxed);
+
+        let Some(bucket) = &self.bucket else {
+            return;
+        };
+
+        let wait = {
+            let mut bucket = bucket.lock().unwrap();
+            let now = Instant::now();
+            let elapsed = now.duration_since(bucket.refilled_at).as_secs_f64();
+            bucket.tokens = (bucket.tokens + elapsed * bucket.bytes_per_sec)
+                .min(bucket.bytes_per_sec)
+                - bytes as f64;
+            bucket.refilled_at = now;
+            if bucket.tokens < 0.0 {
+                Duration::from_secs_f64(-bucket.tokens / bucket.bytes_per_sec)
+            } else {
+                Duration::ZERO
+            }
+        };
+
+        if !wait.is_zero() {
+            tokio::time::sleep(wait).await;
+        }
+    }
+
+    pub fn transferred_bytes(&self) -> u64 {
+        self.transferred_bytes.load(Ordering::Relaxed)
+    }
+}
+
+/// tx is the traffic sent into the tunnel by this side, rx the traffic coming out of it
+#[derive(Clone, Default)]
+pub struct RateLimiters {
+    pub tx: RateLimiter,
+    pub rx: RateLimiter,
+}
+
+impl RateLimiters {
+    pub fn new(max_tx_bps: u64, max_rx_bps: u64) -> Self {
+        Self {
+            tx: RateLimiter::new(max_tx_bps),
+            rx: RateLimiter::new(max_rx_bps),
+        }
+    }
+}
@@ -1,10 +1,14 @@
-use crate::tcp::AsyncStream;
+use crate::otel::OtelSpan;
+use crate::tcp::{AsyncStream, Destination, StreamActivity};
+use crate::util::compression::{QuicRecv, QuicSend};
+use crate::util::rate_limiter::{RateLimiter, RateLimiters};
 use crate::BUFFER_POOL;
-use anyhow::Result;
+use anyhow::{bail, Result};
 use log::debug;
 use quinn::{RecvStream, SendStream};
 use std::fmt::Display;
 use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::sync::Arc;
 use std::time::Duration;
 use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadHalf, WriteHalf};
 use tokio::sync::oneshot;
@@ -15,7 +19,10 @@ pub enum TransferError {
     InternalError,
     InvalidIPAddress,
     InvalidIPFamily,
+    InvalidDomain,
     TimeoutError,
+    /// closed by the idle stream sweep, see `IdleStreams`
+    ReapedError,
 }
 
 impl Display for TransferError {
@@ -24,7 +31,9 @@ impl Display for TransferError {
             Self::InternalError => write!(f, "InternalError"),
             Self::InvalidIPAddress => write!(f, "InvalidIPAddress"),
             Self::InvalidIPFamily => write!(f, "InvalidIPFamily"),
+            Self::InvalidDomain => write!(f, "InvalidDomain"),
             Self::TimeoutError => write!(f, "TimeoutError"),
+            Self::ReapedError => write!(f, "ReapedError"),
         }
     }
 }
@@ -32,11 +41,15 @@ impl Display for TransferError {
 pub struct StreamUtil {}
 
 impl StreamUtil {
+    /// `activity` tracks the stream for the idle sweep, which resets it once idle
     pub fn start_flowing<S: AsyncStream>(
         tag: &'static str,
         stream: S,
-        quic_stream: (SendStream, RecvStream),
+        quic_stream: (QuicSend, QuicRecv),
         stream_timeout_ms: u64,
+        flush_coalesce_ms: u64,
+        limiters: RateLimiters,
+        activity: Option<Arc<StreamActivity>>,
     ) {
         let peer_addr = match stream.peer_addr() {
             Ok(addr) => addr,
@@ -46,11 +59,15 @@ impl StreamUtil {
             }
         };
 
+        let RateLimiters { tx, rx } = limiters;
         let (mut stream_read, mut stream_write) = tokio::io::split(stream);
         let (mut quic_send, mut quic_recv) = quic_stream;
         let index = quic_send.id().index();
 
         debug!("[{tag}] START {index:<3} →  {peer_addr:<20}");
+        let span = Arc::new(Self::start_stream_span(tag, index, peer_addr));
+        let span2 = span.clone();
+        let activity2 = activity.clone();
 
         let (quic_to_stream_tx, quic_to_stream_rx) = oneshot::channel::<()>();
         let (stream_to_quic_tx, stream_to_quic_rx) = oneshot::channel::<()>();
@@ -60,16 +77,24 @@ impl StreamUtil {
             let mut transfer_bytes = 0u64;
             let mut buffer = BUFFER_POOL.alloc_and_fill(BUFFER_SIZE);
             loop {
-                let result = Self::quic_to_stream(
-                    &mut quic_recv,
-                    &mut stream_write,
-                    &mut buffer,
-                    &mut transfer_bytes,
-                    stream_timeout_ms,
-                )
-                .await;
+                let result = tokio::select! {
+                    result = Self::quic_to_stream(
+                        &mut quic_recv,
+                        &mut stream_write,
+                        &mut buffer,
+                        &mut transfer_bytes,
+                        stream_timeout_ms,
+                        &rx,
+                    ) => result,
+                    _ = Self::reaped(activity.as_deref()) => Err(TransferError::ReapedError),
+                };
 
                 match result {
+                    Err(TransferError::ReapedError) => {
+                        quic_recv.stop();
+                        let _ = quic_to_stream_tx.send(());
+                        break;
+                    }
                     Err(TransferError::TimeoutError) => {
                         let _ = quic_to_stream_tx.send(());
                         match stream_to_quic_rx.await {
@@ -84,28 +109,40 @@ impl StreamUtil {
                         break;
                     }
                     _ => {
-                        // ok, continue
+                        if let Some(activity) = &activity {
+                            activity.touch();
+                        }
                     }
                 }
             }
 
             debug!("[{tag}] END  {index:<5}→  {peer_addr}, {transfer_bytes} bytes");
+            span.set_u64("bytes.received", transfer_bytes);
         });
 
         tokio::spawn(async move {
             let mut transfer_bytes = 0u64;
             let mut buffer = BUFFER_POOL.alloc_and_fill(BUFFER_SIZE);
             loop {
-                let result = Self::stream_to_quic(
-                    &mut stream_read,
-                    &mut quic_send,
-                    &mut buffer,
-                    &mut transfer_bytes,
-                    stream_timeout_ms,
-                )
-                .await;
+                let result = tokio::select! {
+                    result = Self::stream_to_quic(
+                        &mut stream_read,
+                        &mut quic_send,
+                        &mut buffer,
+                        &mut transfer_bytes,
+                        stream_timeout_ms,
+                        flush_coalesce_ms,
+                        &tx,
+                    ) => result,
+                    _ = Self::reaped(activity2.as_deref()) => Err(TransferError::ReapedError),
+                };
 
                 match result {
+                    Err(TransferError::ReapedError) => {
+                        quic_send.reset();
+                        let _ = stream_to_quic_tx.send(());
+                        break;
+                    }
                     Err(TransferError::TimeoutError) => {
                         let _ = stream_to_quic_tx.send(());
                         match quic_to_stream_rx.await {
@@ -120,32 +157,169 @@ impl StreamUtil {
                         break;
                     }
                     _ => {
-                        // ok, continue
+                        if let Some(activity) = &activity2 {
+                            activity.touch();
+                        }
                     }
                 }
             }
 
             debug!("[{tag}] END  {index:<4}←  {peer_addr}, {transfer_bytes} bytes");
+            span2.set_u64("bytes.sent", transfer_bytes);
             Ok::<(), anyhow::Error>(())
         });
     }
 
+    /// forwards the stream to a unidirectional quic stream, nothing flows back
+    pub fn start_sending<S: AsyncStream>(
+        tag: &'static str,
+        stream: S,
+        mut quic_send: QuicSend,
+        stream_timeout_ms: u64,
+        flush_coalesce_ms: u64,
+        tx: RateLimiter,
+        activity: Option<Arc<StreamActivity>>,
+    ) {
+        let peer_addr = match stream.peer_addr() {
+            Ok(addr) => addr,
+            Err(e) => {
+                log::error!("failed to obtain peer address:{e}");
+                return;
+            }
+        };
+
+        let (mut stream_read, _) = tokio::io::split(stream);
+        let index = quic_send.id().index();
+
+        debug!("[{tag}] START {index:<3} ⇢  {peer_addr:<20}");
+        let span = Self::start_stream_span(tag, index, peer_addr);
+
+        tokio::spawn(async move {
+            let mut transfer_bytes = 0u64;
+            let mut buffer = BUFFER_POOL.alloc_and_fill(8192);
+            loop {
+                let result = tokio::select! {
+                    result = Self::stream_to_quic(
+                        &mut stream_read,
+                        &mut quic_send,
+                        &mut buffer,
+                        &mut transfer_bytes,
+                        stream_timeout_ms,
+                        flush_coalesce_ms,
+                        &tx,
+                    ) => result,
+                    _ = Self::reaped(activity.as_deref()) => Err(TransferError::ReapedError),
+                };
+
+                match result {
+                    Ok(1..) => {
+                        if let Some(activity) = &activity {
+                            activity.touch();
+                        }
+                    }
+                    Err(TransferError::ReapedError) => {
+                        quic_send.reset();
+                        break;
+                    }
+                    _ => break,
+                }
+            }
+
+            debug!("[{tag}] END  {index:<4}⇠  {peer_addr}, {transfer_bytes} bytes");
+            span.set_u64("bytes.sent", transfer_bytes);
+        });
+    }
+
+    /// forwards a unidirectional quic stream to the stream, nothing flows back
+    pub fn start_receiving<S: AsyncStream>(
+        tag: &'static str,
+        stream: S,
+        mut quic_recv: QuicRecv,
+        stream_timeout_ms: u64,
+        rx: RateLimiter,
+    ) {
+        let peer_addr = match stream.peer_addr() {
+            Ok(addr) => addr,
+            Err(e) => {
+                log::error!("failed to obtain peer address:{e}");
+                return;
+            }
+        };
+
+        let (_, mut stream_write) = tokio::io::split(stream);
+        let index = quic_recv.id().index();
+
+        debug!("[{tag}] START {index:<3} ⇢  {peer_addr:<20}");
+        let span = Self::start_stream_span(tag, index, peer_addr);
+
+        tokio::spawn(async move {
+            let mut transfer_bytes = 0u64;
+            let mut buffer = BUFFER_POOL.alloc_and_fill(8192);
+            while let Ok(1..) = Self::quic_to_stream(
+                &mut quic_recv,
+                &mut stream_write,
+                &mut buffer,
+                &mut transfer_bytes,
+                stream_timeout_ms,
+                &rx,
+            )
+            .await
+            {}
+
+            debug!("[{tag}] END  {index:<5}⇢  {peer_addr}, {transfer_bytes} bytes");
+            span.set_u64("bytes.received", transfer_bytes);
+        });
+    }
+
+    /// never resolves for the streams not tracked by the idle sweep
+    async fn reaped(activity: Option<&StreamActivity>) {
+        match activity {
+            Some(activity) => activity.reaped().await,
+            None => std::future::pending().await,
+        }
+    }
+
+    fn start_stream_span(tag: &'static str, index: u64, peer_addr: SocketAddr) -> OtelSpan {
+        let span = OtelSpan::start("rstun.stream");
+        span.set_attr("stream.tag", tag);
+        span.set_u64("stream.id", index);
+        span.set_attr("peer.addr", peer_addr);
+        span
+    }
+
     async fn stream_to_quic<S: AsyncRead + AsyncWrite + Unpin + Send + 'static>(
         stream_read: &mut ReadHalf<S>,
-        quic_send: &mut SendStream,
+        quic_send: &mut QuicSend,
         buffer: &mut [u8],
         transfer_bytes: &mut u64,
         stream_timeout_ms: u64,
+        flush_coalesce_ms: u64,
+        limiter: &RateLimiter,
     ) -> Result<usize, TransferError> {
-        let len_read = tokio::time::timeout(
+        let mut len_read = tokio::time::timeout(
             Duration::from_millis(stream_timeout_ms),
             stream_read.read(buffer),
         )
         .await
         .map_err(|_: Elapsed| TransferError::TimeoutError)?
         .map_err(|_| TransferError::InternalError)?;
+
+        // coalesce small reads into one write at the cost of up to flush_coalesce_ms of latency,
+        // EOF and errors are left to the next read
+        if flush_coalesce_ms > 0 && len_read > 0 {
+            let deadline = tokio::time::Instant::now() + Duration::from_millis(flush_coalesce_ms);
+            while len_read < buffer.len() {
+                match tokio::time::timeout_at(deadline, stream_read.read(&mut buffer[len_read..]))
+                    .await
+                {
+                    Ok(Ok(len)) if len > 0 => len_read += len,
+                    _ => break,
+                }
+            }
+        }
         if len_read > 0 {
             *transfer_bytes += len_read as u64;
+            limiter.acquire(len_read).await;
             quic_send
                 .write_all(&buffer[..len_read])
                 .await
@@ -154,17 +328,19 @@ impl StreamUtil {
         } else {
             quic_send
                 .finish()
+                .await
                 .map_err(|_| TransferError::InternalError)?;
             Ok(0)
         }
     }
 
     async fn quic_to_stream<S: AsyncRead + AsyncWrite + Unpin + Send + 'static>(
-        quic_recv: &mut RecvStream,
+        quic_recv: &mut QuicRecv,
         stream_write: &mut WriteHalf<S>,
         buffer: &mut [u8],
         transfer_bytes: &mut u64,
         stream_timeout_ms: u64,
+        limiter: &RateLimiter,
     ) -> Result<usize, TransferError> {
         let result = tokio::time::timeout(
             Duration::from_millis(stream_timeout_ms),
@@ -175,6 +351,7 @@ impl StreamUtil {
         .map_err(|_| TransferError::InternalError)?;
         if let Some(len_read) = result {
             *transfer_bytes += len_read as u64;
+            limiter.acquire(len_read).await;
             stream_write
                 .write_all(&buffer[..len_read])
                 .await
@@ -218,42 +395,85 @@ impl StreamUtil {
         Ok(())
     }
 
+    /// a domain name goes with the family 3 and its length ahead of it, the addresses as for
+    /// `write_socket_addr`
+    pub async fn write_destination(
+        quic_send: &mut SendStream,
+        destination: &Destination,
+    ) -> Result<()> {
+        match destination {
+            Destination::Addr(addr) => {
+                Self::write_socket_addr(quic_send, &Some(*addr), false).await
+            }
+            Destination::Domain(domain, port) => {
+                let Ok(len) = u8::try_from(domain.len()) else {
+                    bail!("domain name exceeds 255 bytes: {domain}");
+                };
+                let mut buf = Vec::with_capacity(1 + 1 + domain.len() + 2);
+                buf.extend_from_slice(&[3, len]);
+                buf.extend_from_slice(domain.as_bytes());
+                buf.extend_from_slice(&port.to_be_bytes());
+                quic_send.write_all(&buf).await?;
+                Ok(())
+            }
+        }
+    }
+
     pub async fn read_socket_addr(
         quic_recv: &mut RecvStream,
         stream_timeout_ms: u64,
     ) -> Result<SocketAddr, TransferError> {
-        let mut buf = [0u8; 19];
-        tokio::time::timeout(
-            Duration::from_millis(stream_timeout_ms),
-            quic_recv.read_exact(&mut buf[..7]),
-        )
-        .await
-        .map_err(|_: Elapsed| TransferError::TimeoutError)?
-        .map_err(|_| TransferError::InternalError)?;
+        match Self::read_destination(quic_recv, stream_timeout_ms).await? {
+            Destination::Addr(addr) => Ok(addr),
+            Destination::Domain(..) => {
+                log::error!("unexpected domain name in place of an address");
+                Err(TransferError::InvalidIPFamily)
+            }
+        }
+    }
 
-        match buf[0] {
+    pub async fn read_destination(
+        quic_recv: &mut RecvStream,
+        stream_timeout_ms: u64,
+    ) -> Result<Destination, TransferError> {
+        let mut family = [0u8; 1];
+        Self::read_exact(quic_recv, &mut family, stream_timeout_ms).await?;
+        match family[0] {
             4 => {
+                let mut buf = [0u8; 4 + 2];
+                Self::read_exact(quic_recv, &mut buf, stream_timeout_ms).await?;
                 let ip = Ipv4Addr::from(
-                    <[u8; 4]>::try_from(&buf[1..5]).map_err(|_| TransferError::InvalidIPAddress)?,
+                    <[u8; 4]>::try_from(&buf[..4]).map_err(|_| TransferError::InvalidIPAddress)?,
                 );
-                let port = u16::from_be_bytes(buf[5..7].try_into().unwrap());
-                Ok(SocketAddr::new(ip.into(), port))
+                let port = u16::from_be_bytes([buf[4], buf[5]]);
+                Ok(Destination::Addr(SocketAddr::new(ip.into(), port)))
             }
             6 => {
-                tokio::time::timeout(
-                    Duration::from_millis(stream_timeout_ms),
-                    quic_recv.read_exact(&mut buf[7..]),
-                )
-                .await
-                .map_err(|_: Elapsed| TransferError::TimeoutError)?
-                .map_err(|_| TransferError::InternalError)?;
-
+                let mut buf = [0u8; 16 + 2];
+                Self::read_exact(quic_recv, &mut buf, stream_timeout_ms).await?;
                 let ip = Ipv6Addr::from(
-                    <[u8; 16]>::try_from(&buf[1..17])
+                    <[u8; 16]>::try_from(&buf[..16])
                         .map_err(|_| TransferError::InvalidIPAddress)?,
                 );
-                let port = u16::from_be_bytes(buf[17..19].try_into().unwrap());
-                Ok(SocketAddr::new(ip.into(), port))
+                let port = u16::from_be_bytes([buf[16], buf[17]]);
+                Ok(Destination::Addr(SocketAddr::new(ip.into(), port)))
+            }
+            3 => {
+                let mut len = [0u8; 1];
+                Self::read_exact(quic_recv, &mut len, stream_timeout_ms).await?;
+                let mut buf = vec![0u8; len[0] as usize + 2];
+                Self::read_exact(quic_recv, &mut buf, stream_timeout_ms).await?;
+                let (domain, port) = buf.split_at(len[0] as usize);
+                match std::str::from_utf8(domain) {
+                    Ok(domain) if !domain.is_empty() => Ok(Destination::Domain(
+                        domain.to_string(),
+                        u16::from_be_bytes([port[0], port[1]]),
+                    )),
+                    _ => {
+                        log::error!("invalid domain name");
+                        Err(TransferError::InvalidDomain)
+                    }
+                }
             }
             _ => {
                 log::error!("invalid address family");
@@ -261,4 +481,18 @@ impl StreamUtil {
             }
         }
     }
+
+    async fn read_exact(
+        quic_recv: &mut RecvStream,
+        buf: &mut [u8],
+        stream_timeout_ms: u64,
+    ) -> Result<(), TransferError> {
+        tokio::time::timeout(
+            Duration::from_millis(stream_timeout_ms),
+            quic_recv.read_exact(buf),
+        )
+        .await
+        .map_err(|_: Elapsed| TransferError::TimeoutError)?
+        .map_err(|_| TransferError::InternalError)
+    }
 }